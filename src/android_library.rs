@@ -1,29 +1,102 @@
-use crate::sysv64;
+use crate::{sysv64, sysv64_type};
 use anyhow::Result;
-use log::{debug, info};
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
 use memmap2::{MmapOptions, MmapMut};
 use region::Protection;
 use std::cmp::max;
 use std::collections::HashMap;
 use std::error::Error;
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::ops::{Deref, DerefMut, Range};
+use std::path::Path;
 use std::fmt::{Display, Formatter};
-use std::{fs, slice};
-use std::os::raw::{c_char, c_void};
+use std::slice;
+use std::time::Instant;
+use std::os::raw::{c_char, c_int, c_void};
+use std::sync::{Arc, Mutex};
 use std::ptr::null_mut;
 use xmas_elf::ElfFile;
-use xmas_elf::program::{ProgramHeader, Type};
-use xmas_elf::sections::{SectionData, ShType};
-use xmas_elf::symbol_table::Entry;
+use xmas_elf::header;
+use xmas_elf::program::Type;
+use xmas_elf::sections::{SectionData, SectionHeader, ShType, SHN_XINDEX};
+use xmas_elf::symbol_table::{self, Entry};
 use zero::read_str;
 
-use crate::hook_manager::get_hooks;
+use crate::android_loader::{AndroidLoader, DlopenAction, ProgressCallback, ProtectionPolicy, RelocationPolicy, ResolutionStep, ResolveMode};
+use crate::call_trace::{self, CallTraces};
+use crate::caller::{caller_entry, CallerStubs};
+#[cfg(feature = "demangle")]
+use crate::demangle;
+use crate::dependencies::DependencyGroup;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use crate::dynamic_call::{self, Arg, ReturnType, Value};
+use crate::library_info::{self, DynamicEntry, ProgramHeader, RelocationEntry};
+use crate::mapping_pool::MappingPool;
+use crate::hook_manager;
+use crate::initializers::{self, InitCallback, ProgramArguments};
+use crate::protections::{self, ProtectionSource};
+use crate::registry;
+use crate::stats::{LoadStats, MissingImports, SymbolSource};
 use crate::relocation_types::{RelocationType, RelocType};
+use crate::stubs;
+use crate::tls;
+use crate::versions::{self, SymbolVersion};
+use crate::lazy_binding::{self, LazyBindings, LazyScope};
+use crate::undefined_symbols::UndefinedSymbols;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+use crate::write_watch::{self, WatchedWrite, WriteWatch};
+
+const PT_LOAD: u32 = 1;
+const PT_GNU_STACK: u32 = 0x6474_e551;
+const PT_ARM_EXIDX: u32 = 0x7000_0001;
+/// Size of an `.ARM.exidx` entry
+const EXIDX_ENTRY_SIZE: usize = 8;
+const DT_PLTGOT: u64 = 3;
+const DT_RELRSZ: u64 = 35;
+const DT_RELR: u64 = 36;
+/// What Android used for `DT_RELR` and `DT_RELRSZ` before they were standardized
+const DT_ANDROID_RELR: u64 = 0x6fff_e000;
+const DT_ANDROID_RELRSZ: u64 = 0x6fff_e001;
+const EI_CLASS: usize = 4;
+const EI_DATA: usize = 5;
+#[cfg(target_pointer_width = "32")]
+const HOST_CLASS: u8 = 1;
+#[cfg(target_pointer_width = "64")]
+const HOST_CLASS: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ELFDATA2MSB: u8 = 2;
+
+/// Whether imports fall back to the built-in stubs. They're still compiled without the
+/// feature, just never handed out.
+const BUILTIN_STUBS: bool = cfg!(feature = "builtin-stubs");
+
+/// Relocations applied between two progress reports
+pub const PROGRESS_INTERVAL: usize = 1024;
+
+struct Progress<'l> {
+    callback: Option<&'l ProgressCallback>,
+    done: usize,
+    total: usize,
+}
+
+impl Progress<'_> {
+    fn advance(&mut self) -> Result<()> {
+        self.done += 1;
+        if let Some(callback) = self.callback {
+            if (self.done % PROGRESS_INTERVAL == 0 || self.done == self.total) && callback(self.done, self.total).is_break() {
+                return Err(AndroidLoaderErr::Cancelled.into());
+            }
+        }
+        Ok(())
+    }
+}
 
 #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-type DynEntry = xmas_elf::symbol_table::DynEntry64;
+pub(crate) type DynEntry = xmas_elf::symbol_table::DynEntry64;
 #[cfg(any(target_arch = "x86", target_arch = "arm"))]
-type DynEntry = xmas_elf::symbol_table::DynEntry32;
+pub(crate) type DynEntry = xmas_elf::symbol_table::DynEntry32;
 
 // GnuHashTable adapted from goblin code
 
@@ -109,39 +182,366 @@ impl<'a> GnuHashTable<'a> {
 }
 
 pub struct AndroidLibrary<'a> {
-    pub(crate) file: Box<Vec<u8>>,
-    pub(crate) memory_map: MmapMut,
+    pub(crate) file: Vec<u8>,
+    /// The file as given to the loader, if a preprocessor unwrapped it into `file`
+    pub(crate) original: Option<Vec<u8>>,
+    /// Whether [`AndroidLoader::retain_original_bytes`] was set
+    pub(crate) retains_original: bool,
+    pub(crate) memory_map: Image<'a>,
     pub(crate) dyn_symbols: &'a [DynEntry],
+    /// Section indices of the symbols with `SHN_XINDEX`, empty without a `SHT_SYMTAB_SHNDX`
+    extended_indices: &'a [u32],
     pub(crate) dyn_strs: &'a [u8],
-    pub(crate) gnu_hash_table: Option<GnuHashTable<'a>>
+    pub(crate) gnu_hash_table: Option<GnuHashTable<'a>>,
+    pub(crate) tls_module: Option<usize>,
+    pub(crate) undefined_symbols: UndefinedSymbols,
+    pub(crate) caller_stubs: CallerStubs,
+    /// Stubs of the `JUMP_SLOT`s bound on their first call
+    lazy_bindings: Option<LazyBindings>,
+    /// Stubs of the `JUMP_SLOT`s whose calls are traced
+    call_traces: Option<CallTraces>,
+    pub(crate) registry_id: usize,
+    pub(crate) executable_stack: bool,
+    /// Offset and size of the `PT_ARM_EXIDX` table
+    arm_exidx: Option<(usize, usize)>,
+    pub(crate) stats: LoadStats,
+    pub(crate) soname: Option<String>,
+    /// Dependencies this load brought in, dropped after the library itself
+    pub(crate) dependencies: Option<Arc<DependencyGroup>>,
+    /// `e_entry`, relative to the load base
+    entry: Option<usize>,
+    /// `PT_INTERP` of a position-independent executable
+    interpreter: Option<String>,
+    program_headers: Vec<ProgramHeader>,
+    dynamic_entries: Vec<DynamicEntry>,
+    build_id: Option<Vec<u8>>,
+    /// `_GLOBAL_OFFSET_TABLE_`, relative to the load base
+    global_offset_table: Option<usize>,
+    segments: Vec<Segment>,
+    /// Pages of `PT_GNU_RELRO` made read-only once relocated, relative to the load base
+    relro: Option<Range<usize>>,
+    /// Bytes relocation changed from the segments' file contents, as (offset, bytes) runs
+    relocated: Vec<(usize, Vec<u8>)>,
+    /// Names of the dynamic symbols as decoded by the loader, if it decodes them
+    decoded_names: Option<Vec<String>>,
+    /// Whether the initializers were run, so the finalizers are run on drop
+    pub(crate) initialized: bool,
+    pub(crate) on_fini: Option<Arc<InitCallback>>,
+    program_arguments: Option<Arc<ProgramArguments>>,
+}
+
+/// The memory a library is mapped in
+pub(crate) enum Image<'a> {
+    Mapped(MmapMut),
+    /// Given to [`AndroidLoader::load_into`], made writable again when the library is dropped
+    Buffer(&'a mut [u8]),
+    /// Taken from a [`MappingPool`], of which the image is the first `len` bytes, and given
+    /// back when the library is dropped
+    Pooled { mapping: Option<MmapMut>, len: usize, pool: Arc<MappingPool> },
+    /// `len` bytes after `guard` inaccessible ones, with as many after them
+    Guarded { mapping: MmapMut, guard: usize, len: usize },
+    /// Mapped at [`AndroidLoader::base_address`], unmapped when the library is dropped
+    Fixed { address: usize, len: usize },
+}
+
+impl Image<'_> {
+    /// A zeroed, read-write mapping of `len` bytes at exactly `address`, failing with
+    /// [`AndroidLoaderErr::BaseUnavailable`] if that's not free
+    fn map_fixed(address: usize, len: usize) -> Result<Image<'static>> {
+        if address % region::page::size() != 0 {
+            return Err(AndroidLoaderErr::BaseUnavailable { address }.into());
+        }
+        // Kernels before 4.17 take MAP_FIXED_NOREPLACE as a hint, like other systems do the address
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED_NOREPLACE;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+        let mapped = unsafe { libc::mmap(address as *mut c_void, len, libc::PROT_READ | libc::PROT_WRITE, flags, -1, 0) };
+        if mapped == libc::MAP_FAILED {
+            return Err(AndroidLoaderErr::BaseUnavailable { address }.into());
+        }
+        if mapped as usize != address {
+            unsafe { libc::munmap(mapped, len) };
+            return Err(AndroidLoaderErr::BaseUnavailable { address }.into());
+        }
+        Ok(Image::Fixed { address, len })
+    }
+}
+
+impl Deref for Image<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Image::Mapped(map) => map,
+            Image::Buffer(buffer) => buffer,
+            Image::Pooled { mapping, len, .. } => &mapping.as_ref().unwrap()[..*len],
+            Image::Guarded { mapping, guard, len } => &mapping[*guard..*guard + *len],
+            Image::Fixed { address, len } => unsafe { slice::from_raw_parts(*address as *const u8, *len) },
+        }
+    }
+}
+
+impl DerefMut for Image<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Image::Mapped(map) => map,
+            Image::Buffer(buffer) => buffer,
+            Image::Pooled { mapping, len, .. } => &mut mapping.as_mut().unwrap()[..*len],
+            Image::Guarded { mapping, guard, len } => &mut mapping[*guard..*guard + *len],
+            Image::Fixed { address, len } => unsafe { slice::from_raw_parts_mut(*address as *mut u8, *len) },
+        }
+    }
+}
+
+impl Drop for Image<'_> {
+    fn drop(&mut self) {
+        match self {
+            Image::Buffer(buffer) => {
+                if let Err(err) = unsafe { protections::protect(buffer.as_ptr(), buffer.len(), Protection::READ_WRITE, ProtectionSource::Loader) } {
+                    warn!("Couldn't make the buffer writable again: {err}");
+                }
+            }
+            Image::Pooled { mapping, pool, .. } => pool.release(mapping.take().unwrap()),
+            Image::Fixed { address, len } => unsafe {
+                libc::munmap(*address as *mut c_void, *len);
+            },
+            Image::Mapped(_) | Image::Guarded { .. } => {}
+        }
+    }
+}
+
+/// A `PT_LOAD` segment as it was mapped
+struct Segment {
+    virtual_addr: usize,
+    mem_size: usize,
+    /// The part of the file copied in, the rest is zero-filled
+    data: Range<usize>,
+    protection: Protection,
+}
+
+impl Segment {
+    /// The pages the segment was protected as, given the load base
+    fn pages(&self, base: usize) -> (*const c_void, usize) {
+        let start = region::page::floor((base + self.virtual_addr) as *const c_void);
+        let end = region::page::ceil((base + self.virtual_addr + self.mem_size) as *const c_void);
+        (start, end as usize - start as usize)
+    }
+}
+
+/// A library that is mapped and registered but not relocated yet
+pub(crate) struct Mapped<'a> {
+    pub(crate) library: AndroidLibrary<'a>,
+    elf_file: ElfFile<'a>,
+    symbol_names: Vec<String>,
+    symbol_versions: Vec<Option<SymbolVersion>>,
+    /// `DT_NEEDED` entries
+    pub(crate) needed: Vec<String>,
+}
+
+/// `struct dl_phdr_info`, as bionic defines it
+#[repr(C)]
+struct DlPhdrInfo {
+    addr: usize,
+    name: *const c_char,
+    phdr: *const c_void,
+    phnum: u16,
+    /// Libraries loaded and unloaded so far, for callers caching what they found
+    adds: u64,
+    subs: u64,
+    tls_modid: usize,
+    tls_data: *mut c_void,
+}
+
+/// `dlsym` pseudo-handle searching the libraries loaded after the caller's
+#[cfg(target_pointer_width = "64")]
+const RTLD_NEXT: usize = usize::MAX;
+#[cfg(target_pointer_width = "32")]
+const RTLD_NEXT: usize = 0xffff_fffe;
+
+/// `dlopen` flags, as defined by bionic
+const RTLD_NOLOAD: c_int = 4;
+#[cfg(target_pointer_width = "64")]
+const RTLD_GLOBAL: c_int = 0x100;
+#[cfg(target_pointer_width = "32")]
+const RTLD_GLOBAL: c_int = 2;
+
+/// A library opened through the `dlopen` stub, shared by every `dlopen` of the same path
+struct DlopenedLibrary {
+    handle: usize,
+    references: usize,
+    /// Opened by `dlopen` rather than lent by an [interceptor](AndroidLoader::intercept_dlopen),
+    /// so freed by the last `dlclose`
+    owned: bool,
+}
+
+lazy_static! {
+    static ref DLOPENED: Mutex<HashMap<String, DlopenedLibrary>> = Mutex::new(HashMap::new());
+}
+
+caller_entry!("android_loader_dlsym", 2, "android_loader_dlsym_from");
+caller_entry!("android_loader_dlopen", 2, "android_loader_dlopen_from");
+
+extern "C" {
+    fn android_loader_dlsym();
+    fn android_loader_dlopen();
+}
+
+impl Drop for AndroidLibrary<'_> {
+    fn drop(&mut self) {
+        let image = self.memory_map.as_ptr() as usize..self.memory_map.as_ptr() as usize + self.memory_map.len();
+        stubs::cxa::run_thread_destructors(image);
+        if self.initialized {
+            initializers::finalize(self);
+        }
+        registry::unregister(self.registry_id);
+        stubs::mman::release_owned(self.registry_id);
+        stubs::signal::release_owned(self.registry_id);
+        if let Some(module) = self.tls_module {
+            tls::unregister_module(module);
+        }
+    }
 }
 
 impl AndroidLibrary<'_> {
     pub fn get_symbol(&self, symbol_name: &str) -> Option<*const ()> {
+        // The hash table is of the names as stored
+        if let Some(names) = &self.decoded_names {
+            return self.dyn_symbols.iter()
+                .zip(names)
+                .enumerate()
+                .find(|(index, (symbol, name))| Self::defined(symbol, *index, self.extended_indices) && *name == symbol_name)
+                .map(|(_, (symbol, _))| unsafe { self.memory_map.as_ptr().add(symbol.value() as usize) as *const () });
+        }
         let elf_file = ElfFile::new(&self.file).unwrap();
         match &self.gnu_hash_table {
             Some(hash_table) => {
                 unsafe {
-                    hash_table.lookup(&self, symbol_name, self.dyn_strs)
+                    hash_table.lookup(self, symbol_name, self.dyn_strs)
                 }
             }
             None => unsafe { self.dyn_symbols.iter().find(|sym| sym.get_name(&elf_file) == Ok(symbol_name)).map(|s| self.memory_map.as_ptr().offset(s.value() as isize) as *const ()) }
         }
     }
+
+    /// Call the exported function `symbol_name` with `args`, see [`dynamic_call`](crate::dynamic_call)
+    ///
+    /// # Safety
+    /// The function must take arguments of the kinds given, in that order, and return `returns`.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub unsafe fn call(&self, symbol_name: &str, args: &[Arg], returns: ReturnType) -> Result<Value> {
+        let function = self.get_symbol(symbol_name).ok_or_else(|| AndroidLoaderErr::UnknownSymbol(symbol_name.to_owned()))?;
+        dynamic_call::call(function, args, returns)
+    }
+
+    /// The library's `DT_SONAME`
+    pub fn soname(&self) -> Option<&str> {
+        self.soname.as_deref()
+    }
+
+    /// Dependencies loaded along with this library, in breadth-first order. Dependencies that
+    /// were already loaded by an earlier load aren't included.
+    pub fn dependencies(&self) -> impl Iterator<Item = &AndroidLibrary<'static>> {
+        self.dependencies.iter().flat_map(|group| group.libraries.iter())
+    }
+
+    /// Like [`get_symbol`](Self::get_symbol), but also accepts a demangled C++ name such as
+    /// `foo::bar(char const*, int)`, formatted like `c++filt` prints it (whitespace doesn't
+    /// matter). Without a parameter list the first overload found is returned.
+    #[cfg(feature = "demangle")]
+    pub fn get_symbol_demangled(&self, symbol_name: &str) -> Option<*const ()> {
+        if let Some(symbol) = self.get_symbol(symbol_name) {
+            return Some(symbol);
+        }
+        self.dyn_symbols.iter()
+            .enumerate()
+            .filter(|(index, symbol)| Self::defined(symbol, *index, self.extended_indices))
+            .find(|(index, symbol)| {
+                let name = match &self.decoded_names {
+                    Some(names) => &names[*index],
+                    None => read_str(&self.dyn_strs[symbol.name() as usize..]),
+                };
+                demangle::demangle(name).map_or(false, |demangled| demangle::matches(&demangled, symbol_name))
+            })
+            .map(|(_, symbol)| unsafe { self.memory_map.as_ptr().add(symbol.value() as usize) as *const () })
+    }
+
+    /// Whether the library's `PT_GNU_STACK` header asks for an executable stack. The host's
+    /// stacks are left as they are either way; libraries without the header are reported as
+    /// not asking for one.
+    pub fn wants_executable_stack(&self) -> bool {
+        self.executable_stack
+    }
+
+    /// The 32-bit ARM exception index (`.ARM.exidx`) unwinders search instead of `.eh_frame`,
+    /// as its address and number of 8-byte entries. `None` for libraries of other
+    /// architectures, whose processor-specific headers are left alone.
+    pub fn arm_exidx(&self) -> Option<(*const (), usize)> {
+        self.arm_exidx.map(|(offset, size)| (unsafe { self.memory_map.as_ptr().add(offset) } as *const (), size / EXIDX_ENTRY_SIZE))
+    }
+
+    /// The exception index of the library containing `pc` and its entry count, or null and
+    /// 0 when no loaded library does
+    #[cfg(target_arch = "arm")]
     #[sysv64]
-    fn pthread_stub() -> i32 {
+    unsafe fn dl_unwind_find_exidx(pc: usize, count: *mut c_int) -> *const () {
+        let (address, entries) = AndroidLoader::find_arm_exidx(pc).unwrap_or((std::ptr::null(), 0));
+        *count = entries as c_int;
+        address
+    }
+
+    /// Call `callback` with each loaded library, in load order, until it returns non-zero,
+    /// and return what it last did. The program headers are the retained file's, and
+    /// `dlpi_tls_data` is left null, as blocks are only allocated on first use.
+    #[sysv64]
+    unsafe fn dl_iterate_phdr(callback: usize, data: *mut c_void) -> c_int {
+        let callback: sysv64_type!(fn(*mut DlPhdrInfo, usize, *mut c_void) -> c_int) = std::mem::transmute(callback);
+        // Taken at once, so the callback can load and unload libraries
+        let (adds, subs, libraries) = registry::phdr_infos();
+        for library in libraries {
+            let name = CString::new(library.name).unwrap_or_default();
+            let mut info = DlPhdrInfo {
+                addr: library.base,
+                name: name.as_ptr(),
+                phdr: library.program_headers.0 as *const c_void,
+                phnum: library.program_headers.1 as u16,
+                adds: adds as u64,
+                subs: subs as u64,
+                tls_modid: library.tls_module.unwrap_or(0),
+                tls_data: null_mut(),
+            };
+            let result = callback(&mut info, std::mem::size_of::<DlPhdrInfo>(), data);
+            if result != 0 {
+                return result;
+            }
+        }
         0
     }
 
     #[sysv64]
-    fn undefined_symbol_stub() {
-        panic!("tried to call an undefined symbol");
+    fn pthread_stub() -> i32 {
+        0
     }
 
+    /// `dlopen` called from `caller`, whose loader's [interceptor](AndroidLoader::intercept_dlopen)
+    /// goes first
+    #[no_mangle]
     #[sysv64]
-    unsafe fn dlopen(name: *const c_char) -> *mut c_void {
-        use crate::hook_manager::get_hooks;
-        let mut path_str = CStr::from_ptr(name).to_str().unwrap();
+    unsafe fn android_loader_dlopen_from(name: *const c_char, flags: c_int, caller: usize) -> *mut c_void {
+        let requested = CStr::from_ptr(name).to_str().unwrap();
+        let substitute = match registry::dlopen_interceptor(caller).map(|interceptor| interceptor(requested)) {
+            None | Some(DlopenAction::Default) => None,
+            Some(DlopenAction::Substitute(path)) => {
+                info!("Opening {} in place of {requested}", path.display());
+                Some(path.to_string_lossy().into_owned())
+            }
+            Some(DlopenAction::UseExisting(handle)) => return Self::lend(requested, handle, flags),
+            Some(DlopenAction::Deny) => {
+                info!("Denied dlopen of {requested}");
+                return null_mut();
+            }
+        };
+        let mut path_str = substitute.as_deref().unwrap_or(requested);
 
         let _path: String;
         #[cfg(target_family = "windows")]
@@ -155,17 +555,72 @@ impl AndroidLibrary<'_> {
             path_str = _path.as_str();
         }
 
+        // Relocations are always bound eagerly, so RTLD_LAZY and RTLD_NOW behave the same
+        if let Some(handle) = Self::reopen(path_str, flags) {
+            return handle;
+        }
+        if flags & RTLD_NOLOAD != 0 {
+            return null_mut();
+        }
+
         info!("Loading {}", path_str);
-        match Self::load(path_str) {
-            Ok(lib) => Box::into_raw(Box::new(lib)) as *mut c_void,
-            Err(_) => null_mut(),
+        let library = match Self::load(path_str) {
+            Ok(lib) => Box::new(lib),
+            Err(_) => return null_mut(),
+        };
+        if flags & RTLD_GLOBAL != 0 {
+            registry::make_global(library.registry_id);
+        }
+        let handle = Box::into_raw(library);
+        DLOPENED.lock().unwrap().insert(path_str.to_owned(), DlopenedLibrary { handle: handle as usize, references: 1, owned: true });
+        handle as *mut c_void
+    }
+
+    /// Hand out a handle given by an interceptor for `name`, counting its references like
+    /// those of the libraries `dlopen` opened but never freeing it
+    fn lend(name: &str, handle: *mut c_void, flags: c_int) -> *mut c_void {
+        if handle.is_null() {
+            return null_mut();
         }
+        if flags & RTLD_GLOBAL != 0 {
+            registry::make_global(unsafe { (*(handle as *const AndroidLibrary)).registry_id });
+        }
+        let mut dlopened = DLOPENED.lock().unwrap();
+        match dlopened.values_mut().find(|library| library.handle == handle as usize) {
+            Some(library) => library.references += 1,
+            None => {
+                dlopened.insert(name.to_owned(), DlopenedLibrary { handle: handle as usize, references: 1, owned: false });
+            }
+        }
+        handle
     }
 
+    /// The handle `dlopen` returns for this library, for [`DlopenAction::UseExisting`]
+    pub fn as_handle(&self) -> *mut c_void {
+        self as *const AndroidLibrary as *mut c_void
+    }
+
+    /// Handle of a library this path was already opened as, taking another reference to it
+    fn reopen(path: &str, flags: c_int) -> Option<*mut c_void> {
+        let mut dlopened = DLOPENED.lock().unwrap();
+        let library = dlopened.get_mut(path)?;
+        library.references += 1;
+        if flags & RTLD_GLOBAL != 0 {
+            registry::make_global(unsafe { (*(library.handle as *const AndroidLibrary)).registry_id });
+        }
+        Some(library.handle as *mut c_void)
+    }
+
+    #[no_mangle]
     #[sysv64]
-    unsafe fn dlsym(library: *mut AndroidLibrary, symbol: *const c_char) -> *mut c_void {
+    unsafe fn android_loader_dlsym_from(library: *mut AndroidLibrary, symbol: *const c_char, caller: usize) -> *mut c_void {
         let symbol = CStr::from_ptr(symbol).to_str().unwrap();
         debug!("Symbol requested: {}", symbol);
+        if library as usize == RTLD_NEXT {
+            return registry::next_symbol(registry::containing(caller), symbol)
+                .or_else(|| Self::get_libc_symbol(symbol).map(|symbol| symbol as usize))
+                .map_or(null_mut(), |symbol| symbol as *mut c_void);
+        }
         match library.as_ref().and_then(|lib| lib.get_symbol(symbol)) {
             Some(func) => func as *mut c_void,
             None => null_mut(),
@@ -173,75 +628,575 @@ impl AndroidLibrary<'_> {
     }
 
     #[sysv64]
-    unsafe fn dlclose(library: *mut AndroidLibrary) {
+    unsafe fn dlclose(library: *mut AndroidLibrary) -> c_int {
+        let mut dlopened = DLOPENED.lock().unwrap();
+        if let Some((path, opened)) = dlopened.iter_mut().find(|(_, opened)| opened.handle == library as usize) {
+            opened.references -= 1;
+            if opened.references > 0 {
+                return 0;
+            }
+            let (path, owned) = (path.clone(), opened.owned);
+            dlopened.remove(&path);
+            if !owned {
+                return 0;
+            }
+        }
+        drop(dlopened);
+
         let _ = Box::from_raw(library);
+        0
     }
 
-    fn symbol_finder(symbol_name: &str, hooks: &HashMap<String, usize>) -> *const () {
-        // Check if this function is hooked for this library
+    /// Name of the undefined symbol `address` was resolved to, e.g. the faulting address when
+    /// loaded with [`UndefinedSymbolBehavior::Fault`](crate::undefined_symbols::UndefinedSymbolBehavior::Fault)
+    pub fn undefined_symbol_at(&self, address: usize) -> Option<&str> {
+        self.undefined_symbols.symbol_at(address)
+            .or_else(|| self.lazy_bindings.as_ref()?.symbol_at(address))
+    }
 
-        if let Some(func) = hooks.get(symbol_name) {
-            *func as *const ()
-            // pthread functions are problematic, let's ignore them
-        } else {
-            Self::get_libc_symbol(symbol_name)
+    /// Put the library's memory back the way loading left it, undoing everything written to
+    /// its segments since. Much cheaper than reloading: the segments are copied from the
+    /// retained file and the recorded relocation results written over them, without parsing
+    /// or resolving anything. Memory the library allocated, its TLS and its `dlopen`ed
+    /// libraries aren't touched.
+    pub fn reset(&mut self) -> Result<()> {
+        let base = self.memory_map.as_ptr() as usize;
+        for segment in &self.segments {
+            let (start, len) = segment.pages(base);
+            unsafe { protections::protect(start, len, Protection::READ_WRITE, ProtectionSource::Loader)? };
+            let image = &mut self.memory_map[segment.virtual_addr..segment.virtual_addr + segment.mem_size];
+            let data = &self.file[segment.data.clone()];
+            image[..data.len()].copy_from_slice(data);
+            image[data.len()..].fill(0);
+        }
+        for (offset, bytes) in &self.relocated {
+            self.memory_map[*offset..*offset + bytes.len()].copy_from_slice(bytes);
+        }
+        for segment in &self.segments {
+            let (start, len) = segment.pages(base);
+            unsafe { protections::protect(start, len, segment.protection, ProtectionSource::Loader)? };
+        }
+        self.protect_relro()
+    }
+
+    /// Make the `PT_GNU_RELRO` pages read-only, if the protection policy enforces it
+    fn protect_relro(&self) -> Result<()> {
+        if let Some(relro) = &self.relro {
+            let start = unsafe { self.memory_map.as_ptr().add(relro.start) };
+            unsafe { protections::protect(start, relro.end - relro.start, Protection::READ, ProtectionSource::Loader)? };
+        }
+        Ok(())
+    }
+
+    /// Fail with [`AndroidLoaderErr::WxViolation`] if a page of the image is both writable and
+    /// executable
+    fn check_wx(&self) -> Result<()> {
+        let start = self.memory_map.as_ptr() as usize;
+        for region in region::query_range(self.memory_map.as_ptr(), self.memory_map.len())? {
+            let region = region?;
+            if region.protection().contains(Protection::WRITE_EXECUTE) {
+                let address = max(region.as_ptr::<u8>() as usize, start);
+                return Err(AndroidLoaderErr::WxViolation { address }.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Address of the entry point (`e_entry`), usually only set for executables. One taking
+    /// `(argc, argv, envp)` can be given those of [`program_arguments`](Self::program_arguments).
+    pub fn entry_point(&self) -> Option<*const ()> {
+        self.entry.map(|entry| unsafe { self.memory_map.as_ptr().add(entry) } as *const ())
+    }
+
+    /// The arguments and environment the library was loaded with, see
+    /// [`AndroidLoader::program_arguments`]
+    pub fn program_arguments(&self) -> Option<&ProgramArguments> {
+        self.program_arguments.as_deref()
+    }
+
+    /// The interpreter (`PT_INTERP`) a position-independent executable asks for. Loaded
+    /// executables behave like libraries but aren't meant to be: their initializers and entry
+    /// point expect a process set up by that interpreter.
+    pub fn interpreter(&self) -> Option<&str> {
+        self.interpreter.as_deref()
+    }
+
+    /// The program headers, as in the file
+    pub fn program_headers(&self) -> &[ProgramHeader] {
+        &self.program_headers
+    }
+
+    /// The file the library was loaded from, as given to the loader before any preprocessing,
+    /// if the loader [retains it](AndroidLoader::retain_original_bytes)
+    pub fn original_bytes(&self) -> Option<&[u8]> {
+        match &self.original {
+            Some(original) => Some(original),
+            None if self.retains_original => Some(&self.file),
+            None => None,
+        }
+    }
+
+    /// Where the byte at `address`, in the library's image, is in the file it was loaded from,
+    /// e.g. to patch it on disk. `None` outside the image and for memory no file data was
+    /// mapped to, like `.bss`.
+    pub fn address_to_file_offset(&self, address: usize) -> Option<u64> {
+        let virtual_addr = (address as u64).checked_sub(self.memory_map.as_ptr() as u64)?;
+        self.program_headers.iter()
+            .filter(|header| header.kind == PT_LOAD)
+            .find(|header| (header.virtual_addr..header.virtual_addr + header.file_size).contains(&virtual_addr))
+            .map(|header| header.offset + (virtual_addr - header.virtual_addr))
+    }
+
+    /// Where the symbol [`get_symbol`](Self::get_symbol) finds for `symbol_name` is in the file,
+    /// see [`address_to_file_offset`](Self::address_to_file_offset)
+    pub fn symbol_file_offset(&self, symbol_name: &str) -> Option<u64> {
+        self.address_to_file_offset(self.get_symbol(symbol_name)? as usize)
+    }
+
+    /// Overwrite the start of the data symbol `symbol_name` with `bytes`, e.g. to seed a global
+    /// before calling into the library. Fails with [`AndroidLoaderErr::SymbolTooSmall`] if they
+    /// don't fit in the symbol's size. Pages made read-only, like `PT_GNU_RELRO`, are made
+    /// writable for the write and protected again after it.
+    pub fn write_symbol(&mut self, symbol_name: &str, bytes: &[u8]) -> Result<()> {
+        let symbol = self.dyn_symbols.iter()
+            .enumerate()
+            .filter(|(index, symbol)| Self::defined(symbol, *index, self.extended_indices))
+            .find(|(index, symbol)| match &self.decoded_names {
+                Some(names) => names[*index] == symbol_name,
+                None => read_str(&self.dyn_strs[symbol.name() as usize..]) == symbol_name,
+            })
+            .map(|(_, symbol)| symbol)
+            .ok_or_else(|| AndroidLoaderErr::UnknownSymbol(symbol_name.to_owned()))?;
+        let (offset, size) = (symbol.value() as usize, symbol.size() as usize);
+        if bytes.len() > size {
+            return Err(AndroidLoaderErr::SymbolTooSmall { symbol: symbol_name.to_owned(), size, len: bytes.len() }.into());
+        }
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let range = offset..offset + bytes.len();
+        if range.end > self.memory_map.len() {
+            let base = self.memory_map.as_ptr() as usize;
+            return Err(AndroidLoaderErr::OutsideImage { start: base + range.start, end: base + range.end }.into());
+        }
+
+        // Only the pages written to, of the regions that aren't writable
+        let (start, end) = (self.memory_map.as_ptr() as usize + range.start, self.memory_map.as_ptr() as usize + range.end);
+        let mut protected = Vec::new();
+        for region in region::query_range(start as *const u8, bytes.len())? {
+            let region = region?;
+            if !region.protection().contains(Protection::WRITE) {
+                let from = max(region.as_ptr::<u8>() as usize, start);
+                let to = (region.as_ptr::<u8>() as usize + region.len()).min(end);
+                protected.push((from as *const u8, to - from, region.protection()));
+            }
+        }
+        for (address, len, protection) in &protected {
+            unsafe { protections::protect(*address, *len, *protection | Protection::WRITE, ProtectionSource::Loader)? };
+        }
+        self.memory_map[range].copy_from_slice(bytes);
+        for (address, len, protection) in protected {
+            unsafe { protections::protect(address, len, protection, ProtectionSource::Loader)? };
+        }
+        Ok(())
+    }
+
+    /// The `.dynamic` entries, as in the file
+    pub fn dynamic_entries(&self) -> &[DynamicEntry] {
+        &self.dynamic_entries
+    }
+
+    /// The GNU build ID (`.note.gnu.build-id`), as `readelf -n` shows it in hex, to match the
+    /// library with its debug info
+    pub fn build_id(&self) -> Option<Vec<u8>> {
+        self.build_id.clone()
+    }
+
+    /// The address of the GOT, `_GLOBAL_OFFSET_TABLE_`, which x86's GOT-relative relocations
+    /// and code are relative to
+    pub fn global_offset_table(&self) -> Option<usize> {
+        self.global_offset_table.map(|offset| self.memory_map.as_ptr() as usize + offset)
+    }
+
+    /// Statistics gathered while loading the library
+    pub fn load_stats(&self) -> &LoadStats {
+        &self.stats
+    }
+
+    /// Call `callback` before each write to the addresses in `range`, which must be in the
+    /// image, until the returned watch is dropped. See [`write_watch`](crate::write_watch)
+    /// for how and the caveats.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    pub fn watch_writes(&self, range: Range<usize>, callback: impl Fn(&WatchedWrite) + Send + Sync + 'static) -> Result<WriteWatch> {
+        let image = self.memory_map.as_ptr() as usize..self.memory_map.as_ptr() as usize + self.memory_map.len();
+        if range.is_empty() || range.start < image.start || range.end > image.end {
+            return Err(AndroidLoaderErr::OutsideImage { start: range.start, end: range.end }.into());
+        }
+        write_watch::watch(range, Arc::new(callback))
+    }
+
+    /// Write the image as it is now, relocated and changed by whatever ran since, to `path`
+    /// as a flat binary and return the address it's mapped at, which its offsets are from.
+    /// Pages that can't be read, like segments without `PF_R`, are written as zeros.
+    pub fn dump_image(&self, path: impl AsRef<Path>) -> Result<usize> {
+        let base = self.memory_map.as_ptr() as usize;
+        let end = base + self.memory_map.len();
+        let mut image = vec![0; self.memory_map.len()];
+        for region in region::query_range(self.memory_map.as_ptr(), self.memory_map.len())? {
+            let region = region?;
+            if !region.is_readable() {
+                continue;
+            }
+            let start = max(region.as_ptr::<u8>() as usize, base) - base;
+            let stop = (region.as_ptr::<u8>() as usize + region.len()).min(end) - base;
+            image[start..stop].copy_from_slice(&self.memory_map[start..stop]);
+        }
+        fs::write(path, image)?;
+        Ok(base)
+    }
+
+    fn symbol_finder(
+        symbol_name: &str, version: Option<&str>, hooks: &HashMap<String, usize>, scope: &[usize], loader: &AndroidLoader,
+        undefined_symbols: &mut UndefinedSymbols, caller_stubs: &mut CallerStubs,
+    ) -> (usize, SymbolSource) {
+        match Self::lookup_symbol(symbol_name, version, hooks, scope, loader.bionic_stubs, loader.resolution_steps()) {
+            Some((symbol, SymbolSource::Libc)) => {
+                let bound = Self::caller_sensitive(symbol_name).and_then(|(target, args)| caller_stubs.bind(target, args));
+                (bound.unwrap_or(symbol), SymbolSource::Libc)
+            }
+            Some(found) => found,
+            None => (undefined_symbols.stub(symbol_name), SymbolSource::Undefined),
+        }
+    }
+
+    /// Fail on a relocation of a type the loader can't apply, or skip it under
+    /// [`RelocationPolicy::BestEffort`]
+    // RelocType is u32 on 64-bit hosts
+    #[allow(clippy::useless_conversion)]
+    fn unsupported_relocation(policy: RelocationPolicy, stats: &mut LoadStats, rtype: RelocType) -> Result<()> {
+        match policy {
+            RelocationPolicy::Strict => Err(AndroidLoaderErr::UnsupportedRelocation(rtype).into()),
+            RelocationPolicy::BestEffort => {
+                warn!("Skipping relocation of unsupported type {rtype}");
+                *stats.skipped_relocations.entry(u32::from(rtype)).or_insert(0) += 1;
+                Ok(())
+            }
         }
     }
 
-    fn get_libc_symbol(symbol_name: &str) -> *const () {
+    /// Where `symbol_name` resolves to, without creating anything for it: the first of the
+    /// `steps` defining it
+    pub(crate) fn lookup_symbol(
+        symbol_name: &str, version: Option<&str>, hooks: &HashMap<String, usize>, scope: &[usize], bionic_stubs: bool,
+        steps: &[ResolutionStep],
+    ) -> Option<(usize, SymbolSource)> {
+        steps.iter().find_map(|step| match step {
+            ResolutionStep::Hooks => hooks.get(symbol_name).map(|func| (*func, SymbolSource::Hook)),
+            ResolutionStep::Scope => registry::scope_symbol(scope, symbol_name, version).map(|symbol| (symbol, SymbolSource::Library)),
+            ResolutionStep::Global => registry::global_symbol(scope, symbol_name, version).map(|symbol| (symbol, SymbolSource::Global)),
+            ResolutionStep::Registered => hook_manager::global_symbol(symbol_name).map(|symbol| (symbol, SymbolSource::Registered)),
+            ResolutionStep::Stubs => Self::builtin_stub(symbol_name)
+                .or_else(|| if bionic_stubs { Self::bionic_stub(symbol_name) } else { None })
+                .map(|symbol| (symbol as usize, SymbolSource::Libc)),
+        })
+    }
+
+    /// Stub from the bundle of [`AndroidLoader::with_bionic_stubs`]
+    fn bionic_stub(symbol_name: &str) -> Option<*const ()> {
         if symbol_name.starts_with("pthread_") {
-            Self::pthread_stub as *const ()
+            Some(Self::pthread_stub as *const ())
+        } else {
+            stubs::bionic_lookup(symbol_name)
+        }
+    }
+
+    /// Built-in functions that need their caller, as their implementation taking it after the
+    /// given number of arguments
+    fn caller_sensitive(symbol_name: &str) -> Option<(usize, usize)> {
+        if !BUILTIN_STUBS || hook_manager::global_symbol(symbol_name).is_some() {
+            return None;
+        }
+        match symbol_name {
+            "dlsym" => Some((Self::android_loader_dlsym_from as *const () as usize, 2)),
+            "dlopen" => Some((Self::android_loader_dlopen_from as *const () as usize, 2)),
+            _ => stubs::caller_sensitive(symbol_name),
+        }
+    }
+
+    /// Built-in implementation of `symbol_name`: one registered with
+    /// [`AndroidLoader::register_global_symbol`], then the stubs (never found without the
+    /// `builtin-stubs` feature)
+    pub(crate) fn get_libc_symbol(symbol_name: &str) -> Option<*const ()> {
+        hook_manager::global_symbol(symbol_name)
+            .map(|symbol| symbol as *const ())
+            .or_else(|| Self::builtin_stub(symbol_name))
+    }
+
+    /// The stub of `symbol_name` (never found without the `builtin-stubs` feature)
+    fn builtin_stub(symbol_name: &str) -> Option<*const ()> {
+        if !BUILTIN_STUBS {
+            None
+        } else if symbol_name.starts_with("pthread_") {
+            Some(Self::pthread_stub as *const ())
         } else {
             match symbol_name {
-                "dlopen" => Self::dlopen as *const (),
-                "dlsym" => Self::dlsym as *const (),
-                "dlclose" => Self::dlclose as *const (),
-                _ => Self::undefined_symbol_stub as *const ()
+                "dlopen" => Some(android_loader_dlopen as *const ()),
+                "dlsym" => Some(android_loader_dlsym as *const ()),
+                "dlclose" => Some(Self::dlclose as *const ()),
+                "dl_iterate_phdr" => Some(Self::dl_iterate_phdr as *const ()),
+                "__tls_get_addr" => Some(tls::tls_get_addr as *const ()),
+                #[cfg(target_arch = "arm")]
+                "__aeabi_read_tp" => Some(tls::android_loader_aeabi_read_tp as *const ()),
+                #[cfg(target_arch = "arm")]
+                "dl_unwind_find_exidx" => Some(Self::dl_unwind_find_exidx as *const ()),
+                _ => stubs::lookup(symbol_name)
             }
         }
     }
 
-    fn absolute_reloc<T: Entry>(memory_map: &mut MmapMut, dynsym: &[T], dynstrings: &[u8], hooks: &HashMap<String, usize>, index: usize, offset: usize, addend: usize) {
-        let name =
-            read_str(&dynstrings[(dynsym[index].name() as usize)..]);
-        let symbol = Self::symbol_finder(name, hooks);
+    /// The addend a REL relocation keeps in the 32-bit field it relocates, sign-extended
+    fn implicit_addend(memory_map: &[u8], offset: usize) -> isize {
+        let field: [u8; 4] = memory_map[offset..offset + 4].try_into().unwrap();
+        i32::from_ne_bytes(field) as isize
+    }
 
-        // addend is always 0, but we still add it to be safe
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn absolute_reloc(memory_map: &mut [u8], symbol: usize, offset: usize, addend: usize) {
         // converted to an array in the systme endianess
-        let relocated = addend.wrapping_add(symbol as usize).to_ne_bytes();
+        let relocated = addend.wrapping_add(symbol).to_ne_bytes();
         memory_map[offset..offset + relocated.len()].copy_from_slice(&relocated);
     }
 
-    fn relative_reloc(memory_map: &mut MmapMut, offset: usize, addend: usize) {
-        let relocated = addend
-            .wrapping_add(memory_map.as_mut_ptr() as usize)
-            .to_ne_bytes();
+    /// Writes the low 32 bits of `value`, failing if it doesn't fit (as a signed integer when
+    /// `signed` is set) instead of silently truncating it. `symbol` names the relocation's
+    /// symbol for the error.
+    #[cfg(target_arch = "x86_64")]
+    fn truncating_reloc(memory_map: &mut [u8], value: usize, offset: usize, rtype: RelocType, signed: bool, symbol: &str) -> Result<()> {
+        let fits = if signed {
+            i32::try_from(value as i64).is_ok()
+        } else {
+            u32::try_from(value).is_ok()
+        };
+        if !fits {
+            return Err(AndroidLoaderErr::RelocationOverflow { symbol: symbol.to_owned(), rtype, value, offset }.into());
+        }
+
+        let relocated = (value as u32).to_ne_bytes();
+        memory_map[offset..offset + relocated.len()].copy_from_slice(&relocated);
+        Ok(())
+    }
 
+    /// Writes the low 32 bits of `value` to a REL field, the width of its relocations whatever
+    /// the host's word size
+    #[cfg_attr(not(any(target_arch = "x86", target_arch = "arm")), allow(dead_code))]
+    fn write_field32(memory_map: &mut [u8], offset: usize, value: usize) {
+        let relocated = (value as u32).to_ne_bytes();
         memory_map[offset..offset + relocated.len()].copy_from_slice(&relocated);
     }
 
-    #[cfg(not(target_arch="aarch64"))]
-    const MAX_PAGE_SIZE: usize = 4096;
+    /// `*_RELATIVE` for REL, written back at the field's width
+    #[cfg_attr(not(any(target_arch = "x86", target_arch = "arm")), allow(dead_code))]
+    fn relative_field32(memory_map: &mut [u8], offset: usize, base: usize, addend: Option<i64>) {
+        let value = Self::relative_value(memory_map, offset, base, addend);
+        Self::write_field32(memory_map, offset, value);
+    }
+
+    /// Offset of a TLS symbol within its module's block. Symbol index 0 refers to the module itself.
+    #[cfg(any(target_arch = "arm", target_arch = "x86_64"))]
+    fn tls_symbol_offset(dyn_symbols: &[DynEntry], index: usize) -> usize {
+        if index == 0 { 0 } else { dyn_symbols[index].value() as usize }
+    }
 
-    #[cfg(target_arch="aarch64")]
-    const MAX_PAGE_SIZE: usize = 65536;
+    /// `*_RELATIVE` on every architecture: the load base plus the addend, which is the RELA
+    /// entry's on 64-bit targets (whatever the field holds, as linkers may have applied the
+    /// relocation in place already) and the field's own value for REL on 32-bit ones
+    fn relative_value(memory_map: &[u8], offset: usize, base: usize, addend: Option<i64>) -> usize {
+        let addend = addend.map_or_else(|| Self::implicit_addend(memory_map, offset), |addend| addend as isize);
+        base.wrapping_add(addend as usize)
+    }
+
+    fn relative_reloc(memory_map: &mut [u8], offset: usize, addend: Option<i64>) {
+        let relocated = Self::relative_value(memory_map, offset, memory_map.as_ptr() as usize, addend).to_ne_bytes();
+        memory_map[offset..offset + relocated.len()].copy_from_slice(&relocated);
+    }
+
+    /// Offsets of the relative relocations packed in the `DT_RELR` table. An even entry is
+    /// the offset of one and starts a run after it; an odd one is a bitmap of which of the
+    /// next 63 words (31 on 32-bit) get one too, after which the run moves on by as many.
+    fn relr_offsets(memory_map: &[u8], dynamic_entries: &[DynamicEntry]) -> Result<Vec<usize>> {
+        let value = |tag, android_tag| dynamic_entries.iter()
+            .find(|entry| entry.tag == tag || entry.tag == android_tag)
+            .map(|entry| entry.value as usize);
+        let (table, size) = match (value(DT_RELR, DT_ANDROID_RELR), value(DT_RELRSZ, DT_ANDROID_RELRSZ)) {
+            (Some(table), Some(size)) => (table, size),
+            _ => return Ok(Vec::new()),
+        };
+        let word = std::mem::size_of::<usize>();
+        let entries = table.checked_add(size)
+            .and_then(|end| memory_map.get(table..end))
+            .ok_or_else(|| AndroidLoaderErr::ElfParsingError("DT_RELR table past the image".to_string()))?;
+
+        let mut offsets = Vec::new();
+        let mut next = 0;
+        for entry in entries.chunks_exact(word) {
+            let entry = usize::from_ne_bytes(entry.try_into().unwrap());
+            if entry & 1 == 0 {
+                offsets.push(entry);
+                next = entry.wrapping_add(word);
+            } else {
+                let bits = word * 8 - 1;
+                offsets.extend((0..bits).filter(|bit| (entry >> (bit + 1)) & 1 == 1).map(|bit| next.wrapping_add(bit * word)));
+                next = next.wrapping_add(bits * word);
+            }
+        }
+        if let Some(offset) = offsets.iter().find(|offset| offset.checked_add(word).map_or(true, |end| end > memory_map.len())) {
+            return Err(AndroidLoaderErr::ElfParsingError(format!("DT_RELR relocation at {offset:#x} past the image")).into());
+        }
+        Ok(offsets)
+    }
+
+    /// Where `_GLOBAL_OFFSET_TABLE_` is: `DT_PLTGOT`, or the start of `.got.plt` (`.got` if
+    /// there's none) for libraries without lazily bound imports. The symbol itself is local.
+    fn global_offset_table_offset(elf_file: &ElfFile, dynamic_entries: &[DynamicEntry], image_size: usize) -> Option<usize> {
+        let section = |name| elf_file.section_iter()
+            .find(|section| section.get_name(elf_file) == Ok(name))
+            .map(|section| section.address() as usize);
+        dynamic_entries.iter()
+            .find(|entry| entry.tag == DT_PLTGOT)
+            .map(|entry| entry.value as usize)
+            .or_else(|| section(".got.plt"))
+            .or_else(|| section(".got"))
+            .filter(|offset| *offset < image_size)
+    }
+
+    /// The `SHT_SYMTAB_SHNDX` table of the symbol table at section `symbols`: the section
+    /// index of each symbol whose `st_shndx` is `SHN_XINDEX`, as there are too many to fit
+    pub(crate) fn extended_section_indices<'a>(elf_file: &ElfFile<'a>, symbols: usize) -> Result<&'a [u32]> {
+        let table = elf_file.section_iter()
+            .find(|section| section.get_type() == Ok(ShType::SymTabShIndex) && section.link() as usize == symbols);
+        match table.map(|section| section.get_data(elf_file)) {
+            None => Ok(&[]),
+            Some(Ok(SectionData::SymTabShIndex(indices))) => Ok(indices),
+            Some(_) => Err(AndroidLoaderErr::ElfParsingError("invalid SHT_SYMTAB_SHNDX section".to_string()).into()),
+        }
+    }
+
+    /// Whether the symbol at `index` is defined, going by its extended section index if it
+    /// has one. One that's missing from the table is taken as defined, like before there was one.
+    pub(crate) fn defined(symbol: &DynEntry, index: usize, extended_indices: &[u32]) -> bool {
+        match symbol.shndx() {
+            SHN_XINDEX => extended_indices.get(index).map_or(true, |section| *section != 0),
+            section => section != 0,
+        }
+    }
+
+    /// The string table a symbol table's `sh_link` names, which must be an in-bounds `SHT_STRTAB`
+    pub(crate) fn linked_strings<'a>(elf_file: &ElfFile<'a>, section: SectionHeader<'a>) -> Result<&'a [u8]> {
+        let link = section.link();
+        u16::try_from(link).ok()
+            .filter(|link| *link != 0 && *link < elf_file.header.pt2.sh_count())
+            .and_then(|link| elf_file.section_header(link).ok())
+            .filter(|strings| strings.get_type() == Ok(ShType::StrTab))
+            .filter(|strings| strings.offset().checked_add(strings.size()).map_or(false, |end| end <= elf_file.input.len() as u64))
+            .map(|strings| strings.raw_data(elf_file))
+            .ok_or_else(|| AndroidLoaderErr::ElfParsingError(format!("symbol table's sh_link {link} isn't a string table")).into())
+    }
+
+    /// Runs of bytes in the segments that differ from their file contents
+    fn relocated_runs(library: &AndroidLibrary) -> Vec<(usize, Vec<u8>)> {
+        const BLOCK: usize = 64;
+        let mut runs: Vec<(usize, Vec<u8>)> = Vec::new();
+        for segment in &library.segments {
+            let image = &library.memory_map[segment.virtual_addr..segment.virtual_addr + segment.mem_size];
+            let data = &library.file[segment.data.clone()];
+            for block_start in (0..image.len()).step_by(BLOCK) {
+                let block = &image[block_start..image.len().min(block_start + BLOCK)];
+                let original = |offset: usize| data.get(offset).copied().unwrap_or(0);
+                if block_start + block.len() <= data.len() && block == &data[block_start..block_start + block.len()] {
+                    continue;
+                }
+                for (index, byte) in block.iter().enumerate() {
+                    let offset = block_start + index;
+                    if *byte == original(offset) {
+                        continue;
+                    }
+                    let address = segment.virtual_addr + offset;
+                    match runs.last_mut() {
+                        Some((start, bytes)) if *start + bytes.len() == address => bytes.push(*byte),
+                        _ => runs.push((address, vec![*byte])),
+                    }
+                }
+            }
+        }
+        runs
+    }
+
+    /// Whether segments aligned to `segment_align` never share a page of `page_size` bytes, so
+    /// each can get its own protection. Otherwise every segment is mapped RWX, unless the
+    /// protection policy is strict.
+    fn segments_page_aligned(segment_align: usize, page_size: usize) -> bool {
+        page_size <= segment_align
+    }
 
     pub fn load<'a>(path: &str) -> Result<AndroidLibrary<'a>> {
-        let file = Box::new(fs::read(path)?);
-        let file_leak_ptr = Box::into_raw(file);
-        let file_leak = unsafe { file_leak_ptr.as_ref().unwrap() };
-        let elf_file = ElfFile::new(&file_leak).map_err(|err| AndroidLoaderErr::ElfParsingError(err.to_string()))?;
+        AndroidLoader::new().load_library(path)
+    }
+
+    pub fn load_from_bytes<'a>(file: Vec<u8>) -> Result<AndroidLibrary<'a>> {
+        AndroidLoader::new().load_library_from_bytes(file)
+    }
+
+    /// Parse the ELF headers, rejecting files the host can't relocate
+    pub(crate) fn parse_elf(file: &[u8]) -> Result<ElfFile<'_>> {
+        // Relocations are read and written in the host's byte order
+        let host_data = if cfg!(target_endian = "little") { ELFDATA2LSB } else { ELFDATA2MSB };
+        if file.get(EI_DATA).map_or(false, |data| *data != host_data) {
+            return Err(AndroidLoaderErr::EndianMismatch.into());
+        }
+        Ok(ElfFile::new(file).map_err(|err| AndroidLoaderErr::ElfParsingError(err.to_string()))?)
+    }
+
+    /// Map a library and register it, leaving its relocations to [`relocate`](Self::relocate).
+    /// It's mapped at the start of `buffer` if there is one, and in fresh memory otherwise.
+    pub(crate) fn map<'a>(loader: &AndroidLoader, file: Vec<u8>, buffer: Option<&'a mut [u8]>, base: Option<usize>) -> Result<Mapped<'a>> {
+        // The symbol tables borrow from the file's heap buffer, which stays put when the Vec is moved into the library
+        let file_leak: &'a [u8] = unsafe { slice::from_raw_parts(file.as_ptr(), file.len()) };
+        let started = Instant::now();
+        let mut stats = LoadStats::default();
+        // Only the host's relocation arm is built: a 32-bit library's relocation types would be
+        // taken for the 64-bit ones of the same number
+        if file_leak.get(EI_CLASS).map_or(false, |class| *class != HOST_CLASS) {
+            return Err(AndroidLoaderErr::ClassMismatch.into());
+        }
+        let elf_file = Self::parse_elf(file_leak)?;
+        // Executables expect to be mapped at their link-time addresses
+        let object_type = elf_file.header.pt2.type_();
+        if object_type.as_type() != header::Type::SharedObject {
+            return Err(AndroidLoaderErr::NotASharedObject(object_type.0).into());
+        }
+        let entry = Some(elf_file.header.pt2.entry_point() as usize).filter(|entry| *entry != 0);
+        let interpreter = elf_file.program_iter()
+            .find(|header| header.get_type() == Ok(Type::Interp))
+            .and_then(|header| file_leak.get(header.offset() as usize..))
+            .map(|path| read_str(path).to_owned());
+        if let Some(interpreter) = &interpreter {
+            warn!("This is an executable for {interpreter}, not a library; loading it as one");
+        }
 
         let mut minimum = usize::MAX;
         let mut maximum = usize::MIN;
+        let mut segment_align = 0;
 
         for header in elf_file.program_iter() {
             if header.get_type() == Ok(Type::Load) {
-                let start = region::page::floor(header.virtual_addr() as *const ()) as usize;
-                let end = region::page::ceil(
-                    (header.virtual_addr() as usize + max(header.file_size(), header.mem_size()) as usize)
-                        as *const (),
-                ) as usize;
+                segment_align = max(segment_align, header.align() as usize);
+                let virtual_addr = header.virtual_addr() as usize;
+                let mem_size = max(header.file_size(), header.mem_size()) as usize;
+                let start = region::page::floor(virtual_addr as *const ()) as usize;
+                let end = virtual_addr.checked_add(mem_size)
+                    .filter(|end| *end <= usize::MAX - region::page::size())
+                    .ok_or(AndroidLoaderErr::SegmentOutOfBounds { virtual_addr, mem_size })?;
+                let end = region::page::ceil(end as *const ()) as usize;
 
                 if start < minimum {
                     minimum = start;
@@ -253,22 +1208,67 @@ impl AndroidLibrary<'_> {
             }
         }
 
+        stats.parse_time = started.elapsed();
+        let mapping_started = Instant::now();
+
         let alloc_start = region::page::floor(minimum as *const ()) as usize;
         let alloc_end = region::page::ceil(maximum as *const ()) as usize;
 
-        let mut memory_map = MmapOptions::new().len(alloc_end - alloc_start).map_anon()?;
+        let size = alloc_end - alloc_start;
+        let mut memory_map = match (buffer, base) {
+            (None, Some(address)) => Image::map_fixed(address, size)?,
+            (None, None) if loader.guard_pages != 0 => {
+                let guard = loader.guard_pages * region::page::size();
+                let mapping = MmapOptions::new().len(size + 2 * guard).map_anon()?;
+                unsafe {
+                    protections::protect(mapping.as_ptr(), guard, Protection::NONE, ProtectionSource::Loader)?;
+                    protections::protect(mapping.as_ptr().add(guard + size), guard, Protection::NONE, ProtectionSource::Loader)?;
+                }
+                Image::Guarded { mapping, guard, len: size }
+            }
+            (None, None) => match &loader.mapping_pool {
+                Some(pool) => Image::Pooled { mapping: Some(pool.take(size)?), len: size, pool: pool.clone() },
+                None => Image::Mapped(MmapOptions::new().len(size).map_anon()?),
+            },
+            (Some(buffer), _) => {
+                let alignment = region::page::size();
+                if buffer.len() < size || buffer.as_ptr() as usize % alignment != 0 {
+                    return Err(AndroidLoaderErr::InvalidBuffer { size, alignment }.into());
+                }
+                let buffer = &mut buffer[..size];
+                // Zero-filled like a fresh mapping, for .bss
+                buffer.fill(0);
+                Image::Buffer(buffer)
+            }
+        };
+        let page_aligned = Self::segments_page_aligned(segment_align, region::page::size());
+        if loader.protection_policy == ProtectionPolicy::Strict && !page_aligned {
+            return Err(AndroidLoaderErr::UnrepresentableProtections { segment_align, page_size: region::page::size() }.into());
+        }
+        let all_rwx = match loader.protection_policy {
+            ProtectionPolicy::Strict => false,
+            ProtectionPolicy::Compatible => !page_aligned,
+            ProtectionPolicy::Permissive => true,
+        };
+        let mut segments = Vec::new();
 
         for program_header in elf_file.program_iter() {
             if program_header.get_type() == Ok(Type::Load) {
-                let data = match program_header {
-                    ProgramHeader::Ph32(inner) => inner.raw_data(&elf_file),
-                    ProgramHeader::Ph64(inner) => inner.raw_data(&elf_file),
-                };
-
                 let virtual_addr = program_header.virtual_addr() as usize;
                 let mem_size = program_header.mem_size() as usize;
                 let file_size = program_header.file_size() as usize;
                 let addr = memory_map.as_ptr() as usize;
+                // Segments are mapped at their virtual address from the start of the image, so
+                // one past its end (e.g. after a gap before the first segment) would be
+                // protecting memory that isn't ours
+                if virtual_addr + max(mem_size, file_size) > memory_map.len() {
+                    return Err(AndroidLoaderErr::SegmentOutOfBounds { virtual_addr, mem_size }.into());
+                }
+                // Nothing to map, and protecting no pages would fail
+                if mem_size == 0 {
+                    debug!("Skipping the empty segment at {virtual_addr:#x}");
+                    continue;
+                }
 
                 let start_addr = region::page::floor((addr + virtual_addr) as *const c_void) as *mut c_void;
                 let end_addr = region::page::ceil((addr + virtual_addr + mem_size) as *const c_void);
@@ -277,57 +1277,118 @@ impl AndroidLibrary<'_> {
                     start_addr as usize, end_addr as usize, mem_size, file_size
                 );
 
-                let is_standard_page = region::page::size() <= Self::MAX_PAGE_SIZE;
-
                 let flags = program_header.flags();
                 let mut prot = Protection::NONE.bits();
-                if flags.is_read() || !is_standard_page {
+                if flags.is_read() || all_rwx {
                     header_debug += "R";
                     prot |= Protection::READ.bits();
                 } else {
                     header_debug += "-";
                 }
-                if flags.is_write() || !is_standard_page {
+                if flags.is_write() || all_rwx {
                     header_debug += "W";
                     prot |= Protection::WRITE.bits();
                 } else {
                     header_debug += "-";
                 }
-                if flags.is_execute() || !is_standard_page {
+                if flags.is_execute() || all_rwx {
                     header_debug += "X]";
                     prot |= Protection::EXECUTE.bits();
                 } else {
                     header_debug += "-]";
                 }
                 debug!("{header_debug}");
-                memory_map[virtual_addr..virtual_addr + file_size].copy_from_slice(data);
+                // Inconsistent headers may claim more file data than there is. A pure BSS segment
+                // has none whatever its offset, and is all zero like the rest of the image.
+                let data_start = if file_size == 0 { 0 } else { (program_header.offset() as usize).min(file_leak.len()) };
+                let data = &file_leak[data_start..];
+                let data = &data[..data.len().min(file_size)];
+                if data.len() < file_size {
+                    warn!("Segment at {virtual_addr:#x} has a file size of {file_size} bytes but only {} are in the file, zero-filling the rest", data.len());
+                }
+                memory_map[virtual_addr..virtual_addr + data.len()].copy_from_slice(data);
+                stats.segments += 1;
+                segments.push(Segment {
+                    virtual_addr,
+                    mem_size,
+                    data: data_start..data_start + data.len(),
+                    protection: Protection::from_bits_truncate(prot),
+                });
 
                 unsafe {
-                    region::protect(
+                    protections::protect(
                         start_addr,
                         end_addr as usize - start_addr as usize,
                         Protection::from_bits_truncate(prot),
+                        ProtectionSource::Loader,
                     )?;
                 }
             }
         }
 
-        let hooks = get_hooks();
+        // Whole pages within the image, like bionic
+        let relro = elf_file.program_iter()
+            .find(|header| header.get_type() == Ok(Type::GnuRelro))
+            .filter(|_| loader.protection_policy == ProtectionPolicy::Strict)
+            .map(|header| {
+                let base = memory_map.as_ptr() as usize;
+                let start = region::page::floor((base + header.virtual_addr() as usize) as *const ()) as usize - base;
+                let end = region::page::ceil((base + (header.virtual_addr() + header.mem_size()) as usize) as *const ()) as usize - base;
+                start..end.min(memory_map.len())
+            })
+            .filter(|relro| relro.start < relro.end);
+
+        stats.bytes_mapped = memory_map.len();
+        stats.map_time = mapping_started.elapsed();
+        let parsing_started = Instant::now();
+
+        let tls_segment = elf_file.program_iter()
+            .find(|header| header.get_type() == Ok(Type::Tls))
+            .map(|header| {
+                let offset = header.offset() as usize;
+                let image = offset.checked_add(header.file_size() as usize)
+                    .and_then(|end| file_leak.get(offset..end))
+                    .ok_or_else(|| AndroidLoaderErr::ElfParsingError("PT_TLS initialization image outside the file".to_string()))?;
+                Ok::<_, AndroidLoaderErr>((image, header.mem_size() as usize, header.align() as usize))
+            })
+            .transpose()?;
+        let executable_stack = elf_file.program_iter()
+            .find(|header| header.get_type() == Ok(Type::OsSpecific(PT_GNU_STACK)))
+            .map_or(false, |header| header.flags().is_execute());
+        if executable_stack {
+            warn!("The library requests an executable stack, which won't be provided");
+        }
+        // The same type means something else on other architectures, e.g. PT_AARCH64_UNWIND
+        let arm_exidx = elf_file.program_iter()
+            .filter(|_| elf_file.header.pt2.machine().as_machine() == header::Machine::Arm)
+            .find(|header| header.get_type() == Ok(Type::ProcessorSpecific(PT_ARM_EXIDX)))
+            .map(|header| (header.virtual_addr() as usize, header.mem_size() as usize))
+            .filter(|(offset, size)| offset.checked_add(*size).map_or(false, |end| end <= memory_map.len()));
+
         let mut dyn_symbols: &[DynEntry] = &[];
         let mut dyn_strings: &[u8] = &[];
-        let mut gnu_hash_table = None;
+        let mut gnu_hash_section = None;
+        let (mut versym, mut verdef, mut verneed) = (None, None, None);
+        let mut dynamic_section = None;
+        let (mut dyn_symbols_index, mut gnu_hash_link) = (None, None);
 
-        for section in elf_file.section_iter() {
+        if elf_file.program_iter().filter(|header| header.get_type() == Ok(Type::Dynamic)).count() > 1 {
+            return Err(AndroidLoaderErr::ElfParsingError("more than one PT_DYNAMIC".to_string()).into());
+        }
+        for (index, section) in elf_file.section_iter().enumerate() {
             match section.get_type() {
-                Ok(ShType::OsSpecific(0x6FFFFFF6)) => unsafe {
-                    gnu_hash_table = Some(GnuHashTable::new(section.raw_data(&elf_file), dyn_symbols));
-                }
-                Ok(ShType::StrTab) => {
-                    if section.get_name(&elf_file) == Ok(".dynstr") {
-                        dyn_strings = section.raw_data(&elf_file);
-                    }
+                Ok(ShType::OsSpecific(0x6FFFFFF6)) => {
+                    gnu_hash_section = Some(section.raw_data(&elf_file));
+                    gnu_hash_link = Some(section.link() as usize);
                 }
+                Ok(ShType::OsSpecific(versions::SHT_GNU_VERSYM)) => versym = Some(section.raw_data(&elf_file)),
+                Ok(ShType::OsSpecific(versions::SHT_GNU_VERDEF)) => verdef = Some(section.raw_data(&elf_file)),
+                Ok(ShType::OsSpecific(versions::SHT_GNU_VERNEED)) => verneed = Some(section.raw_data(&elf_file)),
                 Ok(ShType::DynSym) => {
+                    if dyn_symbols_index.replace(index).is_some() {
+                        return Err(AndroidLoaderErr::ElfParsingError("more than one .dynsym".to_string()).into());
+                    }
+                    dyn_strings = Self::linked_strings(&elf_file, section)?;
                     dyn_symbols = match section.get_data(&elf_file).map_err(|err| AndroidLoaderErr::ElfParsingError(err.to_string()))? { // FIXME expensive
                         #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
                         SectionData::DynSymbolTable64(entries) => entries,
@@ -336,73 +1397,471 @@ impl AndroidLibrary<'_> {
                         _ => return Err(AndroidLoaderErr::ElfParsingError("Unsupported Dynamic symbol table data".to_string()).into())
                     };
                 }
-                Ok(ShType::Rel) | Ok(ShType::Rela) => {
-                    match section.get_data(&elf_file) {
-                        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-                        Ok(SectionData::Rela64(relocations)) => {
-                            for relocation in relocations {
-                                match RelocationType::from(relocation.get_type()) {
-                                    RelocationType::Absolute | RelocationType::GlobalData | RelocationType::JumpSlot => {
-                                        Self::absolute_reloc(&mut memory_map, dyn_symbols, dyn_strings, &hooks, relocation.get_symbol_table_index() as usize, relocation.get_offset() as usize, relocation.get_addend() as usize);
-                                    }
-                                    RelocationType::Relative => {
-                                        Self::relative_reloc(&mut memory_map, relocation.get_offset() as usize, relocation.get_addend() as usize);
-                                    }
-                                    RelocationType::Unknown(reloc_number) => {
-                                        return Err(AndroidLoaderErr::UnsupportedRelocation(reloc_number).into());
-                                    }
-                                }
-                            }
-                        }
-                        #[cfg(any(target_arch = "x86", target_arch = "arm"))]
-                        Ok(SectionData::Rel32(relocations)) => {
-                            for relocation in relocations {
-                                let offset = relocation.get_offset() as usize;
-                                let addend = usize::from_ne_bytes(
-                                    memory_map[offset
-                                        ..offset + std::mem::size_of::<usize>()]
-                                        .try_into()
-                                        .unwrap(),
-                                );
-                                match RelocationType::from(relocation.get_type()) {
-                                    RelocationType::Absolute => {
-                                        Self::absolute_reloc(&mut memory_map, dyn_symbols, dyn_strings, &hooks, relocation.get_symbol_table_index() as usize, offset, 0);
-                                    }
-                                    RelocationType::GlobalData | RelocationType::JumpSlot => {
-                                        Self::absolute_reloc(&mut memory_map, dyn_symbols, dyn_strings, &hooks, relocation.get_symbol_table_index() as usize, offset, addend);
-                                    }
-                                    RelocationType::Relative => {
-                                        Self::relative_reloc(&mut memory_map, offset, addend);
-                                    }
-                                    RelocationType::Unknown(reloc_number) => {
-                                        return Err(AndroidLoaderErr::UnsupportedRelocation(reloc_number).into());
-                                    }
-                                }
-                            }
-                        }
-                        _ => {}
-                    }
+                Ok(ShType::Dynamic) if dynamic_section.is_some() => {
+                    return Err(AndroidLoaderErr::ElfParsingError("more than one .dynamic section".to_string()).into());
                 }
+                Ok(ShType::Dynamic) => dynamic_section = Some(section),
                 _ => {}
             }
         }
+        if gnu_hash_link.is_some() && gnu_hash_link != dyn_symbols_index {
+            return Err(AndroidLoaderErr::ElfParsingError(".gnu.hash isn't linked to .dynsym".to_string()).into());
+        }
+
+        let gnu_hash_table = gnu_hash_section.map(|section| unsafe { GnuHashTable::new(section, dyn_symbols) });
+        let symbol_versions = versions::symbol_versions(versym, verdef, verneed, dyn_strings);
+        let (soname, needed) = match dynamic_section {
+            Some(section) => library_info::dynamic_strings(&elf_file, section, dyn_strings)?,
+            None => (None, Vec::new()),
+        };
+        let dynamic_entries = dynamic_section.map_or_else(Vec::new, |section| library_info::dynamic_entries(&elf_file, section));
+        let build_id = library_info::build_id(&elf_file);
+        let global_offset_table = Self::global_offset_table_offset(&elf_file, &dynamic_entries, memory_map.len());
+
+        // Names relocations are resolved by, indexed like the dynamic symbol table
+        let mut symbol_names: Vec<String> = dyn_symbols.iter()
+            .map(|sym| loader.symbol_name(dyn_strings, sym.name() as usize))
+            .collect();
+        let decoded_names = if loader.decodes_names() { Some(symbol_names.clone()) } else { None };
+        if let Some(rewriter) = &loader.symbol_rewriter {
+            rewriter(&mut symbol_names);
+        }
+
+        let undefined_symbols = UndefinedSymbols::new(loader.undefined_symbols.clone(), symbol_names.len())?;
+        stats.symbols = dyn_symbols.len();
+        stats.parse_time += parsing_started.elapsed();
+        let base = memory_map.as_ptr() as usize;
+        let extended_indices = match dyn_symbols_index {
+            Some(index) => Self::extended_section_indices(&elf_file, index)?,
+            None => &[],
+        };
+        // Registered once nothing can fail, as only the library unregisters it when dropped
+        let tls_module = tls_segment.map(|(image, size, align)| tls::register_module(image, size, align));
+        let registry_id = registry::register(
+            base, memory_map.len(), dyn_symbols, dyn_strings, symbol_versions.clone(), decoded_names.clone(), soname.clone(),
+        );
+        if !extended_indices.is_empty() {
+            registry::set_extended_indices(registry_id, extended_indices);
+        }
+        if let Some((offset, size)) = arm_exidx {
+            registry::set_arm_exidx(registry_id, base + offset, size / EXIDX_ENTRY_SIZE);
+        }
+        if let Some(interceptor) = &loader.dlopen_interceptor {
+            registry::set_dlopen_interceptor(registry_id, interceptor.clone());
+        }
+        if loader.isolated {
+            registry::set_isolated(registry_id);
+        }
+        if let Some(limit) = loader.sprintf_limit {
+            registry::set_sprintf_limit(registry_id, limit);
+        }
+        if let Some(fs) = &loader.virtual_fs {
+            registry::set_virtual_fs(registry_id, fs.clone());
+        }
+        let table = elf_file.header.pt2.ph_offset() as usize;
+        let count = elf_file.header.pt2.ph_count() as usize;
+        if file_leak.len() >= table + count * elf_file.header.pt2.ph_entry_size() as usize {
+            registry::set_program_headers(registry_id, file_leak.as_ptr() as usize + table, count);
+        }
+        if let Some(module) = tls_module {
+            registry::set_tls_module(registry_id, module);
+        }
 
-        let android_library = AndroidLibrary {
-            file: unsafe { Box::from_raw(file_leak_ptr) },
+        let library = AndroidLibrary {
+            file,
+            original: None,
+            retains_original: false,
             memory_map,
             gnu_hash_table,
             dyn_symbols,
-            dyn_strs: dyn_strings
+            extended_indices,
+            dyn_strs: dyn_strings,
+            tls_module,
+            undefined_symbols,
+            caller_stubs: CallerStubs::new(base),
+            lazy_bindings: None,
+            call_traces: None,
+            registry_id,
+            executable_stack,
+            arm_exidx,
+            stats,
+            soname,
+            dependencies: None,
+            entry,
+            interpreter,
+            program_headers: library_info::program_headers(&elf_file, file_leak),
+            dynamic_entries,
+            build_id,
+            global_offset_table,
+            segments,
+            relro,
+            relocated: Vec::new(),
+            decoded_names,
+            initialized: false,
+            on_fini: None,
+            program_arguments: loader.program_arguments.clone(),
         };
 
-        Ok(android_library)
+        Ok(Mapped { library, elf_file, symbol_names, symbol_versions, needed })
+    }
+
+    /// Apply a mapped library's relocations, resolving symbols in the libraries of `scope`
+    /// (registry ids, in lookup order) after `hooks`
+    pub(crate) fn relocate<'a>(mapped: Mapped<'a>, loader: &AndroidLoader, scope: &[usize], hooks: &HashMap<String, usize>) -> Result<AndroidLibrary<'a>> {
+        let Mapped { mut library, elf_file, symbol_names, symbol_versions, .. } = mapped;
+        #[cfg(any(target_arch = "arm", target_arch = "x86_64"))]
+        let tls_module = library.tls_module;
+        let relocation_started = Instant::now();
+        let mut relocations = Vec::new();
+        for section in elf_file.section_iter().filter(|section| matches!(section.get_type(), Ok(ShType::Rel) | Ok(ShType::Rela))) {
+            match section.get_data(&elf_file) {
+                #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+                Ok(SectionData::Rela64(entries)) => relocations.extend(entries.iter().map(|relocation| RelocationEntry {
+                    offset: relocation.get_offset(),
+                    rtype: relocation.get_type(),
+                    symbol_index: relocation.get_symbol_table_index(),
+                    addend: Some(relocation.get_addend() as i64),
+                })),
+                #[cfg(any(target_arch = "x86", target_arch = "arm"))]
+                Ok(SectionData::Rel32(entries)) => relocations.extend(entries.iter().map(|relocation| RelocationEntry {
+                    offset: relocation.get_offset() as u64,
+                    rtype: u32::from(relocation.get_type()),
+                    symbol_index: relocation.get_symbol_table_index(),
+                    addend: None,
+                })),
+                _ => {}
+            }
+        }
+        if let Some(transform) = &loader.relocation_transform {
+            transform(&mut relocations);
+        }
+        let total = relocations.len();
+        // Traced imports are bound up front, to their tracing stubs
+        if loader.trace_calls.is_some() && call_trace::SUPPORTED {
+            library.call_traces = Some(CallTraces::new(loader.trace_calls.clone().unwrap(), total)?);
+        } else if loader.lazy_binding && lazy_binding::SUPPORTED && loader.resolve_mode == ResolveMode::Lazy {
+            let lazy_scope = LazyScope {
+                hooks: hooks.clone(),
+                scope: scope.to_vec(),
+                bionic_stubs: loader.bionic_stubs,
+                steps: loader.resolution_steps().to_vec(),
+                behavior: loader.undefined_symbols.clone(),
+            };
+            library.lazy_bindings = Some(LazyBindings::new(lazy_scope, total)?);
+        }
+
+        let AndroidLibrary {
+            memory_map, dyn_symbols, extended_indices, undefined_symbols, caller_stubs, lazy_bindings, call_traces, stats, dynamic_entries,
+            #[cfg(target_arch = "x86")]
+            global_offset_table,
+            ..
+        } = &mut library;
+        let extended_indices: &[u32] = extended_indices;
+        let dyn_symbols: &[DynEntry] = dyn_symbols;
+        // B + A, with the addend in place
+        for offset in Self::relr_offsets(memory_map, dynamic_entries)? {
+            let addend = usize::from_ne_bytes(memory_map[offset..offset + std::mem::size_of::<usize>()].try_into().unwrap());
+            Self::relative_reloc(memory_map, offset, Some(addend as i64));
+            stats.relr_relocations += 1;
+        }
+        #[cfg(target_arch = "x86")]
+        let base = memory_map.as_ptr() as usize;
+        #[cfg(target_arch = "x86")]
+        let got = || global_offset_table.map(|offset| base + offset)
+            .ok_or_else(|| AndroidLoaderErr::ElfParsingError("GOT-relative relocation without a GOT".to_string()));
+        // The GOT entry the linker allocated a symbol, i.e. the first `GLOB_DAT` or `JUMP_SLOT` binding it
+        #[cfg(target_arch = "x86")]
+        let got_entry = |index: u32| relocations.iter()
+            .find(|relocation| {
+                relocation.symbol_index == index
+                    && matches!(RelocationType::from(relocation.rtype as RelocType), RelocationType::GlobalData | RelocationType::JumpSlot)
+            })
+            .map(|relocation| base + relocation.offset as usize)
+            .ok_or_else(|| AndroidLoaderErr::ElfParsingError(format!("GOT32 relocation of symbol {index} without a GOT entry")));
+        #[cfg(any(target_arch = "arm", target_arch = "x86_64"))]
+        let missing_tls = || AndroidLoaderErr::ElfParsingError("TLS relocation without a PT_TLS segment".to_string());
+
+        // Imports may require a specific version from the library defining them
+        let import_version = |index: u32| symbol_versions.get(index as usize)
+            .and_then(Option::as_ref)
+            .filter(|_| dyn_symbols.get(index as usize).map_or(false, |symbol| !Self::defined(symbol, index as usize, extended_indices)))
+            .map(|version| version.name.as_str());
+        let symbol_name = |index: u32| symbol_names.get(index as usize).ok_or_else(|| {
+            AndroidLoaderErr::ElfParsingError(format!("relocation of symbol {index} past the symbol table"))
+        });
+        let mut resolved = HashMap::new();
+        let mut resolution_stats = LoadStats::default();
+        let mut missing = Vec::new();
+        let mut resolve = |index: u32| -> Result<usize> {
+            if let Some(symbol) = resolved.get(&index) {
+                return Ok(*symbol);
+            }
+            let (symbol, source) = Self::symbol_finder(
+                symbol_name(index)?, import_version(index), hooks, scope, loader, undefined_symbols, caller_stubs,
+            );
+            if source == SymbolSource::Undefined {
+                let weak = dyn_symbols.get(index as usize).map_or(false, |symbol| symbol.get_binding() == Ok(symbol_table::Binding::Weak));
+                if loader.resolve_mode == ResolveMode::Strict && !weak {
+                    return Err(AndroidLoaderErr::UnresolvedSymbol { name: symbol_name(index)?.clone() }.into());
+                }
+                missing.push(index);
+            }
+            resolution_stats.count_resolution(source);
+            resolved.insert(index, symbol);
+            Ok(symbol)
+        };
+
+        let mut progress = Progress { callback: loader.progress.as_deref(), done: 0, total };
+        // Kept so a reloaded dependency can be bound again
+        let mut slots = Vec::new();
+
+        for relocation in &relocations {
+            progress.advance()?;
+            *stats.relocations.entry(relocation.rtype).or_insert(0) += 1;
+            let offset = relocation.offset as usize;
+            let index = relocation.symbol_index;
+            let rtype = relocation.rtype as RelocType;
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            {
+                let addend = relocation.addend.unwrap_or(0) as usize;
+                match RelocationType::from(rtype) {
+                    RelocationType::None => {}
+                    // S + A
+                    RelocationType::Absolute => Self::absolute_reloc(memory_map, resolve(index)?, offset, addend),
+                    // S on x86_64 but S + A on aarch64, per their psABIs
+                    RelocationType::GlobalData | RelocationType::JumpSlot => {
+                        let addend = if cfg!(target_arch = "aarch64") { addend } else { 0 };
+                        let name = symbol_name(index)?;
+                        slots.push((memory_map.as_ptr() as usize + offset, index));
+                        let lazy = lazy_bindings.as_mut()
+                            .filter(|_| matches!(RelocationType::from(rtype), RelocationType::JumpSlot))
+                            .filter(|_| Self::caller_sensitive(name).is_none());
+                        match lazy {
+                            Some(lazy) => {
+                                stats.lazily_bound += 1;
+                                let slot = memory_map.as_ptr() as usize + offset;
+                                let stub = lazy.defer(slot, name, import_version(index), addend);
+                                Self::absolute_reloc(memory_map, stub, offset, 0);
+                            }
+                            None => {
+                                let traces = call_traces.as_mut().filter(|_| matches!(RelocationType::from(rtype), RelocationType::JumpSlot));
+                                match traces {
+                                    Some(traces) => {
+                                        let stub = traces.wrap(name, resolve(index)?.wrapping_add(addend));
+                                        Self::absolute_reloc(memory_map, stub, offset, 0);
+                                    }
+                                    None => Self::absolute_reloc(memory_map, resolve(index)?, offset, addend),
+                                }
+                            }
+                        }
+                    }
+                    #[cfg(target_arch = "x86_64")]
+                    RelocationType::Absolute32 | RelocationType::Absolute32Signed | RelocationType::Pc32 => {
+                        let mut value = resolve(index)?.wrapping_add(addend);
+                        if let RelocationType::Pc32 = RelocationType::from(rtype) {
+                            value = value.wrapping_sub(memory_map.as_ptr() as usize + offset);
+                        }
+                        let signed = !matches!(RelocationType::from(rtype), RelocationType::Absolute32);
+                        Self::truncating_reloc(memory_map, value, offset, rtype, signed, symbol_name(index)?)?;
+                    }
+                    RelocationType::Relative => Self::relative_reloc(memory_map, offset, Some(addend as i64)),
+                    // The variable's offset in the module's block, plus the block's from the
+                    // thread pointer, which is negative as static TLS is below it on x86_64.
+                    // Variables the library imports are in the block of the one defining them.
+                    #[cfg(target_arch = "x86_64")]
+                    RelocationType::TlsStaticOffset => {
+                        let symbol = index as usize;
+                        let (module, variable) = if symbol == 0 || dyn_symbols.get(symbol).map_or(false, |entry| Self::defined(entry, symbol, extended_indices)) {
+                            (tls_module.ok_or_else(missing_tls)?, Self::tls_symbol_offset(dyn_symbols, symbol))
+                        } else {
+                            let name = symbol_name(index)?;
+                            registry::scope_tls_symbol(scope, name, import_version(index))
+                                .ok_or_else(|| AndroidLoaderErr::UnresolvedTlsSymbol { name: name.clone() })?
+                        };
+                        let module_offset = tls::static_tp_offset(module)?;
+                        Self::absolute_reloc(memory_map, variable.wrapping_add(module_offset as usize), offset, addend);
+                    }
+                    RelocationType::Unknown(reloc_number) => Self::unsupported_relocation(loader.relocation_policy, stats, reloc_number)?,
+                }
+            }
+            #[cfg(any(target_arch = "x86", target_arch = "arm"))]
+            {
+                let addend = relocation.addend.map_or_else(|| Self::implicit_addend(memory_map, offset) as usize, |addend| addend as usize);
+                match RelocationType::from(rtype) {
+                    RelocationType::None => {}
+                    RelocationType::Absolute => Self::write_field32(memory_map, offset, resolve(index)?.wrapping_add(addend)),
+                    // Like bionic, ignore what's in place: it's usually the lazy binding's PLT entry
+                    RelocationType::GlobalData | RelocationType::JumpSlot => {
+                        slots.push((memory_map.as_ptr() as usize + offset, index));
+                        Self::write_field32(memory_map, offset, resolve(index)?);
+                    }
+                    RelocationType::Relative => {
+                        let base = memory_map.as_ptr() as usize;
+                        Self::relative_field32(memory_map, offset, base, relocation.addend);
+                    }
+                    #[cfg(target_arch = "arm")]
+                    RelocationType::TlsModule => {
+                        Self::write_field32(memory_map, offset, tls_module.ok_or_else(missing_tls)?);
+                    }
+                    #[cfg(target_arch = "arm")]
+                    RelocationType::TlsOffset => {
+                        let value = Self::tls_symbol_offset(dyn_symbols, index as usize).wrapping_add(addend);
+                        Self::write_field32(memory_map, offset, value);
+                    }
+                    #[cfg(target_arch = "arm")]
+                    RelocationType::TlsStaticOffset => {
+                        let module_offset = tls::static_tp_offset(tls_module.ok_or_else(missing_tls)?)?;
+                        let value = Self::tls_symbol_offset(dyn_symbols, index as usize)
+                            .wrapping_add(addend)
+                            .wrapping_add(module_offset as usize);
+                        Self::write_field32(memory_map, offset, value);
+                    }
+                    #[cfg(target_arch = "arm")]
+                    RelocationType::TlsDescriptor => {
+                        // ARM descriptors are (argument, resolver); the in-place addend is the argument word
+                        let module_offset = tls::static_tp_offset(tls_module.ok_or_else(missing_tls)?)?;
+                        let value = Self::tls_symbol_offset(dyn_symbols, index as usize)
+                            .wrapping_add(addend)
+                            .wrapping_add(module_offset as usize);
+                        Self::write_field32(memory_map, offset, value);
+                        Self::write_field32(memory_map, offset + 4, tls::android_loader_tlsdesc_static as *const () as usize);
+                    }
+                    // S + A - P
+                    #[cfg(target_arch = "x86")]
+                    RelocationType::Pc32 => {
+                        let place = memory_map.as_ptr() as usize + offset;
+                        Self::write_field32(memory_map, offset, resolve(index)?.wrapping_add(addend).wrapping_sub(place));
+                    }
+                    // S + A - GOT
+                    #[cfg(target_arch = "x86")]
+                    RelocationType::GotOffset => {
+                        let value = resolve(index)?.wrapping_add(addend).wrapping_sub(got()?);
+                        Self::write_field32(memory_map, offset, value);
+                    }
+                    // GOT + A - P
+                    #[cfg(target_arch = "x86")]
+                    RelocationType::GotPc => {
+                        let place = memory_map.as_ptr() as usize + offset;
+                        Self::write_field32(memory_map, offset, got()?.wrapping_add(addend).wrapping_sub(place));
+                    }
+                    // G + A, G being the entry's offset in the GOT
+                    #[cfg(target_arch = "x86")]
+                    RelocationType::GotEntry => {
+                        let value = got_entry(index)?.wrapping_sub(got()?).wrapping_add(addend);
+                        Self::write_field32(memory_map, offset, value);
+                    }
+                    RelocationType::Unknown(reloc_number) => Self::unsupported_relocation(loader.relocation_policy, stats, reloc_number)?,
+                }
+            }
+        }
+
+        undefined_symbols.finish()?;
+        caller_stubs.finish()?;
+        if let Some(lazy_bindings) = lazy_bindings {
+            lazy_bindings.finish()?;
+        }
+        if let Some(call_traces) = call_traces {
+            call_traces.finish()?;
+        }
+        stats.resolved_by_hook = resolution_stats.resolved_by_hook;
+        stats.resolved_by_library = resolution_stats.resolved_by_library;
+        stats.resolved_by_global = resolution_stats.resolved_by_global;
+        stats.resolved_by_registered = resolution_stats.resolved_by_registered;
+        stats.resolved_by_libc = resolution_stats.resolved_by_libc;
+        stats.undefined = resolution_stats.undefined;
+        for index in missing {
+            let function = dyn_symbols.get(index as usize).map_or(false, |symbol| symbol.get_type() == Ok(symbol_table::Type::Func))
+                || relocations.iter().any(|relocation| {
+                    relocation.symbol_index == index && matches!(RelocationType::from(relocation.rtype as RelocType), RelocationType::JumpSlot)
+                });
+            let names = if function { &mut stats.missing_imports.functions } else { &mut stats.missing_imports.data };
+            names.push(symbol_names[index as usize].clone());
+        }
+        stats.missing_imports.functions.sort();
+        stats.missing_imports.data.sort();
+        stats.relocate_time = relocation_started.elapsed();
+        registry::set_slots(library.registry_id, slots);
+        library.relocated = Self::relocated_runs(&library);
+        library.protect_relro()?;
+        if loader.verify_wx && loader.protection_policy != ProtectionPolicy::Permissive {
+            library.check_wx()?;
+        }
+        Ok(library)
     }
 }
 
 #[derive(Debug)]
-enum AndroidLoaderErr {
+pub enum AndroidLoaderErr {
     ElfParsingError(String),
-    UnsupportedRelocation(RelocType)
+    UnsupportedRelocation(RelocType),
+    /// A truncating relocation's value (`symbol + addend`, minus the field's address for
+    /// PC-relative ones) doesn't fit in its field at `offset` from the load base
+    RelocationOverflow { symbol: String, rtype: RelocType, value: usize, offset: usize },
+    /// Not enough static TLS left for a module of this size
+    StaticTlsExhausted(usize),
+    /// A TLS relocation refers to a module that isn't registered, e.g. one whose library was
+    /// unloaded
+    UnknownTlsModule(usize),
+    /// Static TLS needs the thread pointer, which can't be read on this platform
+    StaticTlsUnsupported,
+    /// The relocation progress callback cancelled the load
+    Cancelled,
+    /// The library's byte order isn't the host's
+    EndianMismatch,
+    /// The library is 32-bit on a 64-bit host or the other way around. It can still be
+    /// [inspected](AndroidLoader::inspect_bytes). Only the host architecture's relocation types
+    /// are compiled in.
+    ClassMismatch,
+    /// The file isn't `ET_DYN` (e.g. a fixed-address executable), given its `e_type`
+    NotASharedObject(u16),
+    /// The file's SHA-256 hash isn't the one [`AndroidLoader::verify_sha256`] expects
+    IntegrityCheckFailed { expected: [u8; 32], actual: [u8; 32] },
+    /// A hook's address is null, or misaligned for code on the architecture
+    InvalidHook { name: String },
+    /// No loaded dependency has this soname, or it isn't in the library paths anymore
+    DependencyNotFound(String),
+    /// The buffer given to [`AndroidLoader::load_into`] is smaller than the library's `size`
+    /// bytes or its start isn't aligned to `alignment`, the page size
+    InvalidBuffer { size: usize, alignment: usize },
+    /// The library can't be mapped at `address`, from [`AndroidLoader::base_address`], as it
+    /// isn't page-aligned or something's mapped there
+    BaseUnavailable { address: usize },
+    /// Imports of the library and the dependencies it brought in that nothing provides, with
+    /// [`AndroidLoader::reject_missing_imports`]
+    MissingImports(MissingImports),
+    /// A strong import of the library or a dependency it brought in that nothing provides,
+    /// with [`ResolveMode::Strict`]
+    UnresolvedSymbol { name: String },
+    /// A thread-local variable the library imports that no library of its scope defines, as
+    /// hooks and stubs can't provide thread-locals
+    UnresolvedTlsSymbol { name: String },
+    /// A `PT_LOAD` segment at `virtual_addr` doesn't fit in the image as laid out, so it
+    /// can't be mapped without touching memory outside it
+    SegmentOutOfBounds { virtual_addr: usize, mem_size: usize },
+    /// Initializer `index` didn't return within [`AndroidLoader::init_timeout`]. It's left
+    /// running, and the libraries of the load are leaked.
+    InitTimeout { index: usize },
+    /// Segments aligned to `segment_align` share pages of the host's `page_size`, so they
+    /// can't be protected as their flags say under [`ProtectionPolicy::Strict`]
+    UnrepresentableProtections { segment_align: usize, page_size: usize },
+    /// The library doesn't export a symbol of this name
+    UnknownSymbol(String),
+    /// A [dynamic call](crate::dynamic_call) of this many arguments, more than fit in the
+    /// registers and [`MAX_STACK_ARGUMENTS`](crate::dynamic_call::MAX_STACK_ARGUMENTS)
+    TooManyArguments(usize),
+    /// The page at `address` is both writable and executable once relocated, with
+    /// [`AndroidLoader::verify_wx`]
+    WxViolation { address: usize },
+    /// The range `start..end` isn't in the library's image, or is empty
+    OutsideImage { start: usize, end: usize },
+    /// [`AndroidLibrary::write_symbol`] was given `len` bytes for `symbol`, which is `size`
+    SymbolTooSmall { symbol: String, size: usize, len: usize },
+    /// The APK isn't a zip archive [`AndroidLoader::load_from_apk`] can read, for this reason
+    #[cfg(feature = "apk")]
+    MalformedApk(String),
+    /// The APK has no entry of this name
+    #[cfg(feature = "apk")]
+    NotInApk(String),
 }
 
 impl Display for AndroidLoaderErr {
@@ -415,7 +1874,18 @@ impl Error for AndroidLoaderErr {}
 
 #[cfg(test)]
 mod tests {
-    use crate::android_library::GnuHashTable;
+    use crate::android_library::{AndroidLibrary, GnuHashTable};
+    #[cfg(target_arch = "x86_64")]
+    use {
+        crate::android_library::AndroidLoaderErr,
+        crate::android_loader::{AndroidLoader, ProtectionPolicy, RelocationPolicy},
+        crate::hook_manager::add_hooks,
+        region::Protection,
+        crate::test_elf::{
+            TestElf, BSS_ADDRESS, R_X86_64_32, R_X86_64_32S, R_X86_64_64, R_X86_64_GLOB_DAT, R_X86_64_JUMP_SLOT, R_X86_64_NONE, R_X86_64_PC32, R_X86_64_RELATIVE,
+        },
+        std::collections::HashMap,
+    };
 
     #[test]
     fn gnu_hash_tests() {
@@ -423,4 +1893,746 @@ mod tests {
         assert_eq!(GnuHashTable::hash("printf"), 0x156b2bb8);
         assert_eq!(GnuHashTable::hash("exit"), 0x7c967e3f);
     }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn packed_relative_relocations() {
+        let mut elf = TestElf::new();
+        let words: Vec<u8> = (0..80u64).flat_map(|index| (0x100 + index).to_le_bytes()).collect();
+        let cells = elf.object("relr_cells", &words);
+        // The start of a run, then a bitmap word for the next 63 words and one for the 63 after
+        let relocated = [0, 1, 2, 5, 40, 63, 64, 70, 79];
+        let offsets: Vec<u64> = relocated.iter().map(|index| cells + index * 8).collect();
+        elf.relr(&offsets);
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+
+        let base = library.memory_map.as_ptr() as u64;
+        let cells = library.get_symbol("relr_cells").unwrap() as *const u64;
+        for index in 0..80u64 {
+            let expected = if relocated.contains(&index) { base + 0x100 + index } else { 0x100 + index };
+            assert_eq!(unsafe { *cells.add(index as usize) }, expected, "word {index}");
+        }
+        assert_eq!(library.load_stats().relr_relocations, relocated.len());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn global_offset_table_from_pltgot() {
+        let library = AndroidLibrary::load_from_bytes(TestElf::new().build()).unwrap();
+        assert_eq!(library.global_offset_table(), None);
+
+        let mut elf = TestElf::new();
+        let got = elf.object("got_start", &[0; 24]);
+        elf.pltgot(got);
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        assert_eq!(library.global_offset_table(), Some(library.get_symbol("got_start").unwrap() as usize));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn pure_bss_segment() {
+        let mut elf = TestElf::new();
+        elf.object("bss_neighbour", &[0x77; 16]);
+        elf.bss(3 * 0x1000 + 10);
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        assert_eq!(library.load_stats().segments, 2);
+
+        let bss = unsafe { std::slice::from_raw_parts_mut(library.memory_map.as_ptr().add(BSS_ADDRESS as usize) as *mut u8, 3 * 0x1000 + 10) };
+        assert!(bss.iter().all(|byte| *byte == 0));
+        let protection = region::query(bss.as_ptr()).unwrap().protection();
+        assert!(protection.contains(Protection::READ_WRITE));
+        if region::page::size() == 0x1000 {
+            assert_eq!(protection, Protection::READ_WRITE);
+        }
+        bss.fill(0xee);
+        assert_eq!(unsafe { *(library.get_symbol("bss_neighbour").unwrap() as *const [u8; 16]) }, [0x77; 16]);
+
+        // Not even BSS
+        let mut elf = TestElf::new();
+        elf.bss(0);
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        assert_eq!(library.load_stats().segments, 1);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn segment_past_image() {
+        let set_load_address = |elf: &mut Vec<u8>, address: u64| {
+            let phoff = u64::from_le_bytes(elf[32..40].try_into().unwrap()) as usize;
+            let phnum = u16::from_le_bytes(elf[56..58].try_into().unwrap()) as usize;
+            let load = (0..phnum).map(|index| phoff + index * 56).find(|header| elf[*header..*header + 4] == [1, 0, 0, 0]).unwrap();
+            elf[load + 16..load + 24].copy_from_slice(&address.to_le_bytes());
+        };
+        let out_of_bounds = |elf: Vec<u8>| {
+            let err = AndroidLibrary::load_from_bytes(elf).err().unwrap();
+            matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::SegmentOutOfBounds { .. }))
+        };
+
+        // Only the pages from the first segment on are allocated, yet it's mapped at its address
+        let mut elf = TestElf::new().build();
+        set_load_address(&mut elf, 0x10000);
+        assert!(out_of_bounds(elf));
+
+        let mut elf = TestElf::new().build();
+        set_load_address(&mut elf, u64::MAX - 16);
+        assert!(out_of_bounds(elf));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn reject_big_endian() {
+        let mut elf = TestElf::new().build();
+        elf[5] = 2; // EI_DATA = ELFDATA2MSB
+        let err = AndroidLibrary::load_from_bytes(elf).err().unwrap();
+        assert!(matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::EndianMismatch)));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn dumped_image() {
+        let mut elf = TestElf::new();
+        elf.object("dumped_marker", &[0x11; 8]);
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let marker = library.get_symbol("dumped_marker").unwrap() as *mut [u8; 8];
+        // Changed after loading, as self-modifying code would
+        unsafe { *marker = *b"modified" };
+
+        let path = std::env::temp_dir().join(format!("android-loader-dump-{}.bin", std::process::id()));
+        let base = library.dump_image(&path).unwrap();
+        let dumped = std::fs::read(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(dumped.len(), library.memory_map.len());
+        let offset = marker as usize - base;
+        assert_eq!(&dumped[offset..offset + 8], b"modified");
+        assert_eq!(dumped, &library.memory_map[..]);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn reject_other_class() {
+        let mut elf = TestElf::new().build();
+        elf[4] = 1; // EI_CLASS = ELFCLASS32
+        let err = AndroidLibrary::load_from_bytes(elf).err().unwrap();
+        assert!(matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::ClassMismatch)));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn oversized_segment_file_size() {
+        let mut elf = TestElf::new();
+        elf.object("marker", &[0x5a; 8]);
+        let mut elf = elf.build();
+        // p_filesz of the PT_LOAD, past the end of the file
+        let file_size = u64::from_le_bytes(elf[96..104].try_into().unwrap());
+        elf[96..104].copy_from_slice(&(file_size + 0x1000).to_le_bytes());
+
+        let library = AndroidLibrary::load_from_bytes(elf).unwrap();
+        let marker = library.get_symbol("marker").unwrap() as *const [u8; 8];
+        assert_eq!(unsafe { *marker }, [0x5a; 8]);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn gnu_stack_flag() {
+        let mut elf = TestElf::new();
+        assert!(!AndroidLibrary::load_from_bytes(elf.build()).unwrap().wants_executable_stack());
+        elf.gnu_stack(false);
+        assert!(!AndroidLibrary::load_from_bytes(elf.build()).unwrap().wants_executable_stack());
+        elf.gnu_stack(true);
+        assert!(AndroidLibrary::load_from_bytes(elf.build()).unwrap().wants_executable_stack());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn arm_exception_index() {
+        let mut elf = TestElf::new();
+        let offset = elf.object("exidx_table", &[0x11; 24]);
+        elf.arm_exidx(offset, 24);
+        // Only an ARM library's header is the exception index
+        assert!(AndroidLibrary::load_from_bytes(elf.build()).unwrap().arm_exidx().is_none());
+
+        elf.machine(40); // EM_ARM
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let table = library.get_symbol("exidx_table").unwrap();
+        assert_eq!(library.arm_exidx(), Some((table, 3)));
+        assert_eq!(AndroidLoader::find_arm_exidx(table as usize + 4), Some((table, 3)));
+        assert_eq!(AndroidLoader::find_arm_exidx(arm_exception_index as *const () as usize), None);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn truncating_relocations() {
+        let mut hooks = HashMap::new();
+        hooks.insert("truncating_low".to_owned(), 0x1234_5678);
+        hooks.insert("truncating_negative".to_owned(), -0x1000isize as usize);
+        add_hooks(hooks);
+
+        let mut elf = TestElf::new();
+        let cells = elf.object("cells", &[0; 12]);
+        elf.relocation(cells, R_X86_64_32, Some("truncating_low"), 8);
+        elf.relocation(cells + 4, R_X86_64_32S, Some("truncating_negative"), 0);
+        elf.relocation(cells + 8, R_X86_64_PC32, Some("cells"), 0);
+
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let cells = library.get_symbol("cells").unwrap() as *const u32;
+        unsafe {
+            assert_eq!(cells.read(), 0x1234_5680);
+            assert_eq!(cells.add(1).read(), 0xffff_f000);
+            assert_eq!(cells.add(2).read(), -8i32 as u32);
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn truncating_relocations_overflow() {
+        let mut hooks = HashMap::new();
+        hooks.insert("overflowing_unsigned".to_owned(), 0x1_0000_0000);
+        hooks.insert("overflowing_signed".to_owned(), 0x8000_0000);
+        add_hooks(hooks);
+
+        for (rtype, symbol, addend) in [(R_X86_64_32, "overflowing_unsigned", 4), (R_X86_64_32S, "overflowing_signed", 0)] {
+            let mut elf = TestElf::new();
+            elf.object("padding", &[0; 8]);
+            let cell = elf.object("cell", &[0; 4]);
+            elf.relocation(cell, rtype, Some(symbol), addend);
+            let elf = elf.build();
+            let info = AndroidLoader::new().inspect_bytes(elf.clone()).unwrap();
+            let cell = info.symbols.iter().find(|info| info.name == "cell").unwrap().value;
+
+            let err = AndroidLibrary::load_from_bytes(elf).err().unwrap();
+            match err.downcast_ref::<AndroidLoaderErr>() {
+                Some(AndroidLoaderErr::RelocationOverflow { symbol: name, rtype: t, value, offset }) => {
+                    assert_eq!((name.as_str(), *t, *offset as u64), (symbol, rtype, cell));
+                    assert_eq!(*value, if rtype == R_X86_64_32 { 0x1_0000_0004 } else { 0x8000_0000 });
+                }
+                _ => panic!("unexpected error {err}"),
+            }
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn dlopen_global() {
+        type Dlopen = extern "C" fn(*const std::os::raw::c_char, i32) -> *mut std::os::raw::c_void;
+        type Dlclose = extern "C" fn(*mut std::os::raw::c_void) -> i32;
+        let dlopen: Dlopen = unsafe { std::mem::transmute(AndroidLibrary::get_libc_symbol("dlopen").unwrap()) };
+        let dlclose: Dlclose = unsafe { std::mem::transmute(AndroidLibrary::get_libc_symbol("dlclose").unwrap()) };
+
+        let mut provider = TestElf::new();
+        provider.function("dlopen_global_answer", &[0xb8, 42, 0, 0, 0, 0xc3]); // mov eax, 42; ret
+        let path = std::env::temp_dir().join(format!("android-loader-dlopen-global-{}.so", std::process::id()));
+        std::fs::write(&path, provider.build()).unwrap();
+        let path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+
+        let mut consumer = TestElf::new();
+        consumer.thunk("call_answer", "dlopen_global_answer");
+        let consumer = consumer.build();
+
+        // RTLD_NOLOAD doesn't load, RTLD_LOCAL keeps the symbols out of the global namespace
+        assert!(dlopen(path.as_ptr(), 4).is_null());
+        let local = dlopen(path.as_ptr(), 0);
+        assert!(!local.is_null());
+        let library = AndroidLibrary::load_from_bytes(consumer.clone()).unwrap();
+        assert_eq!(library.load_stats().resolved_by_global, 0);
+        assert_eq!(library.load_stats().undefined, 1);
+
+        // Opening the same path again promotes it and shares the handle
+        let global = dlopen(path.as_ptr(), 0x100 | 4);
+        assert_eq!(global, local);
+        let library = AndroidLibrary::load_from_bytes(consumer).unwrap();
+        assert_eq!(library.load_stats().resolved_by_global, 1);
+        let call_answer: extern "C" fn() -> u32 = unsafe { std::mem::transmute(library.get_symbol("call_answer").unwrap()) };
+        assert_eq!(call_answer(), 42);
+
+        assert_eq!(dlclose(global), 0);
+        assert_eq!(dlclose(local), 0);
+        assert!(dlopen(path.as_ptr(), 4).is_null());
+        std::fs::remove_file(path.to_str().unwrap()).unwrap();
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn intercepted_dlopen() {
+        use crate::android_loader::DlopenAction;
+        use std::ffi::CString;
+        use std::os::raw::{c_char, c_void};
+
+        let mut substitute = TestElf::new();
+        substitute.function("intercepted_answer", &[0xb8, 7, 0, 0, 0, 0xc3]); // mov eax, 7; ret
+        let path = std::env::temp_dir().join(format!("android-loader-intercepted-{}.so", std::process::id()));
+        std::fs::write(&path, substitute.build()).unwrap();
+        let mut existing = TestElf::new();
+        existing.function("intercepted_existing", &[0xc3]);
+        let existing = Box::new(AndroidLibrary::load_from_bytes(existing.build()).unwrap());
+        let existing_handle = existing.as_handle() as usize;
+
+        let mut elf = TestElf::new();
+        elf.thunk("call_dlopen", "dlopen");
+        elf.thunk("call_dlclose", "dlclose");
+        let substitute_path = path.clone();
+        let loader = AndroidLoader::new().intercept_dlopen(move |name| match name {
+            "libwanted.so" => DlopenAction::Substitute(substitute_path.clone()),
+            "libexisting.so" => DlopenAction::UseExisting(existing_handle as *mut c_void),
+            "libforbidden.so" => DlopenAction::Deny,
+            _ => DlopenAction::Default,
+        });
+        let library = loader.load_library_from_bytes(elf.build()).unwrap();
+        let dlopen: extern "C" fn(*const c_char, i32) -> *mut c_void = unsafe { std::mem::transmute(library.get_symbol("call_dlopen").unwrap()) };
+        let dlclose: extern "C" fn(*mut c_void) -> i32 = unsafe { std::mem::transmute(library.get_symbol("call_dlclose").unwrap()) };
+        let open = |name: &str| dlopen(CString::new(name).unwrap().as_ptr(), 0);
+
+        let wanted = open("libwanted.so");
+        assert!(!wanted.is_null());
+        let answer = unsafe { (*(wanted as *const AndroidLibrary)).get_symbol("intercepted_answer").unwrap() };
+        let answer: extern "C" fn() -> u32 = unsafe { std::mem::transmute(answer) };
+        assert_eq!(answer(), 7);
+        // The substitute is shared with opening its own path
+        assert_eq!(open(path.to_str().unwrap()), wanted);
+        assert_eq!(dlclose(wanted), 0);
+        assert_eq!(dlclose(wanted), 0);
+
+        assert_eq!(open("libexisting.so"), existing.as_handle());
+        assert_eq!(dlclose(existing.as_handle()), 0);
+        assert!(existing.get_symbol("intercepted_existing").is_some());
+        assert!(open("libforbidden.so").is_null());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn protection_policies() {
+        let page = region::page::size();
+        let mut elf = TestElf::new();
+        elf.function("policy_code", &[0xc3]);
+        // Far enough in that the RELRO pages don't reach the code's
+        let cell = elf.object("policy_cell", &vec![0; 3 * page]);
+        elf.relro(cell + page as u64, 8);
+        let mut elf = elf.build();
+        // The one PT_LOAD as R-X, aligned to `align`
+        let set_load = |elf: &mut Vec<u8>, align: u64| {
+            elf[68..72].copy_from_slice(&5u32.to_le_bytes());
+            elf[112..120].copy_from_slice(&align.to_le_bytes());
+        };
+        let load = |elf: &Vec<u8>, policy| AndroidLoader::new().protection_policy(policy).load_library_from_bytes(elf.clone());
+        let protections = |library: &AndroidLibrary| {
+            let code = library.get_symbol("policy_code").unwrap() as *const u8;
+            let relro = unsafe { (library.get_symbol("policy_cell").unwrap() as *const u8).add(page) };
+            (region::query(code).unwrap().protection(), region::query(relro).unwrap().protection())
+        };
+
+        set_load(&mut elf, page as u64);
+        let strict = load(&elf, ProtectionPolicy::Strict).unwrap();
+        assert_eq!(protections(&strict), (Protection::READ_EXECUTE, Protection::READ));
+        let compatible = load(&elf, ProtectionPolicy::Compatible).unwrap();
+        assert_eq!(protections(&compatible), (Protection::READ_EXECUTE, Protection::READ_EXECUTE));
+        let permissive = load(&elf, ProtectionPolicy::Permissive).unwrap();
+        assert_eq!(protections(&permissive), (Protection::READ_WRITE_EXECUTE, Protection::READ_WRITE_EXECUTE));
+
+        // Segments sharing pages can't have their own protections
+        set_load(&mut elf, 0x100);
+        let err = load(&elf, ProtectionPolicy::Strict).err().unwrap();
+        assert!(matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::UnrepresentableProtections { .. })));
+        let compatible = load(&elf, ProtectionPolicy::Compatible).unwrap();
+        assert_eq!(protections(&compatible), (Protection::READ_WRITE_EXECUTE, Protection::READ_WRITE_EXECUTE));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn wx_verification() {
+        let mut elf = TestElf::new();
+        elf.function("verified_code", &[0xc3]);
+        let mut elf = elf.build();
+        let load = |elf: &Vec<u8>, policy| AndroidLoader::new().protection_policy(policy).verify_wx().load_library_from_bytes(elf.clone());
+
+        // The one PT_LOAD is RWX
+        let err = load(&elf, ProtectionPolicy::Compatible).err().unwrap();
+        assert!(matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::WxViolation { .. })));
+        let permissive = load(&elf, ProtectionPolicy::Permissive).unwrap();
+        assert!(permissive.get_symbol("verified_code").is_some());
+
+        elf[68..72].copy_from_slice(&5u32.to_le_bytes());
+        load(&elf, ProtectionPolicy::Strict).unwrap();
+        load(&elf, ProtectionPolicy::Compatible).unwrap();
+    }
+
+    #[test]
+    fn page_compatibility() {
+        // 4KiB-aligned libraries, as built by older NDKs
+        assert!(AndroidLibrary::segments_page_aligned(0x1000, 0x1000));
+        assert!(!AndroidLibrary::segments_page_aligned(0x1000, 0x4000));
+        assert!(!AndroidLibrary::segments_page_aligned(0x1000, 0x10000));
+        // 16KiB-aligned libraries
+        assert!(AndroidLibrary::segments_page_aligned(0x4000, 0x1000));
+        assert!(AndroidLibrary::segments_page_aligned(0x4000, 0x4000));
+        assert!(!AndroidLibrary::segments_page_aligned(0x4000, 0x10000));
+        // 64KiB-aligned libraries
+        assert!(AndroidLibrary::segments_page_aligned(0x10000, 0x10000));
+        // Unaligned segments can't be protected separately on any host
+        assert!(!AndroidLibrary::segments_page_aligned(0, 0x1000));
+    }
+
+    #[cfg(all(target_arch = "x86_64", feature = "demangle"))]
+    #[test]
+    fn demangled_symbol_lookup() {
+        let mut elf = TestElf::new();
+        elf.function("_ZN5codec7Decoder4openEPKci", &[0xb8, 1, 0, 0, 0, 0xc3]); // mov eax, 1; ret
+        elf.function("_ZN5codec7Decoder4openEPKc", &[0xb8, 2, 0, 0, 0, 0xc3]); // mov eax, 2; ret
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+
+        let call = |symbol: *const ()| unsafe { std::mem::transmute::<*const (), extern "C" fn() -> u32>(symbol)() };
+        assert_eq!(call(library.get_symbol_demangled("_ZN5codec7Decoder4openEPKc").unwrap()), 2);
+        assert_eq!(call(library.get_symbol_demangled("codec::Decoder::open(char const*, int)").unwrap()), 1);
+        assert_eq!(call(library.get_symbol_demangled("codec::Decoder::open(char const *)").unwrap()), 2);
+        assert!(library.get_symbol_demangled("codec::Decoder::open").is_some());
+        assert!(library.get_symbol_demangled("codec::Decoder::open(int)").is_none());
+        assert!(library.get_symbol("codec::Decoder::open(char const*)").is_none());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn reset_restores_loaded_state() {
+        let mut elf = TestElf::new();
+        elf.function("reset_answer", &[0xb8, 42, 0, 0, 0, 0xc3]); // mov eax, 42; ret
+        elf.thunk("reset_call", "reset_answer");
+        let pointer = elf.object("reset_pointer", &[0; 8]);
+        elf.relocation(pointer, R_X86_64_64, Some("reset_answer"), 0);
+        elf.object("reset_counter", &[5, 0, 0, 0]);
+        let mut library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+
+        let answer = library.get_symbol("reset_answer").unwrap() as *mut u8;
+        let pointer = library.get_symbol("reset_pointer").unwrap() as *mut usize;
+        let counter = library.get_symbol("reset_counter").unwrap() as *mut u32;
+        unsafe {
+            answer.add(1).write(0);
+            pointer.write(0);
+            counter.write(99);
+        }
+
+        library.reset().unwrap();
+        let call: extern "C" fn() -> u32 = unsafe { std::mem::transmute(library.get_symbol("reset_call").unwrap()) };
+        assert_eq!(call(), 42);
+        unsafe {
+            assert_eq!(pointer.read(), answer as usize);
+            assert_eq!(counter.read(), 5);
+        }
+    }
+
+    #[cfg(all(target_arch = "x86_64", not(feature = "builtin-stubs")))]
+    #[test]
+    fn strict_without_builtin_stubs() {
+        let mut elf = TestElf::new();
+        elf.thunk("strict_lock", "pthread_mutex_lock");
+        elf.thunk("strict_dlsym", "dlsym");
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        assert_eq!(library.load_stats().resolved_by_libc, 0);
+        assert_eq!(library.load_stats().undefined, 2);
+        assert!(AndroidLibrary::get_libc_symbol("pthread_mutex_lock").is_none());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn executables() {
+        let mut elf = TestElf::new();
+        let main = elf.function("pie_main", &[0xb8, 3, 0, 0, 0, 0xc3]); // mov eax, 3; ret
+        elf.executable("/system/bin/linker64", main);
+        let pie = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        assert_eq!(pie.interpreter(), Some("/system/bin/linker64"));
+        assert_eq!(pie.entry_point(), pie.get_symbol("pie_main"));
+
+        let library = AndroidLibrary::load_from_bytes(TestElf::new().build()).unwrap();
+        assert_eq!((library.interpreter(), library.entry_point()), (None, None));
+
+        let mut elf = TestElf::new();
+        elf.object_type(2); // ET_EXEC
+        let err = AndroidLibrary::load_from_bytes(elf.build()).err().unwrap();
+        assert!(matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::NotASharedObject(2))));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn negative_addends() {
+        let mut elf = TestElf::new();
+        let cells = elf.object("negative_cells", &[0; 16]);
+        elf.relocation(cells, R_X86_64_64, Some("negative_cells"), -8);
+        elf.relocation(cells + 8, R_X86_64_RELATIVE, None, -16);
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+
+        let cells = library.get_symbol("negative_cells").unwrap() as *const usize;
+        unsafe {
+            assert_eq!(cells.read(), cells as usize - 8);
+            assert_eq!(cells.add(1).read(), library.memory_map.as_ptr() as usize - 16);
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn slot_addends_ignored() {
+        let mut elf = TestElf::new();
+        let cells = elf.object("slot_cells", &[0; 16]);
+        elf.relocation(cells, R_X86_64_JUMP_SLOT, Some("slot_cells"), 24);
+        elf.relocation(cells + 8, R_X86_64_GLOB_DAT, Some("slot_cells"), -8);
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+
+        let cells = library.get_symbol("slot_cells").unwrap() as *const usize;
+        unsafe {
+            assert_eq!(cells.read(), cells as usize);
+            assert_eq!(cells.add(1).read(), cells as usize);
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn best_effort_relocations() {
+        // Not a relocation type x86_64 defines
+        const UNKNOWN: u32 = 0xf0;
+        let mut elf = TestElf::new();
+        let cells = elf.object("best_effort_cells", &[0x5a; 16]);
+        elf.relocation(cells, UNKNOWN, None, 0);
+        elf.relocation(cells + 8, R_X86_64_64, Some("best_effort_cells"), 4);
+        let elf = elf.build();
+
+        let err = AndroidLoader::new().load_library_from_bytes(elf.clone()).err().unwrap();
+        assert!(matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::UnsupportedRelocation(UNKNOWN))));
+
+        let library = AndroidLoader::new().relocation_policy(RelocationPolicy::BestEffort).load_library_from_bytes(elf).unwrap();
+        let stats = library.load_stats();
+        assert_eq!(stats.skipped_relocations, HashMap::from([(UNKNOWN, 1)]));
+        assert_eq!(stats.relocations.get(&R_X86_64_64), Some(&1));
+        let cells = library.get_symbol("best_effort_cells").unwrap() as *const [usize; 2];
+        assert_eq!(unsafe { *cells }, [0x5a5a5a5a5a5a5a5a, cells as usize + 4]);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn symbol_file_offsets() {
+        let marker = *b"file offset marker";
+        let mut elf = TestElf::new();
+        elf.object("offset_marker", &marker);
+        elf.bss(64);
+        let elf = elf.build();
+        let library = AndroidLibrary::load_from_bytes(elf.clone()).unwrap();
+
+        let expected = elf.windows(marker.len()).position(|window| window == marker).unwrap() as u64;
+        assert_eq!(library.symbol_file_offset("offset_marker"), Some(expected));
+        let address = library.get_symbol("offset_marker").unwrap() as usize;
+        assert_eq!(library.address_to_file_offset(address + 5), Some(expected + 5));
+        assert_eq!(library.address_to_file_offset(library.memory_map.as_ptr() as usize + BSS_ADDRESS as usize), None);
+        assert_eq!(library.address_to_file_offset(4), None);
+        assert_eq!(library.symbol_file_offset("offset_missing"), None);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn seeded_symbols() {
+        let mut elf = TestElf::new();
+        let config = elf.object("seeded_config", &1u64.to_ne_bytes());
+        // So the seed's page is its alone
+        elf.object("seeded_padding", &vec![0; region::page::size()]);
+        let seed = elf.object("seeded_seed", &2u64.to_ne_bytes());
+        elf.getter("read_config", config);
+        elf.getter("read_seed", seed);
+        let mut library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let read_config: extern "C" fn() -> u64 = unsafe { std::mem::transmute(library.get_symbol("read_config").unwrap()) };
+        let read_seed: extern "C" fn() -> u64 = unsafe { std::mem::transmute(library.get_symbol("read_seed").unwrap()) };
+
+        library.write_symbol("seeded_config", &0x1234_5678_9abc_def0u64.to_ne_bytes()).unwrap();
+        assert_eq!(read_config(), 0x1234_5678_9abc_def0);
+        // A prefix leaves the rest as it was
+        library.write_symbol("seeded_config", &[0xff]).unwrap();
+        assert_eq!(read_config(), 0x1234_5678_9abc_deff);
+
+        // Read-only like RELRO, and again once written
+        let address = library.get_symbol("seeded_seed").unwrap() as *const u8;
+        unsafe { region::protect(address, 8, Protection::READ).unwrap() };
+        library.write_symbol("seeded_seed", &42u64.to_ne_bytes()).unwrap();
+        assert_eq!(read_seed(), 42);
+        assert!(!region::query(address).unwrap().protection().contains(Protection::WRITE));
+
+        let err = library.write_symbol("seeded_config", &[0; 9]).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<AndroidLoaderErr>(),
+            Some(AndroidLoaderErr::SymbolTooSmall { size: 8, len: 9, .. })
+        ));
+        assert_eq!(read_config(), 0x1234_5678_9abc_deff);
+        let err = library.write_symbol("seeded_missing", &[0]).err().unwrap();
+        assert!(matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::UnknownSymbol(_))));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn none_relocations_skipped() {
+        let mut elf = TestElf::new();
+        let cells = elf.object("none_cells", &[0x5a; 8]);
+        elf.relocation(0, R_X86_64_NONE, None, 0);
+        elf.relocation(cells, R_X86_64_NONE, None, 0x40);
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        assert_eq!(library.load_stats().relocations.get(&R_X86_64_NONE), Some(&2));
+        assert_eq!(unsafe { *(library.get_symbol("none_cells").unwrap() as *const [u8; 8]) }, [0x5a; 8]);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn relative_ignores_field() {
+        // What `ld -z apply-dynamic-relocs` leaves in place, with a base of 0
+        let mut elf = TestElf::new();
+        let cells = elf.object("relative_cells", &[0x40, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        elf.relocation(cells, R_X86_64_RELATIVE, None, 0x40);
+        elf.relocation(cells + 8, R_X86_64_RELATIVE, None, 0);
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+
+        let base = library.memory_map.as_ptr() as usize;
+        let cells = library.get_symbol("relative_cells").unwrap() as *const usize;
+        unsafe {
+            assert_eq!(cells.read(), base + 0x40);
+            assert_eq!(cells.add(1).read(), base);
+        }
+    }
+
+    #[test]
+    fn implicit_addend_sign_extends() {
+        let memory_map = [0xaa, 0xf0, 0xff, 0xff, 0xff, 0x40, 0, 0, 0, 0xff, 0xff, 0xff, 0x7f];
+        assert_eq!(AndroidLibrary::implicit_addend(&memory_map, 1), -16);
+        assert_eq!(AndroidLibrary::implicit_addend(&memory_map, 5), 0x40);
+        assert_eq!(AndroidLibrary::implicit_addend(&memory_map, 9), i32::MAX as isize);
+    }
+
+    #[test]
+    fn relative_addends() {
+        let memory_map = [0x40, 0, 0, 0, 0xf0, 0xff, 0xff, 0xff];
+        let base = 0x1000_0000;
+        // REL: the field's sign-extended 32 bits
+        assert_eq!(AndroidLibrary::relative_value(&memory_map, 0, base, None), 0x1000_0040);
+        assert_eq!(AndroidLibrary::relative_value(&memory_map, 4, base, None), 0x0fff_fff0);
+        // RELA: the entry's, whatever the field holds
+        assert_eq!(AndroidLibrary::relative_value(&memory_map, 4, base, Some(0x20)), 0x1000_0020);
+        assert_eq!(AndroidLibrary::relative_value(&memory_map, 0, base, Some(-8)), 0x0fff_fff8);
+        assert_eq!(AndroidLibrary::relative_value(&memory_map, 0, base, Some(0)), base);
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn aarch64_relative() {
+        use crate::relocation_types::{RelocationType, RelocType};
+
+        const R_AARCH64_RELATIVE: RelocType = 1027;
+        assert!(matches!(RelocationType::from(R_AARCH64_RELATIVE), RelocationType::Relative));
+
+        let mut memory_map = [0xff; 24];
+        let base = memory_map.as_ptr() as usize;
+        AndroidLibrary::relative_reloc(&mut memory_map, 0, Some(0x40));
+        AndroidLibrary::relative_reloc(&mut memory_map, 8, Some(-0x10));
+        let word = |offset: usize| usize::from_ne_bytes(memory_map[offset..offset + 8].try_into().unwrap());
+        assert_eq!(word(0), base + 0x40);
+        assert_eq!(word(8), base - 0x10);
+        assert_eq!(word(16), usize::MAX);
+    }
+
+    #[test]
+    fn relative_uses_implicit_addend() {
+        // REL keeps the addend in the field, negative ones sign-extended from its 32 bits, and
+        // only those 32 bits are written back, even on a 64-bit host
+        let mut memory_map = [0x40, 0, 0, 0, 0xf0, 0xff, 0xff, 0xff, 0xaa, 0xaa, 0xaa, 0xaa];
+        let base = 0x1000_0000;
+        for offset in [0, 4] {
+            AndroidLibrary::relative_field32(&mut memory_map, offset, base, None);
+        }
+        let field = |offset: usize| u32::from_ne_bytes(memory_map[offset..offset + 4].try_into().unwrap());
+        assert_eq!(field(0), 0x1000_0040);
+        assert_eq!(field(4), 0x0fff_fff0);
+        assert_eq!(field(8), 0xaaaa_aaaa);
+    }
+
+    #[test]
+    fn field32_writes() {
+        let mut memory_map = [0xaa; 12];
+        AndroidLibrary::write_field32(&mut memory_map, 4, 0x1234_5678);
+        // A full word keeps its low 32 bits only
+        AndroidLibrary::write_field32(&mut memory_map, 0, usize::MAX - 0xf);
+        let field = |offset: usize| u32::from_ne_bytes(memory_map[offset..offset + 4].try_into().unwrap());
+        assert_eq!(field(0), 0xffff_fff0);
+        assert_eq!(field(4), 0x1234_5678);
+        assert_eq!(field(8), 0xaaaa_aaaa);
+    }
+
+    /// What `iterated_phdr` looks for and finds
+    #[cfg(target_arch = "x86_64")]
+    struct PhdrSearch {
+        base: usize,
+        phnum: Option<u16>,
+        first_type: u32,
+        visited: usize,
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[crate::sysv64]
+    unsafe fn iterated_phdr(info: *mut super::DlPhdrInfo, size: usize, data: *mut std::os::raw::c_void) -> std::os::raw::c_int {
+        let search = &mut *(data as *mut PhdrSearch);
+        assert_eq!(size, std::mem::size_of::<super::DlPhdrInfo>());
+        search.visited += 1;
+        if (*info).addr == search.base {
+            search.phnum = Some((*info).phnum);
+            search.first_type = *((*info).phdr as *const u32);
+            return 1;
+        }
+        0
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn iterated_program_headers() {
+        let mut elf = TestElf::new();
+        elf.thunk("call_dl_iterate_phdr", "dl_iterate_phdr");
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let iterate: extern "C" fn(usize, *mut PhdrSearch) -> std::os::raw::c_int =
+            unsafe { std::mem::transmute(library.get_symbol("call_dl_iterate_phdr").unwrap()) };
+
+        let mut search = PhdrSearch { base: library.memory_map.as_ptr() as usize, phnum: None, first_type: 0, visited: 0 };
+        // Stopped at this library, with what the callback returned
+        assert_eq!(iterate(iterated_phdr as *const () as usize, &mut search), 1);
+        assert_eq!(search.phnum, Some(library.program_headers().len() as u16));
+        assert_eq!(search.first_type, library.program_headers()[0].kind);
+        assert!(search.visited >= 1);
+
+        // Every library is visited when the callback never stops it
+        let mut search = PhdrSearch { base: 0, phnum: None, first_type: 0, visited: 0 };
+        assert_eq!(iterate(iterated_phdr as *const () as usize, &mut search), 0);
+        assert!(search.visited >= 1);
+        assert_eq!(search.phnum, None);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn malformed_dynamic_sections() {
+        // Offset of a field of section header `index`
+        fn section_field(elf: &[u8], index: usize, field: usize) -> usize {
+            u64::from_le_bytes(elf[40..48].try_into().unwrap()) as usize + index * 64 + field
+        }
+        let parsing_error = |elf: Vec<u8>| {
+            let err = AndroidLibrary::load_from_bytes(elf).err().unwrap();
+            match err.downcast_ref::<AndroidLoaderErr>() {
+                Some(AndroidLoaderErr::ElfParsingError(message)) => message.clone(),
+                _ => panic!("unexpected error {err}"),
+            }
+        };
+        let mut elf = TestElf::new();
+        elf.soname("libmalformed.so");
+        elf.function("malformed_answer", &[0xb8, 42, 0, 0, 0, 0xc3]); // mov eax, 42; ret
+        let elf = elf.build();
+
+        // .dynsym linked to .text and to a section that doesn't exist
+        for link in [4u32, 99] {
+            let mut mislinked = elf.clone();
+            let field = section_field(&mislinked, 1, 40);
+            mislinked[field..field + 4].copy_from_slice(&link.to_le_bytes());
+            assert!(parsing_error(mislinked).contains("sh_link"));
+        }
+
+        // .data posing as a second .dynamic
+        let mut duplicated = elf.clone();
+        let field = section_field(&duplicated, 5, 4);
+        duplicated[field..field + 4].copy_from_slice(&6u32.to_le_bytes());
+        assert!(parsing_error(duplicated).contains(".dynamic"));
+
+        assert!(AndroidLibrary::load_from_bytes(elf).is_ok());
+    }
 }
@@ -0,0 +1,1178 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fs;
+use std::ops::ControlFlow;
+use std::os::raw::c_void;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::android_library::{AndroidLibrary, AndroidLoaderErr};
+#[cfg(feature = "apk")]
+use crate::apk;
+use crate::call_trace::{TraceCallback, TracedCall};
+use crate::dependencies;
+use crate::hook_manager;
+use crate::registry;
+use crate::initializers::{InitAction, InitCallback, ProgramArguments};
+use crate::library_info::{self, LibraryInfo, RelocationEntry, RelocationPlan};
+use crate::mapping_pool::MappingPool;
+use crate::sha256::sha256;
+use crate::undefined_symbols::UndefinedSymbolBehavior;
+use crate::vfs::VirtualFs;
+
+/// Rewrites the names relocations are resolved by, indexed like the dynamic symbol table
+pub type SymbolRewriter = dyn Fn(&mut [String]) + Send + Sync;
+
+/// Rewrites a library's relocations before any is applied
+pub type RelocationTransform = dyn Fn(&mut Vec<RelocationEntry>) + Send + Sync;
+
+/// Told `(done, total)` relocations every so often, can break to cancel the load
+pub type ProgressCallback = dyn Fn(usize, usize) -> ControlFlow<()> + Send + Sync;
+
+/// Decodes a symbol name as stored in the string table, without its terminator
+pub type NameDecoder = dyn Fn(&[u8]) -> String + Send + Sync;
+
+/// Unwraps a compressed or packed library, returning `None` if the data isn't in its format
+pub type Preprocessor = dyn Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync;
+
+/// Told the name a loaded library passes to `dlopen`, and decides what it gets, see
+/// [`AndroidLoader::intercept_dlopen`]
+pub type DlopenInterceptor = dyn Fn(&str) -> DlopenAction + Send + Sync;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DlopenAction {
+    /// Open the name as if there were no interceptor
+    Default,
+    /// Open this file instead
+    Substitute(PathBuf),
+    /// Return this handle: one `dlopen` returned, or [`AndroidLibrary::as_handle`] of a
+    /// library that outlives its use and stays where it is, e.g. boxed. `dlclose` never frees
+    /// it.
+    UseExisting(*mut c_void),
+    /// Fail, returning null
+    Deny,
+}
+
+/// Where a loaded library is mapped
+#[derive(Clone, Debug, PartialEq)]
+pub struct LibraryRegion {
+    pub soname: Option<String>,
+    pub base: usize,
+    pub size: usize,
+}
+
+/// A loaded library, see [`AndroidLoader::loaded_libraries`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoadedLibInfo {
+    pub soname: Option<String>,
+    pub base: usize,
+    pub size: usize,
+    /// The file it was loaded from, `None` for libraries loaded from bytes
+    pub path: Option<PathBuf>,
+}
+
+/// How segments are protected, see [`AndroidLoader::protection_policy`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtectionPolicy {
+    /// Protect each segment as its flags say and make `PT_GNU_RELRO` read-only once relocated.
+    /// Loading fails with [`AndroidLoaderErr::UnrepresentableProtections`] if segments share
+    /// host pages, as with 4KiB-aligned libraries on 16KiB-page hosts.
+    Strict,
+    /// Protect each segment as its flags say if none share a host page, and map them all RWX
+    /// otherwise. `PT_GNU_RELRO` stays writable.
+    Compatible,
+    /// Map every segment RWX, e.g. for libraries modifying their own code
+    Permissive,
+}
+
+impl Default for ProtectionPolicy {
+    fn default() -> Self {
+        ProtectionPolicy::Compatible
+    }
+}
+
+/// What to do with relocations of types the loader can't apply, see
+/// [`AndroidLoader::relocation_policy`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelocationPolicy {
+    /// Fail the load with [`AndroidLoaderErr::UnsupportedRelocation`]
+    Strict,
+    /// Log and skip them, leaving their places as they are in the file, and count them in
+    /// [`LoadStats::skipped_relocations`](crate::stats::LoadStats::skipped_relocations). The
+    /// library may then crash once it uses them, e.g. while bringing up a new architecture.
+    BestEffort,
+}
+
+impl Default for RelocationPolicy {
+    fn default() -> Self {
+        RelocationPolicy::Strict
+    }
+}
+
+/// When imports nothing provides are reported, see [`AndroidLoader::resolve_mode`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResolveMode {
+    /// Fail the load with [`AndroidLoaderErr::UnresolvedSymbol`] on the first strong import
+    /// nothing provides, like `RTLD_NOW`. Weak ones still resolve to the undefined symbol stubs.
+    Strict,
+    /// Resolve them to the undefined symbol stubs, which apply the
+    /// [undefined symbol behavior](AndroidLoader::on_undefined_symbol) once they're used
+    Lazy,
+}
+
+impl Default for ResolveMode {
+    fn default() -> Self {
+        ResolveMode::Lazy
+    }
+}
+
+/// Where imported symbols are looked for, see [`AndroidLoader::resolution_order`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResolutionStep {
+    /// The load's [hooks](AndroidLoader::hook), then those of
+    /// [`add_hooks`](crate::hook_manager::add_hooks)
+    Hooks,
+    /// The library, its dependencies and the [libc provider](AndroidLoader::libc_provider), in
+    /// load order
+    Scope,
+    /// Libraries loaded with `RTLD_GLOBAL`, in load order
+    Global,
+    /// [`AndroidLoader::register_global_symbol`]
+    Registered,
+    /// The built-in stubs, then [`AndroidLoader::with_bionic_stubs`]'s
+    Stubs,
+}
+
+/// The steps symbols are resolved in unless [`AndroidLoader::resolution_order`] says otherwise.
+/// What none of them define resolves to the undefined symbol stubs.
+pub const DEFAULT_RESOLUTION_ORDER: [ResolutionStep; 5] = [
+    ResolutionStep::Hooks,
+    ResolutionStep::Scope,
+    ResolutionStep::Global,
+    ResolutionStep::Registered,
+    ResolutionStep::Stubs,
+];
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+/// Wrappers nested deeper than this are assumed to be preprocessors undoing each other
+const MAX_PREPROCESS_DEPTH: usize = 8;
+
+/// Per-load configuration for loading Android libraries
+#[derive(Default)]
+pub struct AndroidLoader {
+    pub(crate) symbol_rewriter: Option<Box<SymbolRewriter>>,
+    pub(crate) relocation_transform: Option<Box<RelocationTransform>>,
+    hooks: HashMap<String, usize>,
+    name_decoder: Option<Box<NameDecoder>>,
+    preprocessors: Vec<Box<Preprocessor>>,
+    pub(crate) undefined_symbols: UndefinedSymbolBehavior,
+    pub(crate) progress: Option<Box<ProgressCallback>>,
+    pub(crate) library_paths: Vec<PathBuf>,
+    expected_sha256: Option<[u8; 32]>,
+    pub(crate) bionic_stubs: bool,
+    pub(crate) lazy_binding: bool,
+    pub(crate) reject_missing_imports: bool,
+    pub(crate) run_initializers: bool,
+    pub(crate) on_init: Option<Box<InitCallback>>,
+    pub(crate) on_fini: Option<Arc<InitCallback>>,
+    pub(crate) program_arguments: Option<Arc<ProgramArguments>>,
+    pub(crate) init_timeout: Option<Duration>,
+    pub(crate) trace_calls: Option<Arc<TraceCallback>>,
+    /// Registry id of the library standing in for libc
+    pub(crate) libc_provider: Option<usize>,
+    pub(crate) mapping_pool: Option<Arc<MappingPool>>,
+    /// Inaccessible pages mapped on each side of the images
+    pub(crate) guard_pages: usize,
+    pub(crate) base_address: Option<usize>,
+    pub(crate) protection_policy: ProtectionPolicy,
+    pub(crate) relocation_policy: RelocationPolicy,
+    resolution_order: Option<Vec<ResolutionStep>>,
+    pub(crate) resolve_mode: ResolveMode,
+    pub(crate) verify_wx: bool,
+    pub(crate) dlopen_interceptor: Option<Arc<DlopenInterceptor>>,
+    pub(crate) sprintf_limit: Option<usize>,
+    pub(crate) virtual_fs: Option<Arc<dyn VirtualFs>>,
+    pub(crate) isolated: bool,
+    retain_original_bytes: bool,
+}
+
+impl AndroidLoader {
+    pub fn new() -> AndroidLoader {
+        AndroidLoader::default()
+    }
+
+    /// Resolve `symbol_name` to `address` for this load only, before the hooks registered with
+    /// [`add_hooks`](crate::hook_manager::add_hooks). Libraries already loaded that the load
+    /// reuses keep what they were resolved to.
+    pub fn hook(mut self, symbol_name: &str, address: usize) -> AndroidLoader {
+        self.hooks.insert(symbol_name.to_owned(), address);
+        self
+    }
+
+    /// The registered hooks with this loader's on top
+    pub(crate) fn hooks(&self) -> HashMap<String, usize> {
+        let mut hooks = hook_manager::get_hooks().clone();
+        hooks.extend(self.hooks.iter().map(|(name, address)| (name.clone(), *address)));
+        hooks
+    }
+
+    /// Make `address` the built-in implementation of `symbol_name` for every library loaded
+    /// from now on, dependencies included, replacing any stub of that name. Unlike hooks, it's
+    /// only used when no loaded library defines the symbol (unless the
+    /// [resolution order](Self::resolution_order) puts it first), and it works without the
+    /// `builtin-stubs` feature.
+    pub fn register_global_symbol(symbol_name: &str, address: usize) {
+        hook_manager::register_global_symbol(symbol_name, address);
+    }
+
+    /// Set a hook run after the symbol table is read but before any relocation is applied,
+    /// allowing symbols to be renamed or aliased (e.g. redirecting a whole prefix to a shim)
+    pub fn rewrite_symbols(mut self, rewriter: impl Fn(&mut [String]) + Send + Sync + 'static) -> AndroidLoader {
+        self.symbol_rewriter = Some(Box::new(rewriter));
+        self
+    }
+
+    /// Set a hook given every relocation of each library, in table order, before any is
+    /// applied. It can drop, reorder or rewrite them, e.g. to leave out the relocation of an
+    /// anti-tamper check or point another offset at a symbol; relocation statistics and
+    /// progress count what it leaves.
+    pub fn transform_relocations(mut self, transform: impl Fn(&mut Vec<RelocationEntry>) + Send + Sync + 'static) -> AndroidLoader {
+        self.relocation_transform = Some(Box::new(transform));
+        self
+    }
+
+    /// Decode every symbol name read from the string table, for libraries whose names are
+    /// obfuscated there. Symbols are then resolved and looked up by their decoded names, and
+    /// [`rewrite_symbols`](Self::rewrite_symbols) sees those.
+    pub fn decode_symbol_names(mut self, decoder: impl Fn(&[u8]) -> String + Send + Sync + 'static) -> AndroidLoader {
+        self.name_decoder = Some(Box::new(decoder));
+        self
+    }
+
+    pub(crate) fn decodes_names(&self) -> bool {
+        self.name_decoder.is_some()
+    }
+
+    /// The name at `offset` in a string table, decoded
+    pub(crate) fn symbol_name(&self, strings: &[u8], offset: usize) -> String {
+        let name = strings.get(offset..).unwrap_or_default();
+        let name = &name[..name.iter().position(|&byte| byte == 0).unwrap_or(name.len())];
+        match &self.name_decoder {
+            Some(decoder) => decoder(name),
+            None => String::from_utf8_lossy(name).into_owned(),
+        }
+    }
+
+    /// Register a preprocessor for libraries that aren't plain ELF files (e.g. zlib-compressed
+    /// APK entries or packed `.so`s). Preprocessors are tried in registration order until the
+    /// data starts with the ELF magic, so wrappers can be nested.
+    pub fn preprocess(mut self, preprocessor: impl Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static) -> AndroidLoader {
+        self.preprocessors.push(Box::new(preprocessor));
+        self
+    }
+
+    /// Choose what happens when the library calls a symbol nothing provides
+    pub fn on_undefined_symbol(mut self, behavior: UndefinedSymbolBehavior) -> AndroidLoader {
+        self.undefined_symbols = behavior;
+        self
+    }
+
+    /// Report relocation progress every [`PROGRESS_INTERVAL`](crate::android_library::PROGRESS_INTERVAL)
+    /// relocations and once done. Breaking cancels the load with [`AndroidLoaderErr::Cancelled`].
+    pub fn relocation_progress(mut self, callback: impl Fn(usize, usize) -> ControlFlow<()> + Send + Sync + 'static) -> AndroidLoader {
+        self.progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Add a directory to search for the libraries named by `DT_NEEDED` entries. Dependencies
+    /// found there are loaded along with the library, the others' symbols are left to the
+    /// hooks and built-in stubs. Already loaded libraries are reused by soname unless the load
+    /// is [isolated](Self::isolated); a library loaded directly by the host must then outlive
+    /// the ones depending on it.
+    pub fn library_path(mut self, dir: impl AsRef<Path>) -> AndroidLoader {
+        self.library_paths.push(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Load the library and its dependencies as a new, separate instance: dependencies are
+    /// loaded again from the library paths instead of reusing loaded copies, symbols don't
+    /// resolve against libraries opened with `RTLD_GLOBAL`, and later loads don't reuse or
+    /// resolve against these. Each instance then has its own globals. The
+    /// [libc provider](Self::libc_provider) and hooks are still shared.
+    pub fn isolated(mut self) -> AndroidLoader {
+        self.isolated = true;
+        self
+    }
+
+    /// Keep the library's file as given to the loader, before any preprocessing, for
+    /// [`AndroidLibrary::original_bytes`], e.g. to diff it against a
+    /// [dump](AndroidLibrary::dump_image) of the live image. Only costs memory for files a
+    /// preprocessor unwrapped, as the ELF file is kept anyway. Only the library itself is kept,
+    /// not the dependencies found in the library paths.
+    pub fn retain_original_bytes(mut self) -> AndroidLoader {
+        self.retain_original_bytes = true;
+        self
+    }
+
+    /// Refuse to load a library whose file (as read, before any preprocessing) doesn't have
+    /// this SHA-256 hash, failing with [`AndroidLoaderErr::IntegrityCheckFailed`]. Only the
+    /// library itself is checked, not the dependencies found in the library paths.
+    pub fn verify_sha256(mut self, expected: [u8; 32]) -> AndroidLoader {
+        self.expected_sha256 = Some(expected);
+        self
+    }
+
+    /// Resolve symbols against `libc`, e.g. a real bionic `libc.so` loaded beforehand, after
+    /// the hooks, the library and its dependencies but before libraries opened with
+    /// `RTLD_GLOBAL`, the host's libc and the built-in stubs. It's used by the library and the
+    /// dependencies it brings in, so it must outlive them.
+    pub fn libc_provider(mut self, libc: &AndroidLibrary) -> AndroidLoader {
+        self.libc_provider = Some(libc.registry_id);
+        self
+    }
+
+    /// Map the library and the dependencies it brings in in mappings taken from `pool`, which
+    /// gets them back when they're dropped, instead of fresh ones. Loads sharing a pool reuse
+    /// each other's memory, zeroed in between. [`load_into`](Self::load_into) still maps the
+    /// library itself in the buffer.
+    pub fn mapping_pool(mut self, pool: Arc<MappingPool>) -> AndroidLoader {
+        self.mapping_pool = Some(pool);
+        self
+    }
+
+    /// Choose what happens to relocations of types the loader can't apply, in the library and
+    /// the dependencies it brings in, [`RelocationPolicy::Strict`] by default
+    pub fn relocation_policy(mut self, policy: RelocationPolicy) -> AndroidLoader {
+        self.relocation_policy = policy;
+        self
+    }
+
+    /// Look imported symbols up in the steps of `order`, first to last, instead of
+    /// [`DEFAULT_RESOLUTION_ORDER`], e.g. to have [registered symbols](Self::register_global_symbol)
+    /// replace what loaded libraries define. Steps left out aren't searched. It applies to the
+    /// library and the dependencies it brings in, lazily bound symbols included, but not to
+    /// `dlsym`.
+    pub fn resolution_order(mut self, order: &[ResolutionStep]) -> AndroidLoader {
+        self.resolution_order = Some(order.to_vec());
+        self
+    }
+
+    /// Choose whether imports nothing provides fail the load, in the library and the
+    /// dependencies it brings in, [`ResolveMode::Lazy`] by default. [`ResolveMode::Strict`]
+    /// binds every `JUMP_SLOT` up front, ignoring [`lazy_binding`](Self::lazy_binding), and
+    /// stops at the first import missing where [`reject_missing_imports`](Self::reject_missing_imports)
+    /// lists them all.
+    pub fn resolve_mode(mut self, mode: ResolveMode) -> AndroidLoader {
+        self.resolve_mode = mode;
+        self
+    }
+
+    pub(crate) fn resolution_steps(&self) -> &[ResolutionStep] {
+        self.resolution_order.as_deref().unwrap_or(&DEFAULT_RESOLUTION_ORDER)
+    }
+
+    /// Choose how the segments of the library and the dependencies it brings in are
+    /// protected, [`ProtectionPolicy::Compatible`] by default
+    pub fn protection_policy(mut self, policy: ProtectionPolicy) -> AndroidLoader {
+        self.protection_policy = policy;
+        self
+    }
+
+    /// Once the library and the dependencies it brings in are relocated, check that none of
+    /// their pages is both writable and executable, failing the load with
+    /// [`AndroidLoaderErr::WxViolation`] otherwise. Skipped under [`ProtectionPolicy::Permissive`],
+    /// which maps them so on purpose; [`ProtectionPolicy::Compatible`] does too for libraries
+    /// whose segments share host pages, and fails it.
+    pub fn verify_wx(mut self) -> AndroidLoader {
+        self.verify_wx = true;
+        self
+    }
+
+    /// Map `pages` inaccessible pages right before and after the image of the library and the
+    /// dependencies it brings in, so accesses just out of it fault instead of hitting whatever
+    /// is mapped next to it. Symbols and the image are where they'd be without them. It takes
+    /// over from [`mapping_pool`](Self::mapping_pool), and the buffer of
+    /// [`load_into`](Self::load_into) is used as is.
+    pub fn guard_pages(mut self, pages: usize) -> AndroidLoader {
+        self.guard_pages = pages;
+        self
+    }
+
+    /// Map each library at `address`, which must be page-aligned, instead of wherever the
+    /// system puts it, and the dependencies it brings in right after it in load order, so that
+    /// loading the same libraries again gives the same relocated images, e.g. for golden-file
+    /// tests. Loading fails with [`AndroidLoaderErr::BaseUnavailable`] if anything is mapped
+    /// there already, like a library of an earlier load that's still loaded. It takes over from
+    /// [`mapping_pool`](Self::mapping_pool) and [`guard_pages`](Self::guard_pages), and the
+    /// buffer of [`load_into`](Self::load_into) is used as is.
+    pub fn base_address(mut self, address: usize) -> AndroidLoader {
+        self.base_address = Some(address);
+        self
+    }
+
+    /// Fall back to the stubs of the libc functions most libraries use, even without the
+    /// `builtin-stubs` feature: the string and memory functions, the `malloc` family, ctype,
+    /// errno, randomness, `pthread_*`, Android logging and system properties, `getauxval`,
+    /// `sysconf`, sleeping and calendar time. Hooks, loaded
+    /// libraries and [global symbols](Self::register_global_symbol) still take precedence.
+    pub fn with_bionic_stubs(mut self) -> AndroidLoader {
+        self.bionic_stubs = true;
+        self
+    }
+
+    /// Bind `JUMP_SLOT` relocations (calls through the PLT) on their first call, like
+    /// `RTLD_LAZY`, instead of resolving every import up front. Slots of built-in functions
+    /// that need their caller are still bound eagerly. Only on x86_64 and aarch64; other
+    /// targets ignore it.
+    pub fn lazy_binding(mut self) -> AndroidLoader {
+        self.lazy_binding = true;
+        self
+    }
+
+    /// Fail the load with [`AndroidLoaderErr::MissingImports`] listing every import nothing
+    /// provides, in the library and the dependencies it brings in, instead of leaving them to
+    /// the [undefined symbol behavior](Self::on_undefined_symbol) when they're used. Meant for
+    /// finding all the hooks a new library needs in one go; see also [`LoadStats::missing_imports`](crate::stats::LoadStats::missing_imports).
+    pub fn reject_missing_imports(mut self) -> AndroidLoader {
+        self.reject_missing_imports = true;
+        self
+    }
+
+    /// Report every call the library makes through its PLT, with the argument registers and
+    /// return value, once it returns. `JUMP_SLOT`s are then bound up front even with
+    /// [`lazy_binding`](Self::lazy_binding). Only on x86_64 and aarch64; other targets ignore
+    /// it. See [`call_trace`](crate::call_trace) for the caveats.
+    pub fn trace_calls(mut self, callback: impl Fn(&TracedCall) + Send + Sync + 'static) -> AndroidLoader {
+        self.trace_calls = Some(Arc::new(callback));
+        self
+    }
+
+    /// Run the initializers (`DT_INIT` and `DT_INIT_ARRAY`) of the library and the dependencies
+    /// it brings in once they're relocated, and their finalizers when they're dropped. Nothing
+    /// of the loader's is locked while they run, so they can `dlopen` other libraries, which
+    /// are loaded and handed back before the initializer goes on.
+    pub fn run_initializers(mut self) -> AndroidLoader {
+        self.run_initializers = true;
+        self
+    }
+
+    /// Pass `args` and `env` (`KEY=value` strings) to the initializers as `argc`, `argv` and
+    /// `envp`, instead of 0 and null pointers. Some libraries find themselves by `argv[0]`.
+    /// The arrays stay valid while a library loaded with them is, see
+    /// [`AndroidLibrary::program_arguments`]. Panics if a string contains a NUL byte.
+    pub fn program_arguments(mut self, args: Vec<String>, env: Vec<String>) -> AndroidLoader {
+        self.program_arguments = Some(Arc::new(ProgramArguments::new(&args, &env)));
+        self
+    }
+
+    /// Run each initializer on a helper thread, failing the load with
+    /// [`AndroidLoaderErr::InitTimeout`] if one doesn't return within `timeout`. The
+    /// initializer can't be stopped, so it's left running and the libraries of the load are
+    /// leaked, including the buffer of [`load_into`](Self::load_into): it mustn't be freed
+    /// after such a failure. Only used with [`run_initializers`](Self::run_initializers).
+    pub fn init_timeout(mut self, timeout: Duration) -> AndroidLoader {
+        self.init_timeout = Some(timeout);
+        self
+    }
+
+    /// Call `callback` with the index and address of each initializer about to run, which
+    /// is skipped if it returns [`InitAction::Skip`]. Only used with [`run_initializers`](Self::run_initializers).
+    pub fn on_init(mut self, callback: impl Fn(usize, *const ()) -> InitAction + Send + Sync + 'static) -> AndroidLoader {
+        self.on_init = Some(Box::new(callback));
+        self
+    }
+
+    /// Like [`on_init`](Self::on_init), for the finalizers run when the libraries are dropped
+    pub fn on_fini(mut self, callback: impl Fn(usize, *const ()) -> InitAction + Send + Sync + 'static) -> AndroidLoader {
+        self.on_fini = Some(Arc::new(callback));
+        self
+    }
+
+    /// Decide what `dlopen` calls of the library and the dependencies it brings in open, from
+    /// the name they pass, before the libraries already opened or the files are looked at
+    pub fn intercept_dlopen(mut self, interceptor: impl Fn(&str) -> DlopenAction + Send + Sync + 'static) -> AndroidLoader {
+        self.dlopen_interceptor = Some(Arc::new(interceptor));
+        self
+    }
+
+    /// Limit how many bytes (including the terminating null byte) `sprintf` and `vsprintf` may
+    /// write when called by the library and the dependencies it brings in. Longer output is
+    /// truncated and a warning is logged. There is no limit by default.
+    pub fn sprintf_limit(mut self, limit: usize) -> AndroidLoader {
+        self.sprintf_limit = Some(limit);
+        self
+    }
+
+    /// Serve the file stubs (`open`, `stat`, `access`, ...) of the library and the dependencies
+    /// it brings in from `fs`. Without one every access fails with `EACCES`.
+    pub fn virtual_fs(mut self, fs: impl VirtualFs + 'static) -> AndroidLoader {
+        self.virtual_fs = Some(Arc::new(fs));
+        self
+    }
+
+    /// Locate an address in the loaded libraries, e.g. from a crash's backtrace, as the
+    /// library's soname, the closest exported symbol at or below it and the offset from that
+    /// symbol. Addresses outside every loaded library, or before its first symbol, give `None`.
+    pub fn symbolize(address: usize) -> Option<(Option<String>, String, usize)> {
+        registry::symbolize(address)
+    }
+
+    /// The loaded library whose image contains `address`, e.g. to check that a callback
+    /// pointer handed over by library code points into a library, or `None` if none does
+    pub fn owning_library(address: usize) -> Option<LibraryRegion> {
+        registry::owner(address).map(|(soname, base, size)| LibraryRegion { soname, base, size })
+    }
+
+    /// Every library loaded in the process, in load order, like the link map `dl_iterate_phdr`
+    /// walks: the ones loaded directly, their dependencies and those `dlopen`ed
+    pub fn loaded_libraries() -> Vec<LoadedLibInfo> {
+        registry::libraries().into_iter().map(|(soname, base, size, path)| LoadedLibInfo { soname, base, size, path }).collect()
+    }
+
+    /// The `.ARM.exidx` table of the loaded library containing `pc`, as its address and number of
+    /// entries, which is what loaded libraries get from `dl_unwind_find_exidx` on 32-bit ARM
+    pub fn find_arm_exidx(pc: usize) -> Option<(*const (), usize)> {
+        registry::arm_exidx(pc).map(|(address, count)| (address as *const (), count))
+    }
+
+    /// Load a newer copy of the dependency `soname` from the library paths, with its own
+    /// dependencies and initializers, and make the libraries using it use the new copy. Slots
+    /// of `GLOB_DAT` and `JUMP_SLOT` relocations pointing into the old copy are bound again,
+    /// even on read-only pages, and the new copy takes its place in every lookup from then on.
+    /// Returns how many slots were bound again.
+    ///
+    /// Pointers into the old copy that were taken some other way, e.g. through `dlsym` or a
+    /// `R_*_64` relocation, keep pointing at it, so it stays loaded until its dependents are
+    /// dropped. Only dependencies loaded from the library paths can be reloaded.
+    pub fn reload_dependency(&self, soname: &str) -> Result<usize> {
+        dependencies::reload(self, soname)
+    }
+
+    pub fn load_library<'a>(&self, path: &str) -> Result<AndroidLibrary<'a>> {
+        let library = self.load_library_from_bytes(fs::read(path)?)?;
+        registry::set_path(library.registry_id, Path::new(path));
+        Ok(library)
+    }
+
+    /// Load `soname` from the host's ABI directory of the APK at `apk_path`,
+    /// `lib/<`[`HOST_ABI`](crate::apk::HOST_ABI)`>/<soname>`, like
+    /// [`load_library_from_bytes`](Self::load_library_from_bytes) of the entry. Fails with
+    /// [`AndroidLoaderErr::NotInApk`] if there's no such entry. Dependencies are still only
+    /// searched for in the library paths, not in the APK.
+    #[cfg(feature = "apk")]
+    pub fn load_from_apk<'a>(&self, apk_path: impl AsRef<Path>, soname: &str) -> Result<AndroidLibrary<'a>> {
+        let apk = fs::read(apk_path)?;
+        self.load_library_from_bytes(apk::read_entry(&apk, &format!("lib/{}/{soname}", apk::HOST_ABI))?)
+    }
+
+    pub fn load_library_from_bytes<'a>(&self, file: Vec<u8>) -> Result<AndroidLibrary<'a>> {
+        let (file, original) = self.verify(file)?;
+        let library = dependencies::load(self, file, None)?;
+        Ok(self.with_original(library, original))
+    }
+
+    /// Like [`load_library_from_bytes`](Self::load_library_from_bytes), but map the library at
+    /// the start of `buffer`, memory the caller manages, instead of in fresh memory. The buffer
+    /// must be page-aligned, at least as large as the library's image and allowed to be made
+    /// executable. Segments get their protections as usual, and the buffer is made read-write
+    /// again when the library is dropped. Its dependencies are mapped as usual.
+    pub fn load_into<'a>(&self, buffer: &'a mut [u8], file: Vec<u8>) -> Result<AndroidLibrary<'a>> {
+        let (file, original) = self.verify(file)?;
+        let library = dependencies::load(self, file, Some(buffer))?;
+        Ok(self.with_original(library, original))
+    }
+
+    /// Check the file against [`verify_sha256`](Self::verify_sha256) and unwrap it, keeping
+    /// a copy of it if it's wrapped and [retained](Self::retain_original_bytes)
+    fn verify(&self, file: Vec<u8>) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
+        if let Some(expected) = self.expected_sha256 {
+            let actual = sha256(&file);
+            if actual != expected {
+                return Err(AndroidLoaderErr::IntegrityCheckFailed { expected, actual }.into());
+            }
+        }
+        let original = Some(&file).filter(|file| self.retain_original_bytes && !file.starts_with(ELF_MAGIC)).cloned();
+        Ok((self.unwrap_file(file)?, original))
+    }
+
+    fn with_original<'a>(&self, mut library: AndroidLibrary<'a>, original: Option<Vec<u8>>) -> AndroidLibrary<'a> {
+        library.retains_original = self.retain_original_bytes;
+        library.original = original;
+        library
+    }
+
+    /// Read a library's symbols, imports, dependencies and relocations without mapping it, so
+    /// none of its code can run
+    pub fn inspect(&self, path: &str) -> Result<LibraryInfo> {
+        self.inspect_bytes(fs::read(path)?)
+    }
+
+    pub fn inspect_bytes(&self, file: Vec<u8>) -> Result<LibraryInfo> {
+        LibraryInfo::parse(self, &self.unwrap_file(file)?)
+    }
+
+    /// Work out what each relocation's symbol would resolve to, with `hooks` added to the
+    /// registered ones, without loading anything. It's resolved against the library itself,
+    /// the hooks, libraries loaded `RTLD_GLOBAL` and the built-in stubs; dependencies in the
+    /// library paths aren't considered, and caller-sensitive functions like `dlsym` are
+    /// reported at their shared entry point rather than the per-library stub loading binds.
+    pub fn plan_relocations(&self, path: &str, hooks: &HashMap<String, usize>) -> Result<Vec<RelocationPlan>> {
+        self.plan_relocations_from_bytes(fs::read(path)?, hooks)
+    }
+
+    pub fn plan_relocations_from_bytes(&self, file: Vec<u8>, hooks: &HashMap<String, usize>) -> Result<Vec<RelocationPlan>> {
+        library_info::plan_relocations(self, &self.unwrap_file(file)?, hooks)
+    }
+
+    pub(crate) fn unwrap_file(&self, mut file: Vec<u8>) -> Result<Vec<u8>> {
+        for _ in 0..=MAX_PREPROCESS_DEPTH {
+            if file.starts_with(ELF_MAGIC) {
+                return Ok(file);
+            }
+            match self.preprocessors.iter().find_map(|preprocessor| preprocessor(&file)) {
+                Some(unwrapped) => file = unwrapped,
+                // Let the ELF parser report what's wrong with it
+                None => return Ok(file),
+            }
+        }
+
+        Err(AndroidLoaderErr::ElfParsingError(format!("library is still wrapped after {MAX_PREPROCESS_DEPTH} preprocessing passes")).into())
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::collections::HashMap;
+
+    use std::ops::ControlFlow;
+    use std::os::raw::{c_char, c_int};
+    use std::sync::{Arc, Mutex};
+
+    use crate::android_library::{AndroidLibrary, AndroidLoaderErr, PROGRESS_INTERVAL};
+    use crate::android_loader::{AndroidLoader, ResolutionStep};
+    use crate::hook_manager::add_hooks;
+    use crate::sha256::sha256;
+    use crate::sysv64;
+    use crate::test_elf::{TestElf, R_X86_64_64, R_X86_64_RELATIVE};
+
+    #[sysv64]
+    fn shim_add(a: u32, b: u32) -> u32 {
+        a + b
+    }
+
+    #[sysv64]
+    fn registered_answer() -> u32 {
+        42
+    }
+
+    #[test]
+    fn global_symbol() {
+        AndroidLoader::register_global_symbol("registered_answer", registered_answer as *const () as usize);
+
+        let mut elf = TestElf::new();
+        elf.thunk("call_registered", "registered_answer");
+        let library = AndroidLoader::new().load_library_from_bytes(elf.build()).unwrap();
+        let call: extern "C" fn() -> u32 = unsafe { std::mem::transmute(library.get_symbol("call_registered").unwrap()) };
+        assert_eq!(call(), 42);
+        assert_eq!((library.load_stats().resolved_by_registered, library.load_stats().resolved_by_libc), (1, 0));
+
+        // A loaded library defining it takes precedence
+        let mut defining = TestElf::new();
+        defining.function("registered_answer", &[0xb8, 7, 0, 0, 0, 0xc3]); // mov eax, 7; ret
+        defining.thunk("call_defined", "registered_answer");
+        let defining = AndroidLoader::new().load_library_from_bytes(defining.build()).unwrap();
+        let call: extern "C" fn() -> u32 = unsafe { std::mem::transmute(defining.get_symbol("call_defined").unwrap()) };
+        assert_eq!(call(), 7);
+    }
+
+    #[test]
+    fn guarded_image() {
+        let mut elf = TestElf::new();
+        elf.object("guarded_marker", &[0x3c; 8]);
+        let library = AndroidLoader::new().guard_pages(2).load_library_from_bytes(elf.build()).unwrap();
+        let (start, end) = (library.memory_map.as_ptr() as usize, library.memory_map.as_ptr() as usize + library.memory_map.len());
+        assert_eq!(unsafe { *(library.get_symbol("guarded_marker").unwrap() as *const [u8; 8]) }, [0x3c; 8]);
+
+        let page = region::page::size();
+        for address in [start - 1, start - 2 * page, end, end + 2 * page - 1] {
+            assert_eq!(region::query(address as *const u8).unwrap().protection(), region::Protection::NONE, "{address:#x}");
+        }
+    }
+
+    #[test]
+    fn deterministic_base() {
+        let mut elf = TestElf::new();
+        elf.function("based_code", &[0xc3]);
+        let pointer = elf.object("based_pointer", &[0; 8]);
+        elf.relocation(pointer, R_X86_64_64, Some("based_code"), 0);
+        elf.relocation(pointer + 8, R_X86_64_RELATIVE, None, 0x40);
+        elf.object("based_relative", &[0; 8]);
+        elf.thunk("call_based_strlen", "strlen");
+        let elf = elf.build();
+        let base = 0x2000_0000_0000;
+        let loader = AndroidLoader::new().base_address(base);
+
+        let first = loader.load_library_from_bytes(elf.clone()).unwrap();
+        assert_eq!(first.memory_map.as_ptr() as usize, base);
+        assert_eq!(unsafe { *(first.get_symbol("based_pointer").unwrap() as *const usize) }, first.get_symbol("based_code").unwrap() as usize);
+        let err = loader.load_library_from_bytes(elf.clone()).err().unwrap();
+        assert!(matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::BaseUnavailable { address }) if *address == base));
+        let image = first.memory_map.to_vec();
+        drop(first);
+
+        let second = loader.load_library_from_bytes(elf).unwrap();
+        assert!(second.memory_map[..] == image[..]);
+    }
+
+    #[sysv64]
+    fn layered_hook() -> u32 {
+        3
+    }
+
+    #[sysv64]
+    fn layered_registered() -> u32 {
+        2
+    }
+
+    #[test]
+    fn resolution_precedence() {
+        let mut provider = TestElf::new();
+        provider.soname("liblayered.so");
+        provider.function("layered_value", &[0xb8, 1, 0, 0, 0, 0xc3]); // mov eax, 1; ret
+        let provider = AndroidLoader::new().load_library_from_bytes(provider.build()).unwrap();
+        AndroidLoader::register_global_symbol("layered_value", layered_registered as *const () as usize);
+
+        let mut elf = TestElf::new();
+        elf.thunk("call_layered_value", "layered_value");
+        let elf = elf.build();
+        let value = |loader: AndroidLoader| {
+            let library = loader.libc_provider(&provider).load_library_from_bytes(elf.clone()).unwrap();
+            let call: extern "C" fn() -> u32 = unsafe { std::mem::transmute(library.get_symbol("call_layered_value").unwrap()) };
+            call()
+        };
+        let hook = layered_hook as *const () as usize;
+
+        // Defined by a hook, the scope, a registered symbol and no stub
+        assert_eq!(value(AndroidLoader::new().hook("layered_value", hook)), 3);
+        assert_eq!(value(AndroidLoader::new()), 1);
+        let registered_first = [ResolutionStep::Registered, ResolutionStep::Hooks, ResolutionStep::Scope];
+        assert_eq!(value(AndroidLoader::new().hook("layered_value", hook).resolution_order(&registered_first)), 2);
+        assert_eq!(value(AndroidLoader::new().hook("layered_value", hook).resolution_order(&[ResolutionStep::Scope])), 1);
+
+        // Counted as registered rather than as a stub
+        let registered = AndroidLoader::new().libc_provider(&provider).resolution_order(&registered_first).load_library_from_bytes(elf).unwrap();
+        let stats = registered.load_stats();
+        assert_eq!((stats.resolved_by_registered, stats.resolved_by_libc, stats.resolved_by_library), (1, 0, 0));
+    }
+
+    #[test]
+    fn preloaded_libc() {
+        let mut fake_libc = TestElf::new();
+        fake_libc.soname("libc.so");
+        fake_libc.function("strlen", &[0xb8, 42, 0, 0, 0, 0xc3]); // mov eax, 42; ret
+        let fake_libc = AndroidLoader::new().load_library_from_bytes(fake_libc.build()).unwrap();
+
+        let mut elf = TestElf::new();
+        elf.thunk("call_provided_strlen", "strlen");
+        let elf = elf.build();
+        let strlen = |library: &AndroidLibrary| {
+            let call: extern "C" fn(*const c_char) -> usize = unsafe { std::mem::transmute(library.get_symbol("call_provided_strlen").unwrap()) };
+            call(b"ab\0".as_ptr() as *const c_char)
+        };
+
+        let host = AndroidLoader::new().load_library_from_bytes(elf.clone()).unwrap();
+        assert_eq!(strlen(&host), 2);
+        let provided = AndroidLoader::new().libc_provider(&fake_libc).load_library_from_bytes(elf.clone()).unwrap();
+        assert_eq!(strlen(&provided), 42);
+        assert_eq!((provided.load_stats().resolved_by_library, provided.load_stats().resolved_by_libc), (1, 0));
+        // Hooks still come first
+        let hooked = AndroidLoader::new().libc_provider(&fake_libc).hook("strlen", libc::strlen as *const () as usize).load_library_from_bytes(elf).unwrap();
+        assert_eq!(strlen(&hooked), 2);
+    }
+
+    #[test]
+    fn loaded_into_buffer() {
+        let mut elf = TestElf::new();
+        elf.function("buffered_answer", &[0xb8, 5, 0, 0, 0, 0xc3]); // mov eax, 5; ret
+        elf.thunk("call_buffered_hook", "buffered_hook");
+        let elf = elf.build();
+        let mut buffer = memmap2::MmapOptions::new().len(0x10000).map_anon().unwrap();
+        let loader = AndroidLoader::new().hook("buffered_hook", registered_answer as *const () as usize);
+
+        let invalid = |err: anyhow::Error| matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::InvalidBuffer { .. }));
+        assert!(invalid(loader.load_into(&mut buffer[..0x10], elf.clone()).err().unwrap()));
+        assert!(invalid(loader.load_into(&mut buffer[1..], elf.clone()).err().unwrap()));
+
+        let library = loader.load_into(&mut buffer, elf).unwrap();
+        let answer = library.get_symbol("buffered_answer").unwrap();
+        assert_eq!(AndroidLoader::owning_library(answer as usize).unwrap().base, library.memory_map.as_ptr() as usize);
+        let answer: extern "C" fn() -> u32 = unsafe { std::mem::transmute(answer) };
+        let call_hook: extern "C" fn() -> u32 = unsafe { std::mem::transmute(library.get_symbol("call_buffered_hook").unwrap()) };
+        assert_eq!(answer(), 5);
+        assert_eq!(call_hook(), 42);
+        drop(library);
+        // Writable again
+        buffer[0] = 1;
+    }
+
+    #[test]
+    fn dropped_relocation() {
+        let mut elf = TestElf::new();
+        let guarded = elf.object("guarded_cell", &[0x5a; 8]);
+        let kept = elf.object("kept_cell", &[0; 8]);
+        elf.relocation(guarded, R_X86_64_64, Some("tamper_check"), 0);
+        elf.relocation(kept, R_X86_64_64, Some("kept_target"), 0);
+        let elf = elf.build();
+        let info = AndroidLoader::new().inspect_bytes(elf.clone()).unwrap();
+        let guarded = info.symbols.iter().find(|symbol| symbol.name == "guarded_cell").unwrap().value;
+
+        let library = AndroidLoader::new()
+            .hook("tamper_check", 0x1000)
+            .hook("kept_target", 0x2000)
+            .transform_relocations(move |relocations| relocations.retain(|relocation| relocation.offset != guarded))
+            .load_library_from_bytes(elf)
+            .unwrap();
+        assert_eq!(unsafe { *(library.get_symbol("guarded_cell").unwrap() as *const [u8; 8]) }, [0x5a; 8]);
+        assert_eq!(unsafe { *(library.get_symbol("kept_cell").unwrap() as *const usize) }, 0x2000);
+        assert_eq!(library.load_stats().relocations.get(&R_X86_64_64), Some(&1));
+    }
+
+    #[test]
+    fn symbolized_address() {
+        let mut elf = TestElf::new();
+        elf.soname("libsymbolized.so");
+        elf.function("symbolized_first", &[0x90; 16]);
+        elf.function("symbolized_second", &[0xc3]);
+        let library = AndroidLoader::new().load_library_from_bytes(elf.build()).unwrap();
+
+        let second = library.get_symbol("symbolized_second").unwrap() as usize;
+        assert_eq!(AndroidLoader::symbolize(second), Some((Some("libsymbolized.so".to_owned()), "symbolized_second".to_owned(), 0)));
+        assert_eq!(AndroidLoader::symbolize(second - 3), Some((Some("libsymbolized.so".to_owned()), "symbolized_first".to_owned(), 13)));
+        assert_eq!(AndroidLoader::symbolize(symbolized_address as *const () as usize), None);
+    }
+
+    #[test]
+    fn bionic_stub_bundle() {
+        let imports = [
+            "strlen", "memcmp", "toupper", "__errno", "pthread_mutex_lock", "__android_log_write", "__system_property_get", "getauxval", "usleep",
+        ];
+        let mut elf = TestElf::new();
+        for name in imports {
+            elf.thunk(&format!("bundled_{name}"), name);
+        }
+        let library = AndroidLoader::new().with_bionic_stubs().load_library_from_bytes(elf.build()).unwrap();
+        assert_eq!(library.load_stats().undefined, 0);
+        assert_eq!(library.load_stats().resolved_by_libc, imports.len());
+
+        let function = |name: &str| library.get_symbol(&format!("bundled_{name}")).unwrap();
+        unsafe {
+            let strlen: extern "C" fn(*const c_char) -> usize = std::mem::transmute(function("strlen"));
+            let toupper: extern "C" fn(c_int) -> c_int = std::mem::transmute(function("toupper"));
+            let lock: extern "C" fn(*mut ()) -> c_int = std::mem::transmute(function("pthread_mutex_lock"));
+            let log_write: extern "C" fn(c_int, *const c_char, *const c_char) -> c_int = std::mem::transmute(function("__android_log_write"));
+            assert_eq!(strlen(b"bundle\0".as_ptr() as *const c_char), 6);
+            assert_eq!(toupper(b'q' as c_int), b'Q' as c_int);
+            assert_eq!(lock(std::ptr::null_mut()), 0);
+            assert_eq!(log_write(4, b"bundle\0".as_ptr() as *const c_char, b"loaded\0".as_ptr() as *const c_char), 1);
+        }
+    }
+
+    #[test]
+    fn null_hook() {
+        let mut elf = TestElf::new();
+        elf.thunk("call_null_hooked", "null_hooked");
+        let err = AndroidLoader::new().hook("null_hooked", 0).load_library_from_bytes(elf.build()).err().unwrap();
+        match err.downcast_ref::<AndroidLoaderErr>() {
+            Some(AndroidLoaderErr::InvalidHook { name }) => assert_eq!(name, "null_hooked"),
+            _ => panic!("unexpected error {err}"),
+        }
+        assert!(AndroidLoader::new().hook("null_hooked", null_hook as *const () as usize).load_library_from_bytes(elf.build()).is_ok());
+    }
+
+    #[test]
+    fn owned_address() {
+        let mut elf = TestElf::new();
+        elf.soname("libowner.so");
+        elf.function("owned_function", &[0xc3]);
+        let library = AndroidLoader::new().load_library_from_bytes(elf.build()).unwrap();
+
+        let function = library.get_symbol("owned_function").unwrap() as usize;
+        let region = AndroidLoader::owning_library(function).unwrap();
+        assert_eq!(region.soname.as_deref(), Some("libowner.so"));
+        assert!((region.base..region.base + region.size).contains(&function));
+        assert_eq!(AndroidLoader::owning_library(region.base + region.size), None);
+        assert_eq!(AndroidLoader::owning_library(owned_address as *const () as usize), None);
+    }
+
+    #[test]
+    fn listed_libraries() {
+        let mut file = TestElf::new();
+        file.soname("liblisted_file.so");
+        let path = std::env::temp_dir().join(format!("android-loader-listed-{}.so", std::process::id()));
+        std::fs::write(&path, file.build()).unwrap();
+        let mut bytes = TestElf::new();
+        bytes.soname("liblisted_bytes.so");
+
+        let from_file = AndroidLoader::new().load_library(path.to_str().unwrap()).unwrap();
+        let from_bytes = AndroidLoader::new().load_library_from_bytes(bytes.build()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let listed = |soname: &str| AndroidLoader::loaded_libraries().into_iter().find(|library| library.soname.as_deref() == Some(soname));
+        let file_info = listed("liblisted_file.so").unwrap();
+        assert_eq!(
+            (file_info.base, file_info.size, file_info.path.as_deref()),
+            (from_file.memory_map.as_ptr() as usize, from_file.memory_map.len(), Some(path.as_path())),
+        );
+        let bytes_info = listed("liblisted_bytes.so").unwrap();
+        assert_eq!((bytes_info.base, bytes_info.path), (from_bytes.memory_map.as_ptr() as usize, None));
+
+        drop(from_file);
+        assert_eq!(listed("liblisted_file.so"), None);
+        assert!(listed("liblisted_bytes.so").is_some());
+    }
+
+    #[sysv64]
+    fn decoded_target() -> u32 {
+        9
+    }
+
+    #[test]
+    fn decoded_symbol_names() {
+        let mut hooks = HashMap::new();
+        hooks.insert("decoded_hook_target".to_owned(), decoded_target as *const () as usize);
+        add_hooks(hooks);
+
+        // Names are stored with the case of their letters flipped
+        let mut elf = TestElf::new();
+        elf.function("DECODED_ANSWER", &[0xb8, 5, 0, 0, 0, 0xc3]); // mov eax, 5; ret
+        elf.thunk("CALL_DECODED", "DECODED_HOOK_TARGET");
+        let loader = AndroidLoader::new().decode_symbol_names(|name| {
+            name.iter().map(|&byte| if byte.is_ascii_alphabetic() { (byte ^ 0x20) as char } else { byte as char }).collect()
+        });
+
+        let info = loader.inspect_bytes(elf.build()).unwrap();
+        assert_eq!(info.imports, ["decoded_hook_target"]);
+        assert!(info.symbols.iter().any(|symbol| symbol.name == "decoded_answer"));
+
+        let library = loader.load_library_from_bytes(elf.build()).unwrap();
+        assert_eq!(library.get_symbol("DECODED_ANSWER"), None);
+        let answer: extern "C" fn() -> u32 = unsafe { std::mem::transmute(library.get_symbol("decoded_answer").unwrap()) };
+        assert_eq!(answer(), 5);
+        let call: extern "C" fn() -> u32 = unsafe { std::mem::transmute(library.get_symbol("call_decoded").unwrap()) };
+        assert_eq!(call(), 9);
+        assert_eq!(AndroidLoader::symbolize(answer as *const () as usize).map(|(_, name, _)| name), Some("decoded_answer".to_owned()));
+    }
+
+    #[sysv64]
+    fn concurrent_first() -> u32 {
+        1
+    }
+
+    #[sysv64]
+    fn concurrent_second() -> u32 {
+        2
+    }
+
+    #[test]
+    fn concurrent_loads() {
+        let mut elf = TestElf::new();
+        elf.thunk("call_concurrent", "concurrent_target");
+        let elf = Arc::new(elf.build());
+        let call = |library: &AndroidLibrary| {
+            let call: extern "C" fn() -> u32 = unsafe { std::mem::transmute(library.get_symbol("call_concurrent").unwrap()) };
+            call()
+        };
+
+        let threads: Vec<_> = [(concurrent_first as *const () as usize, 1), (concurrent_second as *const () as usize, 2)]
+            .into_iter()
+            .map(|(hook, expected)| {
+                let elf = elf.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..20 {
+                        let library = AndroidLoader::new().hook("concurrent_target", hook).load_library_from_bytes(elf.to_vec()).unwrap();
+                        assert_eq!(call(&library), expected);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // Loading from within a load, with the hooks changing meanwhile
+        let nested = Arc::new(Mutex::new(None));
+        let nested_by_callback = nested.clone();
+        let nested_elf = elf.clone();
+        let outer = AndroidLoader::new()
+            .hook("concurrent_target", concurrent_first as *const () as usize)
+            .relocation_progress(move |_, _| {
+                add_hooks(HashMap::new());
+                let library = AndroidLoader::new().hook("concurrent_target", concurrent_second as *const () as usize)
+                    .load_library_from_bytes(nested_elf.to_vec())
+                    .unwrap();
+                *nested_by_callback.lock().unwrap() = Some(library);
+                ControlFlow::Continue(())
+            })
+            .load_library_from_bytes(elf.to_vec())
+            .unwrap();
+        assert_eq!(call(&outer), 1);
+        assert_eq!(call(nested.lock().unwrap().as_ref().unwrap()), 2);
+    }
+
+    #[test]
+    fn rewrite_symbol_prefix() {
+        let mut hooks = HashMap::new();
+        hooks.insert("rewrite_shim_add".to_owned(), shim_add as *const () as usize);
+        add_hooks(hooks);
+
+        let mut elf = TestElf::new();
+        elf.thunk("call_add", "SSL_add");
+
+        let library = AndroidLoader::new()
+            .rewrite_symbols(|names| {
+                for name in names.iter_mut() {
+                    if let Some(rest) = name.strip_prefix("SSL_") {
+                        *name = format!("rewrite_shim_{rest}");
+                    }
+                }
+            })
+            .load_library_from_bytes(elf.build())
+            .unwrap();
+
+        let call_add: extern "C" fn(u32, u32) -> u32 =
+            unsafe { std::mem::transmute(library.get_symbol("call_add").unwrap()) };
+        assert_eq!(call_add(2, 3), 5);
+    }
+
+    /// Wraps `data` in a gzip member using stored (uncompressed) deflate blocks
+    fn gzip_stored(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+        let mut chunks = data.chunks(0xffff).peekable();
+        while let Some(chunk) = chunks.next() {
+            out.push(chunks.peek().is_none() as u8);
+            out.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+            out.extend_from_slice(&(!(chunk.len() as u16)).to_le_bytes());
+            out.extend_from_slice(chunk);
+        }
+        out.extend_from_slice(&[0; 4]); // CRC32, not checked below
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out
+    }
+
+    /// Minimal gzip decompressor handling only stored deflate blocks
+    fn gunzip_stored(data: &[u8]) -> Option<Vec<u8>> {
+        if !data.starts_with(&[0x1f, 0x8b, 8]) || data[3] != 0 {
+            return None;
+        }
+        let mut out = Vec::new();
+        let mut pos = 10;
+        loop {
+            let last = data[pos] & 1 == 1;
+            let len = u16::from_le_bytes([data[pos + 1], data[pos + 2]]) as usize;
+            out.extend_from_slice(&data[pos + 5..pos + 5 + len]);
+            pos += 5 + len;
+            if last {
+                return Some(out);
+            }
+        }
+    }
+
+    #[test]
+    fn load_wrapped_library() {
+        let mut elf = TestElf::new();
+        elf.function("answer", &[0xb8, 42, 0, 0, 0, 0xc3]); // mov eax, 42; ret
+
+        // A gzip-compressed library inside a simple XOR packer
+        let packed: Vec<u8> = b"XPAK".iter().copied()
+            .chain(gzip_stored(&elf.build()).iter().map(|byte| byte ^ 0x5a))
+            .collect();
+
+        let library = AndroidLoader::new()
+            .preprocess(gunzip_stored)
+            .preprocess(|data| data.strip_prefix(b"XPAK").map(|rest| rest.iter().map(|byte| byte ^ 0x5a).collect()))
+            .load_library_from_bytes(packed.clone())
+            .unwrap();
+        let answer: extern "C" fn() -> u32 = unsafe { std::mem::transmute(library.get_symbol("answer").unwrap()) };
+        assert_eq!(answer(), 42);
+
+        assert!(AndroidLoader::new().preprocess(gunzip_stored).load_library_from_bytes(packed).is_err());
+    }
+
+    #[test]
+    fn cancel_relocation() {
+        let mut elf = TestElf::new();
+        let cells = elf.object("cells", &[0; 8 * 3000]);
+        for index in 0..3000 {
+            elf.relocation(cells + index * 8, R_X86_64_RELATIVE, None, 0);
+        }
+        let elf = elf.build();
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let log = reports.clone();
+        let err = AndroidLoader::new()
+            .relocation_progress(move |done, total| {
+                log.lock().unwrap().push((done, total));
+                if done >= 2 * PROGRESS_INTERVAL { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+            })
+            .load_library_from_bytes(elf.clone())
+            .err()
+            .unwrap();
+        assert!(matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::Cancelled)));
+        assert_eq!(*reports.lock().unwrap(), [(PROGRESS_INTERVAL, 3000), (2 * PROGRESS_INTERVAL, 3000)]);
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let log = reports.clone();
+        AndroidLoader::new()
+            .relocation_progress(move |done, total| {
+                log.lock().unwrap().push((done, total));
+                ControlFlow::Continue(())
+            })
+            .load_library_from_bytes(elf)
+            .unwrap();
+        assert_eq!(reports.lock().unwrap().last(), Some(&(3000, 3000)));
+    }
+
+    #[test]
+    fn verified_load() {
+        let mut elf = TestElf::new();
+        elf.function("verified_answer", &[0xb8, 42, 0, 0, 0, 0xc3]); // mov eax, 42; ret
+        let elf = elf.build();
+        let hash = sha256(&elf);
+
+        let library = AndroidLoader::new().verify_sha256(hash).load_library_from_bytes(elf.clone()).unwrap();
+        assert!(library.get_symbol("verified_answer").is_some());
+
+        let mut tampered = elf;
+        *tampered.last_mut().unwrap() ^= 1;
+        let err = AndroidLoader::new().verify_sha256(hash).load_library_from_bytes(tampered).err().unwrap();
+        match err.downcast_ref::<AndroidLoaderErr>() {
+            Some(AndroidLoaderErr::IntegrityCheckFailed { expected, actual }) => {
+                assert_eq!(*expected, hash);
+                assert_ne!(*actual, hash);
+            }
+            _ => panic!("unexpected error {err}"),
+        }
+    }
+
+    #[test]
+    fn retained_original_bytes() {
+        let mut elf = TestElf::new();
+        elf.object("retained_marker", &[0x3c; 8]);
+        let elf = elf.build();
+        assert!(AndroidLoader::new().load_library_from_bytes(elf.clone()).unwrap().original_bytes().is_none());
+
+        let library = AndroidLoader::new().retain_original_bytes().load_library_from_bytes(elf.clone()).unwrap();
+        assert_eq!(library.original_bytes(), Some(&elf[..]));
+
+        // Kept as given, not as unwrapped
+        let mut wrapped = b"WRAP".to_vec();
+        wrapped.extend_from_slice(&elf);
+        let library = AndroidLoader::new()
+            .preprocess(|file| file.strip_prefix(b"WRAP").map(|elf| elf.to_vec()))
+            .retain_original_bytes()
+            .load_library_from_bytes(wrapped.clone())
+            .unwrap();
+        assert_eq!(library.original_bytes(), Some(&wrapped[..]));
+        assert_eq!(unsafe { *(library.get_symbol("retained_marker").unwrap() as *const [u8; 8]) }, [0x3c; 8]);
+    }
+}
@@ -0,0 +1,103 @@
+//! Reading libraries out of APKs, which are zip archives, for
+//! [`AndroidLoader::load_from_apk`](crate::android_loader::AndroidLoader::load_from_apk) (with
+//! the `apk` feature).
+//!
+//! The archive is read by the `zip` crate: entries stored or deflated, as APKs have them, are
+//! found through the central directory and their CRC-32 is checked.
+
+use anyhow::Result;
+use std::io::{Cursor, Read};
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+use crate::android_library::AndroidLoaderErr;
+
+/// The ABI directory under `lib/` of the libraries the host can load
+#[cfg(target_arch = "x86_64")]
+pub const HOST_ABI: &str = "x86_64";
+#[cfg(target_arch = "x86")]
+pub const HOST_ABI: &str = "x86";
+#[cfg(target_arch = "aarch64")]
+pub const HOST_ABI: &str = "arm64-v8a";
+#[cfg(target_arch = "arm")]
+pub const HOST_ABI: &str = "armeabi-v7a";
+
+fn malformed(err: impl ToString) -> AndroidLoaderErr {
+    AndroidLoaderErr::MalformedApk(err.to_string())
+}
+
+/// The contents of the entry `name` of the archive `apk`, failing with
+/// [`AndroidLoaderErr::NotInApk`] if there's none
+pub(crate) fn read_entry(apk: &[u8], name: &str) -> Result<Vec<u8>> {
+    let mut archive = ZipArchive::new(Cursor::new(apk)).map_err(malformed)?;
+    let mut entry = match archive.by_name(name) {
+        Ok(entry) => entry,
+        Err(ZipError::FileNotFound) => return Err(AndroidLoaderErr::NotInApk(name.to_owned()).into()),
+        Err(err) => return Err(malformed(err).into()),
+    };
+    let mut contents = Vec::with_capacity(entry.size() as usize);
+    // Reading to the end checks the CRC-32
+    entry.read_to_end(&mut contents).map_err(malformed)?;
+    Ok(contents)
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::io::{Cursor, Write};
+    use zip::write::FileOptions;
+    use zip::{CompressionMethod, ZipWriter};
+
+    use crate::android_library::AndroidLoaderErr;
+    use crate::android_loader::AndroidLoader;
+    use crate::apk::read_entry;
+    use crate::test_elf::TestElf;
+
+    /// A zip archive of `entries`, as names, contents, and whether they're deflated
+    fn zip(entries: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents, deflated) in entries {
+            let method = if *deflated { CompressionMethod::Deflated } else { CompressionMethod::Stored };
+            writer.start_file(*name, FileOptions::default().compression_method(method)).unwrap();
+            writer.write_all(contents).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn apk_libraries() {
+        let mut elf = TestElf::new();
+        elf.function("apk_answer", &[0xb8, 42, 0, 0, 0, 0xc3]); // mov eax, 42; ret
+        let elf = elf.build();
+        let path = std::env::temp_dir().join(format!("android-loader-test-{}.apk", std::process::id()));
+        std::fs::write(&path, zip(&[
+            ("AndroidManifest.xml", b"<manifest/>", true),
+            ("lib/arm64-v8a/libstored.so", b"not this one", false),
+            ("lib/x86_64/libstored.so", &elf, false),
+            ("lib/x86_64/libdeflated.so", &elf, true),
+        ])).unwrap();
+
+        let loader = AndroidLoader::new();
+        for soname in ["libstored.so", "libdeflated.so"] {
+            let library = loader.load_from_apk(&path, soname).unwrap();
+            let answer: extern "C" fn() -> u32 = unsafe { std::mem::transmute(library.get_symbol("apk_answer").unwrap()) };
+            assert_eq!(answer(), 42);
+        }
+
+        let err = loader.load_from_apk(&path, "libmissing.so").err().unwrap();
+        assert!(matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::NotInApk(entry)) if entry == "lib/x86_64/libmissing.so"));
+        std::fs::write(&path, b"not a zip").unwrap();
+        let err = loader.load_from_apk(&path, "libstored.so").err().unwrap();
+        assert!(matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::MalformedApk(_))));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn corrupted_entry() {
+        let contents = [0x5au8; 64];
+        let mut archive = zip(&[("lib/x86_64/libstored.so", &contents, false)]);
+        let data = archive.windows(contents.len()).position(|window| window == contents).unwrap();
+        archive[data] ^= 1;
+        let err = read_entry(&archive, "lib/x86_64/libstored.so").err().unwrap();
+        assert!(matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::MalformedApk(_))));
+    }
+}
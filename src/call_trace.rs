@@ -0,0 +1,266 @@
+//! Tracing the calls a library makes through its PLT, with [`AndroidLoader::trace_calls`].
+//!
+//! Each traced `JUMP_SLOT` points at a stub passing the import's context, in a scratch
+//! register, to a shared entry point. The entry takes the caller's return address off the
+//! stack and keeps it with the arguments on a per-thread stack, then calls the import with
+//! the stack as the caller left it, so arguments passed on the stack are where it expects
+//! them. Once the import returns, the call is reported and the entry returns to the caller.
+//! Only x86_64 and aarch64 have the entry point; other targets don't trace.
+//!
+//! Unwinding or `longjmp`ing out of a traced call leaves its entry on the per-thread stack
+//! and isn't reported, and the callback mustn't panic.
+//!
+//! [`AndroidLoader::trace_calls`]: crate::android_loader::AndroidLoader::trace_calls
+
+use anyhow::Result;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::sysv64;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use crate::trampoline;
+use crate::trampoline::TrampolineArena;
+
+/// Whether this target has the entry point
+pub(crate) const SUPPORTED: bool = cfg!(any(target_arch = "x86_64", target_arch = "aarch64"));
+
+/// Integer or pointer arguments passed in registers, and so recorded
+#[cfg(not(target_arch = "aarch64"))]
+pub const TRACED_ARGUMENTS: usize = 6;
+#[cfg(target_arch = "aarch64")]
+pub const TRACED_ARGUMENTS: usize = 8;
+
+/// A call a traced library made, reported once it returned
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TracedCall<'a> {
+    pub symbol: &'a str,
+    /// The integer and pointer argument registers, whether the import takes that many or not
+    pub arguments: [usize; TRACED_ARGUMENTS],
+    /// The integer return register
+    pub result: usize,
+}
+
+/// Callback for [`AndroidLoader::trace_calls`](crate::android_loader::AndroidLoader::trace_calls)
+pub type TraceCallback = dyn Fn(&TracedCall) + Send + Sync;
+
+struct TracedSymbol {
+    name: String,
+    target: usize,
+    callback: Arc<TraceCallback>,
+}
+
+/// A call in progress: its symbol, where it returns to and its arguments
+type PendingCall = (*const TracedSymbol, usize, [usize; TRACED_ARGUMENTS]);
+
+thread_local! {
+    static PENDING: RefCell<Vec<PendingCall>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The stubs of one library's traced imports
+#[cfg_attr(not(any(target_arch = "x86_64", target_arch = "aarch64")), allow(dead_code))]
+pub(crate) struct CallTraces {
+    callback: Arc<TraceCallback>,
+    symbols: TrampolineArena<TracedSymbol>,
+    addresses: HashMap<String, usize>,
+}
+
+impl CallTraces {
+    /// Prepare room for up to `capacity` traced imports
+    pub(crate) fn new(callback: Arc<TraceCallback>, capacity: usize) -> Result<CallTraces> {
+        Ok(CallTraces { callback, symbols: TrampolineArena::new(capacity)?, addresses: HashMap::new() })
+    }
+
+    /// Address to bind the import `name`, resolved to `target`, to so its calls are traced
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub(crate) fn wrap(&mut self, name: &str, target: usize) -> usize {
+        if let Some(address) = self.addresses.get(name) {
+            return *address;
+        }
+        let symbol = TracedSymbol { name: name.to_owned(), target, callback: self.callback.clone() };
+        let address = self.symbols
+            .stub(symbol, |code, context| trampoline::write_preserving(code, android_loader_trace_entry as *const () as usize, context))
+            .expect("more traced imports than relocations, or traced after relocation");
+        self.addresses.insert(name.to_owned(), address);
+        address
+    }
+
+    /// Make the stubs executable once every relocation is applied
+    pub(crate) fn finish(&mut self) -> Result<()> {
+        self.symbols.finish()
+    }
+}
+
+/// Remember a call about to be made and return the import to call
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_trace_enter(symbol: *const TracedSymbol, return_address: usize, arguments: *const [usize; TRACED_ARGUMENTS]) -> usize {
+    PENDING.with(|pending| pending.borrow_mut().push((symbol, return_address, *arguments)));
+    (*symbol).target
+}
+
+/// Report the call that just returned `result` and return where it returns to
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_trace_exit(result: usize) -> usize {
+    let (symbol, return_address, arguments) = PENDING.with(|pending| pending.borrow_mut().pop()).expect("traced call returned twice");
+    let symbol = &*symbol;
+    (symbol.callback)(&TracedCall { symbol: &symbol.name, arguments, result });
+    return_address
+}
+
+// Entered from a stub with the symbol in r10 (x86_64) or x16 (aarch64), the return address
+// on the stack (x86_64) or in x30 (aarch64) and the call's arguments untouched. The integer
+// argument registers are saved first, as the array `android_loader_trace_enter` records.
+#[cfg(target_arch = "x86_64")]
+std::arch::global_asm!(
+    ".text",
+    concat!(".globl ", crate::stubs::varargs::asm_symbol!("android_loader_trace_entry")),
+    concat!(crate::stubs::varargs::asm_symbol!("android_loader_trace_entry"), ":"),
+    "pop r11",
+    "sub rsp, 192",
+    "mov [rsp], rdi",
+    "mov [rsp + 8], rsi",
+    "mov [rsp + 16], rdx",
+    "mov [rsp + 24], rcx",
+    "mov [rsp + 32], r8",
+    "mov [rsp + 40], r9",
+    "mov [rsp + 48], rax",
+    "movaps [rsp + 64], xmm0",
+    "movaps [rsp + 80], xmm1",
+    "movaps [rsp + 96], xmm2",
+    "movaps [rsp + 112], xmm3",
+    "movaps [rsp + 128], xmm4",
+    "movaps [rsp + 144], xmm5",
+    "movaps [rsp + 160], xmm6",
+    "movaps [rsp + 176], xmm7",
+    "mov rdi, r10",
+    "mov rsi, r11",
+    "mov rdx, rsp",
+    concat!("call ", crate::stubs::varargs::asm_symbol!("android_loader_trace_enter")),
+    "mov r11, rax",
+    "mov rdi, [rsp]",
+    "mov rsi, [rsp + 8]",
+    "mov rdx, [rsp + 16]",
+    "mov rcx, [rsp + 24]",
+    "mov r8, [rsp + 32]",
+    "mov r9, [rsp + 40]",
+    "mov rax, [rsp + 48]",
+    "movaps xmm0, [rsp + 64]",
+    "movaps xmm1, [rsp + 80]",
+    "movaps xmm2, [rsp + 96]",
+    "movaps xmm3, [rsp + 112]",
+    "movaps xmm4, [rsp + 128]",
+    "movaps xmm5, [rsp + 144]",
+    "movaps xmm6, [rsp + 160]",
+    "movaps xmm7, [rsp + 176]",
+    "add rsp, 192",
+    "call r11",
+    "sub rsp, 48",
+    "mov [rsp], rax",
+    "mov [rsp + 8], rdx",
+    "movaps [rsp + 16], xmm0",
+    "movaps [rsp + 32], xmm1",
+    "mov rdi, rax",
+    concat!("call ", crate::stubs::varargs::asm_symbol!("android_loader_trace_exit")),
+    "mov r11, rax",
+    "mov rax, [rsp]",
+    "mov rdx, [rsp + 8]",
+    "movaps xmm0, [rsp + 16]",
+    "movaps xmm1, [rsp + 32]",
+    "add rsp, 48",
+    "jmp r11",
+);
+
+// x8 is saved too, as the indirect result register, and x0 to x7 and q0 to q3 on return as
+// they may hold a returned aggregate
+#[cfg(target_arch = "aarch64")]
+std::arch::global_asm!(
+    ".text",
+    concat!(".globl ", crate::stubs::varargs::asm_symbol!("android_loader_trace_entry")),
+    concat!(crate::stubs::varargs::asm_symbol!("android_loader_trace_entry"), ":"),
+    "sub sp, sp, #208",
+    "stp x0, x1, [sp, #0]",
+    "stp x2, x3, [sp, #16]",
+    "stp x4, x5, [sp, #32]",
+    "stp x6, x7, [sp, #48]",
+    "str x8, [sp, #64]",
+    "stp q0, q1, [sp, #80]",
+    "stp q2, q3, [sp, #112]",
+    "stp q4, q5, [sp, #144]",
+    "stp q6, q7, [sp, #176]",
+    "mov x0, x16",
+    "mov x1, x30",
+    "mov x2, sp",
+    concat!("bl ", crate::stubs::varargs::asm_symbol!("android_loader_trace_enter")),
+    "mov x16, x0",
+    "ldp x0, x1, [sp, #0]",
+    "ldp x2, x3, [sp, #16]",
+    "ldp x4, x5, [sp, #32]",
+    "ldp x6, x7, [sp, #48]",
+    "ldr x8, [sp, #64]",
+    "ldp q0, q1, [sp, #80]",
+    "ldp q2, q3, [sp, #112]",
+    "ldp q4, q5, [sp, #144]",
+    "ldp q6, q7, [sp, #176]",
+    "add sp, sp, #208",
+    "blr x16",
+    "sub sp, sp, #128",
+    "stp x0, x1, [sp, #0]",
+    "stp x2, x3, [sp, #16]",
+    "stp x4, x5, [sp, #32]",
+    "stp x6, x7, [sp, #48]",
+    "stp q0, q1, [sp, #64]",
+    "stp q2, q3, [sp, #96]",
+    concat!("bl ", crate::stubs::varargs::asm_symbol!("android_loader_trace_exit")),
+    "mov x30, x0",
+    "ldp x0, x1, [sp, #0]",
+    "ldp x2, x3, [sp, #16]",
+    "ldp x4, x5, [sp, #32]",
+    "ldp x6, x7, [sp, #48]",
+    "ldp q0, q1, [sp, #64]",
+    "ldp q2, q3, [sp, #96]",
+    "add sp, sp, #128",
+    "ret",
+);
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+extern "C" {
+    fn android_loader_trace_entry();
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::ffi::CStr;
+    use std::os::raw::c_char;
+    use std::sync::{Arc, Mutex};
+
+    use crate::android_loader::AndroidLoader;
+    use crate::test_elf::TestElf;
+
+    #[test]
+    fn traced_libc_call() {
+        let mut elf = TestElf::new();
+        elf.thunk("call_traced_strlen", "strlen");
+        elf.thunk("call_traced_strncmp", "strncmp");
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let log = calls.clone();
+        let library = AndroidLoader::new()
+            .trace_calls(move |call| log.lock().unwrap().push((call.symbol.to_owned(), call.arguments, call.result)))
+            .load_library_from_bytes(elf.build())
+            .unwrap();
+
+        let text = b"traced\0";
+        let strlen: extern "C" fn(*const c_char) -> usize = unsafe { std::mem::transmute(library.get_symbol("call_traced_strlen").unwrap()) };
+        assert_eq!(strlen(text.as_ptr() as *const c_char), 6);
+        let strncmp: extern "C" fn(*const c_char, *const c_char, usize) -> i32 = unsafe { std::mem::transmute(library.get_symbol("call_traced_strncmp").unwrap()) };
+        assert_eq!(strncmp(text.as_ptr() as *const c_char, b"trace\0".as_ptr() as *const c_char, 5), 0);
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 2);
+        let (symbol, arguments, result) = &calls[0];
+        assert_eq!((symbol.as_str(), *result), ("strlen", 6));
+        assert_eq!(unsafe { CStr::from_ptr(arguments[0] as *const c_char) }.to_bytes(), b"traced");
+        assert_eq!((calls[1].0.as_str(), calls[1].1[2], calls[1].2), ("strncmp", 5, 0));
+    }
+}
@@ -0,0 +1,206 @@
+//! Telling which loaded library made a call.
+//!
+//! Functions that need to know their caller (e.g. `dlsym(RTLD_NEXT, ...)`) are reached through
+//! an assembly entry point (see [`caller_entry`]) passing the return address as an extra
+//! trailing argument, which [`registry`](crate::registry) maps back to a library.
+//!
+//! The return address is wrong when a library tail-calls such a function (e.g. from a thunk),
+//! so the ones a library imports are bound to it instead: its relocations point at per-library
+//! [`CallerStubs`] passing the library's base address in place of the return address.
+
+use anyhow::Result;
+use std::collections::HashMap;
+
+use crate::trampoline::{self, TrampolineArena, TRAMPOLINE_SIZE};
+
+/// Defines an assembly entry point `$name` for a function with `$args` (at most 6) integer or
+/// pointer arguments, which forwards them followed by its return address to `$target`
+macro_rules! caller_entry {
+    ($name:literal, $args:tt, $target:literal) => {
+        caller_entry!(@x86_64 $name, $args, $target);
+
+        #[cfg(target_arch = "aarch64")]
+        std::arch::global_asm!(
+            ".text",
+            concat!(".globl ", $crate::stubs::varargs::asm_symbol!($name)),
+            concat!($crate::stubs::varargs::asm_symbol!($name), ":"),
+            concat!("mov x", $args, ", x30"),
+            concat!("b ", $crate::stubs::varargs::asm_symbol!($target)),
+        );
+
+        caller_entry!(@arm $name, $args, $target);
+
+        // Copies enough stack words for any argument count, then overwrites the one after the
+        // arguments with the return address
+        #[cfg(target_arch = "x86")]
+        std::arch::global_asm!(
+            ".text",
+            concat!(".globl ", $crate::stubs::varargs::asm_symbol!($name)),
+            concat!($crate::stubs::varargs::asm_symbol!($name), ":"),
+            "push ebp",
+            "mov ebp, esp",
+            "push esi",
+            "push edi",
+            "and esp, -16",
+            "sub esp, 32",
+            "lea esi, [ebp + 8]",
+            "mov edi, esp",
+            "mov ecx, 7",
+            "rep movsd",
+            "mov eax, [ebp + 4]",
+            concat!("mov [esp + 4 * ", $args, "], eax"),
+            concat!("call ", $crate::stubs::varargs::asm_symbol!($target)),
+            "lea esp, [ebp - 8]",
+            "pop edi",
+            "pop esi",
+            "pop ebp",
+            "ret",
+        );
+    };
+
+    // The 7th argument goes on the stack, right above the return address of the forwarded call
+    (@x86_64 $name:literal, 6, $target:literal) => {
+        #[cfg(target_arch = "x86_64")]
+        std::arch::global_asm!(
+            ".text",
+            concat!(".globl ", $crate::stubs::varargs::asm_symbol!($name)),
+            concat!($crate::stubs::varargs::asm_symbol!($name), ":"),
+            "sub rsp, 24",
+            "mov rax, [rsp + 24]",
+            "mov [rsp], rax",
+            concat!("call ", $crate::stubs::varargs::asm_symbol!($target)),
+            "add rsp, 24",
+            "ret",
+        );
+    };
+    (@x86_64 $name:literal, $args:tt, $target:literal) => {
+        #[cfg(target_arch = "x86_64")]
+        std::arch::global_asm!(
+            ".text",
+            concat!(".globl ", $crate::stubs::varargs::asm_symbol!($name)),
+            concat!($crate::stubs::varargs::asm_symbol!($name), ":"),
+            concat!("mov ", $crate::stubs::varargs::variadic_entry!(@x86_64_register $args), ", [rsp]"),
+            concat!("jmp ", $crate::stubs::varargs::asm_symbol!($target)),
+        );
+    };
+
+    (@arm $name:literal, 4, $target:literal) => { caller_entry!(@arm_stack $name, 4, $target); };
+    (@arm $name:literal, 5, $target:literal) => { caller_entry!(@arm_stack $name, 5, $target); };
+    (@arm $name:literal, 6, $target:literal) => { caller_entry!(@arm_stack $name, 6, $target); };
+    (@arm $name:literal, $args:tt, $target:literal) => {
+        #[cfg(target_arch = "arm")]
+        std::arch::global_asm!(
+            ".text",
+            concat!(".globl ", $crate::stubs::varargs::asm_symbol!($name)),
+            concat!($crate::stubs::varargs::asm_symbol!($name), ":"),
+            concat!("mov r", $args, ", lr"),
+            concat!("b ", $crate::stubs::varargs::asm_symbol!($target)),
+        );
+    };
+    // Arguments past r3 are on the stack, so they're copied below a new frame
+    (@arm_stack $name:literal, $args:tt, $target:literal) => {
+        #[cfg(target_arch = "arm")]
+        std::arch::global_asm!(
+            ".text",
+            concat!(".globl ", $crate::stubs::varargs::asm_symbol!($name)),
+            concat!($crate::stubs::varargs::asm_symbol!($name), ":"),
+            "push {{r4, lr}}",
+            "sub sp, sp, #24",
+            "ldr r4, [sp, #32]",
+            "str r4, [sp]",
+            "ldr r4, [sp, #36]",
+            "str r4, [sp, #4]",
+            "ldr r4, [sp, #40]",
+            "str r4, [sp, #8]",
+            concat!("str lr, [sp, #(4 * (", $args, " - 4))]"),
+            concat!("bl ", $crate::stubs::varargs::asm_symbol!($target)),
+            "add sp, sp, #24",
+            "pop {{r4, pc}}",
+        );
+    };
+}
+
+pub(crate) use caller_entry;
+
+/// Room for the stubs of one library, more than it can import caller-sensitive functions
+const STUBS_SIZE: usize = 4096;
+
+/// Stubs binding caller-sensitive functions to the library importing them
+pub(crate) struct CallerStubs {
+    /// Base address of the library, passed as the caller
+    library: usize,
+    /// Stub addresses by target
+    stubs: HashMap<usize, usize>,
+    /// Mapped on the first stub, as most libraries have none
+    arena: Option<TrampolineArena<()>>,
+}
+
+impl CallerStubs {
+    pub(crate) fn new(library: usize) -> CallerStubs {
+        CallerStubs { library, stubs: HashMap::new(), arena: None }
+    }
+
+    /// Stub calling `target` with the first `args` arguments followed by the library's base
+    /// address, where a [`caller_entry`] would pass its return address. `None` if it can't be
+    /// made, leaving the library with the caller entry.
+    pub(crate) fn bind(&mut self, target: usize, args: usize) -> Option<usize> {
+        if let Some(stub) = self.stubs.get(&target) {
+            return Some(*stub);
+        }
+
+        if self.arena.is_none() {
+            self.arena = TrampolineArena::new(STUBS_SIZE / TRAMPOLINE_SIZE).ok();
+        }
+        let library = self.library;
+        let stub = self.arena.as_mut()?.stub((), |code, _| trampoline::write_forwarding(code, target, args, library))?;
+        self.stubs.insert(target, stub);
+        Some(stub)
+    }
+
+    /// Make the stubs executable once every relocation is applied
+    pub(crate) fn finish(&mut self) -> Result<()> {
+        match &mut self.arena {
+            Some(arena) => arena.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::ffi::CString;
+    use std::os::raw::{c_char, c_void};
+
+    use crate::android_library::AndroidLibrary;
+    use crate::test_elf::TestElf;
+
+    /// mov eax, value; ret
+    fn returning(value: u8) -> [u8; 6] {
+        [0xb8, value, 0, 0, 0, 0xc3]
+    }
+
+    #[test]
+    fn rtld_next_through_thunks() {
+        // Thunks jump straight to dlsym, so the return address is the test's
+        let mut first = TestElf::new();
+        first.thunk("tracked_lookup_first", "dlsym");
+        let first = AndroidLibrary::load_from_bytes(first.build()).unwrap();
+        let mut second = TestElf::new();
+        second.function("tracked_value", &returning(2));
+        second.thunk("tracked_lookup_second", "dlsym");
+        let second = AndroidLibrary::load_from_bytes(second.build()).unwrap();
+        let mut third = TestElf::new();
+        third.function("tracked_value", &returning(3));
+        let _third = AndroidLibrary::load_from_bytes(third.build()).unwrap();
+
+        let name = CString::new("tracked_value").unwrap();
+        let next = |library: &AndroidLibrary, lookup: &str| {
+            let lookup: extern "C" fn(usize, *const c_char) -> *mut c_void =
+                unsafe { std::mem::transmute(library.get_symbol(lookup).unwrap()) };
+            let value: extern "C" fn() -> u32 = unsafe { std::mem::transmute(lookup(usize::MAX, name.as_ptr())) };
+            value()
+        };
+        assert_eq!(next(&first, "tracked_lookup_first"), 2);
+        assert_eq!(next(&second, "tracked_lookup_second"), 3);
+    }
+}
@@ -0,0 +1,69 @@
+//! Looking functions up by their C++ signature, with Itanium names demangled by `cpp_demangle`.
+//!
+//! Names are formatted like `c++filt` prints them (`foo::bar(char const*, int&) const`).
+
+use cpp_demangle::{DemangleOptions, Symbol};
+
+/// Demangle an Itanium-mangled symbol name, `None` if it isn't one
+pub(crate) fn demangle(symbol: &str) -> Option<String> {
+    // Clones such as `.cold` or `.constprop.0` keep the original's signature
+    let symbol = symbol.split('.').next()?;
+    if !symbol.starts_with("_Z") {
+        return None;
+    }
+    Symbol::new(symbol).ok()?.demangle(&DemangleOptions::default()).ok()
+}
+
+/// Whether a demangled name matches a user-written signature. Whitespace is ignored, and a
+/// signature without a parameter list matches any overload.
+pub(crate) fn matches(demangled: &str, signature: &str) -> bool {
+    let strip = |s: &str| s.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+    let (demangled, signature) = (strip(demangled), strip(signature));
+    if signature.contains('(') {
+        demangled == signature
+    } else {
+        demangled.split('(').next() == Some(signature.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::demangle::{demangle, matches};
+
+    #[test]
+    fn itanium_names() {
+        let cases = [
+            ("_Z3foov", "foo()"),
+            ("_Z3addii", "add(int, int)"),
+            ("_ZN3foo3barEv", "foo::bar()"),
+            ("_ZNK3foo3barEPKcRi", "foo::bar(char const*, int&) const"),
+            ("_ZN3foo3BarC2Ev", "foo::Bar::Bar()"),
+            ("_ZN3foo3BarD1Ev", "foo::Bar::~Bar()"),
+            ("_ZNSt6vectorIiSaIiEEC2Ev", "std::vector<int, std::allocator<int> >::vector()"),
+            ("_ZN3fooplERKS_", "foo::operator+(foo const&)"),
+            ("_ZN5media6Player4openEPNS_6SourceEPS1_", "media::Player::open(media::Source*, media::Source*)"),
+            ("_ZNSt6vectorIiSaIiEE9push_backERKi", "std::vector<int, std::allocator<int> >::push_back(int const&)"),
+            ("_Z3maxIiET_S0_S0_", "int max<int>(int, int)"),
+            ("_ZN12_GLOBAL__N_14initEv", "(anonymous namespace)::init()"),
+            ("_ZN3foo5countE", "foo::count"),
+            ("_ZN3foo3barEv.cold", "foo::bar()"),
+            ("_Z8callbackPFviE", "callback(void (*)(int))"),
+            ("_ZZ4mainENKUlvE_clEv", "main::{lambda()#1}::operator()() const"),
+            ("_Z5applyILi3EEvv", "void apply<3>()"),
+        ];
+        for (mangled, demangled) in cases {
+            assert_eq!(demangle(mangled).as_deref(), Some(demangled), "{mangled}");
+        }
+
+        assert_eq!(demangle("printf"), None);
+        assert_eq!(demangle("_Z"), None);
+    }
+
+    #[test]
+    fn signature_matching() {
+        assert!(matches("foo::bar(char const*, int&) const", "foo::bar(char const *, int &) const"));
+        assert!(matches("foo::bar(int)", "foo::bar"));
+        assert!(!matches("foo::bar(int)", "foo::bar(long)"));
+        assert!(!matches("foo::barbaz(int)", "foo::bar"));
+    }
+}
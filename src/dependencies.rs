@@ -0,0 +1,358 @@
+//! Loading a library together with its `DT_NEEDED` dependencies.
+//!
+//! Dependencies are discovered breadth-first with a work queue. Every soname is loaded at most
+//! once: names already queued (diamonds and cycles) are skipped and libraries that are already
+//! loaded are reused. All of them are mapped before any is relocated, so symbols resolve across
+//! the whole group regardless of cycles, and dependencies are relocated (and initialized) before
+//! their dependents.
+//!
+//! An [isolated](AndroidLoader::isolated) load neither reuses loaded libraries nor is reused,
+//! so it gets its own copy of every dependency found, kept alive by its root alone.
+//!
+//! A loaded dependency can be [reloaded](AndroidLoader::reload_dependency) from its file: the
+//! new copy takes its place in symbol lookups, and the `GLOB_DAT` and `JUMP_SLOT` slots of other
+//! libraries pointing into the old copy are pointed at the new one. The old copy stays mapped
+//! as long as the group does, since code and data of it may still be in use.
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use log::{debug, info};
+use std::collections::{HashMap, HashSet, VecDeque};
+use region::Protection;
+use std::fs;
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::android_library::{AndroidLibrary, AndroidLoaderErr};
+use crate::android_loader::AndroidLoader;
+use crate::hook_manager;
+use crate::initializers;
+use crate::protections::{self, ProtectionSource};
+use crate::registry;
+
+/// The dependencies one load brought in, shared by every later load reusing one of them
+pub(crate) struct DependencyGroup {
+    pub(crate) libraries: Vec<AndroidLibrary<'static>>,
+    /// Groups of previously loaded dependencies this one's libraries resolve against, only
+    /// held to keep them loaded
+    #[allow(dead_code)]
+    reused: Vec<Arc<DependencyGroup>>,
+    /// Newer copies of the libraries, reloaded in their place
+    reloaded: Mutex<Vec<AndroidLibrary<'static>>>,
+}
+
+lazy_static! {
+    /// Loaded dependencies by soname
+    static ref DEPENDENCIES: Mutex<HashMap<String, Weak<DependencyGroup>>> = Mutex::new(HashMap::new());
+}
+
+/// Load `file` and the dependencies it needs that are found in the loader's library paths, the
+/// former into `buffer` if there is one. Dependencies that can't be found are left to the hooks
+/// and built-in stubs.
+pub(crate) fn load<'a>(loader: &AndroidLoader, file: Vec<u8>, buffer: Option<&'a mut [u8]>) -> Result<AndroidLibrary<'a>> {
+    // Taken once, so nothing global is locked or read while relocating
+    let hooks = loader.hooks();
+    hook_manager::validate(&hooks)?;
+    let root = AndroidLibrary::map(loader, file, buffer, loader.base_address)?;
+    // With a base address, each library goes right after the one mapped before it
+    let end = |library: &AndroidLibrary| library.memory_map.as_ptr() as usize + library.memory_map.len();
+    let mut next_base = loader.base_address.map(|_| end(&root.library));
+    let mut scope = vec![root.library.registry_id];
+    let mut seen: HashSet<String> = root.library.soname.iter().cloned().collect();
+    let mut queue: VecDeque<String> = root.needed.iter().cloned().collect();
+    let mut mapped = Vec::new();
+    let mut reused: Vec<Arc<DependencyGroup>> = Vec::new();
+
+    while let Some(name) = queue.pop_front() {
+        if !seen.insert(name.clone()) {
+            continue;
+        }
+
+        if let Some(id) = registry::by_soname(&name).filter(|_| !loader.isolated) {
+            debug!("Reusing the loaded {name}");
+            scope.push(id);
+            // Libraries the host loaded itself have to outlive the ones depending on them
+            if let Some(group) = DEPENDENCIES.lock().unwrap().get(&name).and_then(Weak::upgrade) {
+                if !reused.iter().any(|other| Arc::ptr_eq(other, &group)) {
+                    reused.push(group);
+                }
+            }
+            continue;
+        }
+
+        let path = match loader.library_paths.iter().map(|dir| dir.join(&name)).find(|path| path.is_file()) {
+            Some(path) => path,
+            None => {
+                debug!("{name} isn't in the library paths, leaving its symbols to the hooks and stubs");
+                continue;
+            }
+        };
+        info!("Loading dependency {}", path.display());
+        let dependency = AndroidLibrary::map(loader, loader.unwrap_file(fs::read(&path)?)?, None, next_base)?;
+        registry::set_path(dependency.library.registry_id, &path);
+        next_base = next_base.map(|_| end(&dependency.library));
+        scope.push(dependency.library.registry_id);
+        queue.extend(dependency.needed.iter().cloned());
+        mapped.push(dependency);
+    }
+
+    if let Some(libc) = loader.libc_provider.filter(|libc| !scope.contains(libc)) {
+        scope.push(libc);
+    }
+
+    // Breadth-first order puts dependents before their dependencies
+    let mut libraries = Vec::with_capacity(mapped.len());
+    while let Some(dependency) = mapped.pop() {
+        libraries.push(AndroidLibrary::relocate(dependency, loader, &scope, &hooks)?);
+    }
+    libraries.reverse();
+    let mut root = AndroidLibrary::relocate(root, loader, &scope, &hooks)?;
+    if loader.reject_missing_imports {
+        let mut missing = root.stats.missing_imports.clone();
+        for library in &libraries {
+            missing.merge(&library.stats.missing_imports);
+        }
+        if !missing.is_empty() {
+            return Err(AndroidLoaderErr::MissingImports(missing).into());
+        }
+    }
+    // Nothing global may be locked from here on, as initializers can re-enter the loader
+    // through `dlopen`
+    if loader.run_initializers {
+        let mut initialized = Ok(());
+        for library in libraries.iter_mut().rev() {
+            initialized = initializers::initialize(library, loader);
+            if initialized.is_err() {
+                break;
+            }
+        }
+        if initialized.is_ok() {
+            initialized = initializers::initialize(&mut root, loader);
+        }
+        if let Err(err) = initialized {
+            // An abandoned initializer may still be running in any of them
+            std::mem::forget(root);
+            std::mem::forget(libraries);
+            std::mem::forget(reused);
+            return Err(err);
+        }
+    }
+
+    if !libraries.is_empty() || !reused.is_empty() {
+        let group = Arc::new(DependencyGroup { libraries, reused, reloaded: Mutex::new(Vec::new()) });
+        if !loader.isolated {
+            let mut dependencies = DEPENDENCIES.lock().unwrap();
+            dependencies.retain(|_, group| group.strong_count() > 0);
+            for library in &group.libraries {
+                if let Some(soname) = &library.soname {
+                    dependencies.insert(soname.clone(), Arc::downgrade(&group));
+                }
+            }
+        }
+        root.dependencies = Some(group);
+    }
+    Ok(root)
+}
+
+/// Write a slot, making its page writable for the write if it isn't, e.g. under RELRO
+pub(crate) unsafe fn write_slot(slot: usize, value: usize) -> Result<()> {
+    let address = slot as *const c_void;
+    let protection = region::query(address)?.protection();
+    let size = std::mem::size_of::<usize>();
+    if !protection.contains(Protection::WRITE) {
+        protections::protect(address, size, protection | Protection::WRITE, ProtectionSource::Loader)?;
+    }
+    (*(slot as *const AtomicUsize)).store(value, Ordering::SeqCst);
+    if !protection.contains(Protection::WRITE) {
+        protections::protect(address, size, protection, ProtectionSource::Loader)?;
+    }
+    Ok(())
+}
+
+/// Load the dependency `soname` again from the loader's library paths and rebind the slots
+/// pointing into the old copy, returning how many were
+pub(crate) fn reload(loader: &AndroidLoader, soname: &str) -> Result<usize> {
+    let not_found = || AndroidLoaderErr::DependencyNotFound(soname.to_owned());
+    let group = DEPENDENCIES.lock().unwrap().get(soname).and_then(Weak::upgrade).ok_or_else(not_found)?;
+    let old = registry::by_soname(soname).ok_or_else(not_found)?;
+    let path = loader.library_paths.iter().map(|dir| dir.join(soname)).find(|path| path.is_file()).ok_or_else(not_found)?;
+    info!("Reloading dependency {}", path.display());
+    let library: AndroidLibrary<'static> = load(loader, loader.unwrap_file(fs::read(&path)?)?, None)?;
+    registry::set_path(library.registry_id, &path);
+    if library.soname.as_deref() != Some(soname) {
+        return Err(not_found().into());
+    }
+
+    registry::replace(old, library.registry_id);
+    let mut rebound = 0;
+    for (slot, value, name) in registry::slots_into(old) {
+        let symbols = (registry::library_symbol(old, &name), registry::library_symbol(library.registry_id, &name));
+        match symbols {
+            // Keeps the addend, if any
+            (Some(old_symbol), Some(new_symbol)) => {
+                unsafe { write_slot(slot, value.wrapping_sub(old_symbol).wrapping_add(new_symbol))? };
+                rebound += 1;
+            }
+            _ => debug!("{name} isn't in the reloaded {soname}, leaving it bound to the old copy"),
+        }
+    }
+    group.reloaded.lock().unwrap().push(library);
+    Ok(rebound)
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::android_library::AndroidLibrary;
+    use crate::android_loader::AndroidLoader;
+    use crate::test_elf::TestElf;
+
+    /// A fresh directory holding `libraries`, as (soname, image)
+    fn library_dir(test: &str, libraries: &[(&str, &TestElf)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("android-loader-{test}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (soname, elf) in libraries {
+            std::fs::write(dir.join(soname), elf.build()).unwrap();
+        }
+        dir
+    }
+
+    fn sonames(library: &AndroidLibrary) -> Vec<String> {
+        library.dependencies().map(|dependency| dependency.soname().unwrap().to_owned()).collect()
+    }
+
+    fn call(library: &AndroidLibrary, name: &str) -> u32 {
+        let function: extern "C" fn() -> u32 = unsafe { std::mem::transmute(library.get_symbol(name).unwrap()) };
+        function()
+    }
+
+    #[test]
+    fn diamond_dependency() {
+        let mut d = TestElf::new();
+        d.soname("libdiamond_d.so");
+        d.function("diamond_d", &[0xb8, 4, 0, 0, 0, 0xc3]); // mov eax, 4; ret
+        let mut b = TestElf::new();
+        b.soname("libdiamond_b.so");
+        b.needed("libdiamond_d.so");
+        b.thunk("diamond_b", "diamond_d");
+        let mut c = TestElf::new();
+        c.soname("libdiamond_c.so");
+        c.needed("libdiamond_d.so");
+        c.thunk("diamond_c", "diamond_d");
+        let dir = library_dir("diamond", &[("libdiamond_b.so", &b), ("libdiamond_c.so", &c), ("libdiamond_d.so", &d)]);
+
+        let mut a = TestElf::new();
+        a.needed("libdiamond_b.so");
+        a.needed("libdiamond_c.so");
+        a.needed("libc.so");
+        a.thunk("call_b", "diamond_b");
+        a.thunk("call_c", "diamond_c");
+        let a = AndroidLoader::new().library_path(&dir).load_library_from_bytes(a.build()).unwrap();
+        assert_eq!(sonames(&a), ["libdiamond_b.so", "libdiamond_c.so", "libdiamond_d.so"]);
+        assert_eq!(call(&a, "call_b"), 4);
+        assert_eq!(call(&a, "call_c"), 4);
+        assert_eq!(a.load_stats().resolved_by_library, 2);
+
+        // A later load reuses the loaded copy and keeps it alive
+        let mut e = TestElf::new();
+        e.needed("libdiamond_d.so");
+        e.thunk("call_d", "diamond_d");
+        let e = AndroidLoader::new().library_path(&dir).load_library_from_bytes(e.build()).unwrap();
+        assert_eq!(sonames(&e), Vec::<String>::new());
+        drop(a);
+        assert_eq!(call(&e, "call_d"), 4);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dependency_cycle() {
+        let mut b = TestElf::new();
+        b.soname("libcycle_b.so");
+        b.needed("libcycle_a.so");
+        b.thunk("cycle_b", "cycle_a");
+        let dir = library_dir("cycle", &[("libcycle_b.so", &b)]);
+
+        let mut a = TestElf::new();
+        a.soname("libcycle_a.so");
+        a.needed("libcycle_b.so");
+        a.function("cycle_a", &[0xb8, 7, 0, 0, 0, 0xc3]); // mov eax, 7; ret
+        a.thunk("call_b", "cycle_b");
+        let a = AndroidLoader::new().library_path(&dir).load_library_from_bytes(a.build()).unwrap();
+        assert_eq!(sonames(&a), ["libcycle_b.so"]);
+        assert_eq!(call(&a, "call_b"), 7);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn isolated_instances() {
+        let mut counter = TestElf::new();
+        counter.soname("libisolated_state.so");
+        counter.object("isolated_count", &[0; 4]);
+        let dir = library_dir("isolated", &[("libisolated_state.so", &counter)]);
+
+        let mut root = TestElf::new();
+        root.soname("libisolated_root.so");
+        root.needed("libisolated_state.so");
+        root.object("isolated_own", &[0; 4]);
+        let root = root.build();
+        let shared = AndroidLoader::new().library_path(&dir).load_library_from_bytes(root.clone()).unwrap();
+        let first = AndroidLoader::new().library_path(&dir).isolated().load_library_from_bytes(root.clone()).unwrap();
+        let second = AndroidLoader::new().library_path(&dir).isolated().load_library_from_bytes(root).unwrap();
+        // A shared load finds the first copy again, isolated ones each bring their own
+        let reused = AndroidLoader::new().library_path(&dir).load_library_from_bytes({
+            let mut other = TestElf::new();
+            other.needed("libisolated_state.so");
+            other.build()
+        }).unwrap();
+        assert_eq!(sonames(&shared), ["libisolated_state.so"]);
+        assert_eq!(sonames(&first), ["libisolated_state.so"]);
+        assert_eq!(sonames(&second), ["libisolated_state.so"]);
+        assert_eq!(sonames(&reused), Vec::<String>::new());
+
+        let count = |library: &AndroidLibrary| library.dependencies().next().unwrap().get_symbol("isolated_count").unwrap() as *mut u32;
+        let own = |library: &AndroidLibrary| library.get_symbol("isolated_own").unwrap() as *mut u32;
+        unsafe {
+            *count(&first) = 5;
+            *own(&first) = 6;
+            assert_eq!((*count(&second), *own(&second)), (0, 0));
+            assert_eq!(*count(&shared), 0);
+        }
+        assert_ne!(count(&first), count(&second));
+        assert_ne!(count(&first), count(&shared));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reloaded_dependency() {
+        let mut first = TestElf::new();
+        first.soname("libhot.so");
+        first.function("hot_value", &[0xb8, 1, 0, 0, 0, 0xc3]); // mov eax, 1; ret
+        let dir = library_dir("reload", &[("libhot.so", &first)]);
+
+        let mut root = TestElf::new();
+        root.needed("libhot.so");
+        root.thunk("call_hot", "hot_value");
+        let loader = AndroidLoader::new().library_path(&dir);
+        let root = loader.load_library_from_bytes(root.build()).unwrap();
+        assert_eq!(call(&root, "call_hot"), 1);
+
+        // The slot is on a read-only page, as it would be after RELRO
+        let region = AndroidLoader::owning_library(root.get_symbol("call_hot").unwrap() as usize).unwrap();
+        unsafe { region::protect(region.base as *const u8, region.size, region::Protection::READ_EXECUTE).unwrap() };
+
+        let mut second = TestElf::new();
+        second.soname("libhot.so");
+        second.function("hot_value", &[0xb8, 2, 0, 0, 0, 0xc3]); // mov eax, 2; ret
+        std::fs::write(dir.join("libhot.so"), second.build()).unwrap();
+        assert_eq!(loader.reload_dependency("libhot.so").unwrap(), 1);
+        assert_eq!(call(&root, "call_hot"), 2);
+        assert!(loader.reload_dependency("libnot_loaded.so").is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
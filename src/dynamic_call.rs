@@ -0,0 +1,185 @@
+//! Calling library functions whose signature is only known at runtime, e.g. entry points a
+//! tool discovers, without transmuting to a function pointer type for each one.
+//!
+//! Arguments are integers, pointers and floats, placed where the sysv64 (AAPCS64 on aarch64)
+//! calling convention wants them: integers and pointers in the integer registers, floats in
+//! the vector registers, in order, with whatever doesn't fit on the stack. Structs passed or
+//! returned by value and variadic functions aren't supported.
+
+use anyhow::Result;
+use std::os::raw::c_void;
+
+use crate::android_library::AndroidLoaderErr;
+use crate::sysv64_type;
+
+#[cfg(target_arch = "x86_64")]
+const INTEGER_REGISTERS: usize = 6;
+#[cfg(target_arch = "aarch64")]
+const INTEGER_REGISTERS: usize = 8;
+const FLOAT_REGISTERS: usize = 8;
+/// Arguments [`call`] can pass on the stack, once the registers are used up
+pub const MAX_STACK_ARGUMENTS: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arg {
+    /// Any integer type, sign- or zero-extended by the caller as its type would be
+    Int(i64),
+    Ptr(*const c_void),
+    F32(f32),
+    F64(f64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReturnType {
+    Void,
+    Int,
+    Ptr,
+    F32,
+    F64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    Void,
+    /// The whole return register: narrower integer types should be truncated to theirs
+    Int(i64),
+    Ptr(*const c_void),
+    F32(f32),
+    F64(f64),
+}
+
+// Every argument register and the stack arguments, so the callee finds its own where it looks
+// for them and ignores the rest, which the caller cleans up
+macro_rules! function_type {
+    ($returns:ty) => {
+        #[cfg(target_arch = "x86_64")]
+        type Function = sysv64_type!(fn(
+            usize, usize, usize, usize, usize, usize,
+            f64, f64, f64, f64, f64, f64, f64, f64,
+            usize, usize, usize, usize, usize, usize, usize, usize,
+        ) -> $returns);
+        #[cfg(target_arch = "aarch64")]
+        type Function = sysv64_type!(fn(
+            usize, usize, usize, usize, usize, usize, usize, usize,
+            f64, f64, f64, f64, f64, f64, f64, f64,
+            usize, usize, usize, usize, usize, usize, usize, usize,
+        ) -> $returns);
+    };
+}
+
+/// The argument registers and stack slots of a call
+struct Arguments {
+    integers: [usize; INTEGER_REGISTERS],
+    floats: [f64; FLOAT_REGISTERS],
+    stack: [usize; MAX_STACK_ARGUMENTS],
+}
+
+impl Arguments {
+    fn new(args: &[Arg]) -> Result<Arguments> {
+        let mut arguments = Arguments { integers: [0; INTEGER_REGISTERS], floats: [0.0; FLOAT_REGISTERS], stack: [0; MAX_STACK_ARGUMENTS] };
+        let (mut integers, mut floats, mut stack) = (0, 0, 0);
+        for arg in args {
+            // A float in a register or stack slot is its bits in the low end
+            let (float, bits) = match *arg {
+                Arg::Int(value) => (false, value as usize),
+                Arg::Ptr(pointer) => (false, pointer as usize),
+                Arg::F32(value) => (true, value.to_bits() as usize),
+                Arg::F64(value) => (true, value.to_bits() as usize),
+            };
+            if !float && integers < INTEGER_REGISTERS {
+                arguments.integers[integers] = bits;
+                integers += 1;
+            } else if float && floats < FLOAT_REGISTERS {
+                arguments.floats[floats] = f64::from_bits(bits as u64);
+                floats += 1;
+            } else if stack < MAX_STACK_ARGUMENTS {
+                arguments.stack[stack] = bits;
+                stack += 1;
+            } else {
+                return Err(AndroidLoaderErr::TooManyArguments(args.len()).into());
+            }
+        }
+        Ok(arguments)
+    }
+}
+
+macro_rules! invoke {
+    ($function:expr, $arguments:expr) => {{
+        let (i, f, s) = (&$arguments.integers, &$arguments.floats, &$arguments.stack);
+        #[cfg(target_arch = "x86_64")]
+        let result = $function(i[0], i[1], i[2], i[3], i[4], i[5], f[0], f[1], f[2], f[3], f[4], f[5], f[6], f[7], s[0], s[1], s[2], s[3], s[4], s[5], s[6], s[7]);
+        #[cfg(target_arch = "aarch64")]
+        let result = $function(i[0], i[1], i[2], i[3], i[4], i[5], i[6], i[7], f[0], f[1], f[2], f[3], f[4], f[5], f[6], f[7], s[0], s[1], s[2], s[3], s[4], s[5], s[6], s[7]);
+        result
+    }};
+}
+
+/// Call `function` with `args`, reading its result as `returns`. Fails with
+/// [`AndroidLoaderErr::TooManyArguments`] if they don't fit in the registers and
+/// [`MAX_STACK_ARGUMENTS`] stack slots.
+///
+/// # Safety
+/// `function` must take arguments of the kinds given, in that order, and return `returns`.
+pub unsafe fn call(function: *const (), args: &[Arg], returns: ReturnType) -> Result<Value> {
+    let arguments = Arguments::new(args)?;
+    Ok(match returns {
+        ReturnType::F32 | ReturnType::F64 => {
+            function_type!(f64);
+            let result = invoke!(std::mem::transmute::<*const (), Function>(function), arguments);
+            match returns {
+                ReturnType::F32 => Value::F32(f32::from_bits(result.to_bits() as u32)),
+                _ => Value::F64(result),
+            }
+        }
+        _ => {
+            function_type!(usize);
+            let result = invoke!(std::mem::transmute::<*const (), Function>(function), arguments);
+            match returns {
+                ReturnType::Void => Value::Void,
+                ReturnType::Ptr => Value::Ptr(result as *const c_void),
+                _ => Value::Int(result as i64),
+            }
+        }
+    })
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use crate::android_library::{AndroidLibrary, AndroidLoaderErr};
+    use crate::dynamic_call::{Arg, ReturnType, Value};
+    use crate::test_elf::TestElf;
+
+    #[test]
+    fn marshalled_calls() {
+        let mut elf = TestElf::new();
+        // mov rax, [rsp+8]; add rax, [rsp+16]; sub rax, rdi; ret: the 7th and 8th minus the 1st
+        elf.function("marshal_stack", &[0x48, 0x8b, 0x44, 0x24, 0x08, 0x48, 0x03, 0x44, 0x24, 0x10, 0x48, 0x29, 0xf8, 0xc3]);
+        // cvtsi2sd xmm1, rdi; addsd xmm0, xmm1; cvtsi2sd xmm1, rsi; addsd xmm0, xmm1; ret
+        elf.function("marshal_mixed", &[0xf2, 0x48, 0x0f, 0x2a, 0xcf, 0xf2, 0x0f, 0x58, 0xc1, 0xf2, 0x48, 0x0f, 0x2a, 0xce, 0xf2, 0x0f, 0x58, 0xc1, 0xc3]);
+        // addss xmm0, xmm1; ret
+        elf.function("marshal_f32", &[0xf3, 0x0f, 0x58, 0xc1, 0xc3]);
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+
+        let stack = library.get_symbol("marshal_stack").unwrap();
+        let direct: extern "sysv64" fn(i64, i64, i64, i64, i64, i64, i64, i64) -> i64 = unsafe { std::mem::transmute(stack) };
+        let args: Vec<Arg> = (1..=8).map(|value| Arg::Int(value * 10)).collect();
+        let marshalled = unsafe { library.call("marshal_stack", &args, ReturnType::Int) }.unwrap();
+        assert_eq!(marshalled, Value::Int(direct(10, 20, 30, 40, 50, 60, 70, 80)));
+        assert_eq!(marshalled, Value::Int(140));
+
+        let mixed = library.get_symbol("marshal_mixed").unwrap();
+        let direct: extern "sysv64" fn(i64, f64, i64) -> f64 = unsafe { std::mem::transmute(mixed) };
+        let marshalled = unsafe { library.call("marshal_mixed", &[Arg::Int(2), Arg::F64(0.5), Arg::Int(-7)], ReturnType::F64) }.unwrap();
+        assert_eq!(marshalled, Value::F64(direct(2, 0.5, -7)));
+        assert_eq!(marshalled, Value::F64(-4.5));
+
+        let marshalled = unsafe { library.call("marshal_f32", &[Arg::F32(1.25), Arg::F32(2.5)], ReturnType::F32) }.unwrap();
+        assert_eq!(marshalled, Value::F32(3.75));
+
+        let too_many = vec![Arg::Int(0); 15];
+        let err = unsafe { library.call("marshal_stack", &too_many, ReturnType::Int) }.err().unwrap();
+        assert!(matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::TooManyArguments(15))));
+        let err = unsafe { library.call("marshal_missing", &[], ReturnType::Void) }.err().unwrap();
+        assert!(matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::UnknownSymbol(_))));
+    }
+}
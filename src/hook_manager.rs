@@ -2,8 +2,26 @@ use lazy_static::lazy_static;
 use std::{collections::HashMap, sync::Mutex};
 use std::sync::MutexGuard;
 
+use crate::android_library::{AndroidLibrary, AndroidLoaderErr};
+use crate::registry;
+
+/// Whether a hook can't be code for the architecture. Instructions are 4-byte aligned on
+/// aarch64, while x86 code can start anywhere and odd addresses are Thumb code on arm, so
+/// hooks aren't required to be pointer-aligned elsewhere: some point at data (e.g. `stdout`).
+#[cfg(target_arch = "aarch64")]
+fn misaligned(address: usize) -> bool {
+    address % 4 != 0
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+fn misaligned(_address: usize) -> bool {
+    false
+}
+
 lazy_static! {
     static ref HOOKS: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+    /// Symbols registered as part of the built-in libc
+    static ref GLOBAL_SYMBOLS: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
 }
 
 /// Get the list of hooks
@@ -19,3 +37,78 @@ pub fn add_hooks(hooks: HashMap<String, usize>) {
         global_hooks.insert(key.clone(), *value);
     }
 }
+
+/// Fail on the first (by name) hook whose address is null or misaligned, which would only
+/// crash once the library called it
+pub(crate) fn validate(hooks: &HashMap<String, usize>) -> Result<(), AndroidLoaderErr> {
+    match hooks.iter().filter(|(_, address)| **address == 0 || misaligned(**address)).map(|(name, _)| name).min() {
+        Some(name) => Err(AndroidLoaderErr::InvalidHook { name: name.clone() }),
+        None => Ok(()),
+    }
+}
+
+pub(crate) fn register_global_symbol(symbol_name: &str, address: usize) {
+    GLOBAL_SYMBOLS.lock().unwrap().insert(symbol_name.to_owned(), address);
+}
+
+pub(crate) fn global_symbol(symbol_name: &str) -> Option<usize> {
+    GLOBAL_SYMBOLS.lock().unwrap().get(symbol_name).copied()
+}
+
+/// Resolve a symbol the way it would be if it weren't hooked, so a hook can call through to the
+/// real definition (like `dlsym(RTLD_NEXT, ...)` from a preloaded library): the first loaded
+/// library defining it, then the built-in libc
+pub fn next_symbol(symbol_name: &str) -> Option<*const ()> {
+    registry::next_symbol(None, symbol_name)
+        .map(|symbol| symbol as *const ())
+        .or_else(|| AndroidLibrary::get_libc_symbol(symbol_name))
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::collections::HashMap;
+    use std::os::raw::c_char;
+
+    use crate::android_library::AndroidLibrary;
+    use crate::hook_manager::{add_hooks, next_symbol};
+    use crate::sysv64;
+    use crate::test_elf::TestElf;
+
+    #[sysv64]
+    fn hooked_target() -> u32 {
+        let real: extern "C" fn() -> u32 = unsafe { std::mem::transmute(next_symbol("rtld_hooked_target").unwrap()) };
+        real() + 1
+    }
+
+    #[test]
+    fn rtld_next() {
+        let mut hooks = HashMap::new();
+        hooks.insert("rtld_hooked_target".to_owned(), hooked_target as *const () as usize);
+        add_hooks(hooks);
+
+        let mut first = TestElf::new();
+        first.function("rtld_next_target", &[0xb8, 1, 0, 0, 0, 0xc3]); // mov eax, 1; ret
+        // mov rsi, rdi; mov rdi, -1 (RTLD_NEXT); sub rsp, 8; call dlsym; add rsp, 8; ret
+        first.caller("lookup_next", &[0x48, 0x89, 0xfe, 0x48, 0xc7, 0xc7, 0xff, 0xff, 0xff, 0xff, 0x48, 0x83, 0xec, 0x08], "dlsym", &[0x48, 0x83, 0xc4, 0x08, 0xc3]);
+        first.thunk("call_hooked", "rtld_hooked_target");
+        let first = AndroidLibrary::load_from_bytes(first.build()).unwrap();
+
+        let mut second = TestElf::new();
+        second.function("rtld_next_target", &[0xb8, 7, 0, 0, 0, 0xc3]); // mov eax, 7; ret
+        second.function("rtld_hooked_target", &[0xb8, 7, 0, 0, 0, 0xc3]);
+        let second = AndroidLibrary::load_from_bytes(second.build()).unwrap();
+
+        let lookup_next: extern "C" fn(*const c_char) -> *const () =
+            unsafe { std::mem::transmute(first.get_symbol("lookup_next").unwrap()) };
+        let next = lookup_next(b"rtld_next_target\0".as_ptr() as *const c_char);
+        assert_eq!(Some(next), second.get_symbol("rtld_next_target"));
+        let next: extern "C" fn() -> u32 = unsafe { std::mem::transmute(next) };
+        assert_eq!(next(), 7);
+
+        // Nothing loaded after `first` defines it, so it falls through to the built-in libc
+        assert_eq!(Some(lookup_next(b"toupper\0".as_ptr() as *const c_char)), AndroidLibrary::get_libc_symbol("toupper"));
+
+        let call_hooked: extern "C" fn() -> u32 = unsafe { std::mem::transmute(first.get_symbol("call_hooked").unwrap()) };
+        assert_eq!(call_hooked(), 8);
+    }
+}
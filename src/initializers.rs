@@ -0,0 +1,350 @@
+//! Running a library's initializers (`DT_INIT`, then `DT_INIT_ARRAY`) once it is relocated
+//! and its finalizers (`DT_FINI_ARRAY` backwards, then `DT_FINI`) when it is dropped, if the
+//! loader is set to with [`AndroidLoader::run_initializers`].
+//!
+//! Libraries loaded together are initialized dependencies first and finalized the other way
+//! round. Initializers are called with `argc`, `argv` and `envp`, those of
+//! [`AndroidLoader::program_arguments`] or 0 and null pointers without them; finalizers are
+//! called without arguments.
+//!
+//! With [`AndroidLoader::init_timeout`] each initializer runs on a helper thread instead,
+//! which is abandoned if it doesn't return in time: native code can't be stopped safely, so
+//! it's left running and the libraries it may use are leaked rather than unmapped under it.
+//! Thread-local state the initializer sets up, like `errno` or TLS variables, is then the
+//! helper's and not the loading thread's.
+
+use anyhow::Result;
+use log::{debug, warn};
+use std::ffi::CString;
+use std::mem::size_of;
+use std::os::raw::{c_char, c_int};
+use std::ptr::null;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use crate::android_library::{AndroidLibrary, AndroidLoaderErr};
+use crate::android_loader::AndroidLoader;
+use crate::sysv64_type;
+
+/// Told the index and address of each initializer (or finalizer) about to run, in the order
+/// they run, and decides whether it does
+pub type InitCallback = dyn Fn(usize, *const ()) -> InitAction + Send + Sync;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InitAction {
+    Run,
+    Skip,
+}
+
+/// The arguments and environment handed to initializers, as null-terminated C arrays that
+/// stay put for as long as a library loaded with them is around
+pub struct ProgramArguments {
+    /// Owns what `argv` and `envp` point to
+    #[allow(dead_code)]
+    strings: Vec<CString>,
+    argv: Vec<*const c_char>,
+    envp: Vec<*const c_char>,
+}
+
+// The arrays are never written to once built
+unsafe impl Send for ProgramArguments {}
+unsafe impl Sync for ProgramArguments {}
+
+impl ProgramArguments {
+    /// Panics if a string contains a NUL byte
+    pub(crate) fn new(args: &[String], env: &[String]) -> ProgramArguments {
+        let strings: Vec<CString> = args.iter().chain(env)
+            .map(|string| CString::new(string.as_str()).expect("program argument with a NUL byte"))
+            .collect();
+        let pointers = |strings: &[CString]| strings.iter().map(|string| string.as_ptr()).chain([null()]).collect();
+        ProgramArguments { argv: pointers(&strings[..args.len()]), envp: pointers(&strings[args.len()..]), strings }
+    }
+
+    pub fn argc(&self) -> c_int {
+        (self.argv.len() - 1) as c_int
+    }
+
+    pub fn argv(&self) -> *const *const c_char {
+        self.argv.as_ptr()
+    }
+
+    pub fn envp(&self) -> *const *const c_char {
+        self.envp.as_ptr()
+    }
+}
+
+const DT_INIT: u64 = 12;
+const DT_FINI: u64 = 13;
+const DT_INIT_ARRAY: u64 = 25;
+const DT_FINI_ARRAY: u64 = 26;
+const DT_INIT_ARRAYSZ: u64 = 27;
+const DT_FINI_ARRAYSZ: u64 = 28;
+
+/// The single function of `tag` and the array of `array_tag`, read from the relocated image.
+/// Array entries of 0 and -1 aren't functions and are left out, like bionic does.
+fn functions(library: &AndroidLibrary, tag: u64, array_tag: u64, size_tag: u64) -> (Option<*const ()>, Vec<*const ()>) {
+    let base = library.memory_map.as_ptr() as usize;
+    let value = |wanted| library.dynamic_entries().iter().find(|entry| entry.tag == wanted).map(|entry| entry.value as usize);
+
+    let single = value(tag).filter(|&offset| offset != 0).map(|offset| (base + offset) as *const ());
+    let array = match (value(array_tag), value(size_tag)) {
+        (Some(offset), Some(size)) => (0..size / size_of::<usize>())
+            .map(|index| unsafe { *((base + offset) as *const usize).add(index) })
+            .filter(|&function| function != 0 && function != usize::MAX)
+            .map(|function| function as *const ())
+            .collect(),
+        _ => Vec::new(),
+    };
+    (single, array)
+}
+
+fn run(kind: &str, functions: &[*const ()], callback: Option<&InitCallback>, mut call: impl FnMut(usize, *const ()) -> Result<()>) -> Result<()> {
+    for (index, &function) in functions.iter().enumerate() {
+        if callback.map_or(InitAction::Run, |callback| callback(index, function)) == InitAction::Skip {
+            debug!("Skipping {kind} {index} at {function:p}");
+            continue;
+        }
+        call(index, function)?;
+    }
+    Ok(())
+}
+
+/// Run `call` on a helper thread, giving up on it after `timeout`. A panic is passed on.
+fn call_with_timeout(timeout: Duration, call: impl FnOnce() + Send + 'static) -> bool {
+    let (sender, receiver) = mpsc::channel();
+    let helper = thread::spawn(move || {
+        call();
+        let _ = sender.send(());
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(()) => true,
+        Err(RecvTimeoutError::Timeout) => false,
+        Err(RecvTimeoutError::Disconnected) => match helper.join() {
+            Err(panic) => std::panic::resume_unwind(panic),
+            Ok(()) => true,
+        },
+    }
+}
+
+/// Run the initializers of a relocated library and remember to run its finalizers on drop
+pub(crate) fn initialize(library: &mut AndroidLibrary, loader: &AndroidLoader) -> Result<()> {
+    let (init, array) = functions(library, DT_INIT, DT_INIT_ARRAY, DT_INIT_ARRAYSZ);
+    let initializers: Vec<*const ()> = init.into_iter().chain(array).collect();
+    // As addresses, to be sent to a helper thread
+    let (argc, argv, envp) = match library.program_arguments() {
+        Some(arguments) => (arguments.argc(), arguments.argv() as usize, arguments.envp() as usize),
+        None => (0, 0, 0),
+    };
+    run("initializer", &initializers, loader.on_init.as_deref(), |index, function| {
+        let function: sysv64_type!(fn(c_int, *const *const c_char, *const *const c_char)) = unsafe { std::mem::transmute(function) };
+        let call = move || function(argc, argv as *const *const c_char, envp as *const *const c_char);
+        match loader.init_timeout {
+            Some(timeout) if !call_with_timeout(timeout, call) => {
+                warn!("Initializer {index} at {:p} didn't return within {timeout:?}, abandoning it", function as *const ());
+                Err(AndroidLoaderErr::InitTimeout { index }.into())
+            }
+            Some(_) => Ok(()),
+            None => {
+                call();
+                Ok(())
+            }
+        }
+    })?;
+    library.initialized = true;
+    library.on_fini = loader.on_fini.clone();
+    Ok(())
+}
+
+/// Run the finalizers of an initialized library that's being dropped
+pub(crate) fn finalize(library: &AndroidLibrary) {
+    let (fini, array) = functions(library, DT_FINI, DT_FINI_ARRAY, DT_FINI_ARRAYSZ);
+    let finalizers: Vec<*const ()> = array.into_iter().rev().chain(fini).collect();
+    let _ = run("finalizer", &finalizers, library.on_fini.as_deref(), |_, function| {
+        let function: sysv64_type!(fn()) = unsafe { std::mem::transmute(function) };
+        function();
+        Ok(())
+    });
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use lazy_static::lazy_static;
+    use std::collections::HashMap;
+    use std::ffi::CStr;
+    use std::os::raw::{c_char, c_int};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use crate::android_library::AndroidLoaderErr;
+    use crate::android_loader::AndroidLoader;
+    use crate::hook_manager::add_hooks;
+    use crate::initializers::InitAction;
+    use crate::sysv64;
+    use crate::test_elf::TestElf;
+
+    lazy_static! {
+        static ref RAN: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+        static ref ARGUMENTS: Mutex<Vec<(c_int, String, String)>> = Mutex::new(Vec::new());
+    }
+
+    #[sysv64]
+    unsafe fn record_program_arguments(argc: c_int, argv: *const *const c_char, envp: *const *const c_char) {
+        let first = |array: *const *const c_char| match array.is_null() || (*array).is_null() {
+            true => String::new(),
+            false => CStr::from_ptr(*array).to_string_lossy().into_owned(),
+        };
+        ARGUMENTS.lock().unwrap().push((argc, first(argv), first(envp)));
+    }
+
+    #[sysv64]
+    fn record_initializer(id: u32) {
+        RAN.lock().unwrap().push(id);
+    }
+
+    /// Exports `name` as a function calling `record_initializer(id)`
+    fn recorder(elf: &mut TestElf, name: &str, id: u8) -> u64 {
+        // sub rsp, 8; mov edi, id; call [record_initializer]; add rsp, 8; ret
+        elf.caller(name, &[0x48, 0x83, 0xec, 0x08, 0xbf, id, 0, 0, 0], "record_initializer", &[0x48, 0x83, 0xc4, 0x08, 0xc3])
+    }
+
+    #[test]
+    fn skipped_initializer() {
+        let mut hooks = HashMap::new();
+        hooks.insert("record_initializer".to_owned(), record_initializer as *const () as usize);
+        add_hooks(hooks);
+
+        let mut elf = TestElf::new();
+        let init = recorder(&mut elf, "initializer_0", 0);
+        for (id, name) in [(1, "initializer_1"), (2, "initializer_2"), (3, "initializer_3"), (4, "finalizer_4"), (5, "finalizer_5")] {
+            recorder(&mut elf, name, id);
+        }
+        elf.init(init);
+        elf.init_array(&["initializer_1", "initializer_2", "initializer_3"]);
+        elf.fini_array(&["finalizer_4", "finalizer_5"]);
+
+        // Nothing runs unless asked to
+        drop(AndroidLoader::new().load_library_from_bytes(elf.build()).unwrap());
+        assert!(RAN.lock().unwrap().is_empty());
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_by_callback = seen.clone();
+        let library = AndroidLoader::new()
+            .run_initializers()
+            .on_init(move |index, function| {
+                seen_by_callback.lock().unwrap().push((index, function as usize));
+                if index == 2 { InitAction::Skip } else { InitAction::Run }
+            })
+            .load_library_from_bytes(elf.build())
+            .unwrap();
+        assert_eq!(*RAN.lock().unwrap(), [0, 1, 3]);
+        let expected: Vec<(usize, usize)> = ["initializer_0", "initializer_1", "initializer_2", "initializer_3"].iter()
+            .enumerate()
+            .map(|(index, name)| (index, library.get_symbol(name).unwrap() as usize))
+            .collect();
+        assert_eq!(*seen.lock().unwrap(), expected);
+
+        drop(library);
+        assert_eq!(*RAN.lock().unwrap(), [0, 1, 3, 5, 4]);
+    }
+
+    #[test]
+    fn initializer_arguments() {
+        let mut hooks = HashMap::new();
+        hooks.insert("record_program_arguments".to_owned(), record_program_arguments as *const () as usize);
+        add_hooks(hooks);
+
+        let mut elf = TestElf::new();
+        elf.thunk("arguments_initializer", "record_program_arguments");
+        elf.init_array(&["arguments_initializer"]);
+        AndroidLoader::new().run_initializers().load_library_from_bytes(elf.build()).unwrap();
+        let args = vec!["/data/app/lib/libmain.so".to_owned(), "--verbose".to_owned()];
+        let library = AndroidLoader::new()
+            .run_initializers()
+            .program_arguments(args, vec!["ANDROID_ROOT=/system".to_owned()])
+            .load_library_from_bytes(elf.build())
+            .unwrap();
+
+        assert_eq!(*ARGUMENTS.lock().unwrap(), [
+            (0, String::new(), String::new()),
+            (2, "/data/app/lib/libmain.so".to_owned(), "ANDROID_ROOT=/system".to_owned()),
+        ]);
+        let arguments = library.program_arguments().unwrap();
+        assert!(unsafe { (*arguments.argv().add(2)).is_null() });
+    }
+
+    #[sysv64]
+    fn block_forever() {
+        loop {
+            std::thread::park();
+        }
+    }
+
+    #[test]
+    fn initializer_timeout() {
+        let mut elf = TestElf::new();
+        elf.thunk("blocking_initializer", "block_forever");
+        elf.init_array(&["blocking_initializer"]);
+        let err = AndroidLoader::new()
+            .run_initializers()
+            .init_timeout(Duration::from_millis(50))
+            .hook("block_forever", block_forever as *const () as usize)
+            .load_library_from_bytes(elf.build())
+            .err()
+            .unwrap();
+        assert!(matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::InitTimeout { index: 0 })));
+
+        // Ones returning in time run as usual
+        let mut elf = TestElf::new();
+        elf.function("returning_initializer", &[0xc3]); // ret
+        elf.init_array(&["returning_initializer"]);
+        let library = AndroidLoader::new()
+            .run_initializers()
+            .init_timeout(Duration::from_secs(10))
+            .load_library_from_bytes(elf.build())
+            .unwrap();
+        assert!(library.initialized);
+    }
+
+    #[test]
+    fn reentrant_dlopen() {
+        use std::ffi::CString;
+        use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+        static HANDLE: AtomicUsize = AtomicUsize::new(0);
+        static ANSWER: AtomicU32 = AtomicU32::new(0);
+
+        let mut helper = TestElf::new();
+        helper.function("reentrant_helper", &[0xb8, 42, 0, 0, 0, 0xc3]); // mov eax, 42; ret
+        let path = std::env::temp_dir().join(format!("android-loader-reentrant-{}.so", std::process::id()));
+        std::fs::write(&path, helper.build()).unwrap();
+        let path_arg = CString::new(path.to_str().unwrap()).unwrap();
+        let symbol = CString::new("reentrant_helper").unwrap();
+        let address = |value: usize| value.to_le_bytes();
+
+        let mut elf = TestElf::new();
+        // sub rsp, 8; movabs rdi, path; mov esi, RTLD_NOW; call [dlopen]; movabs [HANDLE], rax; add rsp, 8; ret
+        let open: Vec<u8> = [0x48, 0x83, 0xec, 0x08, 0x48, 0xbf].into_iter()
+            .chain(address(path_arg.as_ptr() as usize))
+            .chain([0xbe, 2, 0, 0, 0])
+            .collect();
+        let stored: Vec<u8> = [0x48, 0xa3].into_iter().chain(address(&HANDLE as *const AtomicUsize as usize)).chain([0x48, 0x83, 0xc4, 0x08, 0xc3]).collect();
+        elf.caller("reentrant_open", &open, "dlopen", &stored);
+        // sub rsp, 8; movabs rax, [HANDLE]; mov rdi, rax; movabs rsi, symbol; call [dlsym]; call rax;
+        // movabs [ANSWER], eax; add rsp, 8; ret
+        let lookup: Vec<u8> = [0x48, 0x83, 0xec, 0x08, 0x48, 0xa1].into_iter()
+            .chain(address(&HANDLE as *const AtomicUsize as usize))
+            .chain([0x48, 0x89, 0xc7, 0x48, 0xbe])
+            .chain(address(symbol.as_ptr() as usize))
+            .collect();
+        let called: Vec<u8> = [0xff, 0xd0, 0xa3].into_iter().chain(address(&ANSWER as *const AtomicU32 as usize)).chain([0x48, 0x83, 0xc4, 0x08, 0xc3]).collect();
+        elf.caller("reentrant_call", &lookup, "dlsym", &called);
+        elf.init_array(&["reentrant_open", "reentrant_call"]);
+
+        let library = AndroidLoader::new().run_initializers().load_library_from_bytes(elf.build()).unwrap();
+        assert!(library.initialized);
+        assert_ne!(HANDLE.load(Ordering::SeqCst), 0);
+        assert_eq!(ANSWER.load(Ordering::SeqCst), 42);
+        std::fs::remove_file(path).unwrap();
+    }
+}
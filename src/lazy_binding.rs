@@ -0,0 +1,321 @@
+//! Binding `JUMP_SLOT` relocations on their first call, with [`AndroidLoader::lazy_binding`].
+//!
+//! Each slot initially points at a stub passing the slot's context, in a scratch register, to
+//! a shared entry point. The entry saves the argument registers, resolves the symbol the way
+//! relocation would have, patches the slot and jumps to the target, so later calls go
+//! straight there. Only x86_64 and aarch64 have the entry point; other targets bind eagerly.
+//!
+//! A symbol nothing provides on its first call is handled like an undefined one. With
+//! [`UndefinedSymbolBehavior::Fault`], the call jumps to an inaccessible byte of the slot's own,
+//! which [`AndroidLibrary::undefined_symbol_at`] maps back to the symbol.
+//!
+//! [`AndroidLoader::lazy_binding`]: crate::android_loader::AndroidLoader::lazy_binding
+
+use anyhow::Result;
+use log::{debug, error, warn};
+use memmap2::{MmapMut, MmapOptions};
+use region::Protection;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::android_library::AndroidLibrary;
+use crate::android_loader::ResolutionStep;
+use crate::dependencies;
+use crate::protections::{self, ProtectionSource};
+use crate::sysv64;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use crate::trampoline;
+use crate::trampoline::TrampolineArena;
+use crate::undefined_symbols::UndefinedSymbolBehavior;
+
+/// Whether this target has the entry point
+pub(crate) const SUPPORTED: bool = cfg!(any(target_arch = "x86_64", target_arch = "aarch64"));
+
+/// What the symbols of one library resolve against, as of its load
+pub(crate) struct LazyScope {
+    pub(crate) hooks: HashMap<String, usize>,
+    pub(crate) scope: Vec<usize>,
+    pub(crate) bionic_stubs: bool,
+    pub(crate) steps: Vec<ResolutionStep>,
+    pub(crate) behavior: UndefinedSymbolBehavior,
+}
+
+struct LazySlot {
+    /// Address of the GOT entry to patch
+    slot: usize,
+    name: String,
+    version: Option<String>,
+    /// Added to the symbol's address, which is only ever non-zero on aarch64
+    addend: usize,
+    scope: Arc<LazyScope>,
+    /// Where a call jumps to if nothing provides the symbol, with [`UndefinedSymbolBehavior::Fault`]
+    fault_address: usize,
+}
+
+/// The stubs of one library's lazily bound slots
+#[cfg_attr(not(any(target_arch = "x86_64", target_arch = "aarch64")), allow(dead_code))]
+pub(crate) struct LazyBindings {
+    scope: Arc<LazyScope>,
+    slots: TrampolineArena<LazySlot>,
+    /// Inaccessible region for [`UndefinedSymbolBehavior::Fault`], one byte per slot
+    fault_region: Option<MmapMut>,
+}
+
+impl LazyBindings {
+    /// Prepare room for up to `capacity` slots
+    pub(crate) fn new(scope: LazyScope, capacity: usize) -> Result<LazyBindings> {
+        let capacity = capacity.max(1);
+        let fault_region = match scope.behavior {
+            UndefinedSymbolBehavior::Fault => {
+                let region = MmapOptions::new().len(capacity).map_anon()?;
+                unsafe { protections::protect(region.as_ptr(), capacity, Protection::NONE, ProtectionSource::Loader)? };
+                Some(region)
+            }
+            _ => None,
+        };
+        Ok(LazyBindings { scope: Arc::new(scope), slots: TrampolineArena::new(capacity)?, fault_region })
+    }
+
+    /// Address the GOT entry at `slot` points at until its first call
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub(crate) fn defer(&mut self, slot: usize, name: &str, version: Option<&str>, addend: usize) -> usize {
+        let index = self.slots.len();
+        let fault_address = self.fault_region.as_ref().map_or(0, |region| region.as_ptr() as usize + index);
+        let lazy = LazySlot {
+            slot,
+            name: name.to_owned(),
+            version: version.map(str::to_owned),
+            addend,
+            scope: self.scope.clone(),
+            fault_address,
+        };
+        self.slots.stub(lazy, |code, context| trampoline::write_preserving(code, android_loader_lazy_entry as *const () as usize, context))
+            .expect("more lazy slots than relocations, or deferred after relocation")
+    }
+
+    /// Make the stubs executable once every relocation is applied
+    pub(crate) fn finish(&mut self) -> Result<()> {
+        self.slots.finish()
+    }
+
+    /// Name of the symbol of the slot a fault address belongs to
+    pub(crate) fn symbol_at(&self, address: usize) -> Option<&str> {
+        let region = self.fault_region.as_ref()?;
+        let index = address.checked_sub(region.as_ptr() as usize)?;
+        self.slots.context(index).map(|slot| slot.name.as_str())
+    }
+}
+
+#[sysv64]
+fn return_zero() -> usize {
+    0
+}
+
+/// Resolve a slot on its first call and return where to jump
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_lazy_resolve(slot: *const LazySlot) -> usize {
+    let slot = &*slot;
+    let scope = &slot.scope;
+    let found = AndroidLibrary::lookup_symbol(&slot.name, slot.version.as_deref(), &scope.hooks, &scope.scope, scope.bionic_stubs, &scope.steps);
+    match (found, &scope.behavior) {
+        (Some((symbol, source)), _) => {
+            let target = symbol.wrapping_add(slot.addend);
+            debug!("Lazily bound {} to {target:#x} ({source:?})", slot.name);
+            // Made writable for the write under RELRO; left to be resolved again if that fails
+            if let Err(err) = dependencies::write_slot(slot.slot, target) {
+                warn!("Couldn't bind {}: {err}", slot.name);
+            }
+            target
+        }
+        (None, UndefinedSymbolBehavior::Panic) => panic!("tried to call an undefined symbol: {}", slot.name),
+        // Left unbound too, jumping there faults at the call
+        (None, UndefinedSymbolBehavior::Fault) => slot.fault_address,
+        (None, UndefinedSymbolBehavior::Abort) => {
+            error!("tried to call an undefined symbol: {}", slot.name);
+            std::process::abort()
+        }
+        // Left unbound, so every call reaches the callback
+        (None, UndefinedSymbolBehavior::Callback(callback)) => {
+            callback(&slot.name);
+            return_zero as *const () as usize
+        }
+    }
+}
+
+// Entered from a stub with the slot in r10 (x86_64) or x16 (aarch64) and the call's arguments
+// untouched, including the vector register count `al` of variadic calls on x86_64
+#[cfg(target_arch = "x86_64")]
+std::arch::global_asm!(
+    ".text",
+    concat!(".globl ", crate::stubs::varargs::asm_symbol!("android_loader_lazy_entry")),
+    concat!(crate::stubs::varargs::asm_symbol!("android_loader_lazy_entry"), ":"),
+    "push rbp",
+    "mov rbp, rsp",
+    "sub rsp, 192",
+    "mov [rsp], rdi",
+    "mov [rsp + 8], rsi",
+    "mov [rsp + 16], rdx",
+    "mov [rsp + 24], rcx",
+    "mov [rsp + 32], r8",
+    "mov [rsp + 40], r9",
+    "mov [rsp + 48], rax",
+    "movaps [rsp + 64], xmm0",
+    "movaps [rsp + 80], xmm1",
+    "movaps [rsp + 96], xmm2",
+    "movaps [rsp + 112], xmm3",
+    "movaps [rsp + 128], xmm4",
+    "movaps [rsp + 144], xmm5",
+    "movaps [rsp + 160], xmm6",
+    "movaps [rsp + 176], xmm7",
+    "mov rdi, r10",
+    concat!("call ", crate::stubs::varargs::asm_symbol!("android_loader_lazy_resolve")),
+    "mov r11, rax",
+    "mov rdi, [rsp]",
+    "mov rsi, [rsp + 8]",
+    "mov rdx, [rsp + 16]",
+    "mov rcx, [rsp + 24]",
+    "mov r8, [rsp + 32]",
+    "mov r9, [rsp + 40]",
+    "mov rax, [rsp + 48]",
+    "movaps xmm0, [rsp + 64]",
+    "movaps xmm1, [rsp + 80]",
+    "movaps xmm2, [rsp + 96]",
+    "movaps xmm3, [rsp + 112]",
+    "movaps xmm4, [rsp + 128]",
+    "movaps xmm5, [rsp + 144]",
+    "movaps xmm6, [rsp + 160]",
+    "movaps xmm7, [rsp + 176]",
+    "mov rsp, rbp",
+    "pop rbp",
+    "jmp r11",
+);
+
+// x8 is saved too, as the indirect result register
+#[cfg(target_arch = "aarch64")]
+std::arch::global_asm!(
+    ".text",
+    concat!(".globl ", crate::stubs::varargs::asm_symbol!("android_loader_lazy_entry")),
+    concat!(crate::stubs::varargs::asm_symbol!("android_loader_lazy_entry"), ":"),
+    "stp x29, x30, [sp, #-16]!",
+    "mov x29, sp",
+    "sub sp, sp, #208",
+    "stp x0, x1, [sp, #0]",
+    "stp x2, x3, [sp, #16]",
+    "stp x4, x5, [sp, #32]",
+    "stp x6, x7, [sp, #48]",
+    "str x8, [sp, #64]",
+    "stp q0, q1, [sp, #80]",
+    "stp q2, q3, [sp, #112]",
+    "stp q4, q5, [sp, #144]",
+    "stp q6, q7, [sp, #176]",
+    "mov x0, x16",
+    concat!("bl ", crate::stubs::varargs::asm_symbol!("android_loader_lazy_resolve")),
+    "mov x16, x0",
+    "ldp x0, x1, [sp, #0]",
+    "ldp x2, x3, [sp, #16]",
+    "ldp x4, x5, [sp, #32]",
+    "ldp x6, x7, [sp, #48]",
+    "ldr x8, [sp, #64]",
+    "ldp q0, q1, [sp, #80]",
+    "ldp q2, q3, [sp, #112]",
+    "ldp q4, q5, [sp, #144]",
+    "ldp q6, q7, [sp, #176]",
+    "mov sp, x29",
+    "ldp x29, x30, [sp], #16",
+    "br x16",
+);
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+extern "C" {
+    fn android_loader_lazy_entry();
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::collections::HashMap;
+    use std::process::Command;
+
+    use crate::android_loader::{AndroidLoader, DEFAULT_RESOLUTION_ORDER};
+    use crate::lazy_binding::{android_loader_lazy_resolve, LazyBindings, LazyScope};
+    use crate::sysv64;
+    use crate::test_elf::{TestElf, R_X86_64_JUMP_SLOT};
+    use crate::undefined_symbols::UndefinedSymbolBehavior;
+
+    const CHILD_MODE: &str = "ANDROID_LOADER_TEST_LAZY_FAULT";
+
+    #[sysv64]
+    fn late_target(a: u64, b: u64, c: f64) -> u64 {
+        a * 100 + b * 10 + c as u64
+    }
+
+    #[test]
+    fn bound_on_first_call() {
+        let mut elf = TestElf::new();
+        elf.thunk("call_lazy_late", "lazy_late_target");
+        elf.thunk("call_lazy_never", "lazy_never_called");
+        let library = AndroidLoader::new().lazy_binding().load_library_from_bytes(elf.build()).unwrap();
+        let stats = library.load_stats();
+        assert_eq!(stats.relocations.get(&R_X86_64_JUMP_SLOT), Some(&2));
+        assert_eq!(stats.lazily_bound, 2);
+        // Nothing was resolved up front, not even the symbol nothing provides
+        assert_eq!(stats.resolved_by_libc + stats.resolved_by_hook + stats.undefined, 0);
+
+        // Only defined after the load, so found by the first call
+        AndroidLoader::register_global_symbol("lazy_late_target", late_target as *const () as usize);
+        let call: extern "C" fn(u64, u64, f64) -> u64 = unsafe { std::mem::transmute(library.get_symbol("call_lazy_late").unwrap()) };
+        assert_eq!(call(1, 2, 3.0), 123);
+        assert_eq!(call(4, 5, 6.0), 456);
+    }
+
+    #[test]
+    fn fault_mode_addresses() {
+        let scope = LazyScope {
+            hooks: HashMap::new(),
+            scope: Vec::new(),
+            bionic_stubs: false,
+            steps: DEFAULT_RESOLUTION_ORDER.to_vec(),
+            behavior: UndefinedSymbolBehavior::Fault,
+        };
+        let mut bindings = LazyBindings::new(scope, 2).unwrap();
+        let slot = 0usize;
+        bindings.defer(&slot as *const usize as usize, "lazy_fault_missing", None, 0);
+        bindings.finish().unwrap();
+
+        let address = unsafe { android_loader_lazy_resolve(bindings.slots.context(0).unwrap()) };
+        assert_eq!(bindings.symbol_at(address), Some("lazy_fault_missing"));
+        assert_eq!(bindings.symbol_at(address + 1), None);
+        // Left unbound, as with the other behaviors that don't bind
+        assert_eq!(slot, 0);
+    }
+
+    /// Runs in a child process spawned by `faulting_call`, calls the missing symbol and dies
+    #[test]
+    fn lazy_fault_child() {
+        if std::env::var(CHILD_MODE).is_err() {
+            return;
+        }
+        let mut elf = TestElf::new();
+        elf.thunk("call_lazy_missing", "lazy_fault_missing");
+        let library = AndroidLoader::new()
+            .lazy_binding()
+            .on_undefined_symbol(UndefinedSymbolBehavior::Fault)
+            .load_library_from_bytes(elf.build())
+            .unwrap();
+        let call: extern "C" fn() = unsafe { std::mem::transmute(library.get_symbol("call_lazy_missing").unwrap()) };
+        call();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn faulting_call() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "lazy_binding::tests::lazy_fault_child", "--nocapture", "--test-threads=1"])
+            .env(CHILD_MODE, "1")
+            .output()
+            .unwrap();
+        assert_eq!(output.status.signal(), Some(libc::SIGSEGV));
+    }
+}
@@ -2,8 +2,37 @@ extern crate core;
 
 pub mod android_library;
 pub mod android_loader;
+#[cfg(feature = "apk")]
+pub mod apk;
+pub mod call_trace;
+mod caller;
+#[cfg(feature = "demangle")]
+mod demangle;
+mod dependencies;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub mod dynamic_call;
 pub mod hook_manager;
+pub mod initializers;
+mod lazy_binding;
+pub mod library_info;
+pub mod mapping_pool;
+pub mod protections;
+mod registry;
 mod relocation_types;
+#[cfg(all(feature = "seccomp", target_os = "linux"))]
+pub mod seccomp;
+mod sha256;
+pub mod stats;
+pub mod stubs;
+pub mod tls;
+mod trampoline;
+pub mod undefined_symbols;
+mod versions;
+pub mod vfs;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub mod write_watch;
+#[cfg(all(test, target_arch = "x86_64"))]
+mod test_elf;
 
 pub use sysv64::sysv64;
 
@@ -33,21 +62,21 @@ mod tests {
     #[test]
     fn load_android_libraries() {
         let mut hooks = HashMap::new();
-        hooks.insert("arc4random".to_owned(), arc4random as usize);
-        hooks.insert("chmod".to_owned(), chmod as usize);
-        hooks.insert("close".to_owned(), close as usize);
-        hooks.insert("free".to_owned(), free as usize);
-        hooks.insert("fstat".to_owned(), fstat as usize);
-        hooks.insert("ftruncate".to_owned(), ftruncate as usize);
-        hooks.insert("gettimeofday".to_owned(), gettimeofday as usize);
-        hooks.insert("lstat".to_owned(), lstat as usize);
-        hooks.insert("malloc".to_owned(), malloc as usize);
-        hooks.insert("mkdir".to_owned(), mkdir as usize);
-        hooks.insert("open".to_owned(), open as usize);
-        hooks.insert("read".to_owned(), read as usize);
-        hooks.insert("strncpy".to_owned(), strncpy as usize);
-        hooks.insert("umask".to_owned(), umask as usize);
-        hooks.insert("write".to_owned(), write as usize);
+        hooks.insert("arc4random".to_owned(), arc4random as *const () as usize);
+        hooks.insert("chmod".to_owned(), chmod as *const () as usize);
+        hooks.insert("close".to_owned(), close as *const () as usize);
+        hooks.insert("free".to_owned(), free as *const () as usize);
+        hooks.insert("fstat".to_owned(), fstat as *const () as usize);
+        hooks.insert("ftruncate".to_owned(), ftruncate as *const () as usize);
+        hooks.insert("gettimeofday".to_owned(), gettimeofday as *const () as usize);
+        hooks.insert("lstat".to_owned(), lstat as *const () as usize);
+        hooks.insert("malloc".to_owned(), malloc as *const () as usize);
+        hooks.insert("mkdir".to_owned(), mkdir as *const () as usize);
+        hooks.insert("open".to_owned(), open as *const () as usize);
+        hooks.insert("read".to_owned(), read as *const () as usize);
+        hooks.insert("strncpy".to_owned(), strncpy as *const () as usize);
+        hooks.insert("umask".to_owned(), umask as *const () as usize);
+        hooks.insert("write".to_owned(), write as *const () as usize);
         crate::hook_manager::add_hooks(hooks);
 
         let store_services_core =
@@ -0,0 +1,473 @@
+//! Metadata read from a library without mapping or running any of it.
+
+use anyhow::Result;
+use std::collections::HashMap;
+use xmas_elf::dynamic::Tag;
+use xmas_elf::ElfFile;
+use xmas_elf::program;
+use xmas_elf::sections::{SectionData, SectionHeader, ShType, SHN_XINDEX};
+use xmas_elf::symbol_table::{Entry, Type};
+use zero::read_str;
+
+use crate::android_library::{AndroidLibrary, AndroidLoaderErr, DynEntry};
+use crate::android_loader::AndroidLoader;
+use crate::stats::SymbolSource;
+use crate::versions;
+
+/// A symbol the library defines
+#[derive(Clone, Debug, PartialEq)]
+pub struct SymbolInfo {
+    pub name: String,
+    /// Offset from the load base
+    pub value: u64,
+    pub size: u64,
+    pub function: bool,
+    /// Index of the section defining it, read from the `SHT_SYMTAB_SHNDX` table for the
+    /// symbols of libraries with too many sections for `st_shndx`. Special indices like
+    /// `SHN_ABS` are kept as they are.
+    pub section: u32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LibraryInfo {
+    /// `DT_SONAME`
+    pub soname: Option<String>,
+    /// `DT_NEEDED` entries, in order
+    pub needed: Vec<String>,
+    /// Defined dynamic symbols
+    pub symbols: Vec<SymbolInfo>,
+    /// Names of undefined dynamic symbols
+    pub imports: Vec<String>,
+    /// Relocations the library has, by relocation type number
+    pub relocations: HashMap<u32, usize>,
+}
+
+/// How a relocation would be applied, see [`AndroidLoader::plan_relocations`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelocationPlan {
+    /// Offset of the relocated field from the load base
+    pub offset: u64,
+    /// Relocation type number
+    pub rtype: u32,
+    /// `None` for relocations without a symbol, like `RELATIVE` ones
+    pub symbol: Option<String>,
+    pub source: Option<SymbolSource>,
+    /// Address the symbol resolves to. For [`SymbolSource::Library`], which only means the
+    /// library itself here, it's an offset from the load base instead. `None` when undefined.
+    pub address: Option<usize>,
+}
+
+/// A relocation as read from the library's tables, which
+/// [`AndroidLoader::transform_relocations`] may change before it's applied.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelocationEntry {
+    /// Offset of the relocated field from the load base
+    pub offset: u64,
+    /// Relocation type number
+    pub rtype: u32,
+    /// Index of the symbol in the dynamic symbol table, 0 for relocations without one
+    pub symbol_index: u32,
+    /// `r_addend` of a RELA relocation. `None` for REL ones, whose addend is read from the
+    /// relocated field.
+    pub addend: Option<i64>,
+}
+
+/// A program header as in the file
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProgramHeader {
+    /// `p_type`, e.g. 1 for `PT_LOAD` and 2 for `PT_DYNAMIC`
+    pub kind: u32,
+    /// `p_flags`: 1 for execute, 2 for write, 4 for read
+    pub flags: u32,
+    pub offset: u64,
+    /// Relative to the load base
+    pub virtual_addr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub align: u64,
+}
+
+/// An entry of the `.dynamic` section, before the terminating `DT_NULL`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DynamicEntry {
+    pub tag: u64,
+    /// Address entries are relative to the load base
+    pub value: u64,
+}
+
+pub(crate) fn program_headers(elf_file: &ElfFile, file: &[u8]) -> Vec<ProgramHeader> {
+    let table = elf_file.header.pt2.ph_offset() as usize;
+    let entry_size = elf_file.header.pt2.ph_entry_size() as usize;
+    elf_file.program_iter()
+        .enumerate()
+        .map(|(index, header)| {
+            // xmas-elf only hands out the type decoded
+            let kind = file.get(table + index * entry_size..table + index * entry_size + 4)
+                .map_or(0, |kind| u32::from_ne_bytes(kind.try_into().unwrap()));
+            ProgramHeader {
+                kind,
+                flags: header.flags().0,
+                offset: header.offset(),
+                virtual_addr: header.virtual_addr(),
+                file_size: header.file_size(),
+                mem_size: header.mem_size(),
+                align: header.align(),
+            }
+        })
+        .collect()
+}
+
+/// Entries of a `.dynamic` section, read as pairs of native words since xmas-elf only hands
+/// out decoded tags
+pub(crate) fn dynamic_entries(elf_file: &ElfFile, section: SectionHeader) -> Vec<DynamicEntry> {
+    const WORD: usize = std::mem::size_of::<usize>();
+    section.raw_data(elf_file)
+        .chunks_exact(2 * WORD)
+        .map(|entry| DynamicEntry {
+            tag: usize::from_ne_bytes(entry[..WORD].try_into().unwrap()) as u64,
+            value: usize::from_ne_bytes(entry[WORD..].try_into().unwrap()) as u64,
+        })
+        .take_while(|entry| entry.tag != 0)
+        .collect()
+}
+
+/// The descriptor of the `NT_GNU_BUILD_ID` note, in a `SHT_NOTE` section or, for libraries
+/// without sections, a `PT_NOTE` segment
+pub(crate) fn build_id(elf_file: &ElfFile) -> Option<Vec<u8>> {
+    const NT_GNU_BUILD_ID: u32 = 3;
+    let sections = elf_file.section_iter()
+        .filter(|section| section.get_type() == Ok(ShType::Note))
+        .map(|section| (section.offset(), section.size()));
+    let segments = elf_file.program_iter()
+        .filter(|header| header.get_type() == Ok(program::Type::Note))
+        .map(|header| (header.offset(), header.file_size()));
+    sections.chain(segments)
+        .filter_map(|(offset, size)| elf_file.input.get(offset as usize..offset.checked_add(size)? as usize))
+        .find_map(|notes| {
+            let mut notes = notes;
+            // namesz, descsz and type, then the name and descriptor, each padded to 4 bytes
+            while notes.len() >= 12 {
+                let word = |at: usize| u32::from_ne_bytes(notes[at..at + 4].try_into().unwrap()) as usize;
+                let (name_size, desc_size, kind) = (word(0), word(4), word(8) as u32);
+                let desc = align4(name_size)?.checked_add(12)?;
+                let next = desc.checked_add(align4(desc_size)?).filter(|next| *next <= notes.len())?;
+                if kind == NT_GNU_BUILD_ID && notes[12..12 + name_size] == *b"GNU\0" {
+                    return Some(notes[desc..desc + desc_size].to_vec());
+                }
+                notes = &notes[next..];
+            }
+            None
+        })
+}
+
+fn align4(size: usize) -> Option<usize> {
+    size.checked_add(3).map(|size| size & !3)
+}
+
+/// `DT_SONAME` and the `DT_NEEDED` entries of a `.dynamic` section
+pub(crate) fn dynamic_strings(elf_file: &ElfFile, section: SectionHeader, dyn_strings: &[u8]) -> Result<(Option<String>, Vec<String>)> {
+    let parsing_error = |err: &str| AndroidLoaderErr::ElfParsingError(err.to_string());
+    let string = |offset: usize| dyn_strings.get(offset..).map(|strings| read_str(strings).to_owned());
+    let (mut soname, mut needed) = (None, Vec::new());
+    match section.get_data(elf_file).map_err(parsing_error)? {
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        SectionData::Dynamic64(entries) => for entry in entries {
+            match entry.get_tag() {
+                Ok(Tag::Needed) => needed.extend(string(entry.get_val().map_err(parsing_error)? as usize)),
+                Ok(Tag::SoName) => soname = string(entry.get_val().map_err(parsing_error)? as usize),
+                _ => {}
+            }
+        },
+        #[cfg(any(target_arch = "x86", target_arch = "arm"))]
+        SectionData::Dynamic32(entries) => for entry in entries {
+            match entry.get_tag() {
+                Ok(Tag::Needed) => needed.extend(string(entry.get_val().map_err(parsing_error)? as usize)),
+                Ok(Tag::SoName) => soname = string(entry.get_val().map_err(parsing_error)? as usize),
+                _ => {}
+            }
+        },
+        _ => {}
+    }
+    Ok((soname, needed))
+}
+
+/// Resolve every relocation's symbol like loading `file` would, with `hooks` taking precedence
+/// over the registered ones, without mapping or registering anything
+pub(crate) fn plan_relocations(loader: &AndroidLoader, file: &[u8], hooks: &HashMap<String, usize>) -> Result<Vec<RelocationPlan>> {
+    let elf_file = AndroidLibrary::parse_elf(file)?;
+    let parsing_error = |err: &str| AndroidLoaderErr::ElfParsingError(err.to_string());
+    let mut dyn_symbols: &[DynEntry] = &[];
+    let mut dyn_strings: &[u8] = &[];
+    let mut extended_indices: &[u32] = &[];
+    let (mut versym, mut verdef, mut verneed) = (None, None, None);
+    for (index, section) in elf_file.section_iter().enumerate() {
+        match section.get_type() {
+            Ok(ShType::OsSpecific(versions::SHT_GNU_VERSYM)) => versym = Some(section.raw_data(&elf_file)),
+            Ok(ShType::OsSpecific(versions::SHT_GNU_VERDEF)) => verdef = Some(section.raw_data(&elf_file)),
+            Ok(ShType::OsSpecific(versions::SHT_GNU_VERNEED)) => verneed = Some(section.raw_data(&elf_file)),
+            Ok(ShType::DynSym) => {
+                dyn_strings = AndroidLibrary::linked_strings(&elf_file, section)?;
+                extended_indices = AndroidLibrary::extended_section_indices(&elf_file, index)?;
+                dyn_symbols = match section.get_data(&elf_file).map_err(parsing_error)? {
+                    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+                    SectionData::DynSymbolTable64(entries) => entries,
+                    #[cfg(any(target_arch = "x86", target_arch = "arm"))]
+                    SectionData::DynSymbolTable32(entries) => entries,
+                    _ => return Err(parsing_error("Unsupported Dynamic symbol table data").into()),
+                };
+            }
+            _ => {}
+        }
+    }
+
+    let mut symbol_names: Vec<String> = dyn_symbols.iter()
+        .map(|symbol| loader.symbol_name(dyn_strings, symbol.name() as usize))
+        .collect();
+    if let Some(rewriter) = &loader.symbol_rewriter {
+        rewriter(&mut symbol_names);
+    }
+    let symbol_versions = versions::symbol_versions(versym, verdef, verneed, dyn_strings);
+    let mut all_hooks = loader.hooks();
+    all_hooks.extend(hooks.iter().map(|(name, address)| (name.clone(), *address)));
+
+    let resolve = |index: usize| -> (Option<SymbolSource>, Option<usize>) {
+        let symbol = &dyn_symbols[index];
+        let name = &symbol_names[index];
+        let defined = AndroidLibrary::defined(symbol, index, extended_indices);
+        if all_hooks.contains_key(name) || !defined {
+            let version = symbol_versions.get(index).and_then(Option::as_ref).filter(|_| !defined);
+            match AndroidLibrary::lookup_symbol(name, version.map(|version| version.name.as_str()), &all_hooks, &[], loader.bionic_stubs, loader.resolution_steps()) {
+                Some((address, source)) => (Some(source), Some(address)),
+                None => (Some(SymbolSource::Undefined), None),
+            }
+        } else {
+            (Some(SymbolSource::Library), Some(symbol.value() as usize))
+        }
+    };
+
+    let mut plan = Vec::new();
+    let mut push = |offset: u64, rtype: u32, index: usize| {
+        let (source, address) = if index == 0 { (None, None) } else { resolve(index) };
+        let symbol = if index == 0 { None } else { symbol_names.get(index).cloned() };
+        plan.push(RelocationPlan { offset, rtype, symbol, source, address });
+    };
+    for section in elf_file.section_iter() {
+        match section.get_data(&elf_file) {
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            Ok(SectionData::Rela64(relocations)) => for relocation in relocations {
+                push(relocation.get_offset(), relocation.get_type(), relocation.get_symbol_table_index() as usize);
+            },
+            #[cfg(any(target_arch = "x86", target_arch = "arm"))]
+            Ok(SectionData::Rel32(relocations)) => for relocation in relocations {
+                push(relocation.get_offset() as u64, u32::from(relocation.get_type()), relocation.get_symbol_table_index() as usize);
+            },
+            _ => {}
+        }
+    }
+    Ok(plan)
+}
+
+impl LibraryInfo {
+    pub(crate) fn parse(loader: &AndroidLoader, file: &[u8]) -> Result<LibraryInfo> {
+        let elf_file = AndroidLibrary::parse_elf(file)?;
+        let parsing_error = |err: &str| AndroidLoaderErr::ElfParsingError(err.to_string());
+        let mut info = LibraryInfo::default();
+        let mut dyn_symbols: &[DynEntry] = &[];
+        let mut dyn_strings: &[u8] = &[];
+        let mut dynamic_section = None;
+        let mut extended_indices: &[u32] = &[];
+
+        for (index, section) in elf_file.section_iter().enumerate() {
+            match section.get_type() {
+                Ok(ShType::DynSym) => {
+                    dyn_strings = AndroidLibrary::linked_strings(&elf_file, section)?;
+                    extended_indices = AndroidLibrary::extended_section_indices(&elf_file, index)?;
+                    dyn_symbols = match section.get_data(&elf_file).map_err(parsing_error)? {
+                        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+                        SectionData::DynSymbolTable64(entries) => entries,
+                        #[cfg(any(target_arch = "x86", target_arch = "arm"))]
+                        SectionData::DynSymbolTable32(entries) => entries,
+                        _ => return Err(parsing_error("Unsupported Dynamic symbol table data").into()),
+                    };
+                }
+                Ok(ShType::Dynamic) => dynamic_section = Some(section),
+                Ok(ShType::Rel) | Ok(ShType::Rela) => match section.get_data(&elf_file).map_err(parsing_error)? {
+                    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+                    SectionData::Rela64(relocations) => for relocation in relocations {
+                        *info.relocations.entry(relocation.get_type()).or_insert(0) += 1;
+                    },
+                    #[cfg(any(target_arch = "x86", target_arch = "arm"))]
+                    SectionData::Rel32(relocations) => for relocation in relocations {
+                        *info.relocations.entry(u32::from(relocation.get_type())).or_insert(0) += 1;
+                    },
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+
+        if let Some(section) = dynamic_section {
+            (info.soname, info.needed) = dynamic_strings(&elf_file, section, dyn_strings)?;
+        }
+
+        // The first entry is the null symbol
+        for (index, symbol) in dyn_symbols.iter().enumerate().skip(1) {
+            let name = loader.symbol_name(dyn_strings, symbol.name() as usize);
+            if !AndroidLibrary::defined(symbol, index, extended_indices) {
+                info.imports.push(name);
+            } else {
+                let section = match symbol.shndx() {
+                    SHN_XINDEX => extended_indices.get(index).copied().unwrap_or(u32::from(SHN_XINDEX)),
+                    section => u32::from(section),
+                };
+                info.symbols.push(SymbolInfo {
+                    name,
+                    value: symbol.value(),
+                    size: symbol.size(),
+                    function: symbol.get_type() == Ok(Type::Func),
+                    section,
+                });
+            }
+        }
+        Ok(info)
+    }
+
+    pub fn total_relocations(&self) -> usize {
+        self.relocations.values().sum()
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::android_loader::AndroidLoader;
+    use crate::hook_manager::add_hooks;
+    use crate::stats::SymbolSource;
+    use crate::test_elf::{TestElf, R_X86_64_64, R_X86_64_JUMP_SLOT, R_X86_64_RELATIVE};
+
+    #[test]
+    fn gnu_build_id() {
+        let id = [0x8f, 0x3a, 0x1c, 0x52, 0x07, 0xd4, 0x6e, 0x91, 0xb2, 0x40, 0x5d, 0xe8, 0x13, 0x77, 0xc6, 0x0a, 0x29, 0xf1, 0x84, 0x6b];
+        let mut elf = TestElf::new();
+        elf.build_id(&id);
+        let library = AndroidLoader::new().load_library_from_bytes(elf.build()).unwrap();
+        // readelf -n: Build ID: 8f3a1c5207d46e91b2405de81377c60a29f1846b
+        let hex: String = library.build_id().unwrap().iter().map(|byte| format!("{byte:02x}")).collect();
+        assert_eq!(hex, "8f3a1c5207d46e91b2405de81377c60a29f1846b");
+
+        let library = AndroidLoader::new().load_library_from_bytes(TestElf::new().build()).unwrap();
+        assert_eq!(library.build_id(), None);
+    }
+
+    #[test]
+    fn extended_section_indices() {
+        let mut elf = TestElf::new();
+        elf.function("xindex_answer", &[0xb8, 42, 0, 0, 0, 0xc3]); // mov eax, 42; ret
+        elf.thunk("xindex_call", "xindex_missing");
+        elf.object("xindex_cell", &[0; 8]);
+        elf.extended_section_indices();
+        let elf = elf.build();
+
+        let info = AndroidLoader::new().inspect_bytes(elf.clone()).unwrap();
+        assert_eq!(info.imports, ["xindex_missing"]);
+        let sections: Vec<(&str, u32)> = info.symbols.iter().map(|symbol| (symbol.name.as_str(), symbol.section)).collect();
+        assert_eq!(sections, [("xindex_answer", 4), ("xindex_call", 4), ("xindex_cell", 5)]);
+
+        let library = AndroidLoader::new().load_library_from_bytes(elf).unwrap();
+        assert_eq!((library.load_stats().undefined, library.load_stats().resolved_by_library), (1, 0));
+        let answer = library.get_symbol("xindex_answer").unwrap();
+        let (_, name, offset) = AndroidLoader::symbolize(answer as usize + 2).unwrap();
+        assert_eq!((name.as_str(), offset), ("xindex_answer", 2));
+    }
+
+    #[test]
+    fn inspect_matches_load() {
+        let mut elf = TestElf::new();
+        elf.soname("libinspect.so");
+        elf.needed("liblog.so");
+        elf.needed("libc.so");
+        elf.function("inspect_answer", &[0xb8, 42, 0, 0, 0, 0xc3]); // mov eax, 42; ret
+        elf.thunk("inspect_call", "inspect_missing");
+        let cells = elf.object("inspect_cells", &[0; 16]);
+        elf.relocation(cells, R_X86_64_RELATIVE, None, 0);
+        elf.relocation(cells + 8, R_X86_64_RELATIVE, None, 0);
+        let elf = elf.build();
+
+        let info = AndroidLoader::new().inspect_bytes(elf.clone()).unwrap();
+        assert_eq!(info.soname.as_deref(), Some("libinspect.so"));
+        assert_eq!(info.needed, ["liblog.so", "libc.so"]);
+        assert_eq!(info.imports, ["inspect_missing"]);
+        assert_eq!(info.relocations[&R_X86_64_RELATIVE], 2);
+        assert_eq!(info.relocations[&R_X86_64_JUMP_SLOT], 1);
+        assert_eq!(info.total_relocations(), 3);
+
+        let names: Vec<&str> = info.symbols.iter().map(|symbol| symbol.name.as_str()).collect();
+        assert_eq!(names, ["inspect_answer", "inspect_call", "inspect_cells"]);
+        assert!(info.symbols[0].function);
+        assert!(!info.symbols[2].function);
+
+        let library = AndroidLoader::new().load_library_from_bytes(elf).unwrap();
+        assert_eq!(library.load_stats().total_relocations(), info.total_relocations());
+        let base = library.get_symbol("inspect_answer").unwrap() as u64 - info.symbols[0].value;
+        for symbol in &info.symbols {
+            assert_eq!(library.get_symbol(&symbol.name).map(|address| address as u64), Some(base + symbol.value));
+        }
+    }
+
+    #[test]
+    fn layout_accessors() {
+        let mut elf = TestElf::new();
+        elf.soname("liblayout.so");
+        elf.needed("liblog.so");
+        elf.gnu_stack(false);
+        let library = AndroidLoader::new().load_library_from_bytes(elf.build()).unwrap();
+
+        let headers = library.program_headers();
+        assert_eq!(headers.iter().filter(|header| header.kind == 1).count(), 1);
+        assert_eq!(headers[0].flags, 7);
+        assert_eq!(headers[1].kind, 0x6474_e551);
+
+        let tags: Vec<u64> = library.dynamic_entries().iter().map(|entry| entry.tag).collect();
+        assert_eq!(tags, [14, 1]);
+    }
+
+    #[test]
+    fn plan_matches_load() {
+        let mut elf = TestElf::new();
+        elf.thunk("plan_call_hooked", "plan_hooked");
+        elf.thunk("plan_call_strlen", "strlen");
+        elf.thunk("plan_call_missing", "plan_missing");
+        elf.thunk("plan_call_registered", "plan_registered");
+        let cells = elf.object("plan_cells", &[0; 16]);
+        elf.relocation(cells, R_X86_64_64, Some("plan_cells"), 0);
+        elf.relocation(cells + 8, R_X86_64_RELATIVE, None, 0);
+        let elf = elf.build();
+
+        let mut hooks = HashMap::new();
+        hooks.insert("plan_hooked".to_owned(), 0x1234);
+        AndroidLoader::register_global_symbol("plan_registered", 0x5678);
+        let plan = AndroidLoader::new().plan_relocations_from_bytes(elf.clone(), &hooks).unwrap();
+        let sources: Vec<(Option<&str>, Option<SymbolSource>)> = plan.iter().map(|entry| (entry.symbol.as_deref(), entry.source)).collect();
+        assert_eq!(sources, [
+            (Some("plan_hooked"), Some(SymbolSource::Hook)),
+            (Some("strlen"), Some(SymbolSource::Libc)),
+            (Some("plan_missing"), Some(SymbolSource::Undefined)),
+            (Some("plan_registered"), Some(SymbolSource::Registered)),
+            (Some("plan_cells"), Some(SymbolSource::Library)),
+            (None, None),
+        ]);
+
+        add_hooks(hooks);
+        let library = AndroidLoader::new().load_library_from_bytes(elf).unwrap();
+        let base = library.memory_map.as_ptr() as usize;
+        for entry in &plan {
+            let written = unsafe { *((base + entry.offset as usize) as *const usize) };
+            match (entry.source, entry.address) {
+                (Some(SymbolSource::Library), Some(offset)) => assert_eq!(written, base + offset),
+                (Some(_), Some(address)) => assert_eq!(written, address),
+                _ => {}
+            }
+        }
+    }
+}
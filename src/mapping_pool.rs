@@ -0,0 +1,167 @@
+//! Reusing the memory of dropped libraries for the next loads.
+//!
+//! Mapping an image and unmapping it once the library is dropped is two syscalls, which adds
+//! up when loading thousands of small libraries. A [`MappingPool`] given to [`AndroidLoader::mapping_pool`](crate::android_loader::AndroidLoader::mapping_pool)
+//! keeps the mappings of the libraries it mapped when they're dropped, made read-write and
+//! zeroed again, and maps the next libraries of a similar size in them. On Linux zeroing
+//! hands the pages back to the kernel rather than writing every byte.
+
+use memmap2::{MmapMut, MmapOptions};
+use region::Protection;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::protections::{self, ProtectionSource};
+
+/// Mappings kept of each size class unless told otherwise
+pub const DEFAULT_MAPPINGS_PER_CLASS: usize = 16;
+
+/// Freed mappings by size class: their number of pages, a power of two
+pub struct MappingPool {
+    free: Mutex<HashMap<usize, Vec<MmapMut>>>,
+    per_class: usize,
+}
+
+impl Default for MappingPool {
+    fn default() -> MappingPool {
+        MappingPool::new(DEFAULT_MAPPINGS_PER_CLASS)
+    }
+}
+
+impl MappingPool {
+    /// A pool keeping up to `per_class` freed mappings of each size class, unmapping the others
+    pub fn new(per_class: usize) -> MappingPool {
+        MappingPool { free: Mutex::new(HashMap::new()), per_class }
+    }
+
+    /// The size of the mappings images of `size` bytes are mapped in
+    fn class_size(size: usize) -> usize {
+        let page = region::page::size();
+        let pages = ((size + page - 1) / page).max(1);
+        pages.next_power_of_two() * page
+    }
+
+    /// A zeroed, read-write mapping of at least `size` bytes, reused if one was freed
+    pub(crate) fn take(&self, size: usize) -> std::io::Result<MmapMut> {
+        let class_size = MappingPool::class_size(size);
+        match self.free.lock().unwrap().get_mut(&class_size).and_then(Vec::pop) {
+            Some(mapping) => Ok(mapping),
+            None => MmapOptions::new().len(class_size).map_anon(),
+        }
+    }
+
+    /// Keep a mapping [`take`](Self::take) handed out, scrubbed, unless its class is full
+    pub(crate) fn release(&self, mut mapping: MmapMut) {
+        // A mapping that can't be made writable or whose class is full is unmapped instead
+        if unsafe { protections::protect(mapping.as_ptr(), mapping.len(), Protection::READ_WRITE, ProtectionSource::Loader) }.is_err() {
+            return;
+        }
+        let mut free = self.free.lock().unwrap();
+        let class = free.entry(mapping.len()).or_default();
+        if class.len() < self.per_class {
+            MappingPool::scrub(&mut mapping);
+            class.push(mapping);
+        }
+    }
+
+    /// Zero a mapping, by handing its pages back to the kernel where that's what it does:
+    /// anonymous private pages read as zeros after `MADV_DONTNEED`, without being touched
+    fn scrub(mapping: &mut MmapMut) {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            if unsafe { libc::madvise(mapping.as_mut_ptr() as *mut libc::c_void, mapping.len(), libc::MADV_DONTNEED) } == 0 {
+                return;
+            }
+        }
+        mapping.fill(0);
+    }
+
+    /// How many freed mappings are kept
+    pub fn retained(&self) -> usize {
+        self.free.lock().unwrap().values().map(Vec::len).sum()
+    }
+
+    /// Unmap every freed mapping
+    pub fn clear(&self) {
+        self.free.lock().unwrap().clear();
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use memmap2::MmapOptions;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use crate::android_loader::AndroidLoader;
+    use crate::mapping_pool::MappingPool;
+    use crate::test_elf::TestElf;
+
+    #[test]
+    fn scrubbed_on_reuse() {
+        let pool = MappingPool::new(1);
+        let mut mapping = pool.take(100).unwrap();
+        assert_eq!(mapping.len(), region::page::size());
+        mapping[..4].copy_from_slice(b"used");
+        let address = mapping.as_ptr();
+        pool.release(mapping);
+        // The class is full
+        pool.release(MmapOptions::new().len(region::page::size()).map_anon().unwrap());
+        assert_eq!(pool.retained(), 1);
+
+        let reused = pool.take(region::page::size()).unwrap();
+        assert_eq!(reused.as_ptr(), address);
+        assert!(reused.iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn pooled_loads() {
+        let mut elf = TestElf::new();
+        elf.object("pooled_marker", &[0x5a; 16]);
+        let elf = elf.build();
+        let pool = Arc::new(MappingPool::default());
+        let loader = AndroidLoader::new().mapping_pool(pool.clone());
+
+        let first = loader.load_library_from_bytes(elf.clone()).unwrap();
+        let base = first.get_symbol("pooled_marker").unwrap();
+        drop(first);
+        assert_eq!(pool.retained(), 1);
+
+        let second = loader.load_library_from_bytes(elf).unwrap();
+        assert_eq!(second.get_symbol("pooled_marker").unwrap(), base);
+        assert_eq!(unsafe { *(base as *const [u8; 16]) }, [0x5a; 16]);
+        assert_eq!(pool.retained(), 0);
+    }
+
+    /// Pooled mappings against fresh `map_anon` ones, each fully touched like a loaded image.
+    /// Run with `cargo test --release pooled_against_fresh -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn pooled_against_fresh() {
+        const SIZE: usize = 256 * 1024;
+        const ROUNDS: usize = 2000;
+        let touch = |mapping: &mut [u8]| {
+            for page in mapping.chunks_mut(region::page::size()) {
+                page[0] = 1;
+            }
+        };
+
+        let started = Instant::now();
+        for _ in 0..ROUNDS {
+            let mut mapping = MmapOptions::new().len(SIZE).map_anon().unwrap();
+            touch(&mut mapping);
+        }
+        let fresh = started.elapsed();
+
+        let pool = MappingPool::new(1);
+        let started = Instant::now();
+        for _ in 0..ROUNDS {
+            let mut mapping = pool.take(SIZE).unwrap();
+            touch(&mut mapping);
+            pool.release(mapping);
+        }
+        let pooled = started.elapsed();
+
+        println!("{ROUNDS} mappings of {SIZE} bytes: fresh {fresh:?}, pooled {pooled:?}");
+    }
+}
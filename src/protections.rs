@@ -0,0 +1,105 @@
+//! Observing page protection changes, e.g. to audit which memory of a library becomes
+//! executable: every change the loader makes while mapping, relocating and patching images,
+//! and every one a library makes through the `mprotect`, `mmap` and `munmap` stubs.
+//!
+//! The observer is called after each change succeeds, on the thread that made it, so it
+//! shouldn't change protections itself. The write watches' signal handlers, which make a page
+//! writable for a single instruction, aren't reported.
+
+use lazy_static::lazy_static;
+use region::Protection;
+use std::sync::{Arc, Mutex};
+
+/// Who changed a protection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtectionSource {
+    /// The loader, including for [`AndroidLibrary`](crate::android_library::AndroidLibrary)
+    /// methods like `reset` and `write_symbol`
+    Loader,
+    /// A loaded library, through the stubs
+    Library,
+}
+
+/// A protection change that was made
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProtectionChange {
+    /// The start of the range, as given: the whole pages it touches changed
+    pub address: usize,
+    pub len: usize,
+    pub protection: Protection,
+    pub source: ProtectionSource,
+}
+
+/// Callback for [`set_protection_observer`]
+pub type ProtectionObserver = dyn Fn(&ProtectionChange) + Send + Sync;
+
+lazy_static! {
+    static ref OBSERVER: Mutex<Option<Arc<ProtectionObserver>>> = Mutex::new(None);
+}
+
+/// Call `observer` after each protection change from now on, replacing the previous observer
+pub fn set_protection_observer(observer: impl Fn(&ProtectionChange) + Send + Sync + 'static) {
+    *OBSERVER.lock().unwrap() = Some(Arc::new(observer));
+}
+
+/// Stop observing protection changes
+pub fn clear_protection_observer() {
+    *OBSERVER.lock().unwrap() = None;
+}
+
+/// [`region::protect`], reporting the change to the observer if it's made
+pub(crate) unsafe fn protect<T>(address: *const T, len: usize, protection: Protection, source: ProtectionSource) -> region::Result<()> {
+    region::protect(address, len, protection)?;
+    // Not called with the lock held, so it can replace itself
+    let observer = OBSERVER.lock().unwrap().clone();
+    if let Some(observer) = observer {
+        observer(&ProtectionChange { address: address as usize, len, protection, source });
+    }
+    Ok(())
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use memmap2::MmapOptions;
+    use region::Protection;
+    use std::os::raw::{c_int, c_void};
+    use std::sync::{Arc, Mutex};
+
+    use crate::android_library::AndroidLibrary;
+    use crate::protections::{clear_protection_observer, set_protection_observer, ProtectionChange, ProtectionSource};
+    use crate::test_elf::TestElf;
+
+    #[test]
+    fn observed_protections() {
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let recorded = changes.clone();
+        set_protection_observer(move |change| recorded.lock().unwrap().push(*change));
+
+        let mut elf = TestElf::new();
+        elf.function("observed_code", &[0xc3]);
+        elf.thunk("call_mprotect", "mprotect");
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let code = library.get_symbol("observed_code").unwrap() as usize;
+        let mprotect: extern "C" fn(*mut c_void, usize, c_int) -> c_int =
+            unsafe { std::mem::transmute(library.get_symbol("call_mprotect").unwrap()) };
+        let page = MmapOptions::new().len(region::page::size()).map_anon().unwrap();
+        // PROT_READ | PROT_EXEC
+        assert_eq!(mprotect(page.as_ptr() as *mut c_void, page.len(), 5), 0);
+        clear_protection_observer();
+
+        // Other tests change protections at the same time
+        let changes = changes.lock().unwrap();
+        assert!(changes.iter().any(|change| {
+            change.source == ProtectionSource::Loader
+                && (change.address..change.address + change.len).contains(&code)
+                && change.protection.contains(Protection::EXECUTE)
+        }));
+        let expected = ProtectionChange {
+            address: page.as_ptr() as usize,
+            len: page.len(),
+            protection: Protection::READ_EXECUTE,
+            source: ProtectionSource::Library,
+        };
+        assert_eq!(changes.iter().filter(|change| **change == expected).count(), 1);
+    }
+}
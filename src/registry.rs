@@ -0,0 +1,400 @@
+//! Every library currently loaded, in load order.
+
+use lazy_static::lazy_static;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use xmas_elf::symbol_table::Entry;
+use zero::read_str;
+
+use crate::android_library::{AndroidLibrary, DynEntry};
+use crate::android_loader::DlopenInterceptor;
+use crate::versions::SymbolVersion;
+use crate::vfs::VirtualFs;
+
+struct LoadedLibrary {
+    id: usize,
+    base: usize,
+    len: usize,
+    // The library's symbol tables, valid until it unregisters itself when dropped
+    dyn_symbols: *const DynEntry,
+    dyn_symbol_count: usize,
+    dyn_strs: *const u8,
+    dyn_strs_len: usize,
+    /// Section indices of the symbols with `SHN_XINDEX`, see [`AndroidLibrary::defined`]
+    extended_indices: Vec<u32>,
+    /// Version of each dynamic symbol, empty if the library isn't versioned
+    versions: Vec<Option<SymbolVersion>>,
+    /// Decoded names of the dynamic symbols, if they're obfuscated in the string table
+    names: Option<Vec<String>>,
+    soname: Option<String>,
+    /// Opened with `RTLD_GLOBAL`, so its symbols resolve relocations of libraries loaded later
+    global: bool,
+    /// Address and entry count of the library's `PT_ARM_EXIDX` table
+    arm_exidx: Option<(usize, usize)>,
+    /// Addresses of the `GLOB_DAT` and `JUMP_SLOT` slots, with the index of their symbol
+    slots: Vec<(usize, u32)>,
+    /// The library reloaded in its place, which lookups go to instead
+    replacement: Option<usize>,
+    dlopen_interceptor: Option<Arc<DlopenInterceptor>>,
+    /// Most bytes its `sprintf` calls may write
+    sprintf_limit: Option<usize>,
+    virtual_fs: Option<Arc<dyn VirtualFs>>,
+    /// Loaded with [`AndroidLoader::isolated`](crate::android_loader::AndroidLoader::isolated),
+    /// so other loads don't reuse it or resolve against it
+    isolated: bool,
+    /// The file it was loaded from, unless it was loaded from bytes
+    path: Option<PathBuf>,
+    /// Address and count of the program headers, in the retained file
+    program_headers: Option<(usize, usize)>,
+    tls_module: Option<usize>,
+}
+
+unsafe impl Send for LoadedLibrary {}
+
+impl LoadedLibrary {
+    fn contains(&self, address: usize) -> bool {
+        (self.base..self.base + self.len).contains(&address)
+    }
+
+    fn tables(&self) -> (&[DynEntry], &[u8]) {
+        unsafe {
+            (
+                std::slice::from_raw_parts(self.dyn_symbols, self.dyn_symbol_count),
+                std::slice::from_raw_parts(self.dyn_strs, self.dyn_strs_len),
+            )
+        }
+    }
+
+    fn name<'s>(&'s self, index: usize, symbol: &DynEntry, strings: &'s [u8]) -> &'s str {
+        match &self.names {
+            Some(names) => &names[index],
+            None => read_str(&strings[symbol.name() as usize..]),
+        }
+    }
+
+    /// Address of a symbol this library defines. With a `version` only that version matches,
+    /// otherwise unversioned and default definitions do.
+    fn symbol(&self, name: &str, version: Option<&str>) -> Option<usize> {
+        self.definition(name, version).map(|symbol| self.base + symbol.value() as usize)
+    }
+
+    fn definition(&self, name: &str, version: Option<&str>) -> Option<&DynEntry> {
+        let (symbols, strings) = self.tables();
+        symbols.iter()
+            .enumerate()
+            .find(|(index, symbol)| {
+                AndroidLibrary::defined(symbol, *index, &self.extended_indices)
+                    && self.name(*index, symbol, strings) == name
+                    && match (version, self.versions.get(*index).and_then(Option::as_ref)) {
+                        (Some(wanted), Some(defined)) => defined.name == wanted,
+                        (Some(_), None) => false,
+                        (None, Some(defined)) => !defined.hidden,
+                        (None, None) => true,
+                    }
+            })
+            .map(|(_, symbol)| symbol)
+    }
+}
+
+lazy_static! {
+    static ref LIBRARIES: Mutex<Vec<LoadedLibrary>> = Mutex::new(Vec::new());
+}
+
+/// Only changed with `LIBRARIES` locked, so it counts the libraries loaded so far consistently
+/// with those still loaded
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Record a library mapped at `base..base + len` and return its registry id
+pub(crate) fn register(
+    base: usize, len: usize, dyn_symbols: &[DynEntry], dyn_strs: &[u8], versions: Vec<Option<SymbolVersion>>, names: Option<Vec<String>>,
+    soname: Option<String>,
+) -> usize {
+    let mut libraries = LIBRARIES.lock().unwrap();
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    libraries.push(LoadedLibrary {
+        id,
+        base,
+        len,
+        dyn_symbols: dyn_symbols.as_ptr(),
+        dyn_symbol_count: dyn_symbols.len(),
+        dyn_strs: dyn_strs.as_ptr(),
+        dyn_strs_len: dyn_strs.len(),
+        extended_indices: Vec::new(),
+        versions,
+        names,
+        soname,
+        global: false,
+        arm_exidx: None,
+        slots: Vec::new(),
+        replacement: None,
+        dlopen_interceptor: None,
+        sprintf_limit: None,
+        virtual_fs: None,
+        isolated: false,
+        path: None,
+        program_headers: None,
+        tls_module: None,
+    });
+    id
+}
+
+pub(crate) fn unregister(id: usize) {
+    LIBRARIES.lock().unwrap().retain(|library| library.id != id);
+}
+
+/// Registry id of the library whose image contains `address`
+pub(crate) fn containing(address: usize) -> Option<usize> {
+    LIBRARIES.lock().unwrap().iter().find(|library| library.contains(address)).map(|library| library.id)
+}
+
+/// Record the `PT_ARM_EXIDX` table of a registered library
+pub(crate) fn set_extended_indices(id: usize, indices: &[u32]) {
+    if let Some(library) = LIBRARIES.lock().unwrap().iter_mut().find(|library| library.id == id) {
+        library.extended_indices = indices.to_vec();
+    }
+}
+
+pub(crate) fn set_arm_exidx(id: usize, address: usize, count: usize) {
+    if let Some(library) = LIBRARIES.lock().unwrap().iter_mut().find(|library| library.id == id) {
+        library.arm_exidx = Some((address, count));
+    }
+}
+
+pub(crate) fn set_dlopen_interceptor(id: usize, interceptor: Arc<DlopenInterceptor>) {
+    if let Some(library) = LIBRARIES.lock().unwrap().iter_mut().find(|library| library.id == id) {
+        library.dlopen_interceptor = Some(interceptor);
+    }
+}
+
+pub(crate) fn set_sprintf_limit(id: usize, limit: usize) {
+    if let Some(library) = LIBRARIES.lock().unwrap().iter_mut().find(|library| library.id == id) {
+        library.sprintf_limit = Some(limit);
+    }
+}
+
+pub(crate) fn set_virtual_fs(id: usize, fs: Arc<dyn VirtualFs>) {
+    if let Some(library) = LIBRARIES.lock().unwrap().iter_mut().find(|library| library.id == id) {
+        library.virtual_fs = Some(fs);
+    }
+}
+
+pub(crate) fn set_isolated(id: usize) {
+    if let Some(library) = LIBRARIES.lock().unwrap().iter_mut().find(|library| library.id == id) {
+        library.isolated = true;
+    }
+}
+
+pub(crate) fn set_path(id: usize, path: &Path) {
+    if let Some(library) = LIBRARIES.lock().unwrap().iter_mut().find(|library| library.id == id) {
+        library.path = Some(path.to_owned());
+    }
+}
+
+pub(crate) fn set_program_headers(id: usize, address: usize, count: usize) {
+    if let Some(library) = LIBRARIES.lock().unwrap().iter_mut().find(|library| library.id == id) {
+        library.program_headers = Some((address, count));
+    }
+}
+
+pub(crate) fn set_tls_module(id: usize, module: usize) {
+    if let Some(library) = LIBRARIES.lock().unwrap().iter_mut().find(|library| library.id == id) {
+        library.tls_module = Some(module);
+    }
+}
+
+/// A loaded library as `dl_iterate_phdr` reports it
+pub(crate) struct PhdrInfo {
+    pub(crate) base: usize,
+    /// Its path, or soname if it was loaded from bytes
+    pub(crate) name: String,
+    pub(crate) program_headers: (usize, usize),
+    pub(crate) tls_module: Option<usize>,
+}
+
+/// How many libraries were loaded and unloaded so far, and the loaded ones in load order
+pub(crate) fn phdr_infos() -> (usize, usize, Vec<PhdrInfo>) {
+    let libraries = LIBRARIES.lock().unwrap();
+    let loads = NEXT_ID.load(Ordering::Relaxed) - 1;
+    let infos = libraries.iter()
+        .map(|library| PhdrInfo {
+            base: library.base,
+            name: match &library.path {
+                Some(path) => path.to_string_lossy().into_owned(),
+                None => library.soname.clone().unwrap_or_default(),
+            },
+            program_headers: library.program_headers.unwrap_or((0, 0)),
+            tls_module: library.tls_module,
+        })
+        .collect();
+    (loads, loads - libraries.len(), infos)
+}
+
+/// The soname, base, image length and path of every loaded library, in load order
+pub(crate) fn libraries() -> Vec<(Option<String>, usize, usize, Option<PathBuf>)> {
+    LIBRARIES.lock().unwrap().iter().map(|library| (library.soname.clone(), library.base, library.len, library.path.clone())).collect()
+}
+
+/// What decides the `dlopen` calls of the library containing `address`
+pub(crate) fn dlopen_interceptor(address: usize) -> Option<Arc<DlopenInterceptor>> {
+    LIBRARIES.lock().unwrap().iter().find(|library| library.contains(address))?.dlopen_interceptor.clone()
+}
+
+/// How many bytes `sprintf` may write for the library containing `address`
+pub(crate) fn sprintf_limit(address: usize) -> Option<usize> {
+    LIBRARIES.lock().unwrap().iter().find(|library| library.contains(address))?.sprintf_limit
+}
+
+/// The filesystem the file stubs use for the library containing `address`
+pub(crate) fn virtual_fs(address: usize) -> Option<Arc<dyn VirtualFs>> {
+    LIBRARIES.lock().unwrap().iter().find(|library| library.contains(address))?.virtual_fs.clone()
+}
+
+/// Address and entry count of the `PT_ARM_EXIDX` table of the library containing `address`
+pub(crate) fn arm_exidx(address: usize) -> Option<(usize, usize)> {
+    LIBRARIES.lock().unwrap().iter().find(|library| library.contains(address))?.arm_exidx
+}
+
+/// The soname, base and length of the image of the library containing `address`
+pub(crate) fn owner(address: usize) -> Option<(Option<String>, usize, usize)> {
+    let libraries = LIBRARIES.lock().unwrap();
+    let library = libraries.iter().find(|library| library.contains(address))?;
+    Some((library.soname.clone(), library.base, library.len))
+}
+
+/// The soname of the library containing `address`, the closest symbol it defines at or below
+/// the address and the offset from it
+pub(crate) fn symbolize(address: usize) -> Option<(Option<String>, String, usize)> {
+    let libraries = LIBRARIES.lock().unwrap();
+    let library = libraries.iter().find(|library| library.contains(address))?;
+    let (symbols, strings) = library.tables();
+    let offset = address - library.base;
+    let (index, symbol) = symbols.iter()
+        .enumerate()
+        .filter(|(index, symbol)| AndroidLibrary::defined(symbol, *index, &library.extended_indices) && symbol.value() as usize <= offset)
+        .max_by_key(|(_, symbol)| symbol.value())?;
+    let name = library.name(index, symbol, strings).to_owned();
+    Some((library.soname.clone(), name, offset - symbol.value() as usize))
+}
+
+/// First definition of `name` in a library loaded after `after`, or in any library when `after`
+/// is `None`
+pub(crate) fn next_symbol(after: Option<usize>, name: &str) -> Option<usize> {
+    let libraries = LIBRARIES.lock().unwrap();
+    let start = match after {
+        Some(id) => libraries.iter().position(|library| library.id == id)? + 1,
+        None => 0,
+    };
+    libraries[start..].iter().filter(|library| library.replacement.is_none()).find_map(|library| library.symbol(name, None))
+}
+
+/// Add a library's symbols to the global namespace
+pub(crate) fn make_global(id: usize) {
+    if let Some(library) = LIBRARIES.lock().unwrap().iter_mut().find(|library| library.id == id) {
+        library.global = true;
+    }
+}
+
+/// Registry id of a loaded library with this `DT_SONAME`
+pub(crate) fn by_soname(soname: &str) -> Option<usize> {
+    LIBRARIES.lock().unwrap().iter()
+        .find(|library| library.replacement.is_none() && !library.isolated && library.soname.as_deref() == Some(soname))
+        .map(|library| library.id)
+}
+
+/// First definition of `name` among `libraries`. A required `version` is looked for in every
+/// library before falling back to default definitions, like the dynamic linker does.
+fn versioned_symbol<'l>(libraries: impl Iterator<Item = &'l LoadedLibrary> + Clone, name: &str, version: Option<&str>) -> Option<usize> {
+    version.and_then(|version| libraries.clone().find_map(|library| library.symbol(name, Some(version))))
+        .or_else(|| libraries.clone().find_map(|library| library.symbol(name, None)))
+}
+
+/// First definition of `name` in the libraries of `scope`, in that order
+pub(crate) fn scope_symbol(scope: &[usize], name: &str, version: Option<&str>) -> Option<usize> {
+    let libraries = LIBRARIES.lock().unwrap();
+    let scope = scope.iter().filter_map(|id| current(&libraries, *id));
+    versioned_symbol(scope, name, version)
+}
+
+/// TLS module and offset in the module's block of the first definition of the thread-local
+/// variable `name` in the libraries `scope` lists
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn scope_tls_symbol(scope: &[usize], name: &str, version: Option<&str>) -> Option<(usize, usize)> {
+    let libraries = LIBRARIES.lock().unwrap();
+    scope.iter().filter_map(|id| current(&libraries, *id)).find_map(|library| {
+        let symbol = library.definition(name, version).filter(|symbol| symbol.get_type() == Ok(xmas_elf::symbol_table::Type::Tls))?;
+        Some((library.tls_module?, symbol.value() as usize))
+    })
+}
+
+/// First definition of `name` in a library in the global namespace, in load order, unless the
+/// library `scope` starts with was loaded isolated
+pub(crate) fn global_symbol(scope: &[usize], name: &str, version: Option<&str>) -> Option<usize> {
+    let libraries = LIBRARIES.lock().unwrap();
+    if scope.first().and_then(|id| libraries.iter().find(|library| library.id == *id)).map_or(false, |library| library.isolated) {
+        return None;
+    }
+    let global = libraries.iter().filter(|library| library.global && !library.isolated && library.replacement.is_none());
+    versioned_symbol(global, name, version)
+}
+
+/// The library with registry id `id`, or the one that was last reloaded in its place
+fn current(libraries: &[LoadedLibrary], mut id: usize) -> Option<&LoadedLibrary> {
+    loop {
+        let library = libraries.iter().find(|library| library.id == id)?;
+        match library.replacement {
+            Some(replacement) => id = replacement,
+            None => return Some(library),
+        }
+    }
+}
+
+/// Record the `GLOB_DAT` and `JUMP_SLOT` slots of a library, as their addresses and the index
+/// of their symbol
+pub(crate) fn set_slots(id: usize, slots: Vec<(usize, u32)>) {
+    if let Some(library) = LIBRARIES.lock().unwrap().iter_mut().find(|library| library.id == id) {
+        library.slots = slots;
+    }
+}
+
+/// Make `new` take the place of `old` in lookups, including those of scopes naming `old`.
+/// `old` stays registered, so addresses in it are still found.
+pub(crate) fn replace(old: usize, new: usize) {
+    let mut libraries = LIBRARIES.lock().unwrap();
+    let global = match libraries.iter_mut().find(|library| library.id == old) {
+        Some(library) => {
+            library.replacement = Some(new);
+            library.global
+        }
+        None => return,
+    };
+    if let Some(library) = libraries.iter_mut().find(|library| library.id == new) {
+        library.global |= global;
+    }
+}
+
+/// The slots of other libraries that currently point into the image of `target`, as each
+/// slot's address, value and symbol name
+pub(crate) fn slots_into(target: usize) -> Vec<(usize, usize, String)> {
+    let libraries = LIBRARIES.lock().unwrap();
+    let target = match libraries.iter().find(|library| library.id == target) {
+        Some(target) => target,
+        None => return Vec::new(),
+    };
+    let mut found = Vec::new();
+    for library in libraries.iter().filter(|library| library.id != target.id && library.replacement.is_none()) {
+        let (symbols, strings) = library.tables();
+        for &(slot, index) in &library.slots {
+            let value = unsafe { *(slot as *const usize) };
+            if let (true, Some(symbol)) = (target.contains(value), symbols.get(index as usize)) {
+                found.push((slot, value, library.name(index as usize, symbol, strings).to_owned()));
+            }
+        }
+    }
+    found
+}
+
+/// Address of a symbol the library with registry id `id` itself defines
+pub(crate) fn library_symbol(id: usize, name: &str) -> Option<usize> {
+    LIBRARIES.lock().unwrap().iter().find(|library| library.id == id)?.symbol(name, None)
+}
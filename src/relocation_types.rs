@@ -5,10 +5,38 @@ pub type RelocType = u32;
 pub type RelocType = u8;
 
 pub enum RelocationType {
+    /// `R_*_NONE`, padding some linkers leave in the tables
+    None,
     Absolute,
+    #[cfg(target_arch = "x86_64")]
+    Absolute32,
+    #[cfg(target_arch = "x86_64")]
+    Absolute32Signed,
+    /// 32-bit signed offset from the relocated field
+    #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+    Pc32,
     GlobalData,
     JumpSlot,
     Relative,
+    /// `R_386_GOTOFF`, the symbol's offset from the GOT
+    #[cfg(target_arch = "x86")]
+    GotOffset,
+    /// `R_386_GOTPC`, the GOT's offset from the relocated field
+    #[cfg(target_arch = "x86")]
+    GotPc,
+    /// `R_386_GOT32` and `R_386_GOT32X`, the offset of the symbol's GOT entry from the GOT
+    #[cfg(target_arch = "x86")]
+    GotEntry,
+    #[cfg(target_arch = "arm")]
+    TlsModule,
+    #[cfg(target_arch = "arm")]
+    TlsOffset,
+    /// `R_ARM_TLS_TPOFF32` and `R_X86_64_TPOFF64`, the offset of a variable in static TLS
+    /// from the thread pointer, for the initial-exec model
+    #[cfg(any(target_arch = "arm", target_arch = "x86_64"))]
+    TlsStaticOffset,
+    #[cfg(target_arch = "arm")]
+    TlsDescriptor,
     Unknown(RelocType)
 }
 
@@ -16,10 +44,15 @@ impl From<RelocType> for RelocationType {
     #[cfg(target_arch = "x86_64")]
     fn from(reloc: RelocType) -> RelocationType {
         match reloc {
+            0 => RelocationType::None,
             1 => RelocationType::Absolute,
+            2 => RelocationType::Pc32,
             6 => RelocationType::GlobalData,
             7 => RelocationType::JumpSlot,
             8 => RelocationType::Relative,
+            10 => RelocationType::Absolute32,
+            11 => RelocationType::Absolute32Signed,
+            18 => RelocationType::TlsStaticOffset,
             _ => RelocationType::Unknown(reloc)
         }
     }
@@ -27,10 +60,16 @@ impl From<RelocType> for RelocationType {
     #[cfg(target_arch = "x86")]
     fn from(reloc: RelocType) -> RelocationType {
         match reloc {
+            0 => RelocationType::None,
             1 => RelocationType::Absolute,
+            // `R_386_PLT32` too, as calls go straight to the symbol without a PLT
+            2 | 4 => RelocationType::Pc32,
+            3 | 43 => RelocationType::GotEntry,
             6 => RelocationType::GlobalData,
             7 => RelocationType::JumpSlot,
             8 => RelocationType::Relative,
+            9 => RelocationType::GotOffset,
+            10 => RelocationType::GotPc,
             _ => RelocationType::Unknown(reloc)
         }
     }
@@ -38,6 +77,8 @@ impl From<RelocType> for RelocationType {
     #[cfg(target_arch = "aarch64")]
     fn from(reloc: RelocType) -> RelocationType {
         match reloc {
+            // 256 is the withdrawn `R_AARCH64_NULL`, still accepted as NONE
+            0 | 256 => RelocationType::None,
             257 => RelocationType::Absolute,
             1025 => RelocationType::GlobalData,
             1026 => RelocationType::JumpSlot,
@@ -49,7 +90,12 @@ impl From<RelocType> for RelocationType {
     #[cfg(target_arch = "arm")]
     fn from(reloc: RelocType) -> RelocationType {
         match reloc {
+            0 => RelocationType::None,
             2 => RelocationType::Absolute,
+            13 => RelocationType::TlsDescriptor,
+            17 => RelocationType::TlsModule,
+            18 => RelocationType::TlsOffset,
+            19 => RelocationType::TlsStaticOffset,
             21 => RelocationType::GlobalData,
             22 => RelocationType::JumpSlot,
             23 => RelocationType::Relative,
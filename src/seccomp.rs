@@ -0,0 +1,178 @@
+//! Running library code under a seccomp filter that only allows chosen syscalls (Linux, with
+//! the `seccomp` feature).
+//!
+//! A filter applies to the thread installing it and to the threads that thread creates, and can
+//! never be removed. [`SyscallFilter::run`] therefore runs the code on a fresh thread that exits
+//! afterwards, leaving the rest of the process unrestricted. Anything the code shares with
+//! other threads (static state, memory it hands back) isn't protected by the filter, and
+//! threads the library started before aren't filtered at all.
+
+use anyhow::Result;
+use libc::{sock_filter, sock_fprog};
+use std::io;
+use std::os::raw::{c_int, c_long};
+
+const PR_SET_SECCOMP: c_int = 22;
+const PR_SET_NO_NEW_PRIVS: c_int = 38;
+
+// Classic BPF opcodes
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_RET_K: u16 = 0x06;
+
+/// Offsets in `struct seccomp_data`
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+#[cfg(target_arch = "x86")]
+const AUDIT_ARCH: u32 = 0x4000_0003;
+#[cfg(target_arch = "arm")]
+const AUDIT_ARCH: u32 = 0x4000_0028;
+
+/// Jumps are 8 bits, so the allowlist is checked in one run of comparisons at most this long
+const MAX_ALLOWED: usize = 255;
+
+/// Syscalls the filtered thread needs to hand back its result and exit
+const BASELINE: [c_long; 7] = [
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_futex,
+    libc::SYS_munmap,
+    libc::SYS_madvise,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_sigaltstack,
+];
+
+/// What a syscall outside the allowlist does
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Violation {
+    /// Fail with this errno without running
+    Errno(c_int),
+    /// Raise `SIGSYS` in the calling thread
+    Trap,
+    /// Kill the calling thread
+    KillThread,
+    /// Kill the whole process
+    KillProcess,
+}
+
+impl Violation {
+    fn action(self) -> u32 {
+        match self {
+            Violation::Errno(errno) => libc::SECCOMP_RET_ERRNO | (errno as u32 & 0xffff),
+            Violation::Trap => libc::SECCOMP_RET_TRAP,
+            Violation::KillThread => libc::SECCOMP_RET_KILL_THREAD,
+            Violation::KillProcess => libc::SECCOMP_RET_KILL_PROCESS,
+        }
+    }
+}
+
+/// An allowlist of syscalls, by number (`libc::SYS_*`)
+#[derive(Clone, Debug)]
+pub struct SyscallFilter {
+    allowed: Vec<c_long>,
+    violation: Violation,
+}
+
+impl Default for SyscallFilter {
+    fn default() -> Self {
+        SyscallFilter::new()
+    }
+}
+
+impl SyscallFilter {
+    /// Allow only what the filtered thread needs to exit, failing everything else with `EPERM`.
+    /// Libraries that allocate memory typically need `mmap` and `brk` too.
+    pub fn new() -> SyscallFilter {
+        SyscallFilter { allowed: BASELINE.to_vec(), violation: Violation::Errno(libc::EPERM) }
+    }
+
+    pub fn allow(mut self, syscall: c_long) -> SyscallFilter {
+        if !self.allowed.contains(&syscall) {
+            self.allowed.push(syscall);
+        }
+        self
+    }
+
+    pub fn on_violation(mut self, violation: Violation) -> SyscallFilter {
+        self.violation = violation;
+        self
+    }
+
+    /// Run `code` (e.g. a call into a loaded library) on a new thread restricted by the
+    /// filter, returning its result. Panics in `code` are resumed on the calling thread.
+    /// Syscalls made for another architecture (e.g. `int 0x80` on x86_64) always kill the
+    /// process.
+    pub fn run<R: Send + 'static>(&self, code: impl FnOnce() -> R + Send + 'static) -> Result<R> {
+        if self.allowed.len() > MAX_ALLOWED {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("a filter allows at most {MAX_ALLOWED} syscalls")).into());
+        }
+        let program = self.program();
+        std::thread::spawn(move || {
+            install(&program)?;
+            Ok(code())
+        })
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    }
+
+    fn program(&self) -> Vec<sock_filter> {
+        let statement = |code, k| sock_filter { code, jt: 0, jf: 0, k };
+        let mut program = vec![
+            statement(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+            sock_filter { code: BPF_JMP_JEQ_K, jt: 1, jf: 0, k: AUDIT_ARCH },
+            statement(BPF_RET_K, libc::SECCOMP_RET_KILL_PROCESS),
+            statement(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+        ];
+        // Each match jumps over the remaining comparisons and the violation to the allow
+        let count = self.allowed.len();
+        for (index, syscall) in self.allowed.iter().enumerate() {
+            program.push(sock_filter { code: BPF_JMP_JEQ_K, jt: (count - index) as u8, jf: 0, k: *syscall as u32 });
+        }
+        program.push(statement(BPF_RET_K, self.violation.action()));
+        program.push(statement(BPF_RET_K, libc::SECCOMP_RET_ALLOW));
+        program
+    }
+}
+
+fn install(program: &[sock_filter]) -> Result<()> {
+    let program = sock_fprog { len: program.len() as u16, filter: program.as_ptr() as *mut sock_filter };
+    unsafe {
+        // Lets an unprivileged thread install a filter
+        if libc::prctl(PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+            || libc::prctl(PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &program as *const sock_fprog) != 0
+        {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use crate::android_library::AndroidLibrary;
+    use crate::seccomp::SyscallFilter;
+    use crate::test_elf::TestElf;
+
+    #[test]
+    fn filtered_library_call() {
+        let mut elf = TestElf::new();
+        // mov eax, 110 (getppid); syscall; ret
+        elf.function("filtered_getppid", &[0xb8, 110, 0, 0, 0, 0x0f, 0x05, 0xc3]);
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let getppid = library.get_symbol("filtered_getppid").unwrap() as usize;
+        let call = move || {
+            let getppid: extern "C" fn() -> i64 = unsafe { std::mem::transmute(getppid) };
+            getppid()
+        };
+
+        assert_eq!(SyscallFilter::new().run(call).unwrap(), -(libc::EPERM as i64));
+        assert_eq!(SyscallFilter::new().allow(libc::SYS_getppid).run(call).unwrap(), unsafe { libc::getppid() } as i64);
+        // The calling thread isn't restricted
+        assert!(unsafe { libc::getppid() } > 0);
+    }
+}
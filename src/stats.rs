@@ -0,0 +1,197 @@
+//! Statistics gathered while loading a library.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+#[derive(Clone, Debug, Default)]
+pub struct LoadStats {
+    /// `PT_LOAD` segments mapped
+    pub segments: usize,
+    /// Size of the memory mapping holding the image
+    pub bytes_mapped: usize,
+    /// Entries in the dynamic symbol table
+    pub symbols: usize,
+    /// Relocations applied, by relocation type number
+    pub relocations: HashMap<u32, usize>,
+    /// Relocations of types the loader can't apply, by relocation type number, skipped under
+    /// [`RelocationPolicy::BestEffort`](crate::android_loader::RelocationPolicy::BestEffort).
+    /// They're in `relocations` too.
+    pub skipped_relocations: HashMap<u32, usize>,
+    /// Relative relocations applied from the packed `DT_RELR` table, not in `relocations`
+    pub relr_relocations: usize,
+    /// Distinct symbols resolved to a hook
+    pub resolved_by_hook: usize,
+    /// Distinct symbols resolved to the library itself or one of its dependencies
+    pub resolved_by_library: usize,
+    /// Distinct symbols resolved to a library opened with `RTLD_GLOBAL`
+    pub resolved_by_global: usize,
+    /// Distinct symbols resolved to one registered with
+    /// [`AndroidLoader::register_global_symbol`](crate::android_loader::AndroidLoader::register_global_symbol)
+    pub resolved_by_registered: usize,
+    /// Distinct symbols resolved to a built-in libc function
+    pub resolved_by_libc: usize,
+    /// Distinct symbols nothing provided
+    pub undefined: usize,
+    /// `JUMP_SLOT` relocations left to bind on their first call, with
+    /// [`AndroidLoader::lazy_binding`](crate::android_loader::AndroidLoader::lazy_binding)
+    pub lazily_bound: usize,
+    /// The imports nothing provided, see [`MissingImports`]
+    pub missing_imports: MissingImports,
+    pub parse_time: Duration,
+    pub map_time: Duration,
+    pub relocate_time: Duration,
+}
+
+/// Imports resolved to an undefined symbol's stub while relocating, sorted by name. Slots
+/// left to [lazy binding](crate::android_loader::AndroidLoader::lazy_binding) aren't in there,
+/// as they're only resolved on their first call.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MissingImports {
+    /// Imports typed `STT_FUNC` or called through a `JUMP_SLOT`
+    pub functions: Vec<String>,
+    /// Every other import, e.g. a `GLOB_DAT` of an untyped symbol
+    pub data: Vec<String>,
+}
+
+impl MissingImports {
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty() && self.data.is_empty()
+    }
+
+    /// Add the imports of `other`, e.g. those of the dependencies of a load
+    pub(crate) fn merge(&mut self, other: &MissingImports) {
+        for (names, others) in [(&mut self.functions, &other.functions), (&mut self.data, &other.data)] {
+            names.extend(others.iter().cloned());
+            names.sort();
+            names.dedup();
+        }
+    }
+}
+
+/// Where a symbol was resolved from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymbolSource {
+    Hook,
+    Library,
+    Global,
+    Registered,
+    Libc,
+    Undefined,
+}
+
+impl LoadStats {
+    pub fn total_relocations(&self) -> usize {
+        self.relocations.values().sum()
+    }
+
+    pub(crate) fn count_resolution(&mut self, source: SymbolSource) {
+        match source {
+            SymbolSource::Hook => self.resolved_by_hook += 1,
+            SymbolSource::Library => self.resolved_by_library += 1,
+            SymbolSource::Global => self.resolved_by_global += 1,
+            SymbolSource::Registered => self.resolved_by_registered += 1,
+            SymbolSource::Libc => self.resolved_by_libc += 1,
+            SymbolSource::Undefined => self.undefined += 1,
+        }
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::android_library::{AndroidLibrary, AndroidLoaderErr};
+    use crate::android_loader::{AndroidLoader, ResolveMode};
+    use crate::hook_manager::add_hooks;
+    use crate::stats::MissingImports;
+    use crate::test_elf::{TestElf, R_X86_64_64, R_X86_64_GLOB_DAT, R_X86_64_JUMP_SLOT, R_X86_64_RELATIVE};
+
+    #[test]
+    fn relocation_and_symbol_counts() {
+        let mut hooks = HashMap::new();
+        hooks.insert("stats_hooked".to_owned(), 0x1000);
+        add_hooks(hooks);
+
+        let mut elf = TestElf::new();
+        elf.thunk("call_hooked", "stats_hooked");
+        elf.thunk("call_toupper", "toupper");
+        elf.thunk("call_missing", "stats_missing");
+        let cells = elf.object("cells", &[0; 24]);
+        elf.relocation(cells, R_X86_64_64, Some("stats_hooked"), 0);
+        elf.relocation(cells + 8, R_X86_64_RELATIVE, None, 0);
+        elf.relocation(cells + 16, R_X86_64_RELATIVE, None, 0);
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+
+        let stats = library.load_stats();
+        assert_eq!(stats.segments, 1);
+        assert!(stats.bytes_mapped >= 4096);
+        // The null symbol, 4 definitions and 3 imports
+        assert_eq!(stats.symbols, 8);
+        assert_eq!(stats.total_relocations(), 6);
+        assert_eq!(stats.relocations[&R_X86_64_JUMP_SLOT], 3);
+        assert_eq!(stats.relocations[&R_X86_64_64], 1);
+        assert_eq!(stats.relocations[&R_X86_64_RELATIVE], 2);
+        assert_eq!((stats.resolved_by_hook, stats.resolved_by_libc, stats.undefined), (1, 1, 1));
+    }
+
+    #[test]
+    fn missing_imports_grouped() {
+        let mut elf = TestElf::new();
+        elf.thunk("call_strlen", "strlen");
+        elf.thunk("call_second_missing", "stats_missing_second");
+        elf.thunk("call_first_missing", "stats_missing_first");
+        let cells = elf.object("missing_cells", &[0; 24]);
+        elf.relocation(cells, R_X86_64_GLOB_DAT, Some("stats_missing_table"), 0);
+        elf.relocation(cells + 8, R_X86_64_64, Some("stats_missing_first"), 0);
+        elf.relocation(cells + 16, R_X86_64_64, Some("stats_missing_hooked"), 0);
+        let elf = elf.build();
+        let expected = MissingImports {
+            functions: vec!["stats_missing_first".to_owned(), "stats_missing_second".to_owned()],
+            data: vec!["stats_missing_hooked".to_owned(), "stats_missing_table".to_owned()],
+        };
+
+        let library = AndroidLibrary::load_from_bytes(elf.clone()).unwrap();
+        assert_eq!(library.load_stats().missing_imports, expected);
+
+        let err = AndroidLoader::new().reject_missing_imports().load_library_from_bytes(elf.clone()).err().unwrap();
+        match err.downcast_ref::<AndroidLoaderErr>() {
+            Some(AndroidLoaderErr::MissingImports(missing)) => assert_eq!(*missing, expected),
+            _ => panic!("unexpected error: {err}"),
+        }
+
+        let library = AndroidLoader::new()
+            .hook("stats_missing_hooked", 0x1000)
+            .hook("stats_missing_table", 0x2000)
+            .hook("stats_missing_first", 0x3000)
+            .hook("stats_missing_second", 0x4000)
+            .reject_missing_imports()
+            .load_library_from_bytes(elf)
+            .unwrap();
+        assert!(library.load_stats().missing_imports.is_empty());
+    }
+
+    #[test]
+    fn strict_resolution() {
+        let mut elf = TestElf::new();
+        elf.thunk("call_strict_missing", "strict_missing");
+        elf.weak_import("strict_weak");
+        elf.thunk("call_strict_weak", "strict_weak");
+        let elf = elf.build();
+
+        let err = AndroidLoader::new().resolve_mode(ResolveMode::Strict).load_library_from_bytes(elf.clone()).err().unwrap();
+        assert!(matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::UnresolvedSymbol { name }) if name == "strict_missing"));
+        // Even when it'd otherwise be bound lazily
+        let err = AndroidLoader::new().resolve_mode(ResolveMode::Strict).lazy_binding().load_library_from_bytes(elf.clone()).err().unwrap();
+        assert!(matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::UnresolvedSymbol { .. })));
+
+        // Weak imports are allowed to be missing
+        let library = AndroidLoader::new()
+            .resolve_mode(ResolveMode::Strict)
+            .hook("strict_missing", 0x1000)
+            .load_library_from_bytes(elf.clone())
+            .unwrap();
+        assert_eq!(library.load_stats().missing_imports.functions, ["strict_weak"]);
+        let library = AndroidLoader::new().load_library_from_bytes(elf).unwrap();
+        assert_eq!(library.load_stats().missing_imports.functions, ["strict_missing", "strict_weak"]);
+    }
+}
@@ -0,0 +1,138 @@
+//! Android's own functions: logging through liblog's `__android_log_*` and reading system
+//! properties.
+//!
+//! Log messages go to the [`log`] crate, with the tag as the target at the closest level,
+//! fatal ones at error level. System properties are empty unless set with
+//! [`set_system_property`].
+
+use lazy_static::lazy_static;
+use log::{log, Level};
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int, c_void};
+use std::sync::Mutex;
+
+use crate::stubs::format;
+use crate::stubs::varargs::{asm_symbol, variadic_entry, VaList};
+use crate::sysv64;
+
+/// bionic's longest property value, terminator included
+pub const PROP_VALUE_MAX: usize = 92;
+
+lazy_static! {
+    static ref PROPERTIES: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+/// Set the value `__system_property_get(name)` gives loaded libraries, e.g.
+/// `ro.build.version.sdk`, or unset it with `None`. Values are cut to fit bionic's
+/// [`PROP_VALUE_MAX`].
+pub fn set_system_property(name: &str, value: Option<&str>) {
+    let mut properties = PROPERTIES.lock().unwrap();
+    match value {
+        Some(value) => properties.insert(name.to_owned(), value.to_owned()),
+        None => properties.remove(name),
+    };
+}
+
+fn level(priority: c_int) -> Level {
+    match priority {
+        c_int::MIN..=2 => Level::Trace, // ANDROID_LOG_VERBOSE and below
+        3 => Level::Debug,
+        4 => Level::Info,
+        5 => Level::Warn,
+        _ => Level::Error, // ANDROID_LOG_ERROR, ANDROID_LOG_FATAL
+    }
+}
+
+unsafe fn write_log(priority: c_int, tag: *const c_char, message: &[u8]) -> c_int {
+    let tag = if tag.is_null() { "".into() } else { CStr::from_ptr(tag).to_string_lossy() };
+    let message = String::from_utf8_lossy(message);
+    log!(target: &tag, level(priority), "{}", message.trim_end_matches('\n'));
+    1
+}
+
+#[sysv64]
+unsafe fn __android_log_write(priority: c_int, tag: *const c_char, text: *const c_char) -> c_int {
+    write_log(priority, tag, CStr::from_ptr(text).to_bytes())
+}
+
+#[sysv64]
+unsafe fn __android_log_buf_write(_buffer: c_int, priority: c_int, tag: *const c_char, text: *const c_char) -> c_int {
+    __android_log_write(priority, tag, text)
+}
+
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_android_log_vprint(priority: c_int, tag: *const c_char, format: *const c_char, args: *mut c_void) -> c_int {
+    write_log(priority, tag, &format::format(format, &mut VaList::new(args)))
+}
+
+variadic_entry!("android_loader_android_log_print", 3, "android_loader_android_log_vprint");
+
+extern "C" {
+    fn android_loader_android_log_print();
+}
+
+/// Copies the value into `value`, which must hold [`PROP_VALUE_MAX`] bytes, and returns its
+/// length. Unset properties are empty.
+#[sysv64]
+unsafe fn __system_property_get(name: *const c_char, value: *mut c_char) -> c_int {
+    let name = CStr::from_ptr(name).to_string_lossy();
+    let properties = PROPERTIES.lock().unwrap();
+    let property = properties.get(name.as_ref()).map_or(&b""[..], |property| property.as_bytes());
+    let len = property.len().min(PROP_VALUE_MAX - 1);
+    std::ptr::copy_nonoverlapping(property.as_ptr(), value as *mut u8, len);
+    *value.add(len) = 0;
+    len as c_int
+}
+
+pub(crate) fn lookup(symbol_name: &str) -> Option<*const ()> {
+    Some(match symbol_name {
+        "__android_log_write" => __android_log_write as *const (),
+        "__android_log_buf_write" => __android_log_buf_write as *const (),
+        "__android_log_print" => android_loader_android_log_print as *const (),
+        "__android_log_vprint" => android_loader_android_log_vprint as *const (),
+        "__system_property_get" => __system_property_get as *const (),
+        _ => return None,
+    })
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::ffi::CStr;
+    use std::os::raw::{c_char, c_int};
+
+    use crate::android_library::AndroidLibrary;
+    use crate::stubs::android::{set_system_property, PROP_VALUE_MAX};
+    use crate::test_elf::TestElf;
+
+    fn c(s: &[u8]) -> *const c_char {
+        s.as_ptr() as *const c_char
+    }
+
+    #[test]
+    fn loaded_android_functions() {
+        let mut elf = TestElf::new();
+        elf.thunk("call_log_print", "__android_log_print");
+        elf.thunk("call_property_get", "__system_property_get");
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+
+        unsafe {
+            let log_print: unsafe extern "C" fn(c_int, *const c_char, *const c_char, ...) -> c_int =
+                std::mem::transmute(library.get_symbol("call_log_print").unwrap());
+            let property_get: extern "C" fn(*const c_char, *mut c_char) -> c_int =
+                std::mem::transmute(library.get_symbol("call_property_get").unwrap());
+
+            assert_eq!(log_print(4, c(b"loader\0"), c(b"%d\n\0"), 5 as c_int), 1);
+
+            let mut value = [0x7f as c_char; PROP_VALUE_MAX];
+            assert_eq!(property_get(c(b"android.loader.test.unset\0"), value.as_mut_ptr()), 0);
+            assert_eq!(value[0], 0);
+            set_system_property("android.loader.test.sdk", Some("29"));
+            assert_eq!(property_get(c(b"android.loader.test.sdk\0"), value.as_mut_ptr()), 2);
+            assert_eq!(CStr::from_ptr(value.as_ptr()).to_bytes(), b"29");
+            set_system_property("android.loader.test.long", Some(&"x".repeat(200)));
+            assert_eq!(property_get(c(b"android.loader.test.long\0"), value.as_mut_ptr()), PROP_VALUE_MAX as c_int - 1);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+//! `getauxval`, answering from a configurable auxiliary vector.
+//!
+//! `AT_PAGESIZE` defaults to the host's page size, `AT_HWCAP` and `AT_HWCAP2` to what the host
+//! kernel reports on Linux and 0 elsewhere. Anything else is absent unless set.
+
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::os::raw::c_ulong;
+use std::sync::Mutex;
+
+use crate::stubs::errno::{set_errno, ENOENT};
+use crate::sysv64;
+
+pub const AT_PAGESZ: c_ulong = 6;
+pub const AT_HWCAP: c_ulong = 16;
+pub const AT_HWCAP2: c_ulong = 26;
+
+lazy_static! {
+    static ref AUXV: Mutex<HashMap<c_ulong, c_ulong>> = Mutex::new(default_auxv());
+}
+
+fn default_auxv() -> HashMap<c_ulong, c_ulong> {
+    let mut auxv = HashMap::new();
+    auxv.insert(AT_PAGESZ, region::page::size() as c_ulong);
+    auxv.insert(AT_HWCAP, host_value(AT_HWCAP));
+    auxv.insert(AT_HWCAP2, host_value(AT_HWCAP2));
+    auxv
+}
+
+#[cfg(target_os = "linux")]
+fn host_value(kind: c_ulong) -> c_ulong {
+    unsafe { libc::getauxval(kind) }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn host_value(_kind: c_ulong) -> c_ulong {
+    0
+}
+
+/// Set the value `getauxval(kind)` returns to loaded libraries, or remove the entry with `None`
+/// so it fails with `ENOENT`. Useful to steer libraries away from (or towards) SIMD paths.
+pub fn set_auxv_value(kind: c_ulong, value: Option<c_ulong>) {
+    let mut auxv = AUXV.lock().unwrap();
+    match value {
+        Some(value) => auxv.insert(kind, value),
+        None => auxv.remove(&kind),
+    };
+}
+
+#[sysv64]
+fn getauxval(kind: c_ulong) -> c_ulong {
+    match AUXV.lock().unwrap().get(&kind) {
+        Some(value) => *value,
+        None => {
+            set_errno(ENOENT);
+            0
+        }
+    }
+}
+
+pub(crate) fn lookup(symbol_name: &str) -> Option<*const ()> {
+    match symbol_name {
+        "getauxval" => Some(getauxval as *const ()),
+        _ => None,
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::os::raw::c_ulong;
+
+    use crate::android_library::AndroidLibrary;
+    use crate::stubs::auxv::{set_auxv_value, AT_HWCAP, AT_PAGESZ};
+    use crate::stubs::errno::{errno, ENOENT};
+    use crate::test_elf::TestElf;
+
+    #[test]
+    fn loaded_getauxval() {
+        let mut elf = TestElf::new();
+        elf.thunk("call_getauxval", "getauxval");
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let getauxval: extern "C" fn(c_ulong) -> c_ulong =
+            unsafe { std::mem::transmute(library.get_symbol("call_getauxval").unwrap()) };
+
+        assert_eq!(getauxval(AT_PAGESZ), region::page::size() as c_ulong);
+        set_auxv_value(AT_HWCAP, Some(0b1011));
+        assert_eq!(getauxval(AT_HWCAP), 0b1011);
+
+        // AT_ENTRY, which nothing provides
+        assert_eq!(getauxval(9), 0);
+        assert_eq!(errno(), ENOENT);
+    }
+}
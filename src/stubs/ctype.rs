@@ -0,0 +1,294 @@
+//! Character classification in the C locale.
+//!
+//! Besides the functions, the classification tables themselves are exported: glibc's macros
+//! index the tables behind `__ctype_b_loc`/`__ctype_tolower_loc`/`__ctype_toupper_loc` with
+//! any value from -128 to 255, and bionic's older inline versions index `_ctype_` with `c + 1`.
+
+use std::os::raw::{c_char, c_int};
+
+use crate::sysv64;
+
+// glibc's classification bits, as laid out on little endian
+const UPPER: u16 = 1 << 8;
+const LOWER: u16 = 1 << 9;
+const ALPHA: u16 = 1 << 10;
+const DIGIT: u16 = 1 << 11;
+const XDIGIT: u16 = 1 << 12;
+const SPACE: u16 = 1 << 13;
+const PRINT: u16 = 1 << 14;
+const GRAPH: u16 = 1 << 15;
+const BLANK: u16 = 1 << 0;
+const CNTRL: u16 = 1 << 1;
+const PUNCT: u16 = 1 << 2;
+const ALNUM: u16 = 1 << 3;
+
+// bionic's `_ctype_` bits
+const BIONIC_UPPER: u8 = 0x01;
+const BIONIC_LOWER: u8 = 0x02;
+const BIONIC_DIGIT: u8 = 0x04;
+const BIONIC_SPACE: u8 = 0x08;
+const BIONIC_PUNCT: u8 = 0x10;
+const BIONIC_CNTRL: u8 = 0x20;
+const BIONIC_XDIGIT: u8 = 0x40;
+const BIONIC_BLANK: u8 = 0x80;
+
+const fn classify(c: u8) -> u16 {
+    let mut class = 0;
+    if c.is_ascii_uppercase() {
+        class |= UPPER | ALPHA | ALNUM;
+    }
+    if c.is_ascii_lowercase() {
+        class |= LOWER | ALPHA | ALNUM;
+    }
+    if c.is_ascii_digit() {
+        class |= DIGIT | ALNUM;
+    }
+    if c.is_ascii_hexdigit() {
+        class |= XDIGIT;
+    }
+    // Unlike `u8::is_ascii_whitespace`, C counts vertical tab as a space
+    if c == b' ' || (c >= b'\t' && c <= b'\r') {
+        class |= SPACE;
+    }
+    if c == b' ' || c == b'\t' {
+        class |= BLANK;
+    }
+    if c.is_ascii_control() {
+        class |= CNTRL;
+    }
+    if c.is_ascii_punctuation() {
+        class |= PUNCT;
+    }
+    if c.is_ascii_graphic() {
+        class |= GRAPH | PRINT;
+    }
+    if c == b' ' {
+        class |= PRINT;
+    }
+    class
+}
+
+const fn bionic_class(class: u16) -> u8 {
+    let mut bionic = 0;
+    if class & UPPER != 0 { bionic |= BIONIC_UPPER; }
+    if class & LOWER != 0 { bionic |= BIONIC_LOWER; }
+    if class & DIGIT != 0 { bionic |= BIONIC_DIGIT; }
+    if class & SPACE != 0 { bionic |= BIONIC_SPACE; }
+    if class & PUNCT != 0 { bionic |= BIONIC_PUNCT; }
+    if class & CNTRL != 0 { bionic |= BIONIC_CNTRL; }
+    if class & XDIGIT != 0 { bionic |= BIONIC_XDIGIT; }
+    if class & BLANK != 0 { bionic |= BIONIC_BLANK; }
+    bionic
+}
+
+/// Tables are indexed from -128, so entry `i` describes character `i - 128`
+const TABLE_LEN: usize = 384;
+
+const fn class_table() -> [u16; TABLE_LEN] {
+    let mut table = [0; TABLE_LEN];
+    let mut i = 128;
+    while i < 256 {
+        table[i] = classify((i - 128) as u8);
+        i += 1;
+    }
+    table
+}
+
+const fn case_table(upper: bool) -> [i32; TABLE_LEN] {
+    let mut table = [0; TABLE_LEN];
+    let mut i = 0;
+    while i < TABLE_LEN {
+        let c = i as i32 - 128;
+        table[i] = if upper && c >= b'a' as i32 && c <= b'z' as i32 {
+            c - 32
+        } else if !upper && c >= b'A' as i32 && c <= b'Z' as i32 {
+            c + 32
+        } else {
+            c
+        };
+        i += 1;
+    }
+    table
+}
+
+const fn bionic_table() -> [u8; 257] {
+    // Entry 0 is EOF
+    let mut table = [0; 257];
+    let mut i = 0;
+    while i < 128 {
+        table[i + 1] = bionic_class(classify(i as u8));
+        i += 1;
+    }
+    table
+}
+
+static CLASS_TABLE: [u16; TABLE_LEN] = class_table();
+static TOLOWER_TABLE: [i32; TABLE_LEN] = case_table(false);
+static TOUPPER_TABLE: [i32; TABLE_LEN] = case_table(true);
+static BIONIC_TABLE: [u8; 257] = bionic_table();
+
+/// A table pointer the library reads through, pointing at the entry for character 0
+#[repr(transparent)]
+struct TablePointer<T>(*const T);
+
+unsafe impl<T> Sync for TablePointer<T> {}
+
+static CLASS_POINTER: TablePointer<u16> = TablePointer(&CLASS_TABLE[128]);
+static TOLOWER_POINTER: TablePointer<i32> = TablePointer(&TOLOWER_TABLE[128]);
+static TOUPPER_POINTER: TablePointer<i32> = TablePointer(&TOUPPER_TABLE[128]);
+/// bionic's `_ctype_` points at the EOF entry
+static BIONIC_POINTER: TablePointer<c_char> = TablePointer(&BIONIC_TABLE[0] as *const u8 as *const c_char);
+
+#[sysv64]
+fn __ctype_b_loc() -> *const *const u16 {
+    &CLASS_POINTER.0
+}
+
+#[sysv64]
+fn __ctype_tolower_loc() -> *const *const i32 {
+    &TOLOWER_POINTER.0
+}
+
+#[sysv64]
+fn __ctype_toupper_loc() -> *const *const i32 {
+    &TOUPPER_POINTER.0
+}
+
+fn class(c: c_int) -> u16 {
+    match c {
+        0..=255 => CLASS_TABLE[c as usize + 128],
+        _ => 0,
+    }
+}
+
+macro_rules! classifier {
+    ($($name:ident => $class:expr),* $(,)?) => {
+        $(
+            #[sysv64]
+            fn $name(c: c_int) -> c_int {
+                (class(c) & ($class) != 0) as c_int
+            }
+        )*
+    };
+}
+
+classifier! {
+    isalnum => ALNUM,
+    isalpha => ALPHA,
+    isblank => BLANK,
+    iscntrl => CNTRL,
+    isdigit => DIGIT,
+    isgraph => GRAPH,
+    islower => LOWER,
+    isprint => PRINT,
+    ispunct => PUNCT,
+    isspace => SPACE,
+    isupper => UPPER,
+    isxdigit => XDIGIT,
+}
+
+#[sysv64]
+fn isascii(c: c_int) -> c_int {
+    (0..=127).contains(&c) as c_int
+}
+
+#[sysv64]
+fn tolower(c: c_int) -> c_int {
+    match c {
+        -128..=255 => TOLOWER_TABLE[(c + 128) as usize],
+        _ => c,
+    }
+}
+
+#[sysv64]
+fn toupper(c: c_int) -> c_int {
+    match c {
+        -128..=255 => TOUPPER_TABLE[(c + 128) as usize],
+        _ => c,
+    }
+}
+
+pub(crate) fn lookup(symbol_name: &str) -> Option<*const ()> {
+    Some(match symbol_name {
+        "__ctype_b_loc" => __ctype_b_loc as *const (),
+        "__ctype_tolower_loc" => __ctype_tolower_loc as *const (),
+        "__ctype_toupper_loc" => __ctype_toupper_loc as *const (),
+        "_ctype_" => &BIONIC_POINTER as *const TablePointer<c_char> as *const (),
+        "isalnum" => isalnum as *const (),
+        "isalpha" => isalpha as *const (),
+        "isascii" => isascii as *const (),
+        "isblank" => isblank as *const (),
+        "iscntrl" => iscntrl as *const (),
+        "isdigit" => isdigit as *const (),
+        "isgraph" => isgraph as *const (),
+        "islower" => islower as *const (),
+        "isprint" => isprint as *const (),
+        "ispunct" => ispunct as *const (),
+        "isspace" => isspace as *const (),
+        "isupper" => isupper as *const (),
+        "isxdigit" => isxdigit as *const (),
+        "tolower" => tolower as *const (),
+        "toupper" => toupper as *const (),
+        _ => return None,
+    })
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::os::raw::{c_char, c_int};
+
+    use crate::android_library::AndroidLibrary;
+    use crate::stubs::ctype::{ALPHA, DIGIT, PUNCT, SPACE, UPPER};
+    use crate::test_elf::{TestElf, R_X86_64_64};
+
+    #[test]
+    fn loaded_ctype() {
+        let mut elf = TestElf::new();
+        for name in ["tolower", "toupper", "isalpha", "isdigit", "isspace", "__ctype_b_loc", "__ctype_toupper_loc"] {
+            elf.thunk(&format!("call_{name}"), name);
+        }
+        let ctype = elf.object("ctype", &[0; 8]);
+        elf.relocation(ctype, R_X86_64_64, Some("_ctype_"), 0);
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+
+        let function = |name: &str| -> extern "C" fn(c_int) -> c_int {
+            unsafe { std::mem::transmute(library.get_symbol(&format!("call_{name}")).unwrap()) }
+        };
+        let (tolower, toupper) = (function("tolower"), function("toupper"));
+        let (isalpha, isdigit, isspace) = (function("isalpha"), function("isdigit"), function("isspace"));
+
+        assert_eq!(tolower(b'Q' as c_int), b'q' as c_int);
+        assert_eq!(tolower(b'7' as c_int), b'7' as c_int);
+        assert_eq!(toupper(b'q' as c_int), b'Q' as c_int);
+        assert_eq!(toupper(-1), -1);
+        assert_ne!(isalpha(b'z' as c_int), 0);
+        assert_eq!(isalpha(b'1' as c_int), 0);
+        assert_ne!(isdigit(b'1' as c_int), 0);
+        assert_ne!(isspace(0x0b), 0);
+        assert_eq!(isspace(b'x' as c_int), 0);
+        assert_eq!(isalpha(0xe9), 0);
+
+        let ctype_b_loc: extern "C" fn() -> *const *const u16 =
+            unsafe { std::mem::transmute(library.get_symbol("call___ctype_b_loc").unwrap()) };
+        let toupper_loc: extern "C" fn() -> *const *const i32 =
+            unsafe { std::mem::transmute(library.get_symbol("call___ctype_toupper_loc").unwrap()) };
+        unsafe {
+            let table = *ctype_b_loc();
+            assert_eq!(*table.offset(b'A' as isize) & (UPPER | ALPHA), UPPER | ALPHA);
+            assert_ne!(*table.offset(b'9' as isize) & DIGIT, 0);
+            assert_ne!(*table.offset(b'\n' as isize) & SPACE, 0);
+            assert_ne!(*table.offset(b'!' as isize) & PUNCT, 0);
+            assert_eq!(*table.offset(-1), 0);
+            assert_eq!(*table.offset(-128), 0);
+
+            let table = *toupper_loc();
+            assert_eq!(*table.offset(b'b' as isize), b'B' as i32);
+            assert_eq!(*table.offset(-100), -100);
+
+            // bionic's `isdigit(c)` was `(_ctype_ + 1)[c] & _N`
+            let bionic = *(library.get_symbol("ctype").unwrap() as *const *const *const c_char);
+            assert_eq!(*(*bionic).add(1 + b'5' as usize) & 0x04, 0x04);
+            assert_eq!(*(*bionic).add(1 + b'a' as usize) & 0x03, 0x02);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! The C++ ABI's guards of function-local statics: `__cxa_guard_acquire`, `__cxa_guard_release`
+//! and `__cxa_guard_abort`, so each static is initialized once even when several threads get
+//! to it at the same time. And `__cxa_thread_atexit_impl`, registering the destructors of
+//! `thread_local` objects.
+//!
+//! A guard is 64 bits (32 on 32-bit ARM). The compiler checks its first byte, or on ARM its
+//! lowest bit, inline and only calls `__cxa_guard_acquire` while it's clear, so that byte is
+//! set to 1 once initialized, with release ordering for the inline check's acquire load. The
+//! rest of the guard is ours, but the initializations in progress are kept here instead, as the
+//! thread running each one.
+//!
+//! Thread-local destructors run in reverse order of registration when their thread exits,
+//! through a Rust thread-local's destructor, except those of libraries unloaded since, whose
+//! code is gone. When a library is unloaded the destructors it registered on the unloading
+//! thread run then, which is how the main thread's run at all.
+
+use lazy_static::lazy_static;
+use log::error;
+use log::warn;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::os::raw::{c_int, c_void};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread::{self, ThreadId};
+
+use crate::registry;
+use crate::{sysv64, sysv64_type};
+
+lazy_static! {
+    /// Guards being initialized, by address, and the thread initializing each
+    static ref PENDING: Mutex<HashMap<usize, ThreadId>> = Mutex::new(HashMap::new());
+    /// Signalled when an initialization is done or abandoned
+    static ref SETTLED: Condvar = Condvar::new();
+}
+
+/// A `thread_local` object's destructor, the object, and the `__dso_handle` of its library
+struct ThreadDestructor {
+    destructor: usize,
+    object: usize,
+    dso: usize,
+}
+
+impl ThreadDestructor {
+    fn run(&self) {
+        let destructor: sysv64_type!(fn(*mut c_void)) = unsafe { std::mem::transmute(self.destructor) };
+        destructor(self.object as *mut c_void);
+    }
+}
+
+/// This thread's destructors, in registration order
+struct ThreadDestructors(RefCell<Vec<ThreadDestructor>>);
+
+impl Drop for ThreadDestructors {
+    fn drop(&mut self) {
+        // Torn down itself, so destructors registering others can't reach it anymore
+        let destructors = self.0.get_mut();
+        while let Some(destructor) = destructors.pop() {
+            if destructor.dso != 0 && registry::containing(destructor.dso).is_none() {
+                warn!("Skipping a thread-local destructor of an unloaded library, at {:#x}", destructor.destructor);
+                continue;
+            }
+            destructor.run();
+        }
+    }
+}
+
+thread_local! {
+    static THREAD_DESTRUCTORS: ThreadDestructors = const { ThreadDestructors(RefCell::new(Vec::new())) };
+}
+
+unsafe fn initialized(guard: *mut u64) -> &'static AtomicU8 {
+    &*(guard as *const AtomicU8)
+}
+
+/// 1 if the caller is to initialize the static, after waiting for another thread doing so
+/// to finish or give up, 0 if it's initialized. Aborts if the initializer needs the static
+/// itself, which would otherwise wait forever.
+#[sysv64]
+unsafe fn __cxa_guard_acquire(guard: *mut u64) -> c_int {
+    let flag = initialized(guard);
+    if flag.load(Ordering::Acquire) != 0 {
+        return 0;
+    }
+    let mut pending = PENDING.lock().unwrap();
+    loop {
+        if flag.load(Ordering::Acquire) != 0 {
+            return 0;
+        }
+        match pending.get(&(guard as usize)) {
+            Some(thread) if *thread == thread::current().id() => {
+                error!("Recursive initialization of the static guarded at {guard:?}");
+                std::process::abort();
+            }
+            Some(_) => pending = SETTLED.wait(pending).unwrap(),
+            None => {
+                pending.insert(guard as usize, thread::current().id());
+                return 1;
+            }
+        }
+    }
+}
+
+#[sysv64]
+unsafe fn __cxa_guard_release(guard: *mut u64) {
+    let mut pending = PENDING.lock().unwrap();
+    initialized(guard).store(1, Ordering::Release);
+    pending.remove(&(guard as usize));
+    SETTLED.notify_all();
+}
+
+/// The initializer threw: the next thread to get to the static tries again
+#[sysv64]
+unsafe fn __cxa_guard_abort(guard: *mut u64) {
+    PENDING.lock().unwrap().remove(&(guard as usize));
+    SETTLED.notify_all();
+}
+
+/// Run `destructor(object)` when this thread exits, 0 on success
+#[sysv64]
+fn __cxa_thread_atexit_impl(destructor: usize, object: usize, dso: usize) -> c_int {
+    let registered = THREAD_DESTRUCTORS.try_with(|destructors| {
+        destructors.0.borrow_mut().push(ThreadDestructor { destructor, object, dso });
+    });
+    match registered {
+        Ok(()) => 0,
+        Err(_) => {
+            warn!("A thread-local destructor was registered while the thread's were running, it won't run");
+            -1
+        }
+    }
+}
+
+/// Run the destructors this thread registered for the library whose image is `image`, as
+/// it's being unloaded
+pub(crate) fn run_thread_destructors(image: Range<usize>) {
+    loop {
+        // Not borrowed while it runs, as it may register others
+        let destructor = THREAD_DESTRUCTORS.try_with(|destructors| {
+            let mut destructors = destructors.0.borrow_mut();
+            let index = destructors.iter().rposition(|destructor| image.contains(&destructor.dso))?;
+            Some(destructors.remove(index))
+        });
+        match destructor {
+            Ok(Some(destructor)) => destructor.run(),
+            _ => return,
+        }
+    }
+}
+
+pub(crate) fn lookup(symbol_name: &str) -> Option<*const ()> {
+    Some(match symbol_name {
+        "__cxa_guard_acquire" => __cxa_guard_acquire as *const (),
+        "__cxa_guard_release" => __cxa_guard_release as *const (),
+        "__cxa_guard_abort" => __cxa_guard_abort as *const (),
+        "__cxa_thread_atexit_impl" | "__cxa_thread_atexit" => __cxa_thread_atexit_impl as *const (),
+        _ => return None,
+    })
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::os::raw::{c_int, c_void};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier};
+    use std::time::Duration;
+
+    use crate::android_library::AndroidLibrary;
+    use crate::sysv64;
+    use crate::test_elf::TestElf;
+
+    #[test]
+    fn guarded_statics() {
+        let mut elf = TestElf::new();
+        for name in ["__cxa_guard_acquire", "__cxa_guard_release", "__cxa_guard_abort"] {
+            elf.thunk(&format!("call{name}"), name);
+        }
+        elf.object("cxa_guards", &[0; 16]);
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let function = |name: &str| library.get_symbol(&format!("call{name}")).unwrap() as usize;
+        let acquire: extern "C" fn(*mut u64) -> c_int = unsafe { std::mem::transmute(function("__cxa_guard_acquire")) };
+        let release: extern "C" fn(*mut u64) = unsafe { std::mem::transmute(function("__cxa_guard_release")) };
+        let abort: extern "C" fn(*mut u64) = unsafe { std::mem::transmute(function("__cxa_guard_abort")) };
+        let guards = library.get_symbol("cxa_guards").unwrap() as usize;
+
+        // What the compiler emits for a local static, from several threads at once
+        let initializations = Arc::new(AtomicUsize::new(0));
+        let start = Arc::new(Barrier::new(8));
+        let threads: Vec<_> = (0..8).map(|_| {
+            let (initializations, start) = (initializations.clone(), start.clone());
+            std::thread::spawn(move || {
+                let guard = guards as *mut u64;
+                start.wait();
+                if unsafe { *(guard as *const u8) } == 0 && acquire(guard) == 1 {
+                    std::thread::sleep(Duration::from_millis(20));
+                    initializations.fetch_add(1, Ordering::SeqCst);
+                    release(guard);
+                }
+                // Everyone sees it initialized once they're past the guard
+                initializations.load(Ordering::SeqCst)
+            })
+        }).collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), 1);
+        }
+        assert_eq!(unsafe { *(guards as *const u8) }, 1);
+        assert_eq!(acquire(guards as *mut u64), 0);
+
+        // An initializer that threw leaves the static to the next one
+        let guard = (guards + 8) as *mut u64;
+        assert_eq!(acquire(guard), 1);
+        abort(guard);
+        assert_eq!(unsafe { *(guard as *const u8) }, 0);
+        assert_eq!(acquire(guard), 1);
+        release(guard);
+        assert_eq!(acquire(guard), 0);
+    }
+
+    /// A thread-local object's destructor, counting how many times it ran in the object
+    #[sysv64]
+    fn count_destruction(object: *mut c_void) {
+        unsafe { &*(object as *const AtomicUsize) }.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn thread_local_destructors() {
+        let mut elf = TestElf::new();
+        elf.thunk("call_thread_atexit", "__cxa_thread_atexit_impl");
+        elf.object("__dso_handle", &[0; 8]);
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let function = library.get_symbol("call_thread_atexit").unwrap() as usize;
+        let dso = library.get_symbol("__dso_handle").unwrap() as usize;
+        let register = move |object: &'static AtomicUsize| {
+            let atexit: extern "C" fn(usize, usize, usize) -> c_int = unsafe { std::mem::transmute(function) };
+            atexit(count_destruction as *const () as usize, object as *const AtomicUsize as usize, dso)
+        };
+
+        // Run once the thread that registered it exits
+        static EXITED: AtomicUsize = AtomicUsize::new(0);
+        std::thread::spawn(move || {
+            assert_eq!(register(&EXITED), 0);
+            assert_eq!(EXITED.load(Ordering::SeqCst), 0);
+        }).join().unwrap();
+        assert_eq!(EXITED.load(Ordering::SeqCst), 1);
+
+        // Or once the library is unloaded, on the thread unloading it
+        static UNLOADED: AtomicUsize = AtomicUsize::new(0);
+        assert_eq!(register(&UNLOADED), 0);
+        drop(library);
+        assert_eq!(UNLOADED.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,55 @@
+//! `errno`, as bionic exposes it through `__errno()`.
+//!
+//! The constants are the Linux values libraries expect, whatever the host's are.
+
+use std::cell::UnsafeCell;
+use std::os::raw::c_int;
+
+use crate::sysv64;
+
+pub const EPERM: c_int = 1;
+pub const ENOENT: c_int = 2;
+pub const EIO: c_int = 5;
+pub const EBADF: c_int = 9;
+pub const EAGAIN: c_int = 11;
+pub const ENOMEM: c_int = 12;
+pub const EACCES: c_int = 13;
+pub const EFAULT: c_int = 14;
+pub const EEXIST: c_int = 17;
+pub const ENOTDIR: c_int = 20;
+pub const EISDIR: c_int = 21;
+pub const EINVAL: c_int = 22;
+pub const EMFILE: c_int = 24;
+pub const ENOTTY: c_int = 25;
+pub const ESPIPE: c_int = 29;
+pub const EROFS: c_int = 30;
+pub const ERANGE: c_int = 34;
+pub const ENAMETOOLONG: c_int = 36;
+pub const ENOSYS: c_int = 38;
+pub const EOVERFLOW: c_int = 75;
+pub const EILSEQ: c_int = 84;
+
+thread_local! {
+    static ERRNO: UnsafeCell<c_int> = const { UnsafeCell::new(0) };
+}
+
+pub(crate) fn set_errno(value: c_int) {
+    ERRNO.with(|errno| unsafe { *errno.get() = value });
+}
+
+/// The current thread's `errno` as seen by loaded libraries
+pub fn errno() -> c_int {
+    ERRNO.with(|errno| unsafe { *errno.get() })
+}
+
+#[sysv64]
+fn __errno() -> *mut c_int {
+    ERRNO.with(|errno| errno.get())
+}
+
+pub(crate) fn lookup(symbol_name: &str) -> Option<*const ()> {
+    match symbol_name {
+        "__errno" | "__errno_location" => Some(__errno as *const ()),
+        _ => None,
+    }
+}
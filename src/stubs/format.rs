@@ -0,0 +1,359 @@
+//! printf-style formatting shared by the formatted output stubs.
+//!
+//! Follows bionic's behavior where C implementations differ: `%p` prints like `%#x` and `%n`
+//! is refused. `long double` arguments are read as `double`.
+
+use log::error;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_long};
+
+use crate::stubs::varargs::FormatArgs;
+
+const LONG_SIZE: usize = std::mem::size_of::<c_long>();
+const POINTER_SIZE: usize = std::mem::size_of::<usize>();
+
+#[derive(Default)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+/// Format `format` with arguments pulled from `args`, without the terminating null byte
+pub(crate) unsafe fn format(format: *const c_char, args: &mut impl FormatArgs) -> Vec<u8> {
+    format_bytes(CStr::from_ptr(format).to_bytes(), args)
+}
+
+pub(crate) fn format_bytes(format: &[u8], args: &mut impl FormatArgs) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut i = 0;
+
+    while i < format.len() {
+        if format[i] != b'%' {
+            output.push(format[i]);
+            i += 1;
+            continue;
+        }
+        let start = i;
+        i += 1;
+
+        let mut spec = Spec::default();
+        while let Some(flag) = format.get(i) {
+            match flag {
+                b'-' => spec.left = true,
+                b'+' => spec.plus = true,
+                b' ' => spec.space = true,
+                b'#' => spec.alternate = true,
+                b'0' => spec.zero = true,
+                _ => break,
+            }
+            i += 1;
+        }
+
+        if format.get(i) == Some(&b'*') {
+            let width = args.next_int(4) as i32;
+            spec.left |= width < 0;
+            spec.width = width.unsigned_abs() as usize;
+            i += 1;
+        } else {
+            spec.width = parse_number(format, &mut i);
+        }
+
+        if format.get(i) == Some(&b'.') {
+            i += 1;
+            if format.get(i) == Some(&b'*') {
+                let precision = args.next_int(4) as i32;
+                spec.precision = usize::try_from(precision).ok();
+                i += 1;
+            } else {
+                spec.precision = Some(parse_number(format, &mut i));
+            }
+        }
+
+        let mut size = 4;
+        match format.get(i) {
+            Some(b'h') if format.get(i + 1) == Some(&b'h') => { size = 1; i += 2; }
+            Some(b'h') => { size = 2; i += 1; }
+            Some(b'l') if format.get(i + 1) == Some(&b'l') => { size = 8; i += 2; }
+            Some(b'l') => { size = LONG_SIZE; i += 1; }
+            Some(b'q') | Some(b'j') => { size = 8; i += 1; }
+            Some(b'z') | Some(b't') => { size = POINTER_SIZE; i += 1; }
+            Some(b'L') => { i += 1; }
+            _ => {}
+        }
+
+        let conversion = match format.get(i) {
+            Some(&conversion) => conversion,
+            None => {
+                output.extend_from_slice(&format[start..]);
+                break;
+            }
+        };
+        i += 1;
+
+        match conversion {
+            b'd' | b'i' => {
+                let value = sign_extend(args.next_int(size.max(4)), size);
+                let sign = if value < 0 { "-" } else if spec.plus { "+" } else if spec.space { " " } else { "" };
+                let digits = integer_digits(value.unsigned_abs(), 10, false, &spec);
+                pad(&mut output, &spec, sign, &digits, true);
+            }
+            b'u' | b'x' | b'X' | b'o' => {
+                let value = truncate(args.next_int(size.max(4)), size);
+                let (radix, upper) = match conversion {
+                    b'x' => (16, false),
+                    b'X' => (16, true),
+                    b'o' => (8, false),
+                    _ => (10, false),
+                };
+                let mut digits = integer_digits(value, radix, upper, &spec);
+                let prefix = match conversion {
+                    b'x' if spec.alternate && value != 0 => "0x",
+                    b'X' if spec.alternate && value != 0 => "0X",
+                    _ => "",
+                };
+                if conversion == b'o' && spec.alternate && !digits.starts_with('0') {
+                    digits.insert(0, '0');
+                }
+                pad(&mut output, &spec, prefix, &digits, true);
+            }
+            b'c' => {
+                let value = args.next_int(4) as u8;
+                pad_bytes(&mut output, &spec, &[value]);
+            }
+            b's' => {
+                let pointer = args.next_int(POINTER_SIZE) as usize as *const u8;
+                let bytes: &[u8] = if pointer.is_null() {
+                    b"(null)"
+                } else {
+                    let mut len = 0;
+                    while spec.precision.map_or(true, |precision| len < precision) && unsafe { *pointer.add(len) } != 0 {
+                        len += 1;
+                    }
+                    unsafe { std::slice::from_raw_parts(pointer, len) }
+                };
+                let bytes = &bytes[..spec.precision.map_or(bytes.len(), |precision| precision.min(bytes.len()))];
+                pad_bytes(&mut output, &spec, bytes);
+            }
+            b'p' => {
+                let value = args.next_int(POINTER_SIZE);
+                let digits = integer_digits(value, 16, false, &spec);
+                pad(&mut output, &spec, "0x", &digits, true);
+            }
+            b'f' | b'F' | b'e' | b'E' | b'g' | b'G' | b'a' | b'A' => {
+                let value = args.next_double();
+                let sign = if value.is_sign_negative() && !value.is_nan() { "-" } else if spec.plus { "+" } else if spec.space { " " } else { "" };
+                let body = float_body(value.abs(), conversion, &spec);
+                pad(&mut output, &spec, sign, &body, value.is_finite());
+            }
+            b'%' => output.push(b'%'),
+            b'n' => {
+                args.next_int(POINTER_SIZE);
+                error!("%n isn't supported and was ignored");
+            }
+            _ => output.extend_from_slice(&format[start..i]),
+        }
+    }
+
+    output
+}
+
+fn parse_number(format: &[u8], i: &mut usize) -> usize {
+    let mut value = 0usize;
+    while let Some(digit) = format.get(*i).filter(|c| c.is_ascii_digit()) {
+        value = value.saturating_mul(10).saturating_add((digit - b'0') as usize);
+        *i += 1;
+    }
+    value
+}
+
+fn truncate(value: u64, size: usize) -> u64 {
+    if size >= 8 { value } else { value & ((1 << (size * 8)) - 1) }
+}
+
+fn sign_extend(value: u64, size: usize) -> i64 {
+    let shift = 64 - size.min(8) * 8;
+    ((value << shift) as i64) >> shift
+}
+
+fn integer_digits(value: u64, radix: u32, upper: bool, spec: &Spec) -> String {
+    let mut digits = match (radix, upper) {
+        (16, false) => format!("{value:x}"),
+        (16, true) => format!("{value:X}"),
+        (8, _) => format!("{value:o}"),
+        _ => value.to_string(),
+    };
+    match spec.precision {
+        Some(0) if value == 0 => digits.clear(),
+        Some(precision) if digits.len() < precision => {
+            digits.insert_str(0, &"0".repeat(precision - digits.len()));
+        }
+        _ => {}
+    }
+    digits
+}
+
+fn float_body(value: f64, conversion: u8, spec: &Spec) -> String {
+    let upper = conversion.is_ascii_uppercase();
+    if !value.is_finite() {
+        let body = if value.is_nan() { "nan" } else { "inf" };
+        return if upper { body.to_uppercase() } else { body.to_owned() };
+    }
+
+    let precision = spec.precision.unwrap_or(6);
+    let body = match conversion.to_ascii_lowercase() {
+        b'f' => {
+            let mut body = format!("{value:.precision$}");
+            if spec.alternate && precision == 0 {
+                body.push('.');
+            }
+            body
+        }
+        b'e' => exponent_format(value, precision, spec.alternate),
+        b'g' => {
+            let precision = precision.max(1);
+            let exponent = if value == 0.0 { 0 } else { exponent_of(&format!("{:.*e}", precision - 1, value)) };
+            let mut body = if exponent < -4 || exponent >= precision as i32 {
+                exponent_format(value, precision - 1, spec.alternate)
+            } else {
+                format!("{:.*}", (precision as i32 - 1 - exponent) as usize, value)
+            };
+            if !spec.alternate {
+                body = strip_trailing_zeros(&body);
+            }
+            body
+        }
+        _ => hex_float(value, spec.precision),
+    };
+
+    if upper { body.to_uppercase() } else { body }
+}
+
+fn exponent_of(formatted: &str) -> i32 {
+    formatted.split_once('e').map_or(0, |(_, exponent)| exponent.parse().unwrap_or(0))
+}
+
+/// `%e` formatting: Rust writes `1.5e-7` where C wants `1.5e-07`
+fn exponent_format(value: f64, precision: usize, alternate: bool) -> String {
+    let formatted = format!("{value:.precision$e}");
+    let (mantissa, exponent) = formatted.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    let dot = if alternate && precision == 0 { "." } else { "" };
+    let sign = if exponent < 0 { '-' } else { '+' };
+    format!("{mantissa}{dot}e{sign}{:02}", exponent.unsigned_abs())
+}
+
+fn strip_trailing_zeros(body: &str) -> String {
+    let (number, exponent) = match body.find('e') {
+        Some(index) => body.split_at(index),
+        None => (body, ""),
+    };
+    if !number.contains('.') {
+        return body.to_owned();
+    }
+    let number = number.trim_end_matches('0').trim_end_matches('.');
+    format!("{number}{exponent}")
+}
+
+fn hex_float(value: f64, precision: Option<usize>) -> String {
+    if value == 0.0 {
+        return format!("0x0{}p+0", precision.filter(|p| *p > 0).map_or(String::new(), |p| format!(".{}", "0".repeat(p))));
+    }
+    let bits = value.to_bits();
+    let mut exponent = ((bits >> 52) & 0x7ff) as i32;
+    let mut mantissa = bits & ((1 << 52) - 1);
+    let leading = if exponent == 0 {
+        // Subnormal
+        exponent = -1022;
+        0
+    } else {
+        exponent -= 1023;
+        1
+    };
+
+    let mut digits = format!("{mantissa:013x}");
+    match precision {
+        Some(precision) if precision < 13 => {
+            // Round half to even on the dropped nibbles
+            let dropped = (13 - precision) * 4;
+            let half = 1u64 << (dropped - 1);
+            let remainder = mantissa & ((1 << dropped) - 1);
+            mantissa >>= dropped;
+            if remainder > half || (remainder == half && mantissa & 1 == 1) {
+                mantissa += 1;
+            }
+            digits = if precision == 0 { String::new() } else { format!("{mantissa:0precision$x}") };
+            if digits.len() > precision {
+                return hex_float_parts(leading + 1, &digits[1..], exponent);
+            }
+            if precision == 0 && mantissa > 0 {
+                return hex_float_parts(leading + 1, "", exponent);
+            }
+        }
+        Some(precision) => digits.push_str(&"0".repeat(precision - 13)),
+        None => digits = digits.trim_end_matches('0').to_owned(),
+    }
+    hex_float_parts(leading, &digits, exponent)
+}
+
+fn hex_float_parts(leading: u64, digits: &str, exponent: i32) -> String {
+    let dot = if digits.is_empty() { "" } else { "." };
+    let sign = if exponent < 0 { '-' } else { '+' };
+    format!("0x{leading}{dot}{digits}p{sign}{}", exponent.unsigned_abs())
+}
+
+fn pad(output: &mut Vec<u8>, spec: &Spec, prefix: &str, body: &str, allow_zero: bool) {
+    let len = prefix.len() + body.len();
+    let fill = spec.width.saturating_sub(len);
+    if spec.left {
+        output.extend_from_slice(prefix.as_bytes());
+        output.extend_from_slice(body.as_bytes());
+        output.resize(output.len() + fill, b' ');
+    } else if spec.zero && allow_zero && (spec.precision.is_none() || body.contains(['.', 'e', 'p'])) {
+        output.extend_from_slice(prefix.as_bytes());
+        output.resize(output.len() + fill, b'0');
+        output.extend_from_slice(body.as_bytes());
+    } else {
+        output.resize(output.len() + fill, b' ');
+        output.extend_from_slice(prefix.as_bytes());
+        output.extend_from_slice(body.as_bytes());
+    }
+}
+
+fn pad_bytes(output: &mut Vec<u8>, spec: &Spec, bytes: &[u8]) {
+    let fill = spec.width.saturating_sub(bytes.len());
+    if !spec.left {
+        output.resize(output.len() + fill, b' ');
+    }
+    output.extend_from_slice(bytes);
+    if spec.left {
+        output.resize(output.len() + fill, b' ');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stubs::format::format_bytes;
+    use crate::stubs::varargs::tests::{Arg, TestArgs};
+
+    fn check(format: &str, args: Vec<Arg>, expected: &str) {
+        let output = format_bytes(format.as_bytes(), &mut TestArgs(args));
+        assert_eq!(String::from_utf8(output).unwrap(), expected, "format: {format}");
+    }
+
+    #[test]
+    fn c_format_semantics() {
+        check("%d|%5d|%-5d|%05d|%+d|% d", vec![Arg::Int(-42i64 as u64), Arg::Int(42), Arg::Int(42), Arg::Int(-42i64 as u64), Arg::Int(7), Arg::Int(7)], "-42|   42|42   |-0042|+7| 7");
+        check("%u|%hhu|%hd|%lld", vec![Arg::Int(u32::MAX as u64), Arg::Int(0x1ff), Arg::Int(0xffff), Arg::Int(-1i64 as u64)], "4294967295|255|-1|-1");
+        check("%x|%#X|%#o|%.4x|%.0d", vec![Arg::Int(255), Arg::Int(255), Arg::Int(8), Arg::Int(10), Arg::Int(0)], "ff|0XFF|010|000a|");
+        check("%*d|%-*d|%.*s", vec![Arg::Int(4), Arg::Int(1), Arg::Int(-3i64 as u64), Arg::Int(2), Arg::Int(2), Arg::Int(b"abc\0".as_ptr() as u64)], "   1|2  |ab");
+        check("%s|%c|%%|%p", vec![Arg::Int(0), Arg::Int(b'z' as u64), Arg::Int(0x1234)], "(null)|z|%|0x1234");
+        check("%f|%.2f|%8.3f|%-8.1f|%e|%E", vec![Arg::Double(1.5), Arg::Double(2.345), Arg::Double(-1.23456), Arg::Double(2.0), Arg::Double(12345.678), Arg::Double(0.00012)], "1.500000|2.35|  -1.235|2.0     |1.234568e+04|1.200000E-04");
+        check("%g|%g|%g|%G|%#g", vec![Arg::Double(0.0001), Arg::Double(123456.0), Arg::Double(1234567.0), Arg::Double(1e-5), Arg::Double(1.0)], "0.0001|123456|1.23457e+06|1E-05|1.00000");
+        check("%f|%F|%5.1f|%a", vec![Arg::Double(f64::INFINITY), Arg::Double(f64::NAN), Arg::Double(-0.0), Arg::Double(1.0)], "inf|NAN| -0.0|0x1p+0");
+    }
+}
@@ -0,0 +1,865 @@
+//! File stubs over the [virtual filesystem](crate::vfs).
+//!
+//! The stubs taking a path use the filesystem of the library calling them, so they're bound to
+//! the libraries importing them like the other [caller-sensitive](crate::caller) stubs.
+//!
+//! File descriptors handed out by `open` are virtual and only mean something to these stubs,
+//! as are those of the host's [pipes and sockets](crate::stubs::ipc) they stand for.
+//! Descriptors below 3 are never handed out, and writing to them goes to the
+//! [output sink](crate::stubs::stdio::set_output_sink) instead.
+
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::io::SeekFrom;
+use std::os::raw::{c_char, c_int, c_long, c_void};
+use std::sync::{Arc, Mutex};
+
+use crate::caller::caller_entry;
+use crate::stubs::errno::{set_errno, EBADF, EFAULT, EINVAL, EMFILE, ENOMEM, ERANGE};
+use crate::stubs::ipc::HostFd;
+use crate::stubs::malloc;
+use crate::stubs::stdio;
+use crate::sysv64;
+use crate::vfs::{self, FsResult, VirtualDirEntry, VirtualFile, VirtualFs, VirtualMetadata};
+
+const FIRST_FD: c_int = 3;
+const AT_FDCWD: c_int = -100;
+const PATH_MAX: usize = 4096;
+const IOV_MAX: c_int = 1024;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+const AT_SYMLINK_NOFOLLOW: c_int = 0x100;
+const AT_EMPTY_PATH: c_int = 0x1000;
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+const F_DUPFD: c_int = 0;
+const F_GETFD: c_int = 1;
+const F_SETFD: c_int = 2;
+const F_GETFL: c_int = 3;
+const F_SETFL: c_int = 4;
+const F_GETLK: c_int = 5;
+const F_SETLKW: c_int = 7;
+const F_GETLK64: c_int = 12;
+const F_SETLKW64: c_int = 14;
+const F_DUPFD_CLOEXEC: c_int = 1030;
+const FD_CLOEXEC: c_int = 1;
+const F_UNLCK: i16 = 2;
+const O_APPEND: c_int = 0o2000;
+const O_NONBLOCK: c_int = 0o4000;
+const O_CLOEXEC: c_int = 0o2000000;
+/// Flags of `open` only used while opening, not reported by `F_GETFL`: `O_CREAT`, `O_EXCL`,
+/// `O_NOCTTY` and `O_TRUNC`
+const OPEN_ONLY_FLAGS: c_int = 0o100 | 0o200 | 0o400 | 0o1000;
+
+/// What a descriptor refers to
+enum Descriptor {
+    /// With the `FD_CLOEXEC` flag and the `open` flags, which `fcntl` reports and sets
+    Virtual { file: Box<dyn VirtualFile>, fd_flags: c_int, status_flags: c_int },
+    /// A pipe, socket or eventfd of the host's, used without holding the table so a read
+    /// waiting for data doesn't keep the writer out
+    Host(Arc<HostFd>),
+}
+
+lazy_static! {
+    static ref FILES: Mutex<HashMap<c_int, Descriptor>> = Mutex::new(HashMap::new());
+}
+
+/// bionic's `struct stat`
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Default)]
+pub(crate) struct Stat {
+    pub st_dev: u64,
+    pub st_ino: u64,
+    pub st_nlink: u64,
+    pub st_mode: u32,
+    pub st_uid: u32,
+    pub st_gid: u32,
+    __pad0: u32,
+    pub st_rdev: u64,
+    pub st_size: i64,
+    pub st_blksize: i64,
+    pub st_blocks: i64,
+    pub st_times: [i64; 6],
+    __unused: [i64; 3],
+}
+
+#[cfg(target_arch = "aarch64")]
+#[repr(C)]
+#[derive(Default)]
+pub(crate) struct Stat {
+    pub st_dev: u64,
+    pub st_ino: u64,
+    pub st_mode: u32,
+    pub st_nlink: u32,
+    pub st_uid: u32,
+    pub st_gid: u32,
+    pub st_rdev: u64,
+    __pad1: u64,
+    pub st_size: i64,
+    pub st_blksize: i32,
+    __pad2: i32,
+    pub st_blocks: i64,
+    pub st_times: [i64; 6],
+    __unused: [u32; 2],
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "arm"))]
+#[repr(C)]
+#[derive(Default)]
+pub(crate) struct Stat {
+    pub st_dev: u64,
+    __pad0: [u8; 4],
+    __st_ino: u32,
+    pub st_mode: u32,
+    pub st_nlink: u32,
+    pub st_uid: u32,
+    pub st_gid: u32,
+    pub st_rdev: u64,
+    __pad3: [u8; 4],
+    pub st_size: i64,
+    pub st_blksize: u32,
+    pub st_blocks: u64,
+    pub st_times: [u32; 6],
+    pub st_ino: u64,
+}
+
+/// `struct iovec`
+#[repr(C)]
+pub(crate) struct IoVec {
+    pub iov_base: *const c_void,
+    pub iov_len: usize,
+}
+
+/// bionic's `struct dirent`, the same on every architecture
+#[repr(C)]
+pub(crate) struct Dirent {
+    pub d_ino: u64,
+    pub d_off: i64,
+    pub d_reclen: u16,
+    pub d_type: u8,
+    pub d_name: [c_char; 256],
+}
+
+/// What a `DIR*` points to: the listing taken by `opendir` and the entry `readdir` returned last
+struct Dir {
+    entries: Vec<VirtualDirEntry>,
+    position: usize,
+    current: Dirent,
+}
+
+impl Stat {
+    fn new(metadata: &VirtualMetadata) -> Stat {
+        let file_type = match metadata {
+            VirtualMetadata { symlink: true, .. } => S_IFLNK,
+            VirtualMetadata { directory: true, .. } => S_IFDIR,
+            _ => S_IFREG,
+        };
+        // Access, modification and status change times, as seconds and nanoseconds
+        let time = metadata.modified as _;
+        Stat {
+            st_nlink: 1,
+            st_mode: file_type | (metadata.permissions & 0o7777),
+            st_size: metadata.size as i64,
+            st_blksize: 4096,
+            st_blocks: ((metadata.size + 511) / 512) as _,
+            st_times: [time, 0, time, 0, time, 0],
+            ..Stat::default()
+        }
+    }
+}
+
+fn fail<T>(errno: c_int, value: T) -> T {
+    set_errno(errno);
+    value
+}
+
+/// Absolute, normalized form of a path argument
+unsafe fn path_arg(fs: &dyn VirtualFs, path: *const c_char) -> FsResult<String> {
+    if path.is_null() {
+        return Err(EFAULT);
+    }
+    let path = CStr::from_ptr(path).to_string_lossy();
+    Ok(vfs::normalize(&fs.current_dir(), &path))
+}
+
+unsafe fn write_stat(buffer: *mut Stat, metadata: FsResult<VirtualMetadata>) -> c_int {
+    match metadata {
+        Ok(metadata) if !buffer.is_null() => {
+            buffer.write(Stat::new(&metadata));
+            0
+        }
+        Ok(_) => fail(EFAULT, -1),
+        Err(errno) => fail(errno, -1),
+    }
+}
+
+/// Copy `value` and a null terminator into `buffer`, or one from the `malloc` stub if it's null
+unsafe fn return_string(value: &str, buffer: *mut c_char, size: usize) -> *mut c_char {
+    if value.len() + 1 > size {
+        return fail(ERANGE, std::ptr::null_mut());
+    }
+    let buffer = if buffer.is_null() { malloc::malloc(value.len() + 1) as *mut c_char } else { buffer };
+    if buffer.is_null() {
+        return fail(ENOMEM, std::ptr::null_mut());
+    }
+    std::ptr::copy_nonoverlapping(value.as_ptr(), buffer.cast::<u8>(), value.len());
+    *buffer.add(value.len()) = 0;
+    buffer
+}
+
+pub(crate) fn with_file<T>(fd: c_int, error: T, operation: impl FnOnce(&mut dyn VirtualFile) -> FsResult<T>) -> T {
+    let mut files = FILES.lock().unwrap();
+    let result = match files.get_mut(&fd) {
+        Some(Descriptor::Virtual { file, .. }) => operation(file.as_mut()),
+        Some(Descriptor::Host(host)) => {
+            let host = host.clone();
+            drop(files);
+            operation(&mut &*host)
+        }
+        None => Err(EBADF),
+    };
+    result.unwrap_or_else(|errno| fail(errno, error))
+}
+
+fn install(descriptor: Descriptor) -> FsResult<c_int> {
+    install_from(&mut FILES.lock().unwrap(), descriptor, FIRST_FD)
+}
+
+/// Give `descriptor` the lowest free descriptor from `first`
+fn install_from(files: &mut HashMap<c_int, Descriptor>, descriptor: Descriptor, first: c_int) -> FsResult<c_int> {
+    let fd = (first.max(FIRST_FD)..c_int::MAX).find(|fd| !files.contains_key(fd)).ok_or(EMFILE)?;
+    files.insert(fd, descriptor);
+    Ok(fd)
+}
+
+/// Open a path argument in `fs` and give it a descriptor
+pub(crate) unsafe fn open_fd(fs: &dyn VirtualFs, path: *const c_char, flags: c_int) -> FsResult<c_int> {
+    let file = path_arg(fs, path).and_then(|path| fs.open(&path, flags))?;
+    let fd_flags = if flags & O_CLOEXEC != 0 { FD_CLOEXEC } else { 0 };
+    install(Descriptor::Virtual { file, fd_flags, status_flags: flags & !(O_CLOEXEC | OPEN_ONLY_FLAGS) })
+}
+
+/// The host's descriptor behind `fd`, if it's one of the [pipes and sockets](crate::stubs::ipc)
+pub(crate) fn host_fd(fd: c_int) -> FsResult<Option<Arc<HostFd>>> {
+    match FILES.lock().unwrap().get(&fd) {
+        Some(Descriptor::Virtual { .. }) => Ok(None),
+        Some(Descriptor::Host(host)) => Ok(Some(host.clone())),
+        None => Err(EBADF),
+    }
+}
+
+/// Give a descriptor of the host's one of the stubs, closing it if there's none left
+pub(crate) fn install_host_fd(host: HostFd) -> FsResult<c_int> {
+    install(Descriptor::Host(Arc::new(host)))
+}
+
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_open_from(path: *const c_char, flags: c_int, _mode: c_int, caller: usize) -> c_int {
+    open_fd(&*vfs::virtual_fs(caller), path, flags).unwrap_or_else(|errno| fail(errno, -1))
+}
+
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_open_2_from(path: *const c_char, flags: c_int, caller: usize) -> c_int {
+    android_loader_open_from(path, flags, 0, caller)
+}
+
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_openat_from(dir_fd: c_int, path: *const c_char, flags: c_int, mode: c_int, caller: usize) -> c_int {
+    // Only paths that don't depend on the directory descriptor are supported
+    if dir_fd != AT_FDCWD && !path.is_null() && *path != b'/' as c_char {
+        return fail(EBADF, -1);
+    }
+    android_loader_open_from(path, flags, mode, caller)
+}
+
+#[sysv64]
+pub(crate) fn close(fd: c_int) -> c_int {
+    match FILES.lock().unwrap().remove(&fd) {
+        Some(_) => 0,
+        None => fail(EBADF, -1),
+    }
+}
+
+#[sysv64]
+unsafe fn read(fd: c_int, buffer: *mut c_void, count: usize) -> isize {
+    if buffer.is_null() && count > 0 {
+        return fail(EFAULT, -1);
+    }
+    let buffer = std::slice::from_raw_parts_mut(buffer as *mut u8, count);
+    with_file(fd, -1, |file| file.read(buffer).map(|len| len as isize))
+}
+
+#[sysv64]
+unsafe fn write(fd: c_int, buffer: *const c_void, count: usize) -> isize {
+    if buffer.is_null() && count > 0 {
+        return fail(EFAULT, -1);
+    }
+    write_bytes(fd, std::slice::from_raw_parts(buffer as *const u8, count))
+}
+
+fn write_bytes(fd: c_int, bytes: &[u8]) -> isize {
+    if (0..FIRST_FD).contains(&fd) {
+        stdio::write_output(fd, bytes);
+        return bytes.len() as isize;
+    }
+    with_file(fd, -1, |file| file.write(bytes).map(|len| len as isize))
+}
+
+/// The buffers are gathered into a single write, so the sink and the file see them as one
+#[sysv64]
+unsafe fn writev(fd: c_int, iov: *const IoVec, count: c_int) -> isize {
+    if !(0..=IOV_MAX).contains(&count) {
+        return fail(EINVAL, -1);
+    }
+    if iov.is_null() && count > 0 {
+        return fail(EFAULT, -1);
+    }
+    let buffers = if count == 0 { &[] } else { std::slice::from_raw_parts(iov, count as usize) };
+    let mut bytes = Vec::new();
+    for buffer in buffers.iter().filter(|buffer| buffer.iov_len > 0) {
+        if buffer.iov_base.is_null() {
+            return fail(EFAULT, -1);
+        }
+        bytes.extend_from_slice(std::slice::from_raw_parts(buffer.iov_base as *const u8, buffer.iov_len));
+    }
+    write_bytes(fd, &bytes)
+}
+
+pub(crate) fn seek(fd: c_int, offset: i64, whence: c_int) -> i64 {
+    let position = match whence {
+        0 if offset >= 0 => SeekFrom::Start(offset as u64),
+        1 => SeekFrom::Current(offset),
+        2 => SeekFrom::End(offset),
+        _ => return fail(EINVAL, -1),
+    };
+    with_file(fd, -1, |file| file.seek(position).map(|position| position as i64))
+}
+
+#[sysv64]
+fn lseek(fd: c_int, offset: c_long, whence: c_int) -> c_long {
+    seek(fd, offset as i64, whence) as c_long
+}
+
+#[sysv64]
+fn lseek64(fd: c_int, offset: i64, whence: c_int) -> i64 {
+    seek(fd, offset, whence)
+}
+
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_stat_from(path: *const c_char, buffer: *mut Stat, caller: usize) -> c_int {
+    let fs = vfs::virtual_fs(caller);
+    write_stat(buffer, path_arg(&*fs, path).and_then(|path| fs.stat(&path)))
+}
+
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_lstat_from(path: *const c_char, buffer: *mut Stat, caller: usize) -> c_int {
+    let fs = vfs::virtual_fs(caller);
+    write_stat(buffer, path_arg(&*fs, path).and_then(|path| fs.lstat(&path)))
+}
+
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_fstatat_from(dir_fd: c_int, path: *const c_char, buffer: *mut Stat, flags: c_int, caller: usize) -> c_int {
+    if flags & AT_EMPTY_PATH != 0 && !path.is_null() && *path == 0 {
+        return fstat(dir_fd, buffer);
+    }
+    // Like `openat`, only paths that don't depend on the directory descriptor
+    if dir_fd != AT_FDCWD && !path.is_null() && *path != b'/' as c_char {
+        return fail(EBADF, -1);
+    }
+    if flags & AT_SYMLINK_NOFOLLOW != 0 {
+        android_loader_lstat_from(path, buffer, caller)
+    } else {
+        android_loader_stat_from(path, buffer, caller)
+    }
+}
+
+#[sysv64]
+unsafe fn fstat(fd: c_int, buffer: *mut Stat) -> c_int {
+    let metadata = match FILES.lock().unwrap().get(&fd) {
+        Some(Descriptor::Virtual { file, .. }) => file.metadata(),
+        Some(Descriptor::Host(host)) => (&**host).metadata(),
+        None => Err(EBADF),
+    };
+    write_stat(buffer, metadata)
+}
+
+/// The descriptor flags and the file status flags, file locks, which always succeed as no
+/// other process can hold them, and duplicating the host's pipes and sockets. Duplicates share
+/// the host's descriptor, flags included. Virtual files can't be duplicated.
+#[sysv64]
+unsafe fn fcntl(fd: c_int, command: c_int, arg: usize) -> c_int {
+    let mut files = FILES.lock().unwrap();
+    let descriptor = match files.get_mut(&fd) {
+        Some(descriptor) => descriptor,
+        None => return fail(EBADF, -1),
+    };
+    match (command, descriptor) {
+        (F_DUPFD | F_DUPFD_CLOEXEC, Descriptor::Host(host)) => {
+            let duplicate = Descriptor::Host(host.clone());
+            install_from(&mut files, duplicate, arg as c_int).unwrap_or_else(|errno| fail(errno, -1))
+        }
+        (F_GETFD | F_SETFD | F_GETFL | F_SETFL, Descriptor::Host(host)) => {
+            let host = host.clone();
+            drop(files);
+            host.fcntl(command, arg).unwrap_or_else(|errno| fail(errno, -1))
+        }
+        (F_GETFD, Descriptor::Virtual { fd_flags, .. }) => *fd_flags,
+        (F_SETFD, Descriptor::Virtual { fd_flags, .. }) => {
+            *fd_flags = arg as c_int & FD_CLOEXEC;
+            0
+        }
+        (F_GETFL, Descriptor::Virtual { status_flags, .. }) => *status_flags,
+        // Only these can be changed after opening
+        (F_SETFL, Descriptor::Virtual { status_flags, .. }) => {
+            *status_flags = (*status_flags & !(O_APPEND | O_NONBLOCK)) | (arg as c_int & (O_APPEND | O_NONBLOCK));
+            0
+        }
+        // `struct flock` starts with `l_type`
+        (F_GETLK | F_GETLK64, _) if arg != 0 => {
+            *(arg as *mut i16) = F_UNLCK;
+            0
+        }
+        (F_GETLK..=F_SETLKW | F_GETLK64..=F_SETLKW64, _) => 0,
+        _ => fail(EINVAL, -1),
+    }
+}
+
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_access_from(path: *const c_char, mode: c_int, caller: usize) -> c_int {
+    let fs = vfs::virtual_fs(caller);
+    match path_arg(&*fs, path).and_then(|path| fs.access(&path, mode)) {
+        Ok(()) => 0,
+        Err(errno) => fail(errno, -1),
+    }
+}
+
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_realpath_from(path: *const c_char, resolved: *mut c_char, caller: usize) -> *mut c_char {
+    let fs = vfs::virtual_fs(caller);
+    match path_arg(&*fs, path).and_then(|path| fs.realpath(&path)) {
+        Ok(real) => return_string(&real, resolved, PATH_MAX),
+        Err(errno) => fail(errno, std::ptr::null_mut()),
+    }
+}
+
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_getcwd_from(buffer: *mut c_char, size: usize, caller: usize) -> *mut c_char {
+    let size = if buffer.is_null() && size == 0 { usize::MAX } else { size };
+    return_string(&vfs::virtual_fs(caller).current_dir(), buffer, size)
+}
+
+/// The listing includes `.` and `..` first, like the real thing
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_opendir_from(path: *const c_char, caller: usize) -> *mut c_void {
+    let fs = vfs::virtual_fs(caller);
+    let mut entries = match path_arg(&*fs, path).and_then(|path| fs.read_dir(&path)) {
+        Ok(entries) => entries,
+        Err(errno) => return fail(errno, std::ptr::null_mut()),
+    };
+    let dots = [".", ".."].map(|name| VirtualDirEntry { name: name.to_owned(), directory: true });
+    entries.splice(0..0, dots);
+    let current = Dirent { d_ino: 0, d_off: 0, d_reclen: 0, d_type: 0, d_name: [0; 256] };
+    Box::into_raw(Box::new(Dir { entries, position: 0, current })) as *mut c_void
+}
+
+/// Names too long for `d_name` are truncated
+#[sysv64]
+unsafe fn readdir(dir: *mut c_void) -> *mut Dirent {
+    let dir = match (dir as *mut Dir).as_mut() {
+        Some(dir) => dir,
+        None => return fail(EBADF, std::ptr::null_mut()),
+    };
+    let entry = match dir.entries.get(dir.position) {
+        Some(entry) => entry,
+        // The end of the listing leaves errno alone
+        None => return std::ptr::null_mut(),
+    };
+    dir.position += 1;
+
+    let name = &entry.name.as_bytes()[..entry.name.len().min(255)];
+    let current = &mut dir.current;
+    current.d_ino = dir.position as u64;
+    current.d_off = dir.position as i64;
+    current.d_reclen = std::mem::size_of::<Dirent>() as u16;
+    current.d_type = if entry.directory { DT_DIR } else { DT_REG };
+    current.d_name = [0; 256];
+    for (to, from) in current.d_name.iter_mut().zip(name) {
+        *to = *from as c_char;
+    }
+    current
+}
+
+#[sysv64]
+unsafe fn rewinddir(dir: *mut c_void) {
+    if let Some(dir) = (dir as *mut Dir).as_mut() {
+        dir.position = 0;
+    }
+}
+
+#[sysv64]
+unsafe fn closedir(dir: *mut c_void) -> c_int {
+    if dir.is_null() {
+        return fail(EBADF, -1);
+    }
+    drop(Box::from_raw(dir as *mut Dir));
+    0
+}
+
+caller_entry!("android_loader_open", 3, "android_loader_open_from");
+caller_entry!("android_loader_open_2", 2, "android_loader_open_2_from");
+caller_entry!("android_loader_openat", 4, "android_loader_openat_from");
+caller_entry!("android_loader_stat", 2, "android_loader_stat_from");
+caller_entry!("android_loader_lstat", 2, "android_loader_lstat_from");
+caller_entry!("android_loader_fstatat", 4, "android_loader_fstatat_from");
+caller_entry!("android_loader_access", 2, "android_loader_access_from");
+caller_entry!("android_loader_realpath", 2, "android_loader_realpath_from");
+caller_entry!("android_loader_getcwd", 2, "android_loader_getcwd_from");
+caller_entry!("android_loader_opendir", 1, "android_loader_opendir_from");
+
+extern "C" {
+    fn android_loader_open();
+    fn android_loader_open_2();
+    fn android_loader_openat();
+    fn android_loader_stat();
+    fn android_loader_lstat();
+    fn android_loader_fstatat();
+    fn android_loader_access();
+    fn android_loader_realpath();
+    fn android_loader_getcwd();
+    fn android_loader_opendir();
+}
+
+/// The stubs taking their caller, as (implementation, arguments before the caller), for
+/// binding to the libraries importing them
+pub(crate) fn caller_sensitive(symbol_name: &str) -> Option<(usize, usize)> {
+    Some(match symbol_name {
+        "open" | "open64" => (android_loader_open_from as *const () as usize, 3),
+        "__open_2" => (android_loader_open_2_from as *const () as usize, 2),
+        "stat" | "stat64" => (android_loader_stat_from as *const () as usize, 2),
+        "lstat" | "lstat64" => (android_loader_lstat_from as *const () as usize, 2),
+        "access" => (android_loader_access_from as *const () as usize, 2),
+        "realpath" => (android_loader_realpath_from as *const () as usize, 2),
+        "getcwd" => (android_loader_getcwd_from as *const () as usize, 2),
+        "opendir" => (android_loader_opendir_from as *const () as usize, 1),
+        // Binding stubs only pass the first 3 arguments on arm, where these keep the caller
+        // entry and its return address
+        #[cfg(not(target_arch = "arm"))]
+        "openat" | "openat64" => (android_loader_openat_from as *const () as usize, 4),
+        #[cfg(not(target_arch = "arm"))]
+        "fstatat" | "fstatat64" | "newfstatat" => (android_loader_fstatat_from as *const () as usize, 4),
+        _ => return None,
+    })
+}
+
+pub(crate) fn lookup(symbol_name: &str) -> Option<*const ()> {
+    Some(match symbol_name {
+        "open" | "open64" => android_loader_open as *const (),
+        "__open_2" => android_loader_open_2 as *const (),
+        "openat" | "openat64" => android_loader_openat as *const (),
+        "close" => close as *const (),
+        "read" => read as *const (),
+        "write" => write as *const (),
+        "writev" => writev as *const (),
+        "lseek" => lseek as *const (),
+        "lseek64" => lseek64 as *const (),
+        "stat" | "stat64" => android_loader_stat as *const (),
+        "lstat" | "lstat64" => android_loader_lstat as *const (),
+        "fstatat" | "fstatat64" | "newfstatat" => android_loader_fstatat as *const (),
+        "fstat" | "fstat64" => fstat as *const (),
+        "fcntl" | "fcntl64" => fcntl as *const (),
+        "access" => android_loader_access as *const (),
+        "realpath" => android_loader_realpath as *const (),
+        "getcwd" => android_loader_getcwd as *const (),
+        "opendir" => android_loader_opendir as *const (),
+        "readdir" | "readdir64" => readdir as *const (),
+        "rewinddir" => rewinddir as *const (),
+        "closedir" => closedir as *const (),
+        _ => return None,
+    })
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::ffi::CStr;
+    use std::os::raw::{c_char, c_int, c_void};
+
+    use crate::android_library::AndroidLibrary;
+    use crate::android_loader::AndroidLoader;
+    use std::sync::{Arc, Mutex};
+
+    use crate::stubs::errno::{errno, EACCES, EAGAIN, EBADF, EINVAL, ENOENT, ENOTDIR, EROFS};
+    use crate::stubs::fs::{
+        Dirent, IoVec, Stat, DT_DIR, DT_REG, FD_CLOEXEC, F_DUPFD, F_GETFD, F_GETFL, F_GETLK, F_SETFD, F_SETFL, F_SETLKW, F_UNLCK,
+        O_CLOEXEC, O_NONBLOCK, S_IFDIR, S_IFREG,
+    };
+    use crate::stubs::stdio::{set_output_sink, LogSink, OutputSink};
+    use crate::test_elf::TestElf;
+    use crate::vfs::MemoryFs;
+
+    #[test]
+    fn loaded_file_access() {
+        let mut elf = TestElf::new();
+        for name in ["stat", "open", "read", "close", "access", "realpath", "getcwd", "malloc_usable_size", "free"] {
+            elf.thunk(&format!("call_{name}"), name);
+        }
+        let fs = MemoryFs::new().file("/data/app/config.txt", "hello").current_dir("/data");
+        let library = AndroidLoader::new().virtual_fs(fs).load_library_from_bytes(elf.build()).unwrap();
+        macro_rules! function {
+            ($name:literal: $signature:ty) => {
+                unsafe { std::mem::transmute::<*const (), $signature>(library.get_symbol(concat!("call_", $name)).unwrap()) }
+            };
+        }
+        let stat = function!("stat": extern "C" fn(*const c_char, *mut Stat) -> c_int);
+        let open = function!("open": extern "C" fn(*const c_char, c_int, c_int) -> c_int);
+        let read = function!("read": extern "C" fn(c_int, *mut c_void, usize) -> isize);
+        let close = function!("close": extern "C" fn(c_int) -> c_int);
+        let access = function!("access": extern "C" fn(*const c_char, c_int) -> c_int);
+        let realpath = function!("realpath": extern "C" fn(*const c_char, *mut c_char) -> *mut c_char);
+        let getcwd = function!("getcwd": extern "C" fn(*mut c_char, usize) -> *mut c_char);
+        let usable_size = function!("malloc_usable_size": extern "C" fn(*const c_void) -> usize);
+        let free = function!("free": extern "C" fn(*mut c_void));
+
+        let mut metadata = Stat::default();
+        assert_eq!(stat(b"app/config.txt\0".as_ptr() as *const c_char, &mut metadata), 0);
+        assert_eq!(metadata.st_size, 5);
+        assert_eq!(metadata.st_mode & 0o170000, S_IFREG);
+        assert_eq!(stat(b"/data/app\0".as_ptr() as *const c_char, &mut metadata), 0);
+        assert_eq!(metadata.st_mode & 0o170000, S_IFDIR);
+        assert_eq!(stat(b"/data/missing\0".as_ptr() as *const c_char, &mut metadata), -1);
+        assert_eq!(errno(), ENOENT);
+
+        let fd = open(b"/data/./app/../app/config.txt\0".as_ptr() as *const c_char, 0, 0);
+        assert!(fd >= 3);
+        let mut buffer = [0u8; 16];
+        assert_eq!(read(fd, buffer.as_mut_ptr() as *mut c_void, buffer.len()), 5);
+        assert_eq!(&buffer[..5], b"hello");
+        assert_eq!(read(fd, buffer.as_mut_ptr() as *mut c_void, buffer.len()), 0);
+        assert_eq!(close(fd), 0);
+        assert_eq!(close(fd), -1);
+        assert_eq!(open(b"/data/app/config.txt\0".as_ptr() as *const c_char, 2, 0), -1);
+        assert_eq!(errno(), EROFS);
+
+        assert_eq!(access(b"/data/app/config.txt\0".as_ptr() as *const c_char, 4), 0);
+        let mut resolved = [0 as c_char; 4096];
+        realpath(b"app//config.txt\0".as_ptr() as *const c_char, resolved.as_mut_ptr());
+        assert_eq!(unsafe { CStr::from_ptr(resolved.as_ptr()) }.to_bytes(), b"/data/app/config.txt");
+        assert_eq!(unsafe { CStr::from_ptr(getcwd(resolved.as_mut_ptr(), resolved.len())) }.to_bytes(), b"/data");
+        assert!(getcwd(resolved.as_mut_ptr(), 3).is_null());
+        // Allocated by the `malloc` stub without a buffer
+        let cwd = getcwd(std::ptr::null_mut(), 0);
+        assert_eq!(unsafe { CStr::from_ptr(cwd) }.to_bytes(), b"/data");
+        assert_eq!(usable_size(cwd as *const c_void), 6);
+        free(cwd as *mut c_void);
+    }
+
+    #[test]
+    fn filesystems_per_loader() {
+        // stat through a thunk is bound to the library, access is called from it
+        let mut elf = TestElf::new();
+        elf.thunk("call_stat", "stat");
+        elf.caller("call_access", &[0x48, 0x83, 0xec, 0x08], "access", &[0x48, 0x83, 0xc4, 0x08, 0xc3]);
+        let first = AndroidLoader::new().virtual_fs(MemoryFs::new().file("/data/first", "1")).load_library_from_bytes(elf.build()).unwrap();
+        let second = AndroidLoader::new().virtual_fs(MemoryFs::new().file("/data/second", "22")).load_library_from_bytes(elf.build()).unwrap();
+
+        for (library, present, size, absent) in [(&first, &b"/data/first\0"[..], 1, &b"/data/second\0"[..]), (&second, b"/data/second\0", 2, b"/data/first\0")] {
+            let stat: extern "C" fn(*const c_char, *mut Stat) -> c_int = unsafe { std::mem::transmute(library.get_symbol("call_stat").unwrap()) };
+            let access: extern "C" fn(*const c_char, c_int) -> c_int = unsafe { std::mem::transmute(library.get_symbol("call_access").unwrap()) };
+            let mut metadata = Stat::default();
+            assert_eq!(stat(present.as_ptr() as *const c_char, &mut metadata), 0);
+            assert_eq!(metadata.st_size, size);
+            assert_eq!(stat(absent.as_ptr() as *const c_char, &mut metadata), -1);
+            assert_eq!(errno(), ENOENT);
+            assert_eq!(access(present.as_ptr() as *const c_char, 0), 0);
+            assert_eq!(access(absent.as_ptr() as *const c_char, 0), -1);
+        }
+    }
+
+    #[test]
+    fn loaded_file_metadata() {
+        let mut elf = TestElf::new();
+        for name in ["open", "fstat", "fstatat", "close"] {
+            elf.thunk(&format!("call_{name}"), name);
+        }
+        let fs = MemoryFs::new().file("/data/blob.bin", vec![7; 12345]).modified(1_700_000_000);
+        let library = AndroidLoader::new().virtual_fs(fs).load_library_from_bytes(elf.build()).unwrap();
+        macro_rules! function {
+            ($name:literal: $signature:ty) => {
+                unsafe { std::mem::transmute::<*const (), $signature>(library.get_symbol(concat!("call_", $name)).unwrap()) }
+            };
+        }
+        let open = function!("open": extern "C" fn(*const c_char, c_int, c_int) -> c_int);
+        let fstat = function!("fstat": extern "C" fn(c_int, *mut Stat) -> c_int);
+        let fstatat = function!("fstatat": extern "C" fn(c_int, *const c_char, *mut Stat, c_int) -> c_int);
+        let close = function!("close": extern "C" fn(c_int) -> c_int);
+
+        let fd = open(b"/data/blob.bin\0".as_ptr() as *const c_char, 0, 0);
+        let mut metadata = Stat::default();
+        assert_eq!(fstat(fd, &mut metadata), 0);
+        assert_eq!(metadata.st_size, 12345);
+        assert_eq!(metadata.st_blocks, 25);
+        assert_eq!(metadata.st_mode, S_IFREG | 0o644);
+        assert_eq!(metadata.st_times, [1_700_000_000, 0, 1_700_000_000, 0, 1_700_000_000, 0]);
+
+        let mut by_path = Stat::default();
+        assert_eq!(fstatat(-100, b"/data/blob.bin\0".as_ptr() as *const c_char, &mut by_path, 0x100), 0);
+        assert_eq!(by_path.st_size, 12345);
+        let mut empty_path = Stat::default();
+        assert_eq!(fstatat(fd, b"\0".as_ptr() as *const c_char, &mut empty_path, 0x1000), 0);
+        assert_eq!(empty_path.st_size, 12345);
+        assert_eq!(fstatat(fd, b"blob.bin\0".as_ptr() as *const c_char, &mut by_path, 0), -1);
+        assert_eq!(errno(), EBADF);
+
+        assert_eq!(close(fd), 0);
+        assert_eq!(fstat(fd, &mut metadata), -1);
+        assert_eq!(errno(), EBADF);
+    }
+
+    #[test]
+    fn loaded_descriptor_flags() {
+        let mut elf = TestElf::new();
+        for name in ["open", "fcntl", "pipe", "read", "write", "close"] {
+            elf.thunk(&format!("call_{name}"), name);
+        }
+        let fs = MemoryFs::new().file("/data/flags.txt", "flags");
+        let library = AndroidLoader::new().virtual_fs(fs).load_library_from_bytes(elf.build()).unwrap();
+        macro_rules! function {
+            ($name:literal: $signature:ty) => {
+                unsafe { std::mem::transmute::<*const (), $signature>(library.get_symbol(concat!("call_", $name)).unwrap()) }
+            };
+        }
+        let open = function!("open": extern "C" fn(*const c_char, c_int, c_int) -> c_int);
+        let fcntl = function!("fcntl": extern "C" fn(c_int, c_int, usize) -> c_int);
+        let pipe = function!("pipe": extern "C" fn(*mut c_int) -> c_int);
+        let read = function!("read": extern "C" fn(c_int, *mut c_void, usize) -> isize);
+        let write = function!("write": extern "C" fn(c_int, *const c_void, usize) -> isize);
+        let close = function!("close": extern "C" fn(c_int) -> c_int);
+
+        let fd = open(b"/data/flags.txt\0".as_ptr() as *const c_char, 0, 0);
+        assert_eq!(fcntl(fd, F_GETFD, 0), 0);
+        assert_eq!(fcntl(fd, F_SETFD, FD_CLOEXEC as usize), 0);
+        assert_eq!(fcntl(fd, F_GETFD, 0), FD_CLOEXEC);
+        assert_eq!(fcntl(fd, F_GETFL, 0), 0);
+        assert_eq!(fcntl(fd, F_SETFL, (O_NONBLOCK | O_CLOEXEC) as usize), 0);
+        assert_eq!(fcntl(fd, F_GETFL, 0), O_NONBLOCK);
+        // Locks are always free
+        let mut lock = [0i16; 16];
+        assert_eq!(fcntl(fd, F_GETLK, lock.as_mut_ptr() as usize), 0);
+        assert_eq!(lock[0], F_UNLCK);
+        assert_eq!(fcntl(fd, F_SETLKW, lock.as_mut_ptr() as usize), 0);
+        assert_eq!(fcntl(fd, F_DUPFD, 0), -1);
+        assert_eq!(errno(), EINVAL);
+        close(fd);
+        assert_eq!(fcntl(fd, F_GETFD, 0), -1);
+        assert_eq!(errno(), EBADF);
+
+        let fd = open(b"/data/flags.txt\0".as_ptr() as *const c_char, O_CLOEXEC, 0);
+        assert_eq!((fcntl(fd, F_GETFD, 0), fcntl(fd, F_GETFL, 0)), (FD_CLOEXEC, 0));
+        close(fd);
+
+        // The host's pipes get the host's flags, and duplicates write to the same pipe
+        let mut fds = [0; 2];
+        assert_eq!(pipe(fds.as_mut_ptr()), 0);
+        assert_eq!(fcntl(fds[0], F_SETFL, O_NONBLOCK as usize), 0);
+        assert_eq!(fcntl(fds[0], F_GETFL, 0) & O_NONBLOCK, O_NONBLOCK);
+        let mut buffer = [0u8; 8];
+        assert_eq!(read(fds[0], buffer.as_mut_ptr() as *mut c_void, buffer.len()), -1);
+        assert_eq!(errno(), EAGAIN);
+        let duplicate = fcntl(fds[1], F_DUPFD, 10);
+        assert!(duplicate >= 10);
+        close(fds[1]);
+        assert_eq!(write(duplicate, b"dup".as_ptr() as *const c_void, 3), 3);
+        assert_eq!(read(fds[0], buffer.as_mut_ptr() as *mut c_void, buffer.len()), 3);
+        close(duplicate);
+        close(fds[0]);
+    }
+
+    #[test]
+    fn loaded_directory_listing() {
+        let mut elf = TestElf::new();
+        for name in ["opendir", "readdir", "closedir"] {
+            elf.thunk(&format!("call_{name}"), name);
+        }
+        let fs = MemoryFs::new().file("/data/app/lib/libx.so", "").file("/data/app/b.txt", "").file("/data/app/a.txt", "");
+        let library = AndroidLoader::new().virtual_fs(fs).load_library_from_bytes(elf.build()).unwrap();
+        let function = |name: &str| library.get_symbol(&format!("call_{name}")).unwrap();
+        let opendir: extern "C" fn(*const c_char) -> *mut c_void = unsafe { std::mem::transmute(function("opendir")) };
+        let readdir: extern "C" fn(*mut c_void) -> *mut Dirent = unsafe { std::mem::transmute(function("readdir")) };
+        let closedir: extern "C" fn(*mut c_void) -> c_int = unsafe { std::mem::transmute(function("closedir")) };
+        let list = |path: &[u8]| {
+            let dir = opendir(path.as_ptr() as *const c_char);
+            assert!(!dir.is_null());
+            let mut entries = Vec::new();
+            while let Some(entry) = unsafe { readdir(dir).as_ref() } {
+                let name = unsafe { CStr::from_ptr(entry.d_name.as_ptr()) }.to_string_lossy().into_owned();
+                entries.push((name, entry.d_type));
+            }
+            assert_eq!(closedir(dir), 0);
+            entries
+        };
+
+        let dir = |name: &str| (name.to_owned(), DT_DIR);
+        let file = |name: &str| (name.to_owned(), DT_REG);
+        assert_eq!(list(b"/data/app\0"), [dir("."), dir(".."), file("a.txt"), file("b.txt"), dir("lib")]);
+        assert_eq!(list(b"/\0"), [dir("."), dir(".."), dir("data")]);
+        assert!(opendir(b"/data/app/a.txt\0".as_ptr() as *const c_char).is_null());
+        assert_eq!(errno(), ENOTDIR);
+        assert!(opendir(b"/data/missing\0".as_ptr() as *const c_char).is_null());
+        assert_eq!(errno(), ENOENT);
+
+        // Libraries loaded without a filesystem have every access denied
+        let denied = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let opendir: extern "C" fn(*const c_char) -> *mut c_void = unsafe { std::mem::transmute(denied.get_symbol("call_opendir").unwrap()) };
+        assert!(opendir(b"/data/app\0".as_ptr() as *const c_char).is_null());
+        assert_eq!(errno(), EACCES);
+    }
+
+    /// Keeps each write to stderr, as other tests may write to stdout meanwhile
+    struct StderrSink(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl OutputSink for StderrSink {
+        fn write(&mut self, fd: c_int, bytes: &[u8]) {
+            if fd == 2 {
+                self.0.lock().unwrap().push(bytes.to_vec());
+            }
+        }
+    }
+
+    #[test]
+    fn loaded_standard_output() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        set_output_sink(StderrSink(written.clone()));
+
+        let mut elf = TestElf::new();
+        elf.thunk("call_output_write", "write");
+        elf.thunk("call_output_writev", "writev");
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let write: extern "C" fn(c_int, *const c_void, usize) -> isize =
+            unsafe { std::mem::transmute(library.get_symbol("call_output_write").unwrap()) };
+        let writev: extern "C" fn(c_int, *const IoVec, c_int) -> isize =
+            unsafe { std::mem::transmute(library.get_symbol("call_output_writev").unwrap()) };
+
+        assert_eq!(write(2, b"fatal: ".as_ptr() as *const c_void, 7), 7);
+        let parts: [&[u8]; 3] = [b"out of ", b"", b"memory\n"];
+        let iov = parts.map(|part| IoVec { iov_base: part.as_ptr() as *const c_void, iov_len: part.len() });
+        assert_eq!(writev(2, iov.as_ptr(), 3), 14);
+        // Neither a standard descriptor nor an open file
+        assert_eq!(write(99, b"lost".as_ptr() as *const c_void, 4), -1);
+        assert_eq!(errno(), EBADF);
+        set_output_sink(LogSink::default());
+
+        assert_eq!(*written.lock().unwrap(), [&b"fatal: "[..], b"out of memory\n"]);
+    }
+}
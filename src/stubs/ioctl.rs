@@ -0,0 +1,123 @@
+//! `ioctl`, which is mostly device-specific and can't be emulated in general.
+//!
+//! A handler set with [`set_ioctl_handler`] sees every call first. What it leaves to the
+//! default goes to the host for its [pipes and sockets](crate::stubs::ipc), e.g. `FIONREAD`,
+//! and fails with `ENOTTY` for virtual files, as it does for regular files on Linux.
+
+use lazy_static::lazy_static;
+use std::os::raw::{c_int, c_void};
+use std::sync::{Arc, Mutex};
+
+use crate::stubs::errno::{set_errno, ENOTTY};
+use crate::stubs::fs;
+use crate::sysv64;
+
+/// What an `ioctl` call does, as decided by an [`IoctlHandler`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoctlAction {
+    /// What it does without a handler
+    Default,
+    /// Return this without doing anything, e.g. 0 to pretend it worked
+    Return(c_int),
+    /// Fail with this `errno`
+    Fail(c_int),
+}
+
+/// Decides `ioctl` calls from the descriptor, the request (bionic's `int`, as unsigned so
+/// `_IOR`-style numbers compare as written) and the argument
+pub type IoctlHandler = dyn Fn(c_int, u32, *mut c_void) -> IoctlAction + Send + Sync;
+
+lazy_static! {
+    static ref HANDLER: Mutex<Option<Arc<IoctlHandler>>> = Mutex::new(None);
+}
+
+/// Decide the `ioctl` calls of loaded libraries with `handler` from now on
+pub fn set_ioctl_handler(handler: impl Fn(c_int, u32, *mut c_void) -> IoctlAction + Send + Sync + 'static) {
+    *HANDLER.lock().unwrap() = Some(Arc::new(handler));
+}
+
+/// Go back to the default for every `ioctl` call
+pub fn clear_ioctl_handler() {
+    *HANDLER.lock().unwrap() = None;
+}
+
+#[sysv64]
+unsafe fn ioctl(fd: c_int, request: c_int, arg: *mut c_void) -> c_int {
+    // Not held during the call, so the handler can call into the stubs itself
+    let handler = HANDLER.lock().unwrap().clone();
+    let action = handler.map_or(IoctlAction::Default, |handler| handler(fd, request as u32, arg));
+    let result = match action {
+        IoctlAction::Return(value) => return value,
+        IoctlAction::Fail(errno) => Err(errno),
+        IoctlAction::Default => fs::host_fd(fd).and_then(|host| match host {
+            Some(host) => host.ioctl(request as u32, arg),
+            None => Err(ENOTTY),
+        }),
+    };
+    result.unwrap_or_else(|errno| {
+        set_errno(errno);
+        -1
+    })
+}
+
+pub(crate) fn lookup(symbol_name: &str) -> Option<*const ()> {
+    match symbol_name {
+        "ioctl" => Some(ioctl as *const ()),
+        _ => None,
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64", target_os = "linux"))]
+mod tests {
+    use std::os::raw::{c_int, c_void};
+
+    use crate::android_library::AndroidLibrary;
+    use crate::stubs::errno::{errno, EBADF, ENOTTY, EPERM};
+    use crate::stubs::ioctl::{clear_ioctl_handler, set_ioctl_handler, IoctlAction};
+    use crate::test_elf::TestElf;
+
+    const FIONREAD: c_int = 0x541b;
+    const TCGETS: u32 = 0x5401;
+
+    #[test]
+    fn loaded_ioctl() {
+        let mut elf = TestElf::new();
+        for name in ["ioctl", "pipe", "write", "close"] {
+            elf.thunk(&format!("call_{name}"), name);
+        }
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let function = |name: &str| library.get_symbol(&format!("call_{name}")).unwrap();
+        let ioctl: extern "C" fn(c_int, c_int, *mut c_void) -> c_int = unsafe { std::mem::transmute(function("ioctl")) };
+        let pipe: extern "C" fn(*mut c_int) -> c_int = unsafe { std::mem::transmute(function("pipe")) };
+        let write: extern "C" fn(c_int, *const c_void, usize) -> isize = unsafe { std::mem::transmute(function("write")) };
+        let close: extern "C" fn(c_int) -> c_int = unsafe { std::mem::transmute(function("close")) };
+
+        // The host's pipes answer for themselves
+        let mut fds = [0; 2];
+        assert_eq!(pipe(fds.as_mut_ptr()), 0);
+        assert_eq!(write(fds[1], b"queued".as_ptr() as *const c_void, 6), 6);
+        let mut available: c_int = 0;
+        assert_eq!(ioctl(fds[0], FIONREAD, &mut available as *mut c_int as *mut c_void), 0);
+        assert_eq!(available, 6);
+        assert_eq!(ioctl(fds[0], TCGETS as c_int, std::ptr::null_mut()), -1);
+        assert_eq!(errno(), ENOTTY);
+        assert_eq!(ioctl(1000, FIONREAD, &mut available as *mut c_int as *mut c_void), -1);
+        assert_eq!(errno(), EBADF);
+
+        let pipe_fd = fds[0];
+        set_ioctl_handler(move |fd, request, _| match request {
+            TCGETS => IoctlAction::Return(0),
+            _ if fd == pipe_fd => IoctlAction::Fail(EPERM),
+            _ => IoctlAction::Default,
+        });
+        assert_eq!(ioctl(fds[0], TCGETS as c_int, std::ptr::null_mut()), 0);
+        assert_eq!(ioctl(fds[0], FIONREAD, &mut available as *mut c_int as *mut c_void), -1);
+        assert_eq!(errno(), EPERM);
+        assert_eq!(ioctl(fds[1], FIONREAD, &mut available as *mut c_int as *mut c_void), 0);
+        clear_ioctl_handler();
+        assert_eq!(ioctl(fds[0], TCGETS as c_int, std::ptr::null_mut()), -1);
+
+        close(fds[0]);
+        close(fds[1]);
+    }
+}
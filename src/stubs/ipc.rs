@@ -0,0 +1,228 @@
+//! Pipes, socket pairs and eventfds for a library's own threads to talk to each other,
+//! created by the host and given descriptors of the [file stubs](crate::stubs::fs).
+//!
+//! They're read and written with the host's `read` and `write`, so they block and fail as
+//! the host's do, without holding up the other descriptors. Only on Linux and Android, whose
+//! flags bionic's match.
+
+use std::io::SeekFrom;
+use std::os::raw::{c_int, c_uint, c_void};
+
+use crate::stubs::errno::{set_errno, EIO, EMFILE, ESPIPE};
+use crate::stubs::fs;
+use crate::sysv64;
+use crate::vfs::{FsResult, VirtualFile, VirtualMetadata};
+
+/// A descriptor of the host's, closed when dropped
+pub(crate) struct HostFd(c_int);
+
+/// The host's `errno` after a failed call
+fn host_errno() -> c_int {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(EIO)
+}
+
+impl HostFd {
+    fn read(&self, buffer: &mut [u8]) -> FsResult<usize> {
+        match unsafe { libc::read(self.0, buffer.as_mut_ptr() as *mut c_void, buffer.len() as _) } {
+            -1 => Err(host_errno()),
+            len => Ok(len as usize),
+        }
+    }
+
+    fn write(&self, buffer: &[u8]) -> FsResult<usize> {
+        match unsafe { libc::write(self.0, buffer.as_ptr() as *const c_void, buffer.len() as _) } {
+            -1 => Err(host_errno()),
+            len => Ok(len as usize),
+        }
+    }
+
+    /// `fcntl` of the host's descriptor, for a command taking an integer or nothing
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) fn fcntl(&self, command: c_int, arg: usize) -> FsResult<c_int> {
+        match unsafe { libc::fcntl(self.0, command, arg) } {
+            -1 => Err(host_errno()),
+            result => Ok(result),
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub(crate) fn fcntl(&self, _command: c_int, _arg: usize) -> FsResult<c_int> {
+        Err(crate::stubs::errno::EINVAL)
+    }
+
+    /// `ioctl` of the host's descriptor
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(crate) unsafe fn ioctl(&self, request: u32, arg: *mut c_void) -> FsResult<c_int> {
+        match libc::ioctl(self.0, request as _, arg) {
+            -1 => Err(host_errno()),
+            result => Ok(result),
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub(crate) unsafe fn ioctl(&self, _request: u32, _arg: *mut c_void) -> FsResult<c_int> {
+        Err(crate::stubs::errno::ENOTTY)
+    }
+}
+
+impl Drop for HostFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+impl VirtualFile for &HostFd {
+    fn read(&mut self, buffer: &mut [u8]) -> FsResult<usize> {
+        HostFd::read(self, buffer)
+    }
+
+    fn write(&mut self, buffer: &[u8]) -> FsResult<usize> {
+        HostFd::write(self, buffer)
+    }
+
+    fn seek(&mut self, _position: SeekFrom) -> FsResult<u64> {
+        Err(ESPIPE)
+    }
+
+    fn metadata(&self) -> FsResult<VirtualMetadata> {
+        Ok(VirtualMetadata { permissions: 0o600, ..VirtualMetadata::file(0) })
+    }
+}
+
+/// Give the two host descriptors in `host` descriptors of the stubs, written to `fds`
+unsafe fn install_pair(host: [c_int; 2], fds: *mut c_int) -> c_int {
+    match (fs::install_host_fd(HostFd(host[0])), fs::install_host_fd(HostFd(host[1]))) {
+        (Ok(first), Ok(second)) => {
+            *fds = first;
+            *fds.add(1) = second;
+            0
+        }
+        (first, second) => {
+            for fd in [first, second].into_iter().flatten() {
+                fs::close(fd);
+            }
+            set_errno(EMFILE);
+            -1
+        }
+    }
+}
+
+/// `flags` are `O_CLOEXEC` and `O_NONBLOCK`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[sysv64]
+unsafe fn pipe2(fds: *mut c_int, flags: c_int) -> c_int {
+    let mut host = [0; 2];
+    if libc::pipe2(host.as_mut_ptr(), flags) != 0 {
+        set_errno(host_errno());
+        return -1;
+    }
+    install_pair(host, fds)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[sysv64]
+unsafe fn pipe(fds: *mut c_int) -> c_int {
+    pipe2(fds, 0)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[sysv64]
+unsafe fn socketpair(domain: c_int, kind: c_int, protocol: c_int, fds: *mut c_int) -> c_int {
+    let mut host = [0; 2];
+    if libc::socketpair(domain, kind, protocol, host.as_mut_ptr()) != 0 {
+        set_errno(host_errno());
+        return -1;
+    }
+    install_pair(host, fds)
+}
+
+/// `flags` are `EFD_CLOEXEC`, `EFD_NONBLOCK` and `EFD_SEMAPHORE`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[sysv64]
+fn eventfd(initial: c_uint, flags: c_int) -> c_int {
+    let host = unsafe { libc::eventfd(initial, flags) };
+    if host == -1 {
+        set_errno(host_errno());
+        return -1;
+    }
+    fs::install_host_fd(HostFd(host)).unwrap_or_else(|errno| {
+        set_errno(errno);
+        -1
+    })
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn lookup(symbol_name: &str) -> Option<*const ()> {
+    Some(match symbol_name {
+        "pipe" => pipe as *const (),
+        "pipe2" => pipe2 as *const (),
+        "socketpair" => socketpair as *const (),
+        "eventfd" => eventfd as *const (),
+        _ => return None,
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn lookup(_symbol_name: &str) -> Option<*const ()> {
+    None
+}
+
+#[cfg(all(test, target_arch = "x86_64", target_os = "linux"))]
+mod tests {
+    use std::os::raw::{c_int, c_uint, c_void};
+
+    use crate::android_library::AndroidLibrary;
+    use crate::stubs::errno::{errno, EBADF};
+    use crate::test_elf::TestElf;
+
+    #[test]
+    fn loaded_pipes() {
+        let names = ["pipe", "socketpair", "eventfd", "read", "write", "close"];
+        let mut elf = TestElf::new();
+        for name in names {
+            elf.thunk(&format!("call_{name}"), name);
+        }
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let function = |name: &str| library.get_symbol(&format!("call_{name}")).unwrap();
+        let pipe: extern "C" fn(*mut c_int) -> c_int = unsafe { std::mem::transmute(function("pipe")) };
+        let socketpair: extern "C" fn(c_int, c_int, c_int, *mut c_int) -> c_int = unsafe { std::mem::transmute(function("socketpair")) };
+        let eventfd: extern "C" fn(c_uint, c_int) -> c_int = unsafe { std::mem::transmute(function("eventfd")) };
+        let read: extern "C" fn(c_int, *mut c_void, usize) -> isize = unsafe { std::mem::transmute(function("read")) };
+        let write: extern "C" fn(c_int, *const c_void, usize) -> isize = unsafe { std::mem::transmute(function("write")) };
+        let close: extern "C" fn(c_int) -> c_int = unsafe { std::mem::transmute(function("close")) };
+
+        let mut fds = [0; 2];
+        assert_eq!(pipe(fds.as_mut_ptr()), 0);
+        assert_eq!(write(fds[1], b"ping".as_ptr() as *const c_void, 4), 4);
+        let mut buffer = [0u8; 8];
+        assert_eq!(read(fds[0], buffer.as_mut_ptr() as *mut c_void, buffer.len()), 4);
+        assert_eq!(&buffer[..4], b"ping");
+        assert_eq!(close(fds[1]), 0);
+        // End of file once the write end is closed
+        assert_eq!(read(fds[0], buffer.as_mut_ptr() as *mut c_void, buffer.len()), 0);
+        assert_eq!(close(fds[0]), 0);
+        assert_eq!(read(fds[0], buffer.as_mut_ptr() as *mut c_void, buffer.len()), -1);
+        assert_eq!(errno(), EBADF);
+
+        let mut sockets = [0; 2];
+        assert_eq!(socketpair(1, 1, 0, sockets.as_mut_ptr()), 0); // AF_UNIX, SOCK_STREAM
+        assert_eq!(write(sockets[0], b"pong".as_ptr() as *const c_void, 4), 4);
+        assert_eq!(read(sockets[1], buffer.as_mut_ptr() as *mut c_void, buffer.len()), 4);
+        assert_eq!(&buffer[..4], b"pong");
+        close(sockets[0]);
+        close(sockets[1]);
+
+        // A read waiting on one thread doesn't keep a write on another out
+        let event = eventfd(0, 0);
+        assert!(event >= 3);
+        let waiter = std::thread::spawn(move || {
+            let mut count = 0u64;
+            assert_eq!(read(event, &mut count as *mut u64 as *mut c_void, 8), 8);
+            count
+        });
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert_eq!(write(event, &3u64 as *const u64 as *const c_void, 8), 8);
+        assert_eq!(waiter.join().unwrap(), 3);
+        close(event);
+    }
+}
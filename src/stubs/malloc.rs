@@ -0,0 +1,224 @@
+//! The `malloc` family, bionic's extras like `memalign`, `malloc_usable_size` and `mallinfo`
+//! included, on top of the host's `malloc`.
+//!
+//! Every allocation is recorded with its requested size, which is what `malloc_usable_size`
+//! reports. Aligned ones are carved out of a larger host allocation. Pointers the stubs didn't
+//! hand out, e.g. from a hooked `malloc`, go straight to the host's functions.
+
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::os::raw::{c_int, c_void};
+use std::ptr::null_mut;
+use std::sync::Mutex;
+
+use crate::stubs::errno::{set_errno, EINVAL, ENOMEM};
+use crate::sysv64;
+
+/// What the host's `malloc` aligns to at least
+const MIN_ALIGNMENT: usize = 2 * std::mem::size_of::<usize>();
+
+struct Allocation {
+    size: usize,
+    /// What the host's `malloc` returned, before any alignment
+    base: usize,
+}
+
+lazy_static! {
+    /// Live allocations by the address handed out
+    static ref ALLOCATIONS: Mutex<HashMap<usize, Allocation>> = Mutex::new(HashMap::new());
+}
+
+/// bionic's `struct mallinfo`, all of it `size_t`
+#[repr(C)]
+#[derive(Default)]
+pub(crate) struct Mallinfo {
+    pub arena: usize,
+    pub ordblks: usize,
+    pub smblks: usize,
+    pub hblks: usize,
+    pub hblkhd: usize,
+    pub usmblks: usize,
+    pub fsmblks: usize,
+    pub uordblks: usize,
+    pub fordblks: usize,
+    pub keepcost: usize,
+}
+
+/// `size` bytes aligned to `alignment`, a power of two
+unsafe fn allocate(size: usize, alignment: usize) -> *mut c_void {
+    let padding = if alignment > MIN_ALIGNMENT { alignment - 1 } else { 0 };
+    let base = match size.max(1).checked_add(padding) {
+        Some(total) => libc::malloc(total),
+        None => null_mut(),
+    };
+    if base.is_null() {
+        set_errno(ENOMEM);
+        return null_mut();
+    }
+    let address = (base as usize + padding) & !(alignment - 1);
+    ALLOCATIONS.lock().unwrap().insert(address, Allocation { size, base: base as usize });
+    address as *mut c_void
+}
+
+#[sysv64]
+pub(crate) unsafe fn malloc(size: usize) -> *mut c_void {
+    allocate(size, MIN_ALIGNMENT)
+}
+
+#[sysv64]
+unsafe fn calloc(count: usize, size: usize) -> *mut c_void {
+    let total = match count.checked_mul(size) {
+        Some(total) => total,
+        None => {
+            set_errno(ENOMEM);
+            return null_mut();
+        }
+    };
+    let pointer = allocate(total, MIN_ALIGNMENT);
+    if !pointer.is_null() {
+        std::ptr::write_bytes(pointer as *mut u8, 0, total);
+    }
+    pointer
+}
+
+#[sysv64]
+unsafe fn free(pointer: *mut c_void) {
+    if pointer.is_null() {
+        return;
+    }
+    match ALLOCATIONS.lock().unwrap().remove(&(pointer as usize)) {
+        Some(allocation) => libc::free(allocation.base as *mut c_void),
+        None => libc::free(pointer),
+    }
+}
+
+/// Always moves the allocation, so aligned ones are handled like any other
+#[sysv64]
+unsafe fn realloc(pointer: *mut c_void, size: usize) -> *mut c_void {
+    if pointer.is_null() {
+        return malloc(size);
+    }
+    let old_size = match ALLOCATIONS.lock().unwrap().get(&(pointer as usize)) {
+        Some(allocation) => allocation.size,
+        None => return libc::realloc(pointer, size),
+    };
+    let moved = allocate(size, MIN_ALIGNMENT);
+    if !moved.is_null() {
+        std::ptr::copy_nonoverlapping(pointer as *const u8, moved as *mut u8, old_size.min(size));
+        free(pointer);
+    }
+    moved
+}
+
+/// bionic rounds an alignment that isn't a power of two up to one
+#[sysv64]
+unsafe fn memalign(alignment: usize, size: usize) -> *mut c_void {
+    match alignment.checked_next_power_of_two() {
+        Some(alignment) => allocate(size, alignment),
+        None => {
+            set_errno(EINVAL);
+            null_mut()
+        }
+    }
+}
+
+#[sysv64]
+unsafe fn posix_memalign(result: *mut *mut c_void, alignment: usize, size: usize) -> c_int {
+    if !alignment.is_power_of_two() || alignment % std::mem::size_of::<usize>() != 0 {
+        return EINVAL;
+    }
+    let pointer = allocate(size, alignment);
+    if pointer.is_null() {
+        return ENOMEM;
+    }
+    *result = pointer;
+    0
+}
+
+#[sysv64]
+unsafe fn aligned_alloc(alignment: usize, size: usize) -> *mut c_void {
+    if !alignment.is_power_of_two() {
+        set_errno(EINVAL);
+        return null_mut();
+    }
+    allocate(size, alignment)
+}
+
+/// The requested size of the stubs' own allocations. Others are asked of the host where it has
+/// `malloc_usable_size`, and are 0 elsewhere.
+#[sysv64]
+unsafe fn malloc_usable_size(pointer: *const c_void) -> usize {
+    if let Some(allocation) = ALLOCATIONS.lock().unwrap().get(&(pointer as usize)) {
+        return allocation.size;
+    }
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    if !pointer.is_null() {
+        return libc::malloc_usable_size(pointer as *mut c_void);
+    }
+    0
+}
+
+/// Only counts the stubs' own allocations, as the blocks in use
+#[sysv64]
+fn mallinfo() -> Mallinfo {
+    let allocations = ALLOCATIONS.lock().unwrap();
+    let in_use = allocations.values().map(|allocation| allocation.size).sum();
+    Mallinfo { arena: in_use, ordblks: allocations.len(), uordblks: in_use, ..Mallinfo::default() }
+}
+
+pub(crate) fn lookup(symbol_name: &str) -> Option<*const ()> {
+    Some(match symbol_name {
+        "malloc" | "__libc_malloc" => malloc as *const (),
+        "calloc" | "__libc_calloc" => calloc as *const (),
+        "realloc" | "__libc_realloc" => realloc as *const (),
+        "free" | "__libc_free" => free as *const (),
+        "memalign" | "__libc_memalign" => memalign as *const (),
+        "posix_memalign" => posix_memalign as *const (),
+        "aligned_alloc" => aligned_alloc as *const (),
+        "malloc_usable_size" => malloc_usable_size as *const (),
+        "mallinfo" => mallinfo as *const (),
+        _ => return None,
+    })
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::os::raw::c_void;
+
+    use crate::android_library::AndroidLibrary;
+    use crate::stubs::malloc::Mallinfo;
+    use crate::test_elf::TestElf;
+
+    #[test]
+    fn loaded_allocation_functions() {
+        let names = ["memalign", "malloc_usable_size", "__libc_malloc", "realloc", "free", "mallinfo"];
+        let mut elf = TestElf::new();
+        for name in names {
+            elf.thunk(&format!("call_{name}"), name);
+        }
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let function = |name: &str| library.get_symbol(&format!("call_{name}")).unwrap();
+
+        unsafe {
+            let memalign: extern "C" fn(usize, usize) -> *mut c_void = std::mem::transmute(function("memalign"));
+            let usable_size: extern "C" fn(*const c_void) -> usize = std::mem::transmute(function("malloc_usable_size"));
+            let malloc: extern "C" fn(usize) -> *mut c_void = std::mem::transmute(function("__libc_malloc"));
+            let realloc: extern "C" fn(*mut c_void, usize) -> *mut c_void = std::mem::transmute(function("realloc"));
+            let free: extern "C" fn(*mut c_void) = std::mem::transmute(function("free"));
+            let mallinfo: extern "C" fn() -> Mallinfo = std::mem::transmute(function("mallinfo"));
+
+            let aligned = memalign(256, 100);
+            assert_eq!(aligned as usize % 256, 0);
+            assert_eq!(usable_size(aligned), 100);
+            assert!(mallinfo().uordblks >= 100);
+
+            let grown = malloc(10);
+            (grown as *mut u8).write_bytes(0x5a, 10);
+            let grown = realloc(grown, 5000);
+            assert_eq!(usable_size(grown), 5000);
+            assert_eq!(*(grown as *const [u8; 10]), [0x5a; 10]);
+            free(grown);
+            free(aligned);
+        }
+    }
+}
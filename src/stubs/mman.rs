@@ -0,0 +1,248 @@
+//! Memory mapping stubs for libraries that map or reprotect memory themselves.
+//!
+//! Anonymous mappings are host mappings tracked here and owned by the library that made them,
+//! so they're released when it unloads. File mappings are private copies of the
+//! [virtual file](crate::vfs)'s contents. `mprotect` applies to any address, as libraries
+//! commonly reprotect their own image.
+
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use memmap2::{MmapMut, MmapOptions};
+use region::Protection;
+use std::io::SeekFrom;
+use std::os::raw::{c_int, c_long, c_void};
+use std::sync::Mutex;
+
+use crate::caller::caller_entry;
+use crate::protections::{self, ProtectionSource};
+use crate::registry;
+use crate::stubs::errno::{set_errno, EBADF, EINVAL, ENOMEM};
+use crate::stubs::fs;
+use crate::sysv64;
+
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const PROT_EXEC: c_int = 4;
+const MAP_SHARED: c_int = 0x01;
+const MAP_FIXED: c_int = 0x10;
+const MAP_ANONYMOUS: c_int = 0x20;
+const MAP_FAILED: *mut c_void = usize::MAX as *mut c_void;
+
+struct Mapping {
+    map: MmapMut,
+    /// Registry id of the library that made the mapping, if any
+    owner: Option<usize>,
+}
+
+// MmapMut only holds the mapping's address
+unsafe impl Send for Mapping {}
+
+impl Mapping {
+    fn range(&self) -> std::ops::Range<usize> {
+        self.map.as_ptr() as usize..self.map.as_ptr() as usize + self.map.len()
+    }
+}
+
+lazy_static! {
+    static ref MAPPINGS: Mutex<Vec<Mapping>> = Mutex::new(Vec::new());
+}
+
+fn protection(prot: c_int) -> Protection {
+    let mut protection = Protection::NONE;
+    if prot & PROT_READ != 0 {
+        protection |= Protection::READ;
+    }
+    if prot & PROT_WRITE != 0 {
+        protection |= Protection::WRITE;
+    }
+    if prot & PROT_EXEC != 0 {
+        protection |= Protection::EXECUTE;
+    }
+    protection
+}
+
+unsafe fn protect(address: *const c_void, len: usize, prot: c_int) -> Result<(), c_int> {
+    protections::protect(address, len, protection(prot), ProtectionSource::Library).map_err(|err| {
+        debug!("mprotect({address:?}, {len}, {prot:#x}) failed: {err}");
+        match err {
+            region::Error::UnmappedRegion => ENOMEM,
+            _ => EINVAL,
+        }
+    })
+}
+
+/// Fill `target` from a virtual file, as a private mapping of it would see it
+fn read_file(fd: c_int, offset: i64, target: &mut [u8]) -> Result<(), c_int> {
+    fs::with_file(fd, Err(EBADF), |file| {
+        file.seek(SeekFrom::Start(offset as u64))?;
+        let mut filled = 0;
+        while filled < target.len() {
+            match file.read(&mut target[filled..])? {
+                0 => break,
+                len => filled += len,
+            }
+        }
+        Ok(Ok(()))
+    })
+}
+
+unsafe fn map(address: *mut c_void, len: usize, prot: c_int, flags: c_int, fd: c_int, offset: i64, caller: usize) -> Result<*mut c_void, c_int> {
+    if len == 0 || offset < 0 || offset as usize % region::page::size() != 0 {
+        return Err(EINVAL);
+    }
+    if flags & MAP_ANONYMOUS == 0 && flags & MAP_SHARED != 0 {
+        warn!("Shared mapping of virtual file descriptor {fd} is mapped privately");
+    }
+
+    let mut mappings = MAPPINGS.lock().unwrap();
+    let start = if flags & MAP_FIXED != 0 {
+        // Only supported inside a mapping made here, e.g. a reservation being committed
+        let end = (address as usize).checked_add(len).ok_or(EINVAL)?;
+        if address as usize % region::page::size() != 0
+            || !mappings.iter().any(|mapping| mapping.range().start <= address as usize && end <= mapping.range().end) {
+            return Err(EINVAL);
+        }
+        protect(address, len, PROT_READ | PROT_WRITE)?;
+        std::ptr::write_bytes(address as *mut u8, 0, len);
+        address as *mut u8
+    } else {
+        let map = MmapOptions::new().len(len).map_anon().map_err(|_| ENOMEM)?;
+        let start = map.as_ptr() as *mut u8;
+        mappings.push(Mapping { map, owner: registry::containing(caller) });
+        start
+    };
+    drop(mappings);
+
+    if flags & MAP_ANONYMOUS == 0 {
+        if let Err(errno) = read_file(fd, offset, std::slice::from_raw_parts_mut(start, len)) {
+            if flags & MAP_FIXED == 0 {
+                unmap(start as usize, len);
+            }
+            return Err(errno);
+        }
+    }
+    protect(start as *const c_void, len, prot)?;
+    Ok(start as *mut c_void)
+}
+
+/// Release the tracked mappings fully inside `address..address + len`. Partially unmapped
+/// mappings are made inaccessible instead, as they can't be split.
+fn unmap(address: usize, len: usize) {
+    let end = address.saturating_add(len);
+    let mut mappings = MAPPINGS.lock().unwrap();
+    mappings.retain(|mapping| {
+        let range = mapping.range();
+        if address <= range.start && range.end <= end {
+            return false;
+        }
+        let (start, stop) = (address.max(range.start), end.min(range.end));
+        if start < stop {
+            unsafe {
+                let _ = protections::protect(start as *const c_void, stop - start, Protection::NONE, ProtectionSource::Library);
+            }
+        }
+        true
+    });
+}
+
+/// Release every mapping a library made, once it's unloaded
+pub(crate) fn release_owned(owner: usize) {
+    MAPPINGS.lock().unwrap().retain(|mapping| mapping.owner != Some(owner));
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+pub(crate) fn is_tracked(address: usize) -> bool {
+    MAPPINGS.lock().unwrap().iter().any(|mapping| mapping.range().contains(&address))
+}
+
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_mmap_from(address: *mut c_void, len: usize, prot: c_int, flags: c_int, fd: c_int, offset: c_long, caller: usize) -> *mut c_void {
+    map(address, len, prot, flags, fd, offset as i64, caller).unwrap_or_else(|errno| {
+        set_errno(errno);
+        MAP_FAILED
+    })
+}
+
+caller_entry!("android_loader_mmap", 6, "android_loader_mmap_from");
+
+extern "C" {
+    fn android_loader_mmap();
+}
+
+#[sysv64]
+fn munmap(address: *mut c_void, len: usize) -> c_int {
+    if address as usize % region::page::size() != 0 || len == 0 {
+        set_errno(EINVAL);
+        return -1;
+    }
+    unmap(address as usize, len);
+    0
+}
+
+#[sysv64]
+unsafe fn mprotect(address: *mut c_void, len: usize, prot: c_int) -> c_int {
+    match protect(address, len, prot) {
+        Ok(()) => 0,
+        Err(errno) => {
+            set_errno(errno);
+            -1
+        }
+    }
+}
+
+pub(crate) fn lookup(symbol_name: &str) -> Option<*const ()> {
+    Some(match symbol_name {
+        // off_t and off64_t are the same size on 64-bit targets
+        #[cfg(target_pointer_width = "64")]
+        "mmap64" => android_loader_mmap as *const (),
+        "mmap" => android_loader_mmap as *const (),
+        "munmap" => munmap as *const (),
+        "mprotect" => mprotect as *const (),
+        _ => return None,
+    })
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::os::raw::{c_int, c_long, c_void};
+
+    use crate::android_library::AndroidLibrary;
+    use crate::stubs::mman::{is_tracked, MAP_ANONYMOUS, MAP_FAILED, PROT_EXEC, PROT_READ, PROT_WRITE};
+    use crate::test_elf::TestElf;
+
+    type Mmap = extern "C" fn(*mut c_void, usize, c_int, c_int, c_int, c_long) -> *mut c_void;
+
+    #[test]
+    fn self_mapped_code() {
+        let mut elf = TestElf::new();
+        elf.thunk("call_mmap", "mmap");
+        elf.thunk("call_mprotect", "mprotect");
+        elf.thunk("call_munmap", "munmap");
+        // sub rsp, 8; call mmap; add rsp, 8; ret
+        elf.caller("owned_mmap", &[0x48, 0x83, 0xec, 0x08], "mmap", &[0x48, 0x83, 0xc4, 0x08, 0xc3]);
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+
+        let mmap: Mmap = unsafe { std::mem::transmute(library.get_symbol("call_mmap").unwrap()) };
+        let mprotect: extern "C" fn(*mut c_void, usize, c_int) -> c_int =
+            unsafe { std::mem::transmute(library.get_symbol("call_mprotect").unwrap()) };
+        let munmap: extern "C" fn(*mut c_void, usize) -> c_int =
+            unsafe { std::mem::transmute(library.get_symbol("call_munmap").unwrap()) };
+
+        let code = mmap(std::ptr::null_mut(), 4096, PROT_READ | PROT_WRITE, 0x02 | MAP_ANONYMOUS, -1, 0);
+        assert_ne!(code, MAP_FAILED);
+        unsafe { std::ptr::copy_nonoverlapping([0xb8, 42, 0, 0, 0, 0xc3].as_ptr(), code as *mut u8, 6) }; // mov eax, 42; ret
+        assert_eq!(mprotect(code, 4096, PROT_READ | PROT_EXEC), 0);
+        let function: extern "C" fn() -> u32 = unsafe { std::mem::transmute(code) };
+        assert_eq!(function(), 42);
+        assert_eq!(munmap(code, 4096), 0);
+        assert!(!is_tracked(code as usize));
+
+        let owned_mmap: Mmap = unsafe { std::mem::transmute(library.get_symbol("owned_mmap").unwrap()) };
+        let owned = owned_mmap(std::ptr::null_mut(), 8192, PROT_READ, 0x02 | MAP_ANONYMOUS, -1, 0);
+        assert_ne!(owned, MAP_FAILED);
+        assert!(is_tracked(owned as usize));
+        drop(library);
+        assert!(!is_tracked(owned as usize));
+    }
+}
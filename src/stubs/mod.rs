@@ -0,0 +1,72 @@
+//! Built-in implementations of common libc functions, used for symbols that aren't hooked.
+
+pub mod android;
+pub mod auxv;
+mod ctype;
+pub(crate) mod cxa;
+pub mod errno;
+mod format;
+mod fs;
+pub mod ioctl;
+mod ipc;
+mod malloc;
+pub(crate) mod mman;
+pub mod process;
+mod random;
+pub(crate) mod signal;
+pub mod stdio;
+mod stream;
+mod string;
+pub mod sysconf;
+pub mod time;
+mod wchar;
+pub(crate) mod varargs;
+
+pub(crate) fn lookup(symbol_name: &str) -> Option<*const ()> {
+    stdio::lookup(symbol_name)
+        .or_else(|| ctype::lookup(symbol_name))
+        .or_else(|| errno::lookup(symbol_name))
+        .or_else(|| fs::lookup(symbol_name))
+        .or_else(|| ipc::lookup(symbol_name))
+        .or_else(|| ioctl::lookup(symbol_name))
+        .or_else(|| stream::lookup(symbol_name))
+        .or_else(|| mman::lookup(symbol_name))
+        .or_else(|| auxv::lookup(symbol_name))
+        .or_else(|| sysconf::lookup(symbol_name))
+        .or_else(|| time::lookup(symbol_name))
+        .or_else(|| string::lookup(symbol_name))
+        .or_else(|| signal::lookup(symbol_name))
+        .or_else(|| android::lookup(symbol_name))
+        .or_else(|| wchar::lookup(symbol_name))
+        .or_else(|| process::lookup(symbol_name))
+        .or_else(|| malloc::lookup(symbol_name))
+        .or_else(|| random::lookup(symbol_name))
+        .or_else(|| cxa::lookup(symbol_name))
+}
+
+/// The stubs that need their caller, as their implementation taking it after the given number
+/// of arguments
+pub(crate) fn caller_sensitive(symbol_name: &str) -> Option<(usize, usize)> {
+    signal::caller_sensitive(symbol_name)
+        .or_else(|| stdio::caller_sensitive(symbol_name))
+        .or_else(|| fs::caller_sensitive(symbol_name))
+        .or_else(|| stream::caller_sensitive(symbol_name))
+}
+
+/// The stubs most libraries need, which [`AndroidLoader::with_bionic_stubs`] falls back to:
+/// the string and memory functions, the `malloc` family, ctype, errno, randomness, Android
+/// logging and system properties, `getauxval`, `sysconf`, sleeping and calendar time.
+/// `pthread_*` comes on top.
+///
+/// [`AndroidLoader::with_bionic_stubs`]: crate::android_loader::AndroidLoader::with_bionic_stubs
+pub(crate) fn bionic_lookup(symbol_name: &str) -> Option<*const ()> {
+    string::lookup(symbol_name)
+        .or_else(|| malloc::lookup(symbol_name))
+        .or_else(|| ctype::lookup(symbol_name))
+        .or_else(|| errno::lookup(symbol_name))
+        .or_else(|| random::lookup(symbol_name))
+        .or_else(|| android::lookup(symbol_name))
+        .or_else(|| auxv::lookup(symbol_name))
+        .or_else(|| sysconf::lookup(symbol_name))
+        .or_else(|| time::lookup(symbol_name))
+}
@@ -0,0 +1,131 @@
+//! Process identity: `getpid`, `gettid` and the user and group ids.
+//!
+//! Each id is the host's own unless overridden with [`set_process_ids`], e.g. to look like an
+//! app's sandbox. Thread ids are the host's on Linux and Android, and elsewhere numbered after
+//! the process id in the order threads first ask for theirs.
+
+use lazy_static::lazy_static;
+use std::os::raw::c_int;
+use std::sync::Mutex;
+
+use crate::sysv64;
+
+/// The ids the stubs report, the host's own where `None`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProcessIds {
+    pub pid: Option<c_int>,
+    pub uid: Option<u32>,
+    pub euid: Option<u32>,
+    pub gid: Option<u32>,
+    pub egid: Option<u32>,
+}
+
+lazy_static! {
+    static ref PROCESS_IDS: Mutex<ProcessIds> = Mutex::new(ProcessIds::default());
+}
+
+/// Set the ids `getpid`, `getuid`, `geteuid`, `getgid` and `getegid` return to loaded libraries
+pub fn set_process_ids(ids: ProcessIds) {
+    *PROCESS_IDS.lock().unwrap() = ids;
+}
+
+fn ids() -> ProcessIds {
+    *PROCESS_IDS.lock().unwrap()
+}
+
+#[cfg(unix)]
+fn host_ids() -> [u32; 4] {
+    unsafe { [libc::getuid(), libc::geteuid(), libc::getgid(), libc::getegid()] }
+}
+
+/// Root, as there are no such ids
+#[cfg(not(unix))]
+fn host_ids() -> [u32; 4] {
+    [0; 4]
+}
+
+#[sysv64]
+fn getpid() -> c_int {
+    ids().pid.unwrap_or(std::process::id() as c_int)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[sysv64]
+fn gettid() -> c_int {
+    unsafe { libc::syscall(libc::SYS_gettid) as c_int }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+#[sysv64]
+fn gettid() -> c_int {
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    static NEXT: AtomicI32 = AtomicI32::new(1);
+    thread_local! {
+        static TID: c_int = std::process::id() as c_int + NEXT.fetch_add(1, Ordering::Relaxed);
+    }
+    TID.with(|tid| *tid)
+}
+
+#[sysv64]
+fn getuid() -> u32 {
+    ids().uid.unwrap_or(host_ids()[0])
+}
+
+#[sysv64]
+fn geteuid() -> u32 {
+    ids().euid.unwrap_or(host_ids()[1])
+}
+
+#[sysv64]
+fn getgid() -> u32 {
+    ids().gid.unwrap_or(host_ids()[2])
+}
+
+#[sysv64]
+fn getegid() -> u32 {
+    ids().egid.unwrap_or(host_ids()[3])
+}
+
+pub(crate) fn lookup(symbol_name: &str) -> Option<*const ()> {
+    Some(match symbol_name {
+        "getpid" => getpid as *const (),
+        "gettid" => gettid as *const (),
+        "getuid" => getuid as *const (),
+        "geteuid" => geteuid as *const (),
+        "getgid" => getgid as *const (),
+        "getegid" => getegid as *const (),
+        _ => return None,
+    })
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::os::raw::c_int;
+
+    use crate::android_library::AndroidLibrary;
+    use crate::stubs::process::{set_process_ids, ProcessIds};
+    use crate::test_elf::TestElf;
+
+    #[test]
+    fn loaded_process_ids() {
+        let mut elf = TestElf::new();
+        for name in ["getpid", "gettid", "getuid"] {
+            elf.thunk(&format!("call_{name}"), name);
+        }
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let getpid: extern "C" fn() -> c_int = unsafe { std::mem::transmute(library.get_symbol("call_getpid").unwrap()) };
+        let gettid: extern "C" fn() -> c_int = unsafe { std::mem::transmute(library.get_symbol("call_gettid").unwrap()) };
+        let getuid: extern "C" fn() -> u32 = unsafe { std::mem::transmute(library.get_symbol("call_getuid").unwrap()) };
+
+        assert_eq!(getpid(), std::process::id() as c_int);
+        set_process_ids(ProcessIds { pid: Some(4321), uid: Some(10123), ..ProcessIds::default() });
+        assert_eq!(getpid(), 4321);
+        assert_eq!(getuid(), 10123);
+        set_process_ids(ProcessIds::default());
+
+        let tid = gettid();
+        assert_eq!(gettid(), tid);
+        assert_ne!(std::thread::spawn(move || gettid()).join().unwrap(), tid);
+    }
+}
@@ -0,0 +1,99 @@
+//! Randomness: `getrandom` from the host's entropy source, and bionic's `arc4random` family
+//! from a per-thread CSPRNG seeded by it, like bionic's own ChaCha-based one.
+
+use rand::rngs::OsRng;
+use rand::{Rng, RngCore};
+use std::os::raw::{c_uint, c_void};
+
+use crate::stubs::errno::{set_errno, EINVAL, EIO};
+use crate::sysv64;
+
+const GRND_NONBLOCK: c_uint = 1;
+const GRND_RANDOM: c_uint = 2;
+
+/// Fills the whole buffer, so `GRND_NONBLOCK` never has anything to do: like the host's
+/// `getrandom`, it only matters before the entropy pool is initialized.
+#[sysv64]
+unsafe fn getrandom(buffer: *mut c_void, length: usize, flags: c_uint) -> isize {
+    if flags & !(GRND_NONBLOCK | GRND_RANDOM) != 0 {
+        set_errno(EINVAL);
+        return -1;
+    }
+    if length == 0 {
+        return 0;
+    }
+    match OsRng.try_fill_bytes(std::slice::from_raw_parts_mut(buffer as *mut u8, length)) {
+        Ok(()) => length as isize,
+        Err(_) => {
+            set_errno(EIO);
+            -1
+        }
+    }
+}
+
+#[sysv64]
+fn arc4random() -> u32 {
+    rand::thread_rng().gen()
+}
+
+#[sysv64]
+unsafe fn arc4random_buf(buffer: *mut c_void, length: usize) {
+    if length != 0 {
+        rand::thread_rng().fill_bytes(std::slice::from_raw_parts_mut(buffer as *mut u8, length));
+    }
+}
+
+/// Uniform below `upper_bound` without modulo bias, 0 if it's below 2
+#[sysv64]
+fn arc4random_uniform(upper_bound: u32) -> u32 {
+    if upper_bound < 2 {
+        return 0;
+    }
+    rand::thread_rng().gen_range(0..upper_bound)
+}
+
+pub(crate) fn lookup(symbol_name: &str) -> Option<*const ()> {
+    Some(match symbol_name {
+        "getrandom" => getrandom as *const (),
+        "arc4random" => arc4random as *const (),
+        "arc4random_buf" => arc4random_buf as *const (),
+        "arc4random_uniform" => arc4random_uniform as *const (),
+        _ => return None,
+    })
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::os::raw::{c_uint, c_void};
+
+    use crate::android_library::AndroidLibrary;
+    use crate::test_elf::TestElf;
+
+    #[test]
+    fn loaded_random_bytes() {
+        let mut elf = TestElf::new();
+        for name in ["arc4random_buf", "arc4random_uniform", "getrandom"] {
+            elf.thunk(&format!("call_{name}"), name);
+        }
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let function = |name: &str| library.get_symbol(&format!("call_{name}")).unwrap();
+        let arc4random_buf: extern "C" fn(*mut c_void, usize) = unsafe { std::mem::transmute(function("arc4random_buf")) };
+        let arc4random_uniform: extern "C" fn(u32) -> u32 = unsafe { std::mem::transmute(function("arc4random_uniform")) };
+        let getrandom: extern "C" fn(*mut c_void, usize, c_uint) -> isize = unsafe { std::mem::transmute(function("getrandom")) };
+
+        let mut first = [0u8; 32];
+        let mut second = [0u8; 32];
+        arc4random_buf(first.as_mut_ptr() as *mut c_void, first.len());
+        arc4random_buf(second.as_mut_ptr() as *mut c_void, second.len());
+        assert_ne!(first, second);
+        assert_ne!(first, [0; 32]);
+
+        assert!((0..1000).map(|_| arc4random_uniform(10)).all(|value| value < 10));
+        assert_eq!(arc4random_uniform(1), 0);
+
+        let mut buffer = [0u8; 64];
+        assert_eq!(getrandom(buffer.as_mut_ptr() as *mut c_void, buffer.len(), 1), 64);
+        assert_ne!(buffer, [0; 64]);
+        assert_eq!(getrandom(buffer.as_mut_ptr() as *mut c_void, buffer.len(), 0x80), -1);
+    }
+}
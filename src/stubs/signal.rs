@@ -0,0 +1,234 @@
+//! Signal stubs over a virtual signal table, leaving the host's own dispositions alone.
+//!
+//! `signal` and `sigaction` record a handler for the calling library only, and `raise` runs
+//! the recorded handler synchronously on the calling thread. Nothing is ever delivered by the
+//! host: a library's handlers only run when it raises the signal itself. Signals left at their
+//! default action that would terminate the process abort it.
+
+use lazy_static::lazy_static;
+use log::error;
+use std::collections::HashMap;
+use std::os::raw::{c_int, c_ulong, c_void};
+use std::sync::Mutex;
+
+use crate::caller::caller_entry;
+use crate::registry;
+use crate::stubs::errno::{set_errno, EINVAL};
+use crate::{sysv64, sysv64_type};
+
+const SIG_DFL: usize = 0;
+const SIG_IGN: usize = 1;
+const SIG_ERR: usize = usize::MAX;
+const SA_SIGINFO: c_int = 4;
+const SIGKILL: c_int = 9;
+const SIGSTOP: c_int = 19;
+/// Signals whose default action is to do nothing
+const IGNORED_BY_DEFAULT: [c_int; 3] = [17, 23, 28]; // SIGCHLD, SIGURG, SIGWINCH
+const NSIG: c_int = 65;
+
+/// bionic's `struct sigaction`
+#[cfg(target_pointer_width = "64")]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct SigAction {
+    pub sa_flags: c_int,
+    pub sa_handler: usize,
+    pub sa_mask: c_ulong,
+    pub sa_restorer: usize,
+}
+
+#[cfg(target_pointer_width = "32")]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct SigAction {
+    pub sa_handler: usize,
+    pub sa_mask: c_ulong,
+    pub sa_flags: c_int,
+    pub sa_restorer: usize,
+}
+
+impl SigAction {
+    fn new(handler: usize, flags: c_int) -> SigAction {
+        SigAction { sa_flags: flags, sa_handler: handler, sa_mask: 0, sa_restorer: 0 }
+    }
+}
+
+lazy_static! {
+    /// Handlers by registry id of the library that installed them (`None` for the host) and
+    /// signal number
+    static ref HANDLERS: Mutex<HashMap<(Option<usize>, c_int), SigAction>> = Mutex::new(HashMap::new());
+}
+
+pub(crate) fn release_owned(owner: usize) {
+    HANDLERS.lock().unwrap().retain(|(library, _), _| *library != Some(owner));
+}
+
+/// Record `action` for the caller's library and return the one it replaces
+fn install(caller: usize, signal: c_int, action: Option<SigAction>) -> Result<SigAction, c_int> {
+    if !(1..NSIG).contains(&signal) || (action.is_some() && (signal == SIGKILL || signal == SIGSTOP)) {
+        return Err(EINVAL);
+    }
+    let key = (registry::containing(caller), signal);
+    let mut handlers = HANDLERS.lock().unwrap();
+    let previous = handlers.get(&key).copied().unwrap_or_else(|| SigAction::new(SIG_DFL, 0));
+    if let Some(action) = action {
+        handlers.insert(key, action);
+    }
+    Ok(previous)
+}
+
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_signal_from(signal: c_int, handler: usize, caller: usize) -> usize {
+    match install(caller, signal, Some(SigAction::new(handler, 0))) {
+        Ok(previous) => previous.sa_handler,
+        Err(errno) => {
+            set_errno(errno);
+            SIG_ERR
+        }
+    }
+}
+
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_sigaction_from(signal: c_int, action: *const SigAction, previous: *mut SigAction, caller: usize) -> c_int {
+    match install(caller, signal, action.as_ref().copied()) {
+        Ok(replaced) => {
+            if let Some(previous) = previous.as_mut() {
+                *previous = replaced;
+            }
+            0
+        }
+        Err(errno) => {
+            set_errno(errno);
+            -1
+        }
+    }
+}
+
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_raise_from(signal: c_int, caller: usize) -> c_int {
+    if !(1..NSIG).contains(&signal) {
+        set_errno(EINVAL);
+        return -1;
+    }
+    let action = HANDLERS.lock().unwrap().get(&(registry::containing(caller), signal)).copied();
+    match action.map_or(SIG_DFL, |action| action.sa_handler) {
+        SIG_IGN => {}
+        SIG_DFL if IGNORED_BY_DEFAULT.contains(&signal) => {}
+        SIG_DFL => {
+            error!("Signal {signal} raised without a handler, aborting");
+            std::process::abort();
+        }
+        handler if action.map_or(false, |action| action.sa_flags & SA_SIGINFO != 0) => {
+            // A zeroed siginfo_t, 128 bytes on every architecture, with only the number set
+            let mut info = [0u8; 128];
+            info[..4].copy_from_slice(&signal.to_ne_bytes());
+            let handler: sysv64_type!(fn(c_int, *mut c_void, *mut c_void)) = std::mem::transmute(handler);
+            handler(signal, info.as_mut_ptr() as *mut c_void, std::ptr::null_mut());
+        }
+        handler => {
+            let handler: sysv64_type!(fn(c_int)) = std::mem::transmute(handler);
+            handler(signal);
+        }
+    }
+    0
+}
+
+caller_entry!("android_loader_signal", 2, "android_loader_signal_from");
+caller_entry!("android_loader_sigaction", 3, "android_loader_sigaction_from");
+caller_entry!("android_loader_raise", 1, "android_loader_raise_from");
+
+extern "C" {
+    fn android_loader_signal();
+    fn android_loader_sigaction();
+    fn android_loader_raise();
+}
+
+/// The stubs taking their caller, as (implementation, arguments before the caller), for
+/// binding to the libraries importing them
+pub(crate) fn caller_sensitive(symbol_name: &str) -> Option<(usize, usize)> {
+    Some(match symbol_name {
+        "signal" | "bsd_signal" => (android_loader_signal_from as *const () as usize, 2),
+        "sigaction" | "sigaction64" => (android_loader_sigaction_from as *const () as usize, 3),
+        "raise" => (android_loader_raise_from as *const () as usize, 1),
+        _ => return None,
+    })
+}
+
+pub(crate) fn lookup(symbol_name: &str) -> Option<*const ()> {
+    Some(match symbol_name {
+        "signal" | "bsd_signal" => android_loader_signal as *const (),
+        "sigaction" | "sigaction64" => android_loader_sigaction as *const (),
+        "raise" => android_loader_raise as *const (),
+        _ => return None,
+    })
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::os::raw::c_int;
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    use crate::android_library::AndroidLibrary;
+    use crate::stubs::errno::{errno, EINVAL};
+    use crate::stubs::signal::{SigAction, SA_SIGINFO, SIG_DFL, SIG_ERR, SIG_IGN};
+    use crate::sysv64;
+    use crate::test_elf::TestElf;
+
+    static HANDLED: AtomicI32 = AtomicI32::new(0);
+
+    #[sysv64]
+    fn handler(signal: c_int) {
+        HANDLED.fetch_add(signal, Ordering::SeqCst);
+    }
+
+    #[sysv64]
+    fn info_handler(signal: c_int, info: *const c_int, _context: *const ()) {
+        HANDLED.fetch_add(100 * unsafe { *info }, Ordering::SeqCst);
+        assert_eq!(unsafe { *info }, signal);
+    }
+
+    fn signal_library() -> AndroidLibrary<'static> {
+        let mut elf = TestElf::new();
+        for name in ["signal", "sigaction", "raise"] {
+            elf.thunk(&format!("call_{name}"), name);
+        }
+        AndroidLibrary::load_from_bytes(elf.build()).unwrap()
+    }
+
+    #[test]
+    fn raised_signals() {
+        const SIGPIPE: c_int = 13;
+        const SIGALRM: c_int = 14;
+        let library = signal_library();
+        let function = |name: &str| library.get_symbol(&format!("call_{name}")).unwrap();
+        let signal: extern "C" fn(c_int, usize) -> usize = unsafe { std::mem::transmute(function("signal")) };
+        let sigaction: extern "C" fn(c_int, *const SigAction, *mut SigAction) -> c_int = unsafe { std::mem::transmute(function("sigaction")) };
+        let raise: extern "C" fn(c_int) -> c_int = unsafe { std::mem::transmute(function("raise")) };
+
+        assert_eq!(signal(SIGPIPE, handler as *const () as usize), SIG_DFL);
+        assert_eq!(raise(SIGPIPE), 0);
+        assert_eq!(HANDLED.load(Ordering::SeqCst), SIGPIPE);
+        assert_eq!(signal(SIGPIPE, SIG_IGN), handler as *const () as usize);
+        assert_eq!(raise(SIGPIPE), 0);
+        assert_eq!(HANDLED.load(Ordering::SeqCst), SIGPIPE);
+
+        let action = SigAction::new(info_handler as *const () as usize, SA_SIGINFO);
+        let mut previous = SigAction::new(SIG_ERR, 0);
+        assert_eq!(sigaction(SIGALRM, &action, &mut previous), 0);
+        assert_eq!(previous.sa_handler, SIG_DFL);
+        assert_eq!(raise(SIGALRM), 0);
+        assert_eq!(HANDLED.load(Ordering::SeqCst), SIGPIPE + 100 * SIGALRM);
+        assert_eq!(sigaction(SIGALRM, std::ptr::null(), &mut previous), 0);
+        assert_eq!(previous.sa_flags, SA_SIGINFO);
+
+        // Signals that can't be caught, and other libraries' tables
+        assert_eq!(signal(9, handler as *const () as usize), SIG_ERR);
+        assert_eq!(errno(), EINVAL);
+        let other = signal_library();
+        let other_signal: extern "C" fn(c_int, usize) -> usize = unsafe { std::mem::transmute(other.get_symbol("call_signal").unwrap()) };
+        assert_eq!(other_signal(SIGPIPE, SIG_IGN), SIG_DFL);
+    }
+}
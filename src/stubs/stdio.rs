@@ -0,0 +1,222 @@
+//! Formatted output stubs, and where output to the standard descriptors goes.
+//!
+//! The unbounded `sprintf`/`vsprintf` format into an internal buffer first, so the optional
+//! limit set with [`AndroidLoader::sprintf_limit`](crate::android_loader::AndroidLoader::sprintf_limit)
+//! for the calling library can be enforced before anything is written to its buffer.
+//!
+//! Whatever loaded libraries write to descriptors 0, 1 and 2, be it with `write`, `writev` or
+//! through `stdout` and `stderr`, is handed to an [`OutputSink`] instead of the host's own
+//! descriptors. By default that's [`LogSink`].
+
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use std::os::raw::{c_char, c_int, c_void};
+use std::sync::Mutex;
+
+use crate::caller::caller_entry;
+use crate::registry;
+use crate::stubs::format;
+use crate::stubs::varargs::{asm_symbol, variadic_entry, VaList};
+use crate::sysv64;
+
+/// Receives loaded libraries' output to the standard descriptors
+pub trait OutputSink: Send {
+    /// Called with the bytes of each write to `fd`, which is 0, 1 or 2
+    fn write(&mut self, fd: c_int, bytes: &[u8]);
+
+    /// Called by `fflush` on `stdout` or `stderr`, for sinks that buffer
+    fn flush(&mut self, _fd: c_int) {}
+}
+
+/// Logs the output line by line, with the stream's name, at error level for `stderr` and info
+/// level otherwise. Unfinished lines wait for their newline or a flush.
+#[derive(Default)]
+pub struct LogSink {
+    lines: [Vec<u8>; 3],
+}
+
+impl LogSink {
+    fn log(fd: c_int, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        match fd {
+            0 => info!("stdin: {line}"),
+            1 => info!("stdout: {line}"),
+            _ => error!("stderr: {line}"),
+        }
+    }
+}
+
+impl OutputSink for LogSink {
+    fn write(&mut self, fd: c_int, bytes: &[u8]) {
+        let pending = &mut self.lines[fd as usize];
+        pending.extend_from_slice(bytes);
+        while let Some(end) = pending.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            Self::log(fd, &line[..end]);
+        }
+    }
+
+    fn flush(&mut self, fd: c_int) {
+        let pending = std::mem::take(&mut self.lines[fd as usize]);
+        if !pending.is_empty() {
+            Self::log(fd, &pending);
+        }
+    }
+}
+
+lazy_static! {
+    static ref OUTPUT_SINK: Mutex<Box<dyn OutputSink>> = Mutex::new(Box::new(LogSink::default()));
+}
+
+/// Send loaded libraries' output to the standard descriptors to `sink` from now on
+pub fn set_output_sink(sink: impl OutputSink + 'static) {
+    *OUTPUT_SINK.lock().unwrap() = Box::new(sink);
+}
+
+pub(crate) fn write_output(fd: c_int, bytes: &[u8]) {
+    OUTPUT_SINK.lock().unwrap().write(fd, bytes);
+}
+
+pub(crate) fn flush_output(fd: c_int) {
+    OUTPUT_SINK.lock().unwrap().flush(fd);
+}
+
+unsafe fn copy_output(buffer: *mut c_char, output: &[u8], capacity: usize) {
+    if capacity == 0 {
+        return;
+    }
+    let len = output.len().min(capacity - 1);
+    std::ptr::copy_nonoverlapping(output.as_ptr(), buffer.cast::<u8>(), len);
+    *buffer.add(len) = 0;
+}
+
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_vsprintf_from(buffer: *mut c_char, format: *const c_char, args: *mut c_void, caller: usize) -> c_int {
+    let output = format::format(format, &mut VaList::new(args));
+
+    match registry::sprintf_limit(caller) {
+        Some(limit) if output.len() >= limit => {
+            warn!("sprintf output of {} bytes truncated to the {} byte limit", output.len(), limit);
+            copy_output(buffer, &output, limit);
+            limit.saturating_sub(1) as c_int
+        }
+        _ => {
+            copy_output(buffer, &output, output.len() + 1);
+            output.len() as c_int
+        }
+    }
+}
+
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_vsnprintf(buffer: *mut c_char, size: usize, format: *const c_char, args: *mut c_void) -> c_int {
+    let output = format::format(format, &mut VaList::new(args));
+    copy_output(buffer, &output, size);
+    output.len() as c_int
+}
+
+variadic_entry!(caller "android_loader_sprintf", 2, "android_loader_vsprintf_from");
+caller_entry!("android_loader_vsprintf", 3, "android_loader_vsprintf_from");
+variadic_entry!("android_loader_snprintf", 3, "android_loader_vsnprintf");
+
+extern "C" {
+    fn android_loader_sprintf();
+    fn android_loader_vsprintf();
+    fn android_loader_snprintf();
+}
+
+/// The stubs taking their caller, as (implementation, arguments before the caller), for
+/// binding to the libraries importing them. `sprintf` is variadic, so it only gets the return
+/// address.
+pub(crate) fn caller_sensitive(symbol_name: &str) -> Option<(usize, usize)> {
+    match symbol_name {
+        "vsprintf" => Some((android_loader_vsprintf_from as *const () as usize, 3)),
+        _ => None,
+    }
+}
+
+pub(crate) fn lookup(symbol_name: &str) -> Option<*const ()> {
+    Some(match symbol_name {
+        "sprintf" => android_loader_sprintf as *const (),
+        "vsprintf" => android_loader_vsprintf as *const (),
+        "snprintf" => android_loader_snprintf as *const (),
+        "vsnprintf" => android_loader_vsnprintf as *const (),
+        _ => return None,
+    })
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::ffi::CStr;
+    use std::os::raw::{c_char, c_int};
+
+    use crate::android_library::AndroidLibrary;
+    use crate::android_loader::AndroidLoader;
+    use crate::test_elf::TestElf;
+
+    #[test]
+    fn loaded_sprintf() {
+        let mut elf = TestElf::new();
+        elf.thunk("call_sprintf", "sprintf");
+        elf.thunk("call_snprintf", "snprintf");
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+
+        let sprintf: unsafe extern "C" fn(*mut c_char, *const c_char, ...) -> c_int =
+            unsafe { std::mem::transmute(library.get_symbol("call_sprintf").unwrap()) };
+        let snprintf: unsafe extern "C" fn(*mut c_char, usize, *const c_char, ...) -> c_int =
+            unsafe { std::mem::transmute(library.get_symbol("call_snprintf").unwrap()) };
+
+        // Enough arguments to spill past the registers onto the stack
+        let long = "x".repeat(5000);
+        let long = std::ffi::CString::new(long).unwrap();
+        let mut buffer = vec![0 as c_char; 8192];
+        let len = unsafe {
+            sprintf(buffer.as_mut_ptr(), b"%s|%d|%05.1f|%x|%c|%d|%d|%g\0".as_ptr() as *const c_char,
+                    long.as_ptr(), -12 as c_int, 2.25f64, 255 as c_int, b'q' as c_int, 6 as c_int, 7 as c_int, 0.5f64)
+        };
+        let output = unsafe { CStr::from_ptr(buffer.as_ptr()) }.to_str().unwrap();
+        assert_eq!(output, format!("{}|-12|002.2|ff|q|6|7|0.5", "x".repeat(5000)));
+        assert_eq!(len as usize, output.len());
+
+        let len = unsafe { snprintf(buffer.as_mut_ptr(), 4, b"%d\0".as_ptr() as *const c_char, 123456 as c_int) };
+        assert_eq!(len, 6);
+        assert_eq!(unsafe { CStr::from_ptr(buffer.as_ptr()) }.to_bytes(), b"123");
+    }
+
+    #[test]
+    fn sprintf_limit_per_loader() {
+        // sprintf only knows its caller from the return address, so it's called from the library
+        let build = || {
+            let mut elf = TestElf::new();
+            elf.caller("call_sprintf", &[0x48, 0x83, 0xec, 0x08], "sprintf", &[0x48, 0x83, 0xc4, 0x08, 0xc3]);
+            elf.thunk("call_vsprintf", "vsprintf");
+            elf.build()
+        };
+        let limited = AndroidLoader::new().sprintf_limit(16).load_library_from_bytes(build()).unwrap();
+        let unlimited = AndroidLibrary::load_from_bytes(build()).unwrap();
+
+        let long = std::ffi::CString::new("x".repeat(100)).unwrap();
+        let mut buffer = vec![0 as c_char; 128];
+        for (library, expected) in [(&limited, 15), (&unlimited, 100)] {
+            let sprintf: unsafe extern "C" fn(*mut c_char, *const c_char, ...) -> c_int =
+                unsafe { std::mem::transmute(library.get_symbol("call_sprintf").unwrap()) };
+            let len = unsafe { sprintf(buffer.as_mut_ptr(), b"%s\0".as_ptr() as *const c_char, long.as_ptr()) };
+            assert_eq!(len, expected);
+            assert_eq!(unsafe { CStr::from_ptr(buffer.as_ptr()) }.to_bytes().len(), expected as usize);
+        }
+
+        // vsprintf is bound to the library importing it, so even a thunk gets the limit. The
+        // va_list has every register argument consumed, leaving the string on the stack area.
+        #[repr(C)]
+        struct RawVaList(u32, u32, *const u64, *const u8);
+        let stack = [long.as_ptr() as u64];
+        for (library, expected) in [(&limited, 15), (&unlimited, 100)] {
+            let vsprintf: unsafe extern "C" fn(*mut c_char, *const c_char, *mut RawVaList) -> c_int =
+                unsafe { std::mem::transmute(library.get_symbol("call_vsprintf").unwrap()) };
+            let mut args = RawVaList(48, 176, stack.as_ptr(), std::ptr::null());
+            let len = unsafe { vsprintf(buffer.as_mut_ptr(), b"%s\0".as_ptr() as *const c_char, &mut args) };
+            assert_eq!(len, expected);
+        }
+    }
+}
@@ -0,0 +1,549 @@
+//! Buffered stdio (`FILE*`) stubs over the [virtual filesystem](crate::vfs).
+//!
+//! Streams aren't buffered at all: each call goes straight to the descriptor `fopen` opened.
+//! `stdout` and `stderr` go to the [output sink](crate::stubs::stdio::set_output_sink), and
+//! `stdin` is always at its end. Besides the `stdin`/`stdout`/`stderr` variables, old NDKs' `__sF` array
+//! of the three standard streams is provided.
+
+use lazy_static::lazy_static;
+use std::cell::UnsafeCell;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int, c_long, c_void};
+use std::sync::Mutex;
+
+use crate::caller::caller_entry;
+use crate::stubs::errno::{set_errno, EBADF, EINVAL};
+use crate::stubs::format;
+use crate::stubs::fs;
+use crate::stubs::stdio;
+use crate::stubs::varargs::{asm_symbol, variadic_entry, VaList};
+use crate::sysv64;
+use crate::vfs::{self, FsResult};
+
+const O_WRONLY: c_int = 0o1;
+const O_RDWR: c_int = 0o2;
+const O_CREAT: c_int = 0o100;
+const O_TRUNC: c_int = 0o1000;
+const O_APPEND: c_int = 0o2000;
+const EOF: c_int = -1;
+
+/// bionic's `sizeof(FILE)`, so `__sF` entries are where libraries expect them
+#[cfg(target_pointer_width = "64")]
+const FILE_SIZE: usize = 152;
+#[cfg(target_pointer_width = "32")]
+const FILE_SIZE: usize = 84;
+
+/// What a `FILE*` points to. Its contents are never used, only its address.
+#[repr(C)]
+struct FileStorage(UnsafeCell<[u8; FILE_SIZE]>);
+
+unsafe impl Sync for FileStorage {}
+
+/// A variable holding a `FILE*`, like `stdout`
+#[repr(transparent)]
+struct StreamVariable(*const FileStorage);
+
+unsafe impl Sync for StreamVariable {}
+
+#[allow(non_upper_case_globals)]
+static __sF: [FileStorage; 3] = [
+    FileStorage(UnsafeCell::new([0; FILE_SIZE])),
+    FileStorage(UnsafeCell::new([0; FILE_SIZE])),
+    FileStorage(UnsafeCell::new([0; FILE_SIZE])),
+];
+static STDIN: StreamVariable = StreamVariable(&__sF[0]);
+static STDOUT: StreamVariable = StreamVariable(&__sF[1]);
+static STDERR: StreamVariable = StreamVariable(&__sF[2]);
+
+struct Stream {
+    fd: c_int,
+    eof: bool,
+    error: bool,
+    /// The `FILE` of an `fopen`ed stream, `None` for the standard ones
+    #[allow(dead_code)]
+    storage: Option<Box<FileStorage>>,
+}
+
+impl Stream {
+    fn new(fd: c_int, storage: Option<Box<FileStorage>>) -> Stream {
+        Stream { fd, eof: false, error: false, storage }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> FsResult<usize> {
+        match self.fd {
+            0 => Err(EBADF),
+            1 | 2 => {
+                stdio::write_output(self.fd, bytes);
+                Ok(bytes.len())
+            }
+            fd => fs::with_file(fd, Err(EBADF), |file| Ok(file.write(bytes))),
+        }
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> FsResult<usize> {
+        match self.fd {
+            0 => Ok(0),
+            1 | 2 => Err(EBADF),
+            fd => fs::with_file(fd, Err(EBADF), |file| Ok(file.read(buffer))),
+        }
+    }
+
+    fn flush(&mut self) {
+        if let 1 | 2 = self.fd {
+            stdio::flush_output(self.fd);
+        }
+    }
+
+    /// Read until `buffer` is full or the end, recording either of them
+    fn fill(&mut self, buffer: &mut [u8]) -> usize {
+        let mut done = 0;
+        while done < buffer.len() {
+            match self.read(&mut buffer[done..]) {
+                Ok(0) => {
+                    self.eof = true;
+                    break;
+                }
+                Ok(len) => done += len,
+                Err(errno) => {
+                    set_errno(errno);
+                    self.error = true;
+                    break;
+                }
+            }
+        }
+        done
+    }
+
+    /// Write all of `bytes`, returning whether it was
+    fn write_all(&mut self, bytes: &[u8]) -> bool {
+        let mut done = 0;
+        while done < bytes.len() {
+            match self.write(&bytes[done..]) {
+                Ok(len) if len > 0 => done += len,
+                result => {
+                    set_errno(result.err().unwrap_or(EBADF));
+                    self.error = true;
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+lazy_static! {
+    /// Open streams by `FILE*`
+    static ref STREAMS: Mutex<HashMap<usize, Stream>> = Mutex::new(
+        __sF.iter().enumerate().map(|(fd, storage)| (storage as *const FileStorage as usize, Stream::new(fd as c_int, None))).collect()
+    );
+}
+
+fn with_stream<T>(file: *mut c_void, error: T, operation: impl FnOnce(&mut Stream) -> T) -> T {
+    match STREAMS.lock().unwrap().get_mut(&(file as usize)) {
+        Some(stream) => operation(stream),
+        None => {
+            set_errno(EBADF);
+            error
+        }
+    }
+}
+
+/// `open` flags for an `fopen` mode
+fn open_flags(mode: &[u8]) -> Option<c_int> {
+    let update = mode.contains(&b'+');
+    let access = |write_only| if update { O_RDWR } else { write_only };
+    Some(match mode.first()? {
+        b'r' => access(0),
+        b'w' => access(O_WRONLY) | O_CREAT | O_TRUNC,
+        b'a' => access(O_WRONLY) | O_CREAT | O_APPEND,
+        _ => return None,
+    })
+}
+
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_fopen_from(path: *const c_char, mode: *const c_char, caller: usize) -> *mut c_void {
+    let flags = match mode.as_ref().and_then(|_| open_flags(CStr::from_ptr(mode).to_bytes())) {
+        Some(flags) => flags,
+        None => {
+            set_errno(EINVAL);
+            return std::ptr::null_mut();
+        }
+    };
+    let fd = match fs::open_fd(&*vfs::virtual_fs(caller), path, flags) {
+        Ok(fd) => fd,
+        Err(errno) => {
+            set_errno(errno);
+            return std::ptr::null_mut();
+        }
+    };
+    let storage = Box::new(FileStorage(UnsafeCell::new([0; FILE_SIZE])));
+    let file = &*storage as *const FileStorage as *mut c_void;
+    STREAMS.lock().unwrap().insert(file as usize, Stream::new(fd, Some(storage)));
+    file
+}
+
+/// The standard streams are flushed but stay open
+#[sysv64]
+fn fclose(file: *mut c_void) -> c_int {
+    let mut streams = STREAMS.lock().unwrap();
+    match streams.get(&(file as usize)).map(|stream| stream.fd) {
+        Some(fd) if fd > 2 => {
+            streams.remove(&(file as usize));
+            drop(streams);
+            if fs::close(fd) == 0 { 0 } else { EOF }
+        }
+        Some(_) => {
+            streams.get_mut(&(file as usize)).unwrap().flush();
+            0
+        }
+        None => {
+            set_errno(EBADF);
+            EOF
+        }
+    }
+}
+
+#[sysv64]
+unsafe fn fread(buffer: *mut c_void, size: usize, count: usize, file: *mut c_void) -> usize {
+    let total = match size.checked_mul(count) {
+        Some(0) | None => return 0,
+        Some(total) => total,
+    };
+    let buffer = std::slice::from_raw_parts_mut(buffer as *mut u8, total);
+    with_stream(file, 0, |stream| stream.fill(buffer) / size)
+}
+
+#[sysv64]
+unsafe fn fwrite(buffer: *const c_void, size: usize, count: usize, file: *mut c_void) -> usize {
+    let total = match size.checked_mul(count) {
+        Some(0) | None => return 0,
+        Some(total) => total,
+    };
+    let buffer = std::slice::from_raw_parts(buffer as *const u8, total);
+    with_stream(file, 0, |stream| if stream.write_all(buffer) { count } else { 0 })
+}
+
+#[sysv64]
+unsafe fn fputs(s: *const c_char, file: *mut c_void) -> c_int {
+    with_stream(file, EOF, |stream| if stream.write_all(CStr::from_ptr(s).to_bytes()) { 0 } else { EOF })
+}
+
+#[sysv64]
+unsafe fn puts(s: *const c_char) -> c_int {
+    let stdout = STDOUT.0 as *mut c_void;
+    with_stream(stdout, EOF, |stream| if stream.write_all(CStr::from_ptr(s).to_bytes()) && stream.write_all(b"\n") { 0 } else { EOF })
+}
+
+#[sysv64]
+fn fputc(c: c_int, file: *mut c_void) -> c_int {
+    with_stream(file, EOF, |stream| if stream.write_all(&[c as u8]) { c as u8 as c_int } else { EOF })
+}
+
+#[sysv64]
+fn fgetc(file: *mut c_void) -> c_int {
+    with_stream(file, EOF, |stream| {
+        let mut byte = [0];
+        if stream.fill(&mut byte) == 1 { byte[0] as c_int } else { EOF }
+    })
+}
+
+/// Reads up to a newline (kept) or `size - 1` characters, whichever comes first
+#[sysv64]
+unsafe fn fgets(buffer: *mut c_char, size: c_int, file: *mut c_void) -> *mut c_char {
+    if size <= 0 {
+        set_errno(EINVAL);
+        return std::ptr::null_mut();
+    }
+    with_stream(file, std::ptr::null_mut(), |stream| {
+        let mut len = 0;
+        let mut byte = [0];
+        while len + 1 < size as usize && stream.fill(&mut byte) == 1 {
+            *buffer.add(len) = byte[0] as c_char;
+            len += 1;
+            if byte[0] == b'\n' {
+                break;
+            }
+        }
+        if len == 0 && size > 1 {
+            return std::ptr::null_mut();
+        }
+        *buffer.add(len) = 0;
+        buffer
+    })
+}
+
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_vfprintf(file: *mut c_void, format: *const c_char, args: *mut c_void) -> c_int {
+    let output = format::format(format, &mut VaList::new(args));
+    with_stream(file, -1, |stream| if stream.write_all(&output) { output.len() as c_int } else { -1 })
+}
+
+#[no_mangle]
+#[sysv64]
+unsafe fn android_loader_vprintf(format: *const c_char, args: *mut c_void) -> c_int {
+    android_loader_vfprintf(STDOUT.0 as *mut c_void, format, args)
+}
+
+variadic_entry!("android_loader_fprintf", 2, "android_loader_vfprintf");
+variadic_entry!("android_loader_printf", 1, "android_loader_vprintf");
+
+extern "C" {
+    fn android_loader_fprintf();
+    fn android_loader_printf();
+}
+
+fn seek(file: *mut c_void, offset: i64, whence: c_int) -> c_int {
+    let fd = with_stream(file, None, |stream| Some(stream.fd));
+    match fd {
+        Some(fd) if fs::seek(fd, offset, whence) >= 0 => {
+            with_stream(file, (), |stream| stream.eof = false);
+            0
+        }
+        _ => -1,
+    }
+}
+
+#[sysv64]
+fn fseek(file: *mut c_void, offset: c_long, whence: c_int) -> c_int {
+    seek(file, offset as i64, whence)
+}
+
+#[sysv64]
+fn fseeko64(file: *mut c_void, offset: i64, whence: c_int) -> c_int {
+    seek(file, offset, whence)
+}
+
+fn tell(file: *mut c_void) -> i64 {
+    match with_stream(file, None, |stream| Some(stream.fd)) {
+        Some(fd) => fs::seek(fd, 0, 1),
+        None => -1,
+    }
+}
+
+#[sysv64]
+fn ftell(file: *mut c_void) -> c_long {
+    tell(file) as c_long
+}
+
+#[sysv64]
+fn ftello64(file: *mut c_void) -> i64 {
+    tell(file)
+}
+
+#[sysv64]
+fn rewind(file: *mut c_void) {
+    if seek(file, 0, 0) == 0 {
+        with_stream(file, (), |stream| stream.error = false);
+    }
+}
+
+/// Flushes the output sink for the standard streams, everything else is unbuffered
+#[sysv64]
+fn fflush(file: *mut c_void) -> c_int {
+    if file.is_null() {
+        STREAMS.lock().unwrap().values_mut().for_each(Stream::flush);
+        return 0;
+    }
+    with_stream(file, EOF, |stream| {
+        stream.flush();
+        0
+    })
+}
+
+#[sysv64]
+fn feof(file: *mut c_void) -> c_int {
+    with_stream(file, 0, |stream| stream.eof as c_int)
+}
+
+#[sysv64]
+fn ferror(file: *mut c_void) -> c_int {
+    with_stream(file, 0, |stream| stream.error as c_int)
+}
+
+#[sysv64]
+fn clearerr(file: *mut c_void) {
+    with_stream(file, (), |stream| {
+        stream.eof = false;
+        stream.error = false;
+    });
+}
+
+#[sysv64]
+fn fileno(file: *mut c_void) -> c_int {
+    with_stream(file, -1, |stream| stream.fd)
+}
+
+caller_entry!("android_loader_fopen", 2, "android_loader_fopen_from");
+
+extern "C" {
+    fn android_loader_fopen();
+}
+
+/// The stubs taking their caller, as (implementation, arguments before the caller), for
+/// binding to the libraries importing them
+pub(crate) fn caller_sensitive(symbol_name: &str) -> Option<(usize, usize)> {
+    match symbol_name {
+        "fopen" | "fopen64" => Some((android_loader_fopen_from as *const () as usize, 2)),
+        _ => None,
+    }
+}
+
+pub(crate) fn lookup(symbol_name: &str) -> Option<*const ()> {
+    Some(match symbol_name {
+        "stdin" => &STDIN as *const StreamVariable as *const (),
+        "stdout" => &STDOUT as *const StreamVariable as *const (),
+        "stderr" => &STDERR as *const StreamVariable as *const (),
+        "__sF" => &__sF as *const [FileStorage; 3] as *const (),
+        "fopen" | "fopen64" => android_loader_fopen as *const (),
+        "fclose" => fclose as *const (),
+        "fread" => fread as *const (),
+        "fwrite" => fwrite as *const (),
+        "fputs" => fputs as *const (),
+        "puts" => puts as *const (),
+        "fputc" | "putc" => fputc as *const (),
+        "fgetc" | "getc" => fgetc as *const (),
+        "fgets" => fgets as *const (),
+        "fprintf" => android_loader_fprintf as *const (),
+        "vfprintf" => android_loader_vfprintf as *const (),
+        "printf" => android_loader_printf as *const (),
+        "vprintf" => android_loader_vprintf as *const (),
+        #[cfg(target_pointer_width = "64")]
+        "fseeko" => fseek as *const (),
+        #[cfg(target_pointer_width = "64")]
+        "ftello" => ftell as *const (),
+        "fseek" => fseek as *const (),
+        "fseeko64" => fseeko64 as *const (),
+        "ftell" => ftell as *const (),
+        "ftello64" => ftello64 as *const (),
+        "rewind" => rewind as *const (),
+        "fflush" => fflush as *const (),
+        "feof" => feof as *const (),
+        "ferror" => ferror as *const (),
+        "clearerr" => clearerr as *const (),
+        "fileno" => fileno as *const (),
+        _ => return None,
+    })
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::ffi::CStr;
+    use std::io::SeekFrom;
+    use std::os::raw::{c_char, c_int, c_void};
+    use std::sync::{Arc, Mutex};
+
+    use crate::android_loader::AndroidLoader;
+    use crate::stubs::errno::EINVAL;
+    use crate::test_elf::TestElf;
+    use crate::vfs::{FsResult, VirtualFile, VirtualFs, VirtualMetadata};
+
+    /// A filesystem of one writable file at `/data/out.txt`
+    struct WritableFs(Arc<Mutex<Vec<u8>>>);
+
+    struct WritableFile {
+        contents: Arc<Mutex<Vec<u8>>>,
+        position: usize,
+    }
+
+    impl VirtualFs for WritableFs {
+        fn stat(&self, _path: &str) -> FsResult<VirtualMetadata> {
+            Ok(VirtualMetadata::file(self.0.lock().unwrap().len() as u64))
+        }
+
+        fn open(&self, _path: &str, flags: i32) -> FsResult<Box<dyn VirtualFile>> {
+            if flags & 0o1000 != 0 {
+                self.0.lock().unwrap().clear();
+            }
+            Ok(Box::new(WritableFile { contents: self.0.clone(), position: 0 }))
+        }
+    }
+
+    impl VirtualFile for WritableFile {
+        fn read(&mut self, buffer: &mut [u8]) -> FsResult<usize> {
+            let contents = self.contents.lock().unwrap();
+            let len = buffer.len().min(contents.len().saturating_sub(self.position));
+            buffer[..len].copy_from_slice(&contents[self.position..self.position + len]);
+            self.position += len;
+            Ok(len)
+        }
+
+        fn write(&mut self, buffer: &[u8]) -> FsResult<usize> {
+            let mut contents = self.contents.lock().unwrap();
+            contents.truncate(self.position);
+            contents.extend_from_slice(buffer);
+            self.position += buffer.len();
+            Ok(buffer.len())
+        }
+
+        fn seek(&mut self, position: SeekFrom) -> FsResult<u64> {
+            self.position = match position {
+                SeekFrom::Start(offset) => offset as usize,
+                SeekFrom::Current(offset) => (self.position as i64 + offset) as usize,
+                SeekFrom::End(offset) => (self.contents.lock().unwrap().len() as i64 + offset) as usize,
+            };
+            Ok(self.position as u64)
+        }
+
+        fn metadata(&self) -> FsResult<VirtualMetadata> {
+            Ok(VirtualMetadata::file(self.contents.lock().unwrap().len() as u64))
+        }
+    }
+
+    fn c(s: &[u8]) -> *const c_char {
+        s.as_ptr() as *const c_char
+    }
+
+    #[test]
+    fn loaded_streams() {
+        let contents = Arc::new(Mutex::new(Vec::new()));
+
+        let names = ["fopen", "fclose", "fprintf", "fread", "fgets", "fseek", "ftell", "feof", "fputs"];
+        let mut elf = TestElf::new();
+        for name in names {
+            elf.thunk(&format!("call_{name}"), name);
+        }
+        let library = AndroidLoader::new().virtual_fs(WritableFs(contents.clone())).load_library_from_bytes(elf.build()).unwrap();
+        let function = |name: &str| library.get_symbol(&format!("call_{name}")).unwrap();
+
+        unsafe {
+            let fopen: extern "C" fn(*const c_char, *const c_char) -> *mut c_void = std::mem::transmute(function("fopen"));
+            let fclose: extern "C" fn(*mut c_void) -> c_int = std::mem::transmute(function("fclose"));
+            let fprintf: unsafe extern "C" fn(*mut c_void, *const c_char, ...) -> c_int = std::mem::transmute(function("fprintf"));
+            let fread: extern "C" fn(*mut c_void, usize, usize, *mut c_void) -> usize = std::mem::transmute(function("fread"));
+            let fgets: extern "C" fn(*mut c_char, c_int, *mut c_void) -> *mut c_char = std::mem::transmute(function("fgets"));
+            let fseek: extern "C" fn(*mut c_void, i64, c_int) -> c_int = std::mem::transmute(function("fseek"));
+            let ftell: extern "C" fn(*mut c_void) -> i64 = std::mem::transmute(function("ftell"));
+            let feof: extern "C" fn(*mut c_void) -> c_int = std::mem::transmute(function("feof"));
+            let fputs: extern "C" fn(*const c_char, *mut c_void) -> c_int = std::mem::transmute(function("fputs"));
+
+            let file = fopen(c(b"/data/out.txt\0"), c(b"w+\0"));
+            assert!(!file.is_null());
+            assert_eq!(fprintf(file, c(b"%s=%d\n\0"), c(b"answer\0"), 42 as c_int), 10);
+            assert_eq!(fputs(c(b"second line\0"), file), 0);
+            assert_eq!(ftell(file), 21);
+            assert_eq!(*contents.lock().unwrap(), b"answer=42\nsecond line");
+
+            assert_eq!(fseek(file, 0, 0), 0);
+            let mut line = [0 as c_char; 32];
+            assert_eq!(fgets(line.as_mut_ptr(), line.len() as c_int, file), line.as_mut_ptr());
+            assert_eq!(CStr::from_ptr(line.as_ptr()).to_bytes(), b"answer=42\n");
+            let mut rest = [0u8; 32];
+            assert_eq!(fread(rest.as_mut_ptr() as *mut c_void, 1, rest.len(), file), 11);
+            assert_eq!(&rest[..11], b"second line");
+            assert_eq!(feof(file), 1);
+            assert_eq!(fclose(file), 0);
+
+            assert!(fopen(c(b"/data/out.txt\0"), c(b"x\0")).is_null());
+            assert_eq!(crate::stubs::errno::errno(), EINVAL);
+
+            // stdout goes to the output sink, and stays open
+            let stdout = *(super::lookup("stdout").unwrap() as *const *mut c_void);
+            assert_eq!(fputs(c(b"to the log\n\0"), stdout), 0);
+            assert_eq!(fclose(stdout), 0);
+            assert_eq!(fputs(c(b"still open\n\0"), stdout), 0);
+        }
+    }
+}
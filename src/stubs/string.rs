@@ -0,0 +1,335 @@
+//! `<string.h>` functions on null-terminated strings and on memory. Comparisons treat
+//! characters as `unsigned char`, like C requires.
+//!
+//! `strdup` and `strndup` allocate through the `malloc` stub, so their results count towards
+//! `mallinfo` and go back through the `free` stub like any other allocation.
+
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr::null_mut;
+
+use crate::stubs::malloc;
+use crate::sysv64;
+
+/// Length of `s`, looking at no more than `max` characters
+unsafe fn bounded_length(s: *const c_char, max: usize) -> usize {
+    let mut length = 0;
+    while length < max && *s.add(length) != 0 {
+        length += 1;
+    }
+    length
+}
+
+unsafe fn length(s: *const c_char) -> usize {
+    bounded_length(s, usize::MAX)
+}
+
+/// Compares at most `n` characters, stopping after the first terminator
+unsafe fn compare(a: *const c_char, b: *const c_char, n: usize) -> c_int {
+    for i in 0..n {
+        let (a, b) = (*a.cast::<u8>().add(i), *b.cast::<u8>().add(i));
+        if a != b || a == 0 {
+            return a as c_int - b as c_int;
+        }
+    }
+    0
+}
+
+/// A copy of the first `len` characters of `s` from the `malloc` stub, null-terminated
+unsafe fn duplicate(s: *const c_char, len: usize) -> *mut c_char {
+    // `malloc` sets `errno` itself
+    let copy = malloc::malloc(len + 1) as *mut c_char;
+    if copy.is_null() {
+        return null_mut();
+    }
+    s.copy_to_nonoverlapping(copy, len);
+    *copy.add(len) = 0;
+    copy
+}
+
+#[sysv64]
+unsafe fn strlen(s: *const c_char) -> usize {
+    length(s)
+}
+
+#[sysv64]
+unsafe fn strcmp(a: *const c_char, b: *const c_char) -> c_int {
+    compare(a, b, usize::MAX)
+}
+
+#[sysv64]
+unsafe fn strncmp(a: *const c_char, b: *const c_char, n: usize) -> c_int {
+    compare(a, b, n)
+}
+
+#[sysv64]
+unsafe fn strcpy(destination: *mut c_char, source: *const c_char) -> *mut c_char {
+    source.copy_to_nonoverlapping(destination, length(source) + 1);
+    destination
+}
+
+/// Copies at most `n` characters and pads the rest of the `n` with nulls. A source of `n` or
+/// more characters leaves the destination unterminated.
+#[sysv64]
+unsafe fn strncpy(destination: *mut c_char, source: *const c_char, n: usize) -> *mut c_char {
+    let copied = bounded_length(source, n);
+    source.copy_to_nonoverlapping(destination, copied);
+    destination.add(copied).write_bytes(0, n - copied);
+    destination
+}
+
+#[sysv64]
+unsafe fn strcat(destination: *mut c_char, source: *const c_char) -> *mut c_char {
+    strcpy(destination.add(length(destination)), source);
+    destination
+}
+
+#[sysv64]
+unsafe fn strdup(s: *const c_char) -> *mut c_char {
+    duplicate(s, length(s))
+}
+
+#[sysv64]
+unsafe fn strndup(s: *const c_char, n: usize) -> *mut c_char {
+    duplicate(s, bounded_length(s, n))
+}
+
+/// The terminator counts as part of the string, so `strchr(s, 0)` finds it
+#[sysv64]
+unsafe fn strchr(s: *const c_char, c: c_int) -> *mut c_char {
+    let c = c as u8;
+    let mut at = s;
+    loop {
+        if *at as u8 == c {
+            return at as *mut c_char;
+        }
+        if *at == 0 {
+            return null_mut();
+        }
+        at = at.add(1);
+    }
+}
+
+#[sysv64]
+unsafe fn strrchr(s: *const c_char, c: c_int) -> *mut c_char {
+    let c = c as u8;
+    let (mut at, mut last) = (s, null_mut());
+    loop {
+        if *at as u8 == c {
+            last = at as *mut c_char;
+        }
+        if *at == 0 {
+            return last;
+        }
+        at = at.add(1);
+    }
+}
+
+/// An empty needle is found at the start of the haystack
+#[sysv64]
+unsafe fn strstr(haystack: *const c_char, needle: *const c_char) -> *mut c_char {
+    let needle_length = length(needle);
+    let mut at = haystack;
+    loop {
+        if compare(at, needle, needle_length) == 0 {
+            return at as *mut c_char;
+        }
+        if *at == 0 {
+            return null_mut();
+        }
+        at = at.add(1);
+    }
+}
+
+#[sysv64]
+unsafe fn memcpy(destination: *mut c_void, source: *const c_void, n: usize) -> *mut c_void {
+    (source as *const u8).copy_to_nonoverlapping(destination as *mut u8, n);
+    destination
+}
+
+#[sysv64]
+unsafe fn memmove(destination: *mut c_void, source: *const c_void, n: usize) -> *mut c_void {
+    (source as *const u8).copy_to(destination as *mut u8, n);
+    destination
+}
+
+#[sysv64]
+unsafe fn memset(destination: *mut c_void, c: c_int, n: usize) -> *mut c_void {
+    (destination as *mut u8).write_bytes(c as u8, n);
+    destination
+}
+
+#[sysv64]
+unsafe fn memcmp(a: *const c_void, b: *const c_void, n: usize) -> c_int {
+    if n == 0 {
+        return 0;
+    }
+    let (a, b) = (std::slice::from_raw_parts(a as *const u8, n), std::slice::from_raw_parts(b as *const u8, n));
+    a.iter().zip(b).find(|(a, b)| a != b).map_or(0, |(a, b)| *a as c_int - *b as c_int)
+}
+
+#[sysv64]
+unsafe fn memchr(s: *const c_void, c: c_int, n: usize) -> *mut c_void {
+    if n == 0 {
+        return null_mut();
+    }
+    std::slice::from_raw_parts(s as *const u8, n).iter()
+        .position(|&byte| byte == c as u8)
+        .map_or(null_mut(), |index| (s as *mut u8).add(index) as *mut c_void)
+}
+
+pub(crate) fn lookup(symbol_name: &str) -> Option<*const ()> {
+    Some(match symbol_name {
+        "strlen" => strlen as *const (),
+        "strcmp" => strcmp as *const (),
+        "strncmp" => strncmp as *const (),
+        "strcpy" => strcpy as *const (),
+        "strncpy" => strncpy as *const (),
+        "strcat" => strcat as *const (),
+        "strdup" => strdup as *const (),
+        "strndup" => strndup as *const (),
+        "strchr" => strchr as *const (),
+        "strrchr" => strrchr as *const (),
+        "strstr" => strstr as *const (),
+        "memcpy" => memcpy as *const (),
+        "memmove" => memmove as *const (),
+        "memset" => memset as *const (),
+        "memcmp" => memcmp as *const (),
+        "memchr" => memchr as *const (),
+        _ => return None,
+    })
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::ffi::CStr;
+    use std::os::raw::{c_char, c_int, c_void};
+
+    use crate::android_library::AndroidLibrary;
+    use crate::test_elf::TestElf;
+
+    type Compare = extern "C" fn(*const c_char, *const c_char, usize) -> c_int;
+    type Copy = extern "C" fn(*mut c_char, *const c_char, usize) -> *mut c_char;
+    type Search = extern "C" fn(*const c_char, c_int) -> *mut c_char;
+
+    fn c(s: &[u8]) -> *const c_char {
+        s.as_ptr() as *const c_char
+    }
+
+    #[test]
+    fn loaded_strings() {
+        let names = ["strlen", "strcmp", "strncmp", "strcpy", "strncpy", "strcat", "strdup", "strndup", "strchr", "strrchr", "strstr", "malloc_usable_size", "free"];
+        let mut elf = TestElf::new();
+        for name in names {
+            elf.thunk(&format!("call_{name}"), name);
+        }
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let function = |name: &str| library.get_symbol(&format!("call_{name}")).unwrap();
+
+        unsafe {
+            let strlen: extern "C" fn(*const c_char) -> usize = std::mem::transmute(function("strlen"));
+            assert_eq!(strlen(c(b"\0")), 0);
+            assert_eq!(strlen(c(b"four\0")), 4);
+
+            let strcmp: extern "C" fn(*const c_char, *const c_char) -> c_int = std::mem::transmute(function("strcmp"));
+            let strncmp: Compare = std::mem::transmute(function("strncmp"));
+            assert_eq!(strcmp(c(b"abc\0"), c(b"abc\0")), 0);
+            assert!(strcmp(c(b"abc\0"), c(b"abd\0")) < 0);
+            assert!(strcmp(c(b"ab\0"), c(b"\0")) > 0);
+            // Characters compare as unsigned
+            assert!(strcmp(c(b"\xe9\0"), c(b"a\0")) > 0);
+            assert_eq!(strncmp(c(b"abcx\0"), c(b"abcy\0"), 3), 0);
+            assert!(strncmp(c(b"abcx\0"), c(b"abcy\0"), 4) < 0);
+            assert_eq!(strncmp(c(b"ab\0x"), c(b"ab\0y"), 4), 0);
+            assert_eq!(strncmp(c(b"a\0"), c(b"b\0"), 0), 0);
+
+            let strcpy: extern "C" fn(*mut c_char, *const c_char) -> *mut c_char = std::mem::transmute(function("strcpy"));
+            let strncpy: Copy = std::mem::transmute(function("strncpy"));
+            let strcat: extern "C" fn(*mut c_char, *const c_char) -> *mut c_char = std::mem::transmute(function("strcat"));
+            let mut buffer = [b'#' as c_char; 8];
+            let destination = buffer.as_mut_ptr();
+            assert_eq!(strcpy(destination, c(b"hi\0")), destination);
+            assert_eq!(strcat(destination, c(b" you\0")), destination);
+            assert_eq!(CStr::from_ptr(destination).to_bytes(), b"hi you");
+            assert_eq!(buffer[7], b'#' as c_char);
+
+            // Shorter sources are padded, exactly-long ones aren't terminated
+            let mut buffer = [b'#' as c_char; 6];
+            strncpy(buffer.as_mut_ptr(), c(b"ab\0"), 5);
+            assert_eq!(buffer.map(|c| c as u8), *b"ab\0\0\0#");
+            strncpy(buffer.as_mut_ptr(), c(b"vwxyz\0"), 5);
+            assert_eq!(buffer.map(|c| c as u8), *b"vwxyz#");
+            strncpy(buffer.as_mut_ptr(), c(b"\0"), 0);
+            assert_eq!(buffer[0], b'v' as c_char);
+
+            let strdup: extern "C" fn(*const c_char) -> *mut c_char = std::mem::transmute(function("strdup"));
+            let strndup: extern "C" fn(*const c_char, usize) -> *mut c_char = std::mem::transmute(function("strndup"));
+            let usable_size: extern "C" fn(*const c_void) -> usize = std::mem::transmute(function("malloc_usable_size"));
+            let free: extern "C" fn(*mut c_void) = std::mem::transmute(function("free"));
+            for (copy, expected) in [
+                (strdup(c(b"copy me\0")), &b"copy me"[..]),
+                (strdup(c(b"\0")), b""),
+                (strndup(c(b"truncate\0"), 5), b"trunc"),
+                (strndup(c(b"abc\0"), 10), b"abc"),
+                // Not terminated within the limit
+                (strndup(c(b"xyz"), 3), b"xyz"),
+            ] {
+                assert_eq!(CStr::from_ptr(copy).to_bytes(), expected);
+                // Tracked by the `malloc` stub, terminator included
+                assert_eq!(usable_size(copy as *const c_void), expected.len() + 1);
+                free(copy as *mut c_void);
+            }
+
+            let strchr: Search = std::mem::transmute(function("strchr"));
+            let strrchr: Search = std::mem::transmute(function("strrchr"));
+            let strstr: extern "C" fn(*const c_char, *const c_char) -> *mut c_char = std::mem::transmute(function("strstr"));
+            let path = c(b"/a/b/c\0");
+            assert_eq!(strchr(path, b'/' as c_int) as *const c_char, path);
+            assert_eq!(strrchr(path, b'/' as c_int) as *const c_char, path.add(4));
+            assert_eq!(strchr(path, 0) as *const c_char, path.add(6));
+            assert_eq!(strrchr(path, 0) as *const c_char, path.add(6));
+            assert!(strchr(path, b'z' as c_int).is_null());
+            assert!(strrchr(c(b"\0"), b'a' as c_int).is_null());
+            assert_eq!(strstr(path, c(b"b/c\0")) as *const c_char, path.add(3));
+            assert_eq!(strstr(path, c(b"\0")) as *const c_char, path);
+            assert!(strstr(path, c(b"c/\0")).is_null());
+            assert!(strstr(c(b"\0"), c(b"a\0")).is_null());
+        }
+    }
+
+    #[test]
+    fn loaded_memory_functions() {
+        let names = ["memcpy", "memmove", "memset", "memcmp", "memchr"];
+        let mut elf = TestElf::new();
+        for name in names {
+            elf.thunk(&format!("call_{name}"), name);
+        }
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let function = |name: &str| library.get_symbol(&format!("call_{name}")).unwrap();
+
+        unsafe {
+            let memcpy: extern "C" fn(*mut u8, *const u8, usize) -> *mut u8 = std::mem::transmute(function("memcpy"));
+            let memmove: extern "C" fn(*mut u8, *const u8, usize) -> *mut u8 = std::mem::transmute(function("memmove"));
+            let memset: extern "C" fn(*mut u8, c_int, usize) -> *mut u8 = std::mem::transmute(function("memset"));
+            let memcmp: extern "C" fn(*const u8, *const u8, usize) -> c_int = std::mem::transmute(function("memcmp"));
+            let memchr: extern "C" fn(*const u8, c_int, usize) -> *mut u8 = std::mem::transmute(function("memchr"));
+
+            let mut buffer = *b"abcdef\0\0";
+            assert_eq!(memcpy(buffer.as_mut_ptr().add(6), b"gh".as_ptr(), 2), buffer.as_mut_ptr().add(6));
+            assert_eq!(&buffer, b"abcdefgh");
+            // Overlapping, both ways
+            memmove(buffer.as_mut_ptr().add(2), buffer.as_ptr(), 4);
+            assert_eq!(&buffer, b"ababcdgh");
+            memmove(buffer.as_mut_ptr(), buffer.as_ptr().add(2), 6);
+            assert_eq!(&buffer, b"abcdghgh");
+            memset(buffer.as_mut_ptr().add(4), b'z' as c_int, 4);
+            assert_eq!(&buffer, b"abcdzzzz");
+
+            assert_eq!(memcmp(b"ab\0x".as_ptr(), b"ab\0y".as_ptr(), 3), 0);
+            assert!(memcmp(b"ab\0x".as_ptr(), b"ab\0y".as_ptr(), 4) < 0);
+            assert!(memcmp(b"\xe9".as_ptr(), b"a".as_ptr(), 1) > 0);
+            assert_eq!(memcmp(std::ptr::null(), std::ptr::null(), 0), 0);
+            assert_eq!(memchr(buffer.as_ptr(), b'z' as c_int, 8) as *const u8, buffer.as_ptr().add(4));
+            assert!(memchr(buffer.as_ptr(), b'z' as c_int, 4).is_null());
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! `sysconf`, answering bionic's `_SC_*` names from the host, with configurable overrides.
+//!
+//! The page size is the host's, the processor counts and memory sizes are what the host
+//! reports on Linux (the processors [`std::thread::available_parallelism`] finds elsewhere), and
+//! `_SC_CLK_TCK` is the 100 Linux always gives userspace. Other names fail with `EINVAL` unless
+//! set.
+
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::os::raw::{c_int, c_long};
+use std::sync::Mutex;
+
+use crate::stubs::errno::{set_errno, EINVAL};
+use crate::sysv64;
+
+// bionic's numbering, which isn't glibc's
+pub const _SC_ARG_MAX: c_int = 0x00;
+pub const _SC_CHILD_MAX: c_int = 0x05;
+pub const _SC_CLK_TCK: c_int = 0x06;
+pub const _SC_OPEN_MAX: c_int = 0x0b;
+pub const _SC_PAGESIZE: c_int = 0x27;
+pub const _SC_PAGE_SIZE: c_int = 0x28;
+pub const _SC_NPROCESSORS_CONF: c_int = 0x60;
+pub const _SC_NPROCESSORS_ONLN: c_int = 0x61;
+pub const _SC_PHYS_PAGES: c_int = 0x62;
+pub const _SC_AVPHYS_PAGES: c_int = 0x63;
+
+lazy_static! {
+    static ref OVERRIDES: Mutex<HashMap<c_int, c_long>> = Mutex::new(HashMap::new());
+}
+
+/// Make `sysconf(name)` return `value` to loaded libraries, or what it would by default again
+/// with `None`
+pub fn set_sysconf_value(name: c_int, value: Option<c_long>) {
+    let mut overrides = OVERRIDES.lock().unwrap();
+    match value {
+        Some(value) => overrides.insert(name, value),
+        None => overrides.remove(&name),
+    };
+}
+
+/// What the host answers for the glibc equivalent of the bionic `name`, if it's one we pass on
+#[cfg(target_os = "linux")]
+fn host_value(name: c_int) -> Option<c_long> {
+    let host_name = match name {
+        _SC_ARG_MAX => libc::_SC_ARG_MAX,
+        _SC_CHILD_MAX => libc::_SC_CHILD_MAX,
+        _SC_OPEN_MAX => libc::_SC_OPEN_MAX,
+        _SC_NPROCESSORS_CONF => libc::_SC_NPROCESSORS_CONF,
+        _SC_NPROCESSORS_ONLN => libc::_SC_NPROCESSORS_ONLN,
+        _SC_PHYS_PAGES => libc::_SC_PHYS_PAGES,
+        _SC_AVPHYS_PAGES => libc::_SC_AVPHYS_PAGES,
+        _ => return None,
+    };
+    // -1 without errno for limits the host doesn't have, which is as good an answer here
+    Some(unsafe { libc::sysconf(host_name) })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn host_value(name: c_int) -> Option<c_long> {
+    match name {
+        _SC_NPROCESSORS_CONF | _SC_NPROCESSORS_ONLN => {
+            Some(std::thread::available_parallelism().map_or(1, |count| count.get() as c_long))
+        }
+        _ => None,
+    }
+}
+
+fn default_value(name: c_int) -> Option<c_long> {
+    match name {
+        _SC_PAGESIZE | _SC_PAGE_SIZE => Some(region::page::size() as c_long),
+        _SC_CLK_TCK => Some(100),
+        _ => host_value(name),
+    }
+}
+
+#[sysv64]
+fn sysconf(name: c_int) -> c_long {
+    let value = OVERRIDES.lock().unwrap().get(&name).copied();
+    match value.or_else(|| default_value(name)) {
+        Some(value) => value,
+        None => {
+            set_errno(EINVAL);
+            -1
+        }
+    }
+}
+
+pub(crate) fn lookup(symbol_name: &str) -> Option<*const ()> {
+    match symbol_name {
+        "sysconf" => Some(sysconf as *const ()),
+        _ => None,
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::os::raw::{c_int, c_long};
+
+    use crate::android_library::AndroidLibrary;
+    use crate::stubs::errno::{errno, EINVAL};
+    use crate::stubs::sysconf::{set_sysconf_value, _SC_CLK_TCK, _SC_NPROCESSORS_ONLN, _SC_PAGESIZE, _SC_PHYS_PAGES};
+    use crate::test_elf::TestElf;
+
+    #[test]
+    fn loaded_sysconf() {
+        let mut elf = TestElf::new();
+        elf.thunk("call_sysconf", "sysconf");
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let sysconf: extern "C" fn(c_int) -> c_long = unsafe { std::mem::transmute(library.get_symbol("call_sysconf").unwrap()) };
+
+        assert_eq!(sysconf(_SC_PAGESIZE), region::page::size() as c_long);
+        assert_eq!(sysconf(_SC_NPROCESSORS_ONLN), unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) });
+        assert!(sysconf(_SC_NPROCESSORS_ONLN) >= 1);
+        assert_eq!(sysconf(_SC_CLK_TCK), 100);
+
+        set_sysconf_value(_SC_PHYS_PAGES, Some(4096));
+        assert_eq!(sysconf(_SC_PHYS_PAGES), 4096);
+        set_sysconf_value(_SC_PHYS_PAGES, None);
+        assert_eq!(sysconf(_SC_PHYS_PAGES), unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) });
+
+        // Not a name bionic has
+        assert_eq!(sysconf(0x1000), -1);
+        assert_eq!(errno(), EINVAL);
+        set_sysconf_value(0x1000, Some(128));
+        assert_eq!(sysconf(0x1000), 128);
+    }
+}
@@ -0,0 +1,480 @@
+//! Sleeping, done with `std::thread::sleep`, and the host clock broken down into calendar time
+//! and formatted. Sleeps are never interrupted, so they always run to completion.
+//!
+//! Local time is UTC unless another zone is given with [`set_timezone`]. Zones are a fixed
+//! offset, there is no daylight saving time.
+
+use lazy_static::lazy_static;
+use std::cell::UnsafeCell;
+use std::ffi::{CStr, CString};
+use std::io::Write;
+use std::os::raw::{c_char, c_int, c_long, c_uint};
+use std::ptr::null;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::stubs::errno::{set_errno, EINVAL, EOVERFLOW};
+use crate::sysv64;
+
+/// bionic's `time_t`
+type TimeT = c_long;
+
+/// bionic's `struct tm`
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Tm {
+    tm_sec: c_int,
+    tm_min: c_int,
+    tm_hour: c_int,
+    tm_mday: c_int,
+    tm_mon: c_int,
+    tm_year: c_int,
+    tm_wday: c_int,
+    tm_yday: c_int,
+    tm_isdst: c_int,
+    tm_gmtoff: c_long,
+    tm_zone: *const c_char,
+}
+
+impl Default for Tm {
+    fn default() -> Tm {
+        Tm { tm_sec: 0, tm_min: 0, tm_hour: 0, tm_mday: 0, tm_mon: 0, tm_year: 0, tm_wday: 0, tm_yday: 0, tm_isdst: 0, tm_gmtoff: 0, tm_zone: null() }
+    }
+}
+
+struct Timezone {
+    /// Seconds east of UTC
+    offset: c_long,
+    /// Leaked, as `tm_zone` outlives a change of zone
+    name: &'static CStr,
+}
+
+lazy_static! {
+    static ref TIMEZONE: Mutex<Timezone> = Mutex::new(Timezone { offset: 0, name: leak_name("UTC") });
+    static ref UTC: &'static CStr = leak_name("UTC");
+}
+
+thread_local! {
+    /// What `gmtime` and `localtime` return
+    static STATIC_TM: UnsafeCell<Tm> = UnsafeCell::new(Tm::default());
+}
+
+fn leak_name(name: &str) -> &'static CStr {
+    Box::leak(CString::new(name.replace('\0', "")).unwrap().into_boxed_c_str())
+}
+
+/// Set the zone `localtime` and `mktime` work in, `offset` seconds east of UTC, and the
+/// abbreviation `strftime` prints for it
+pub fn set_timezone(name: &str, offset: c_long) {
+    *TIMEZONE.lock().unwrap() = Timezone { offset, name: leak_name(name) };
+}
+
+fn timezone() -> (c_long, *const c_char) {
+    let timezone = TIMEZONE.lock().unwrap();
+    (timezone.offset, timezone.name.as_ptr())
+}
+
+/// A `time_t` or `long`, only 32 bits on 32-bit targets, as an `i64`
+#[allow(clippy::useless_conversion)]
+fn wide(value: c_long) -> i64 {
+    i64::from(value)
+}
+
+/// Days since the epoch of a proleptic Gregorian date, `month` from 1
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The year, month from 1 and day of the days since the epoch
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    (year_of_era + era * 400 + (month <= 2) as i64, month, day)
+}
+
+/// Break `time` down into `tm` as seen `offset` seconds east of UTC, failing with EOVERFLOW if
+/// the year doesn't fit
+fn break_down(time: i64, offset: c_long, zone: *const c_char, tm: &mut Tm) -> bool {
+    let local = match time.checked_add(wide(offset)) {
+        Some(local) => local,
+        None => {
+            set_errno(EOVERFLOW);
+            return false;
+        }
+    };
+    let (days, seconds) = (local.div_euclid(86_400), local.rem_euclid(86_400));
+    let (year, month, day) = civil_from_days(days);
+    let tm_year = match c_int::try_from(year - 1900) {
+        Ok(tm_year) => tm_year,
+        Err(_) => {
+            set_errno(EOVERFLOW);
+            return false;
+        }
+    };
+    *tm = Tm {
+        tm_sec: (seconds % 60) as c_int,
+        tm_min: (seconds / 60 % 60) as c_int,
+        tm_hour: (seconds / 3600) as c_int,
+        tm_mday: day as c_int,
+        tm_mon: (month - 1) as c_int,
+        tm_year,
+        // The epoch was a Thursday
+        tm_wday: (days + 4).rem_euclid(7) as c_int,
+        tm_yday: (days - days_from_civil(year, 1, 1)) as c_int,
+        tm_isdst: 0,
+        tm_gmtoff: offset,
+        tm_zone: zone,
+    };
+    true
+}
+
+/// The seconds since the epoch of `tm` read as `offset` seconds east of UTC, its fields out of
+/// their ranges carried over as `mktime` does
+fn seconds_of(tm: &Tm, offset: c_long) -> i64 {
+    let months = tm.tm_year as i64 * 12 + tm.tm_mon as i64;
+    let days = days_from_civil(1900 + months.div_euclid(12), months.rem_euclid(12) + 1, 1) + tm.tm_mday as i64 - 1;
+    days * 86_400 + tm.tm_hour as i64 * 3600 + tm.tm_min as i64 * 60 + tm.tm_sec as i64 - wide(offset)
+}
+
+#[sysv64]
+unsafe fn time(result: *mut TimeT) -> TimeT {
+    let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as TimeT,
+        Err(before) => -(before.duration().as_secs() as TimeT),
+    };
+    if let Some(result) = result.as_mut() {
+        *result = now;
+    }
+    now
+}
+
+/// The zone is given with [`set_timezone`] rather than read from `TZ`
+#[sysv64]
+fn tzset() {}
+
+#[sysv64]
+unsafe fn gmtime_r(time: *const TimeT, result: *mut Tm) -> *mut Tm {
+    match break_down(wide(*time), 0, UTC.as_ptr(), &mut *result) {
+        true => result,
+        false => std::ptr::null_mut(),
+    }
+}
+
+#[sysv64]
+unsafe fn localtime_r(time: *const TimeT, result: *mut Tm) -> *mut Tm {
+    let (offset, zone) = timezone();
+    match break_down(wide(*time), offset, zone, &mut *result) {
+        true => result,
+        false => std::ptr::null_mut(),
+    }
+}
+
+/// Per thread, where bionic shares one between threads
+#[sysv64]
+unsafe fn gmtime(time: *const TimeT) -> *mut Tm {
+    gmtime_r(time, STATIC_TM.with(UnsafeCell::get))
+}
+
+#[sysv64]
+unsafe fn localtime(time: *const TimeT) -> *mut Tm {
+    localtime_r(time, STATIC_TM.with(UnsafeCell::get))
+}
+
+/// Normalizes `tm`, in the zone `offset` seconds east of UTC
+unsafe fn make_time(tm: *mut Tm, offset: c_long, zone: *const c_char) -> TimeT {
+    let seconds = seconds_of(&*tm, offset);
+    match TimeT::try_from(seconds) {
+        Ok(time) if break_down(seconds, offset, zone, &mut *tm) => time,
+        Ok(_) => -1,
+        Err(_) => {
+            set_errno(EOVERFLOW);
+            -1
+        }
+    }
+}
+
+/// `tm_isdst` is ignored, as the zone has no daylight saving time
+#[sysv64]
+unsafe fn mktime(tm: *mut Tm) -> TimeT {
+    let (offset, zone) = timezone();
+    make_time(tm, offset, zone)
+}
+
+#[sysv64]
+unsafe fn timegm(tm: *mut Tm) -> TimeT {
+    make_time(tm, 0, UTC.as_ptr())
+}
+
+const DAYS: [&str; 7] = ["Sunday", "Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday"];
+const MONTHS: [&str; 12] = ["January", "February", "March", "April", "May", "June", "July", "August", "September", "October", "November", "December"];
+
+/// The ISO 8601 week-based year and week of `tm`
+fn iso_week(tm: &Tm) -> (i64, i64) {
+    let weeks_in = |year: i64| {
+        let starts = |year: i64| (year + year.div_euclid(4) - year.div_euclid(100) + year.div_euclid(400)).rem_euclid(7);
+        if starts(year) == 4 || starts(year - 1) == 3 {
+            53
+        } else {
+            52
+        }
+    };
+    let year = tm.tm_year as i64 + 1900;
+    let weekday = (tm.tm_wday as i64 + 6).rem_euclid(7) + 1;
+    let week = (tm.tm_yday as i64 + 1 - weekday + 10) / 7;
+    if week < 1 {
+        (year - 1, weeks_in(year - 1))
+    } else if week > weeks_in(year) {
+        (year + 1, 1)
+    } else {
+        (year, week)
+    }
+}
+
+/// `format` expanded for `tm`, the C locale's way. Unknown conversions are copied as they are,
+/// the `E` and `O` modifiers are ignored.
+unsafe fn format_time(format: &[u8], tm: &Tm) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut bytes = format.iter().copied();
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            output.push(byte);
+            continue;
+        }
+        let mut conversion = bytes.next();
+        if let Some(b'E' | b'O') = conversion {
+            conversion = bytes.next();
+        }
+        let year = tm.tm_year as i64 + 1900;
+        let hour12 = match tm.tm_hour % 12 {
+            0 => 12,
+            hour => hour,
+        };
+        let day = DAYS.get(tm.tm_wday as usize).copied().unwrap_or("?");
+        let month = MONTHS.get(tm.tm_mon as usize).copied().unwrap_or("?");
+        let am_pm = if tm.tm_hour < 12 { "AM" } else { "PM" };
+        // Writing to a Vec can't fail
+        let _ = match conversion {
+            Some(b'a') => write!(output, "{}", &day[..3.min(day.len())]),
+            Some(b'A') => write!(output, "{day}"),
+            Some(b'b' | b'h') => write!(output, "{}", &month[..3.min(month.len())]),
+            Some(b'B') => write!(output, "{month}"),
+            Some(b'c') => {
+                output.extend(format_time(b"%a %b %e %H:%M:%S %Y", tm));
+                Ok(())
+            }
+            Some(b'C') => write!(output, "{:02}", year.div_euclid(100)),
+            Some(b'd') => write!(output, "{:02}", tm.tm_mday),
+            Some(b'D' | b'x') => {
+                output.extend(format_time(b"%m/%d/%y", tm));
+                Ok(())
+            }
+            Some(b'e') => write!(output, "{:2}", tm.tm_mday),
+            Some(b'F') => {
+                output.extend(format_time(b"%Y-%m-%d", tm));
+                Ok(())
+            }
+            Some(b'g') => write!(output, "{:02}", iso_week(tm).0.rem_euclid(100)),
+            Some(b'G') => write!(output, "{}", iso_week(tm).0),
+            Some(b'H') => write!(output, "{:02}", tm.tm_hour),
+            Some(b'I') => write!(output, "{:02}", hour12),
+            Some(b'j') => write!(output, "{:03}", tm.tm_yday + 1),
+            Some(b'k') => write!(output, "{:2}", tm.tm_hour),
+            Some(b'l') => write!(output, "{:2}", hour12),
+            Some(b'm') => write!(output, "{:02}", tm.tm_mon + 1),
+            Some(b'M') => write!(output, "{:02}", tm.tm_min),
+            Some(b'n') => writeln!(output),
+            Some(b'p') => write!(output, "{am_pm}"),
+            Some(b'P') => write!(output, "{}", am_pm.to_lowercase()),
+            Some(b'r') => {
+                output.extend(format_time(b"%I:%M:%S %p", tm));
+                Ok(())
+            }
+            Some(b'R') => {
+                output.extend(format_time(b"%H:%M", tm));
+                Ok(())
+            }
+            Some(b's') => write!(output, "{}", seconds_of(tm, tm.tm_gmtoff)),
+            Some(b'S') => write!(output, "{:02}", tm.tm_sec),
+            Some(b't') => write!(output, "\t"),
+            Some(b'T' | b'X') => {
+                output.extend(format_time(b"%H:%M:%S", tm));
+                Ok(())
+            }
+            Some(b'u') => write!(output, "{}", (tm.tm_wday + 6) % 7 + 1),
+            Some(b'U') => write!(output, "{:02}", (tm.tm_yday + 7 - tm.tm_wday) / 7),
+            Some(b'V') => write!(output, "{:02}", iso_week(tm).1),
+            Some(b'w') => write!(output, "{}", tm.tm_wday),
+            Some(b'W') => write!(output, "{:02}", (tm.tm_yday + 7 - (tm.tm_wday + 6) % 7) / 7),
+            Some(b'y') => write!(output, "{:02}", year.rem_euclid(100)),
+            Some(b'Y') => write!(output, "{year}"),
+            Some(b'z') => {
+                let offset = tm.tm_gmtoff.unsigned_abs() / 60;
+                let sign = if tm.tm_gmtoff < 0 { '-' } else { '+' };
+                write!(output, "{sign}{:02}{:02}", offset / 60, offset % 60)
+            }
+            Some(b'Z') if !tm.tm_zone.is_null() => {
+                output.extend(CStr::from_ptr(tm.tm_zone).to_bytes());
+                Ok(())
+            }
+            Some(b'Z') => Ok(()),
+            Some(b'%') => write!(output, "%"),
+            Some(other) => write!(output, "%{}", other as char),
+            None => write!(output, "%"),
+        };
+    }
+    output
+}
+
+/// 0 if the result and its terminator don't fit in `size` bytes
+#[sysv64]
+unsafe fn strftime(buffer: *mut c_char, size: usize, format: *const c_char, tm: *const Tm) -> usize {
+    let output = format_time(CStr::from_ptr(format).to_bytes(), &*tm);
+    if output.len() >= size {
+        return 0;
+    }
+    std::ptr::copy_nonoverlapping(output.as_ptr(), buffer as *mut u8, output.len());
+    *buffer.add(output.len()) = 0;
+    output.len()
+}
+
+#[repr(C)]
+pub(crate) struct Timespec {
+    tv_sec: c_long,
+    tv_nsec: c_long,
+}
+
+#[sysv64]
+unsafe fn nanosleep(request: *const Timespec, remaining: *mut Timespec) -> c_int {
+    let request = &*request;
+    if request.tv_sec < 0 || !(0..1_000_000_000).contains(&request.tv_nsec) {
+        set_errno(EINVAL);
+        return -1;
+    }
+    std::thread::sleep(Duration::new(request.tv_sec as u64, request.tv_nsec as u32));
+    if let Some(remaining) = remaining.as_mut() {
+        *remaining = Timespec { tv_sec: 0, tv_nsec: 0 };
+    }
+    0
+}
+
+#[sysv64]
+fn usleep(microseconds: c_uint) -> c_int {
+    std::thread::sleep(Duration::from_micros(microseconds as u64));
+    0
+}
+
+/// Returns the seconds left to sleep, always 0
+#[sysv64]
+fn sleep(seconds: c_uint) -> c_uint {
+    std::thread::sleep(Duration::from_secs(seconds as u64));
+    0
+}
+
+pub(crate) fn lookup(symbol_name: &str) -> Option<*const ()> {
+    match symbol_name {
+        "nanosleep" => Some(nanosleep as *const ()),
+        "usleep" => Some(usleep as *const ()),
+        "sleep" => Some(sleep as *const ()),
+        "time" => Some(time as *const ()),
+        "tzset" => Some(tzset as *const ()),
+        "gmtime" => Some(gmtime as *const ()),
+        "gmtime_r" => Some(gmtime_r as *const ()),
+        "localtime" => Some(localtime as *const ()),
+        "localtime_r" => Some(localtime_r as *const ()),
+        "mktime" => Some(mktime as *const ()),
+        "timegm" => Some(timegm as *const ()),
+        "strftime" => Some(strftime as *const ()),
+        _ => None,
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::ffi::CStr;
+    use std::os::raw::{c_char, c_int, c_long, c_uint};
+    use std::time::{Duration, Instant};
+
+    use crate::android_library::AndroidLibrary;
+    use crate::stubs::errno::{errno, EINVAL};
+    use crate::stubs::time::{set_timezone, Timespec, Tm};
+    use crate::test_elf::TestElf;
+
+    #[test]
+    fn loaded_sleep() {
+        let mut elf = TestElf::new();
+        elf.thunk("call_nanosleep", "nanosleep");
+        elf.thunk("call_usleep", "usleep");
+        elf.thunk("call_sleep", "sleep");
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let nanosleep: extern "C" fn(*const Timespec, *mut Timespec) -> c_int =
+            unsafe { std::mem::transmute(library.get_symbol("call_nanosleep").unwrap()) };
+        let usleep: extern "C" fn(c_uint) -> c_int = unsafe { std::mem::transmute(library.get_symbol("call_usleep").unwrap()) };
+        let sleep: extern "C" fn(c_uint) -> c_uint = unsafe { std::mem::transmute(library.get_symbol("call_sleep").unwrap()) };
+
+        let started = Instant::now();
+        let mut remaining = Timespec { tv_sec: 5, tv_nsec: 5 };
+        assert_eq!(nanosleep(&Timespec { tv_sec: 0, tv_nsec: 20_000_000 }, &mut remaining), 0);
+        assert_eq!((remaining.tv_sec, remaining.tv_nsec), (0, 0));
+        assert!(started.elapsed() >= Duration::from_millis(20));
+
+        let started = Instant::now();
+        assert_eq!(usleep(20_000), 0);
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        assert_eq!(sleep(0), 0);
+
+        assert_eq!(nanosleep(&Timespec { tv_sec: 0, tv_nsec: 1_000_000_000 }, std::ptr::null_mut()), -1);
+        assert_eq!(errno(), EINVAL);
+    }
+
+    #[test]
+    fn loaded_time_formatting() {
+        let names = ["gmtime_r", "localtime", "mktime", "strftime"];
+        let mut elf = TestElf::new();
+        for name in names {
+            elf.thunk(&format!("call_{name}"), name);
+        }
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let function = |name: &str| library.get_symbol(&format!("call_{name}")).unwrap();
+        let gmtime_r: extern "C" fn(*const c_long, *mut Tm) -> *mut Tm = unsafe { std::mem::transmute(function("gmtime_r")) };
+        let localtime: extern "C" fn(*const c_long) -> *mut Tm = unsafe { std::mem::transmute(function("localtime")) };
+        let mktime: extern "C" fn(*mut Tm) -> c_long = unsafe { std::mem::transmute(function("mktime")) };
+        let strftime: extern "C" fn(*mut c_char, usize, *const c_char, *const Tm) -> usize =
+            unsafe { std::mem::transmute(function("strftime")) };
+        let format = |format: &[u8], tm: &Tm| {
+            let mut buffer = [0 as c_char; 128];
+            let written = strftime(buffer.as_mut_ptr(), buffer.len(), format.as_ptr() as *const c_char, tm);
+            let formatted = unsafe { CStr::from_ptr(buffer.as_ptr()) }.to_str().unwrap().to_owned();
+            assert_eq!(written, formatted.len());
+            formatted
+        };
+
+        let epoch: c_long = 1_000_000_000;
+        let mut tm = Tm::default();
+        assert_eq!(gmtime_r(&epoch, &mut tm), &mut tm as *mut Tm);
+        assert_eq!((tm.tm_year, tm.tm_mon, tm.tm_mday, tm.tm_wday, tm.tm_yday), (101, 8, 9, 0, 251));
+        assert_eq!(format(b"%Y-%m-%d %H:%M:%S %Z %z\0", &tm), "2001-09-09 01:46:40 UTC +0000");
+        assert_eq!(format(b"%c|%j|%I %p|%G-W%V-%u|%s|%%|%q\0", &tm), "Sun Sep  9 01:46:40 2001|252|01 AM|2001-W36-7|1000000000|%|%q");
+        let mut small = [0 as c_char; 8];
+        assert_eq!(strftime(small.as_mut_ptr(), small.len(), b"%F\0".as_ptr() as *const c_char, &tm), 0);
+
+        set_timezone("CEST", 2 * 3600);
+        let local = unsafe { *localtime(&epoch) };
+        assert_eq!(format(b"%A %d %B %T %Z %z\0", &local), "Sunday 09 September 03:46:40 CEST +0200");
+        // Out of range fields carry over
+        let mut overflowing = Tm { tm_year: 101, tm_mon: 7, tm_mday: 40, tm_hour: 3, tm_min: 46, tm_sec: 40, ..Tm::default() };
+        assert_eq!(mktime(&mut overflowing), epoch);
+        assert_eq!((overflowing.tm_mon, overflowing.tm_mday, overflowing.tm_wday), (8, 9, 0));
+        set_timezone("UTC", 0);
+    }
+}
@@ -0,0 +1,333 @@
+//! Reading C variadic arguments.
+//!
+//! Stable Rust can't define variadic functions, so variadic stubs are small assembly entry
+//! points (see [`variadic_entry`]) which spill the argument registers, build a `va_list` and
+//! call the matching `v*` implementation. [`VaList`] then walks that `va_list` per the target's
+//! calling convention.
+
+use std::os::raw::c_void;
+
+/// Source of the arguments consumed by a format string
+pub(crate) trait FormatArgs {
+    /// Next integer argument of a C type `size` bytes wide (4 or 8)
+    fn next_int(&mut self, size: usize) -> u64;
+    fn next_double(&mut self) -> f64;
+}
+
+/// A `va_list` as received by a `v*` function
+pub(crate) struct VaList {
+    raw: *mut c_void,
+}
+
+impl VaList {
+    /// `raw` is the value the `va_list` parameter was passed as: a pointer to the `va_list`
+    /// structure on x86_64 and aarch64, the argument pointer itself on x86 and arm
+    pub unsafe fn new(raw: *mut c_void) -> VaList {
+        VaList { raw }
+    }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn truncate(value: u64, size: usize) -> u64 {
+    if size >= 8 { value } else { value & ((1 << (size * 8)) - 1) }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+struct RawVaList {
+    gp_offset: u32,
+    fp_offset: u32,
+    overflow_arg_area: *mut u8,
+    reg_save_area: *mut u8,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl FormatArgs for VaList {
+    fn next_int(&mut self, size: usize) -> u64 {
+        unsafe {
+            let list = &mut *(self.raw as *mut RawVaList);
+            let value = if list.gp_offset < 48 {
+                let value = (list.reg_save_area.add(list.gp_offset as usize) as *const u64).read();
+                list.gp_offset += 8;
+                value
+            } else {
+                let value = (list.overflow_arg_area as *const u64).read_unaligned();
+                list.overflow_arg_area = list.overflow_arg_area.add(8);
+                value
+            };
+            truncate(value, size)
+        }
+    }
+
+    fn next_double(&mut self) -> f64 {
+        unsafe {
+            let list = &mut *(self.raw as *mut RawVaList);
+            if list.fp_offset < 176 {
+                let value = (list.reg_save_area.add(list.fp_offset as usize) as *const f64).read();
+                list.fp_offset += 16;
+                value
+            } else {
+                let value = (list.overflow_arg_area as *const f64).read_unaligned();
+                list.overflow_arg_area = list.overflow_arg_area.add(8);
+                value
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+#[repr(C)]
+struct RawVaList {
+    stack: *mut u8,
+    gr_top: *mut u8,
+    vr_top: *mut u8,
+    gr_offs: i32,
+    vr_offs: i32,
+}
+
+#[cfg(target_arch = "aarch64")]
+impl FormatArgs for VaList {
+    fn next_int(&mut self, size: usize) -> u64 {
+        unsafe {
+            let list = &mut *(self.raw as *mut RawVaList);
+            let value = if list.gr_offs < 0 {
+                let value = (list.gr_top.offset(list.gr_offs as isize) as *const u64).read();
+                list.gr_offs += 8;
+                value
+            } else {
+                let value = (list.stack as *const u64).read();
+                list.stack = list.stack.add(8);
+                value
+            };
+            truncate(value, size)
+        }
+    }
+
+    fn next_double(&mut self) -> f64 {
+        unsafe {
+            let list = &mut *(self.raw as *mut RawVaList);
+            if list.vr_offs < 0 {
+                let value = (list.vr_top.offset(list.vr_offs as isize) as *const f64).read();
+                list.vr_offs += 16;
+                value
+            } else {
+                let value = (list.stack as *const f64).read();
+                list.stack = list.stack.add(8);
+                value
+            }
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "arm"))]
+impl VaList {
+    unsafe fn take<T>(&mut self, align: usize) -> T {
+        let mut pointer = self.raw as usize;
+        pointer = (pointer + align - 1) / align * align;
+        let value = (pointer as *const T).read_unaligned();
+        self.raw = (pointer + std::mem::size_of::<T>()) as *mut c_void;
+        value
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "arm"))]
+impl FormatArgs for VaList {
+    fn next_int(&mut self, size: usize) -> u64 {
+        // 64-bit integers are 8-byte aligned on the stack on arm, but only 4-byte aligned on x86
+        let align = if cfg!(target_arch = "arm") { size } else { 4 };
+        unsafe {
+            if size == 8 {
+                self.take::<u64>(align)
+            } else {
+                self.take::<u32>(4) as u64
+            }
+        }
+    }
+
+    fn next_double(&mut self) -> f64 {
+        let align = if cfg!(target_arch = "arm") { 8 } else { 4 };
+        unsafe { self.take::<f64>(align) }
+    }
+}
+
+/// Name of a symbol as seen by the assembler
+#[cfg(any(target_vendor = "apple", all(target_family = "windows", target_arch = "x86")))]
+macro_rules! asm_symbol {
+    ($name:literal) => { concat!("_", $name) };
+}
+
+#[cfg(not(any(target_vendor = "apple", all(target_family = "windows", target_arch = "x86"))))]
+macro_rules! asm_symbol {
+    ($name:literal) => { $name };
+}
+
+/// Defines an assembly entry point `$name` for a variadic function with `$named` fixed
+/// (integer or pointer) arguments, which forwards them followed by a `va_list` to `$target`.
+/// With `caller`, the entry's return address follows the `va_list`, as with
+/// [`caller_entry`](crate::caller::caller_entry).
+macro_rules! variadic_entry {
+    ($name:literal, $named:tt, $target:literal) => {
+        variadic_entry!(@entry $name, $named, $target, "", "", "", "");
+    };
+    (caller $name:literal, $named:tt, $target:literal) => {
+        variadic_entry!(
+            @entry $name, $named, $target,
+            concat!("mov ", variadic_entry!(@x86_64_register_after $named), ", [rsp + 216]"),
+            concat!("mov x", variadic_entry!(@next $named), ", x30"),
+            concat!("mov r", variadic_entry!(@next $named), ", lr"),
+            concat!("mov eax, [ebp + 4]\nmov [esp + 4 * ", variadic_entry!(@next $named), "], eax")
+        );
+    };
+    (@entry $name:literal, $named:tt, $target:literal, $x86_64:expr, $aarch64:expr, $arm:expr, $x86:expr) => {
+        #[cfg(target_arch = "x86_64")]
+        std::arch::global_asm!(
+            ".text",
+            concat!(".globl ", asm_symbol!($name)),
+            concat!(asm_symbol!($name), ":"),
+            "sub rsp, 216",
+            "mov [rsp], rdi",
+            "mov [rsp + 8], rsi",
+            "mov [rsp + 16], rdx",
+            "mov [rsp + 24], rcx",
+            "mov [rsp + 32], r8",
+            "mov [rsp + 40], r9",
+            "movaps [rsp + 48], xmm0",
+            "movaps [rsp + 64], xmm1",
+            "movaps [rsp + 80], xmm2",
+            "movaps [rsp + 96], xmm3",
+            "movaps [rsp + 112], xmm4",
+            "movaps [rsp + 128], xmm5",
+            "movaps [rsp + 144], xmm6",
+            "movaps [rsp + 160], xmm7",
+            concat!("mov dword ptr [rsp + 176], ", $named, " * 8"),
+            "mov dword ptr [rsp + 180], 48",
+            "lea rax, [rsp + 224]",
+            "mov [rsp + 184], rax",
+            "mov [rsp + 192], rsp",
+            // The va_list goes in the register following the named arguments
+            concat!("lea ", variadic_entry!(@x86_64_register $named), ", [rsp + 176]"),
+            $x86_64,
+            concat!("call ", asm_symbol!($target)),
+            "add rsp, 216",
+            "ret",
+        );
+
+        #[cfg(target_arch = "aarch64")]
+        std::arch::global_asm!(
+            ".text",
+            concat!(".globl ", asm_symbol!($name)),
+            concat!(asm_symbol!($name), ":"),
+            "stp x29, x30, [sp, #-16]!",
+            "mov x29, sp",
+            "sub sp, sp, #224",
+            "stp x0, x1, [sp, #0]",
+            "stp x2, x3, [sp, #16]",
+            "stp x4, x5, [sp, #32]",
+            "stp x6, x7, [sp, #48]",
+            "stp q0, q1, [sp, #64]",
+            "stp q2, q3, [sp, #96]",
+            "stp q4, q5, [sp, #128]",
+            "stp q6, q7, [sp, #160]",
+            "add x9, x29, #16",
+            "str x9, [sp, #192]",
+            "add x9, sp, #64",
+            "str x9, [sp, #200]",
+            "add x9, sp, #192",
+            "str x9, [sp, #208]",
+            concat!("mov w9, #-((8 - ", $named, ") * 8)"),
+            "str w9, [sp, #216]",
+            "mov w9, #-128",
+            "str w9, [sp, #220]",
+            concat!("add x", $named, ", sp, #192"),
+            $aarch64,
+            concat!("bl ", asm_symbol!($target)),
+            "mov sp, x29",
+            "ldp x29, x30, [sp], #16",
+            "ret",
+        );
+
+        // Spilling r0-r3 right below the stack arguments makes all arguments contiguous
+        #[cfg(target_arch = "arm")]
+        std::arch::global_asm!(
+            ".text",
+            concat!(".globl ", asm_symbol!($name)),
+            concat!(asm_symbol!($name), ":"),
+            "push {{r0-r3}}",
+            "push {{r4, lr}}",
+            concat!("add r", $named, ", sp, #(8 + 4 * ", $named, ")"),
+            $arm,
+            concat!("bl ", asm_symbol!($target)),
+            "pop {{r4, lr}}",
+            "add sp, sp, #16",
+            "bx lr",
+        );
+
+        #[cfg(target_arch = "x86")]
+        std::arch::global_asm!(
+            ".text",
+            concat!(".globl ", asm_symbol!($name)),
+            concat!(asm_symbol!($name), ":"),
+            "push ebp",
+            "mov ebp, esp",
+            "and esp, -16",
+            "sub esp, 16",
+            "mov eax, [ebp + 8]",
+            "mov [esp], eax",
+            "mov eax, [ebp + 12]",
+            "mov [esp + 4], eax",
+            "mov eax, [ebp + 16]",
+            "mov [esp + 8], eax",
+            concat!("lea eax, [ebp + 8 + 4 * ", $named, "]"),
+            concat!("mov [esp + 4 * ", $named, "], eax"),
+            $x86,
+            concat!("call ", asm_symbol!($target)),
+            "mov esp, ebp",
+            "pop ebp",
+            "ret",
+        );
+    };
+    (@x86_64_register 1) => { "rsi" };
+    (@x86_64_register 2) => { "rdx" };
+    (@x86_64_register 3) => { "rcx" };
+    (@x86_64_register 4) => { "r8" };
+    (@x86_64_register 5) => { "r9" };
+    (@x86_64_register_after 1) => { "rdx" };
+    (@x86_64_register_after 2) => { "rcx" };
+    (@x86_64_register_after 3) => { "r8" };
+    (@x86_64_register_after 4) => { "r9" };
+    // The x86 entry has room for 4 arguments, and arm passes at most 4 in registers
+    (@next 1) => { "2" };
+    (@next 2) => { "3" };
+}
+
+pub(crate) use {asm_symbol, variadic_entry};
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::stubs::varargs::FormatArgs;
+
+    pub(crate) enum Arg {
+        Int(u64),
+        Double(f64),
+    }
+
+    /// Arguments given up front, for testing formatters without going through assembly
+    pub(crate) struct TestArgs(pub Vec<Arg>);
+
+    impl FormatArgs for TestArgs {
+        fn next_int(&mut self, size: usize) -> u64 {
+            match self.0.remove(0) {
+                Arg::Int(value) if size < 8 => value & ((1 << (size * 8)) - 1),
+                Arg::Int(value) => value,
+                Arg::Double(_) => panic!("expected an integer argument"),
+            }
+        }
+
+        fn next_double(&mut self) -> f64 {
+            match self.0.remove(0) {
+                Arg::Double(value) => value,
+                Arg::Int(_) => panic!("expected a double argument"),
+            }
+        }
+    }
+}
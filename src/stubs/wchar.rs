@@ -0,0 +1,310 @@
+//! `<wchar.h>` functions on null-terminated wide strings, and the conversions between them and
+//! multibyte strings.
+//!
+//! `wchar_t` is 32 bits on every Android architecture, 32-bit ARM included, holding UTF-32.
+//! Multibyte strings are always UTF-8, as bionic's only locales are C.UTF-8 and "C", which
+//! bionic also treats as UTF-8. Invalid sequences fail with `EILSEQ`.
+
+use std::os::raw::{c_char, c_int};
+use std::ptr::null_mut;
+
+use crate::stubs::errno::{set_errno, EILSEQ};
+use crate::sysv64;
+
+/// bionic's `wchar_t`
+type WChar = u32;
+
+/// The longest UTF-8 sequence
+const MB_CUR_MAX: usize = 4;
+const FAILED: usize = usize::MAX;
+
+unsafe fn length(s: *const WChar) -> usize {
+    let mut length = 0;
+    while *s.add(length) != 0 {
+        length += 1;
+    }
+    length
+}
+
+/// Compares at most `n` characters, stopping after the first terminator
+unsafe fn compare(a: *const WChar, b: *const WChar, n: usize) -> c_int {
+    for i in 0..n {
+        let (a, b) = (*a.add(i), *b.add(i));
+        if a != b {
+            return if a < b { -1 } else { 1 };
+        }
+        if a == 0 {
+            break;
+        }
+    }
+    0
+}
+
+/// The character starting `s` and its length in bytes, reading at most `n` bytes. `None` for
+/// invalid and incomplete sequences.
+unsafe fn decode(s: *const c_char, n: usize) -> Option<(WChar, usize)> {
+    let lead = *s.cast::<u8>();
+    let len = match lead {
+        0x00..=0x7f => 1,
+        0xc2..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf4 => 4,
+        _ => return None,
+    };
+    if len > n {
+        return None;
+    }
+    let mut bytes = [lead, 0, 0, 0];
+    for (i, byte) in bytes.iter_mut().enumerate().take(len).skip(1) {
+        *byte = *s.cast::<u8>().add(i);
+        // Also stops at a terminator
+        if *byte & 0xc0 != 0x80 {
+            return None;
+        }
+    }
+    let c = std::str::from_utf8(&bytes[..len]).ok()?.chars().next()?;
+    Some((c as WChar, len))
+}
+
+/// The UTF-8 encoding of `c`, `None` for surrogates and values beyond Unicode
+fn encode(c: WChar) -> Option<([u8; MB_CUR_MAX], usize)> {
+    let c = char::from_u32(c)?;
+    let mut bytes = [0; MB_CUR_MAX];
+    let len = c.encode_utf8(&mut bytes).len();
+    Some((bytes, len))
+}
+
+#[sysv64]
+unsafe fn wcslen(s: *const WChar) -> usize {
+    length(s)
+}
+
+#[sysv64]
+unsafe fn wcscmp(a: *const WChar, b: *const WChar) -> c_int {
+    compare(a, b, usize::MAX)
+}
+
+#[sysv64]
+unsafe fn wcsncmp(a: *const WChar, b: *const WChar, n: usize) -> c_int {
+    compare(a, b, n)
+}
+
+#[sysv64]
+unsafe fn wcscpy(destination: *mut WChar, source: *const WChar) -> *mut WChar {
+    source.copy_to_nonoverlapping(destination, length(source) + 1);
+    destination
+}
+
+#[sysv64]
+unsafe fn wcscat(destination: *mut WChar, source: *const WChar) -> *mut WChar {
+    wcscpy(destination.add(length(destination)), source);
+    destination
+}
+
+#[sysv64]
+unsafe fn wcschr(s: *const WChar, c: WChar) -> *mut WChar {
+    let mut at = s;
+    loop {
+        if *at == c {
+            return at as *mut WChar;
+        }
+        if *at == 0 {
+            return null_mut();
+        }
+        at = at.add(1);
+    }
+}
+
+/// Converts up to `n` characters, terminated if the terminator is among them, and returns
+/// how many were converted without it. A null `destination` only counts them.
+#[sysv64]
+unsafe fn mbstowcs(destination: *mut WChar, source: *const c_char, n: usize) -> usize {
+    let limit = if destination.is_null() { usize::MAX } else { n };
+    let (mut at, mut count) = (source, 0);
+    while count < limit {
+        let (c, len) = match decode(at, MB_CUR_MAX) {
+            Some(decoded) => decoded,
+            None => {
+                set_errno(EILSEQ);
+                return FAILED;
+            }
+        };
+        if !destination.is_null() {
+            *destination.add(count) = c;
+        }
+        if c == 0 {
+            break;
+        }
+        at = at.add(len);
+        count += 1;
+    }
+    count
+}
+
+/// Writes up to `n` bytes, never part of a character, terminated if there's room, and returns
+/// how many were written without the terminator. A null `destination` only counts them.
+#[sysv64]
+unsafe fn wcstombs(destination: *mut c_char, source: *const WChar, n: usize) -> usize {
+    let limit = if destination.is_null() { usize::MAX } else { n };
+    let (mut at, mut written) = (source, 0);
+    loop {
+        if *at == 0 {
+            if written < limit && !destination.is_null() {
+                *destination.add(written) = 0;
+            }
+            return written;
+        }
+        let (bytes, len) = match encode(*at) {
+            Some(encoded) => encoded,
+            None => {
+                set_errno(EILSEQ);
+                return FAILED;
+            }
+        };
+        if written + len > limit {
+            return written;
+        }
+        if !destination.is_null() {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), destination.add(written) as *mut u8, len);
+        }
+        written += len;
+        at = at.add(1);
+    }
+}
+
+/// UTF-8 is stateless, so a null `s` gives 0
+#[sysv64]
+unsafe fn mbtowc(destination: *mut WChar, s: *const c_char, n: usize) -> c_int {
+    if s.is_null() {
+        return 0;
+    }
+    if n == 0 {
+        return -1;
+    }
+    match decode(s, n) {
+        Some((c, len)) => {
+            if !destination.is_null() {
+                *destination = c;
+            }
+            if c == 0 { 0 } else { len as c_int }
+        }
+        None => {
+            set_errno(EILSEQ);
+            -1
+        }
+    }
+}
+
+#[sysv64]
+unsafe fn wctomb(s: *mut c_char, c: WChar) -> c_int {
+    if s.is_null() {
+        return 0;
+    }
+    match encode(c) {
+        Some((bytes, len)) => {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), s as *mut u8, len);
+            len as c_int
+        }
+        None => {
+            set_errno(EILSEQ);
+            -1
+        }
+    }
+}
+
+/// What `MB_CUR_MAX` expands to
+#[sysv64]
+fn __ctype_get_mb_cur_max() -> usize {
+    MB_CUR_MAX
+}
+
+pub(crate) fn lookup(symbol_name: &str) -> Option<*const ()> {
+    Some(match symbol_name {
+        "wcslen" => wcslen as *const (),
+        "wcscmp" => wcscmp as *const (),
+        "wcsncmp" => wcsncmp as *const (),
+        "wcscpy" => wcscpy as *const (),
+        "wcscat" => wcscat as *const (),
+        "wcschr" => wcschr as *const (),
+        "mbstowcs" => mbstowcs as *const (),
+        "wcstombs" => wcstombs as *const (),
+        "mbtowc" => mbtowc as *const (),
+        "wctomb" => wctomb as *const (),
+        "__ctype_get_mb_cur_max" => __ctype_get_mb_cur_max as *const (),
+        _ => return None,
+    })
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::ffi::CStr;
+    use std::os::raw::{c_char, c_int};
+
+    use crate::android_library::AndroidLibrary;
+    use crate::stubs::errno::{errno, EILSEQ};
+    use crate::test_elf::TestElf;
+
+    fn c(s: &[u8]) -> *const c_char {
+        s.as_ptr() as *const c_char
+    }
+
+    #[test]
+    fn loaded_wide_strings() {
+        let names = ["wcslen", "wcscmp", "wcscpy", "mbstowcs", "wcstombs", "mbtowc", "wctomb"];
+        let mut elf = TestElf::new();
+        for name in names {
+            elf.thunk(&format!("call_{name}"), name);
+        }
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let function = |name: &str| library.get_symbol(&format!("call_{name}")).unwrap();
+
+        unsafe {
+            let wcslen: extern "C" fn(*const u32) -> usize = std::mem::transmute(function("wcslen"));
+            let wcscmp: extern "C" fn(*const u32, *const u32) -> c_int = std::mem::transmute(function("wcscmp"));
+            let wcscpy: extern "C" fn(*mut u32, *const u32) -> *mut u32 = std::mem::transmute(function("wcscpy"));
+            let mbstowcs: extern "C" fn(*mut u32, *const c_char, usize) -> usize = std::mem::transmute(function("mbstowcs"));
+            let wcstombs: extern "C" fn(*mut c_char, *const u32, usize) -> usize = std::mem::transmute(function("wcstombs"));
+            let mbtowc: extern "C" fn(*mut u32, *const c_char, usize) -> c_int = std::mem::transmute(function("mbtowc"));
+            let wctomb: extern "C" fn(*mut c_char, u32) -> c_int = std::mem::transmute(function("wctomb"));
+
+            let text = "h\u{e9}llo \u{4e16}\u{1f600}\0";
+            assert_eq!(mbstowcs(std::ptr::null_mut(), c(text.as_bytes()), 0), 8);
+            let mut wide = [0x7f7f_7f7f_u32; 12];
+            assert_eq!(mbstowcs(wide.as_mut_ptr(), c(text.as_bytes()), wide.len()), 8);
+            let expected: Vec<u32> = text.chars().map(|c| c as u32).collect();
+            assert_eq!(&wide[..9], &expected[..]);
+            assert_eq!(wcslen(wide.as_ptr()), 8);
+
+            let mut copy = [0u32; 12];
+            wcscpy(copy.as_mut_ptr(), wide.as_ptr());
+            assert_eq!(wcscmp(copy.as_ptr(), wide.as_ptr()), 0);
+            copy[2] = 0x10ffff;
+            assert!(wcscmp(copy.as_ptr(), wide.as_ptr()) > 0);
+
+            // Back again, and without splitting a character when out of room
+            assert_eq!(wcstombs(std::ptr::null_mut(), wide.as_ptr(), 0), text.len() - 1);
+            let mut narrow = [0x7f as c_char; 32];
+            assert_eq!(wcstombs(narrow.as_mut_ptr(), wide.as_ptr(), narrow.len()), text.len() - 1);
+            assert_eq!(CStr::from_ptr(narrow.as_ptr()).to_str().unwrap(), text.trim_end_matches('\0'));
+            let mut short = [0x7f as c_char; 8];
+            assert_eq!(wcstombs(short.as_mut_ptr(), wide.as_ptr(), 8), 7);
+            assert_eq!(short[7], 0x7f);
+
+            let mut c32 = 0;
+            assert_eq!(mbtowc(&mut c32, c("\u{4e16}".as_bytes()), 3), 3);
+            assert_eq!(c32, 0x4e16);
+            assert_eq!(mbtowc(&mut c32, c("\u{4e16}".as_bytes()), 2), -1);
+            assert_eq!(mbtowc(std::ptr::null_mut(), std::ptr::null(), 0), 0);
+            let mut bytes = [0 as c_char; 4];
+            assert_eq!(wctomb(bytes.as_mut_ptr(), 0x1f600), 4);
+            assert_eq!(bytes.map(|byte| byte as u8), [0xf0, 0x9f, 0x98, 0x80]);
+
+            // Invalid in both directions
+            assert_eq!(mbstowcs(wide.as_mut_ptr(), c(b"a\xffb\0"), wide.len()), usize::MAX);
+            assert_eq!(errno(), EILSEQ);
+            let surrogate = [0x61, 0xd800, 0];
+            assert_eq!(wcstombs(narrow.as_mut_ptr(), surrogate.as_ptr(), narrow.len()), usize::MAX);
+            assert_eq!(wctomb(bytes.as_mut_ptr(), 0x110000), -1);
+        }
+    }
+}
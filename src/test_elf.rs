@@ -0,0 +1,753 @@
+//! Builds minimal x86_64 shared objects in memory so the loader can be tested
+//! without shipping binary fixtures.
+//!
+//! The produced image has a single RWX `PT_LOAD` covering everything, with
+//! file offsets equal to virtual addresses.
+
+#![allow(dead_code)]
+
+pub(crate) const R_X86_64_NONE: u32 = 0;
+pub(crate) const R_X86_64_64: u32 = 1;
+pub(crate) const R_X86_64_PC32: u32 = 2;
+pub(crate) const R_X86_64_GLOB_DAT: u32 = 6;
+pub(crate) const R_X86_64_JUMP_SLOT: u32 = 7;
+pub(crate) const R_X86_64_RELATIVE: u32 = 8;
+pub(crate) const R_X86_64_32: u32 = 10;
+pub(crate) const R_X86_64_32S: u32 = 11;
+pub(crate) const R_X86_64_TPOFF64: u32 = 18;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+/// Virtual address of the segment added by [`TestElf::bss`]
+pub(crate) const BSS_ADDRESS: u64 = 0x10_0000;
+const SHT_DYNSYM: u32 = 11;
+
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_TLS: u8 = 6;
+const STB_GLOBAL: u8 = 1;
+const STB_WEAK: u8 = 2;
+
+#[derive(PartialEq)]
+enum SymbolKind {
+    Function,
+    Object,
+    Tls,
+    Import,
+}
+
+struct Symbol {
+    name: String,
+    kind: SymbolKind,
+    offset: u64,
+    size: u64,
+    /// Version defined (or required, for imports) and whether it's hidden
+    version: Option<(String, bool)>,
+}
+
+struct Relocation {
+    offset: u64,
+    rtype: u32,
+    symbol: Option<String>,
+    addend: i64,
+}
+
+enum DynamicValue {
+    /// A string in `.dynstr`, e.g. for `DT_NEEDED`
+    String(String),
+    /// The address of an offset in `.text`
+    Text(u64),
+    /// The address of an offset in `.data`
+    Data(u64),
+    Value(u64),
+}
+
+#[derive(Default)]
+pub(crate) struct TestElf {
+    text: Vec<u8>,
+    data: Vec<u8>,
+    symbols: Vec<Symbol>,
+    relocations: Vec<Relocation>,
+    /// (offset of a rip-relative disp32 in `.text`, offset of the next instruction, offset of
+    /// the GOT slot it refers to in `.data`)
+    got_references: Vec<(u64, u64, u64)>,
+    /// Flags of a `PT_GNU_STACK` header, if any
+    gnu_stack: Option<u32>,
+    /// `.dynamic` entries other than the terminator
+    dynamic: Vec<(u64, DynamicValue)>,
+    /// `PT_INTERP` path and entry point offset in `.text`, for a PIE
+    executable: Option<(String, u64)>,
+    /// `e_type` other than `ET_DYN`
+    object_type: Option<u16>,
+    /// `e_machine` other than `EM_X86_64`
+    machine: Option<u16>,
+    /// Offset in `.data` and size of a `PT_ARM_EXIDX` header, if any
+    arm_exidx: Option<(u64, u64)>,
+    /// Offset in `.data` of the `DT_RELR` table and the offsets in `.data` it relocates
+    relr: Option<(u64, Vec<u64>)>,
+    /// Size of a read-write `PT_LOAD` with no file data after the other, if any
+    bss: Option<u64>,
+    /// Offset in `.data` and size of a `PT_GNU_RELRO` header, if any
+    relro: Option<(u64, u64)>,
+    /// Offset in `.data`, file size and memory size of a `PT_TLS` header, if any
+    tls: Option<(u64, u64, u64)>,
+    /// Descriptor of a `.note.gnu.build-id` section, if any
+    build_id: Option<Vec<u8>>,
+    /// Imports bound `STB_WEAK`
+    weak_imports: Vec<String>,
+    /// Whether defined symbols have `SHN_XINDEX` and their section in a `SHT_SYMTAB_SHNDX`
+    extended_indices: bool,
+}
+
+impl TestElf {
+    pub fn new() -> TestElf {
+        TestElf::default()
+    }
+
+    /// Adds an exported function and returns its offset in `.text`.
+    pub fn function(&mut self, name: &str, code: &[u8]) -> u64 {
+        align(&mut self.text, 16);
+        let offset = self.text.len() as u64;
+        self.text.extend_from_slice(code);
+        self.symbols.push(Symbol { name: name.to_owned(), kind: SymbolKind::Function, offset, size: code.len() as u64, version: None });
+        offset
+    }
+
+    /// Adds an exported data object and returns its offset in `.data`.
+    pub fn object(&mut self, name: &str, bytes: &[u8]) -> u64 {
+        align(&mut self.data, 8);
+        let offset = self.data.len() as u64;
+        self.data.extend_from_slice(bytes);
+        self.symbols.push(Symbol { name: name.to_owned(), kind: SymbolKind::Object, offset, size: bytes.len() as u64, version: None });
+        offset
+    }
+
+    /// Exports `name` as a thread-local variable of `size` bytes at `offset` in the `PT_TLS`
+    /// block.
+    pub fn tls_variable(&mut self, name: &str, offset: u64, size: u64) {
+        self.symbols.push(Symbol { name: name.to_owned(), kind: SymbolKind::Tls, offset, size, version: None });
+    }
+
+    /// Declares an undefined symbol.
+    pub fn import(&mut self, name: &str) {
+        if !self.symbols.iter().any(|sym| sym.name == name) {
+            self.symbols.push(Symbol { name: name.to_owned(), kind: SymbolKind::Import, offset: 0, size: 0, version: None });
+        }
+    }
+
+    /// Declares an undefined symbol, bound weakly.
+    pub fn weak_import(&mut self, name: &str) {
+        self.import(name);
+        self.weak_imports.push(name.to_owned());
+    }
+
+    /// Adds an exported function defining `version` of `name`, the default one unless `hidden`.
+    pub fn versioned_function(&mut self, name: &str, code: &[u8], version: &str, hidden: bool) -> u64 {
+        let offset = self.function(name, code);
+        self.symbols.last_mut().unwrap().version = Some((version.to_owned(), hidden));
+        offset
+    }
+
+    /// Declares an undefined symbol requiring `version` from `libdep.so`.
+    pub fn versioned_import(&mut self, name: &str, version: &str) {
+        self.import(name);
+        let symbol = self.symbols.iter_mut().find(|sym| sym.name == name).unwrap();
+        symbol.version = Some((version.to_owned(), false));
+    }
+
+    /// Adds a relocation patching `.data` at `offset`.
+    pub fn relocation(&mut self, offset: u64, rtype: u32, symbol: Option<&str>, addend: i64) {
+        if let Some(name) = symbol {
+            self.import(name);
+        }
+        self.relocations.push(Relocation { offset, rtype, symbol: symbol.map(str::to_owned), addend });
+    }
+
+    /// Reserves a GOT slot bound to `import` with a `JUMP_SLOT` relocation and
+    /// returns its offset in `.data`.
+    pub fn got_slot(&mut self, import: &str) -> u64 {
+        align(&mut self.data, 8);
+        let offset = self.data.len() as u64;
+        self.data.extend_from_slice(&[0; 8]);
+        self.relocation(offset, R_X86_64_JUMP_SLOT, Some(import), 0);
+        offset
+    }
+
+    /// Exports `name` as a function tail-calling `import` through its GOT slot,
+    /// so calling `name` forwards all arguments to whatever `import` resolved to.
+    pub fn thunk(&mut self, name: &str, import: &str) {
+        let slot = self.got_slot(import);
+        let offset = self.function(name, &[0xff, 0x25, 0, 0, 0, 0]);
+        self.got_references.push((offset + 2, offset + 6, slot));
+    }
+
+    /// Exports `name` as `prologue`, a `call [rip+disp]` to `import` through its GOT slot,
+    /// then `epilogue`, and returns its offset in `.text`. Unlike a thunk, the call returns
+    /// into this library.
+    pub fn caller(&mut self, name: &str, prologue: &[u8], import: &str, epilogue: &[u8]) -> u64 {
+        let slot = self.got_slot(import);
+        let code: Vec<u8> = prologue.iter().copied()
+            .chain([0xff, 0x15, 0, 0, 0, 0])
+            .chain(epilogue.iter().copied())
+            .collect();
+        let offset = self.function(name, &code);
+        let call = offset + prologue.len() as u64;
+        self.got_references.push((call + 2, call + 6, slot));
+        offset
+    }
+
+    /// Adds a `DT_NEEDED` entry for `library`.
+    pub fn needed(&mut self, library: &str) {
+        self.dynamic.push((1, DynamicValue::String(library.to_owned())));
+    }
+
+    /// Sets the `DT_SONAME`.
+    pub fn soname(&mut self, name: &str) {
+        self.dynamic.push((14, DynamicValue::String(name.to_owned())));
+    }
+
+    /// Adds a `DT_INIT` entry for `function` (an offset returned by [`function`](Self::function)).
+    pub fn init(&mut self, function: u64) {
+        self.dynamic.push((12, DynamicValue::Text(function)));
+    }
+
+    /// Adds a `DT_INIT_ARRAY` of the exported `functions`.
+    pub fn init_array(&mut self, functions: &[&str]) {
+        self.function_array(25, 27, functions);
+    }
+
+    /// Adds a `DT_FINI_ARRAY` of the exported `functions`.
+    pub fn fini_array(&mut self, functions: &[&str]) {
+        self.function_array(26, 28, functions);
+    }
+
+    /// An array of function pointers in `.data` filled by `R_X86_64_64` relocations, with
+    /// `.dynamic` entries for its address and size
+    fn function_array(&mut self, array_tag: u64, size_tag: u64, functions: &[&str]) {
+        align(&mut self.data, 8);
+        let offset = self.data.len() as u64;
+        for (index, function) in functions.iter().enumerate() {
+            self.data.extend_from_slice(&[0; 8]);
+            self.relocation(offset + index as u64 * 8, R_X86_64_64, Some(function), 0);
+        }
+        self.dynamic.push((array_tag, DynamicValue::Data(offset)));
+        self.dynamic.push((size_tag, DynamicValue::Value(functions.len() as u64 * 8)));
+    }
+
+    /// Adds a `PT_GNU_STACK` header requesting an executable stack or not.
+    pub fn gnu_stack(&mut self, executable: bool) {
+        self.gnu_stack = Some(if executable { 7 } else { 6 });
+    }
+
+    /// Makes this a position-independent executable with a `PT_INTERP` of `interpreter`,
+    /// entered at `entry` (an offset returned by [`function`](Self::function)).
+    pub fn executable(&mut self, interpreter: &str, entry: u64) {
+        self.executable = Some((interpreter.to_owned(), entry));
+    }
+
+    /// Sets the `e_type`, e.g. 2 for `ET_EXEC`.
+    pub fn object_type(&mut self, object_type: u16) {
+        self.object_type = Some(object_type);
+    }
+
+    /// Sets the `e_machine`, e.g. 40 for `EM_ARM`.
+    pub fn machine(&mut self, machine: u16) {
+        self.machine = Some(machine);
+    }
+
+    /// Adds a `PT_ARM_EXIDX` header covering `size` bytes from `offset` in `.data`.
+    pub fn arm_exidx(&mut self, offset: u64, size: u64) {
+        self.arm_exidx = Some((offset, size));
+    }
+
+    /// Adds a second, read-write `PT_LOAD` of `size` bytes with no file data at
+    /// [`BSS_ADDRESS`], past everything else.
+    pub fn bss(&mut self, size: u64) {
+        self.bss = Some(size);
+    }
+
+    /// Adds a `PT_GNU_RELRO` header covering `size` bytes at `offset` in `.data`.
+    pub fn relro(&mut self, offset: u64, size: u64) {
+        self.relro = Some((offset, size));
+    }
+
+    /// Adds a `PT_TLS` header whose initialization image is the `file_size` bytes at `offset`
+    /// in `.data`, zero-filled up to `mem_size`.
+    pub fn tls(&mut self, offset: u64, file_size: u64, mem_size: u64) {
+        self.tls = Some((offset, file_size, mem_size));
+    }
+
+    /// Exports `name` as a function returning the 32-bit thread-local at `offset` in the
+    /// `PT_TLS` block, read through a GOT slot holding its `R_X86_64_TPOFF64` offset from the
+    /// thread pointer, like initial-exec code does.
+    pub fn initial_exec(&mut self, name: &str, offset: i64) {
+        self.initial_exec_reloc(name, None, offset);
+    }
+
+    /// Like [`TestElf::initial_exec`], for the thread-local variable `variable` the library
+    /// imports.
+    pub fn initial_exec_import(&mut self, name: &str, variable: &str) {
+        self.import(variable);
+        self.initial_exec_reloc(name, Some(variable), 0);
+    }
+
+    fn initial_exec_reloc(&mut self, name: &str, variable: Option<&str>, offset: i64) {
+        align(&mut self.data, 8);
+        let slot = self.data.len() as u64;
+        self.data.extend_from_slice(&[0; 8]);
+        self.relocation(slot, R_X86_64_TPOFF64, variable, offset);
+        // mov rax, [rip+slot]; mov eax, fs:[rax]; ret
+        let function = self.function(name, &[0x48, 0x8b, 0x05, 0, 0, 0, 0, 0x64, 0x8b, 0x00, 0xc3]);
+        self.got_references.push((function + 3, function + 7, slot));
+    }
+
+    /// Exports `name` as a function returning the 64-bit word at `offset` in `.data`, read
+    /// rip-relative like code accessing its own globals does.
+    pub fn getter(&mut self, name: &str, offset: u64) {
+        // mov rax, [rip+offset]; ret
+        let function = self.function(name, &[0x48, 0x8b, 0x05, 0, 0, 0, 0, 0xc3]);
+        self.got_references.push((function + 3, function + 7, offset));
+    }
+
+    /// Adds a `DT_PLTGOT` entry for `offset` in `.data`.
+    pub fn pltgot(&mut self, offset: u64) {
+        self.dynamic.push((3, DynamicValue::Data(offset)));
+    }
+
+    /// Adds a `DT_RELR` table of relative relocations at the sorted, 8-byte aligned `offsets`
+    /// in `.data`.
+    pub fn relr(&mut self, offsets: &[u64]) {
+        // The encoding's length only depends on the gaps between the offsets
+        let words = relr_encode(offsets, 0).len();
+        align(&mut self.data, 8);
+        let table = self.data.len() as u64;
+        self.data.resize(self.data.len() + words * 8, 0);
+        self.dynamic.push((36, DynamicValue::Data(table))); // DT_RELR
+        self.dynamic.push((35, DynamicValue::Value(words as u64 * 8))); // DT_RELRSZ
+        self.dynamic.push((37, DynamicValue::Value(8))); // DT_RELRENT
+        self.relr = Some((table, offsets.to_vec()));
+    }
+
+    /// Gives defined symbols the section index `SHN_XINDEX`, with their actual section in a
+    /// `SHT_SYMTAB_SHNDX` table, as in libraries with too many sections.
+    pub fn extended_section_indices(&mut self) {
+        self.extended_indices = true;
+    }
+
+    /// Adds a `.note.gnu.build-id` section with the build ID `id`.
+    pub fn build_id(&mut self, id: &[u8]) {
+        self.build_id = Some(id.to_vec());
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut symbols: Vec<&Symbol> = self.symbols.iter().filter(|sym| sym.kind != SymbolKind::Import).collect();
+        symbols.extend(self.symbols.iter().filter(|sym| sym.kind == SymbolKind::Import));
+
+        let mut dynstr = vec![0u8];
+        let mut name_offsets = Vec::new();
+        for sym in &symbols {
+            name_offsets.push(dynstr.len() as u32);
+            dynstr.extend_from_slice(sym.name.as_bytes());
+            dynstr.push(0);
+        }
+        let mut extra_sections = self.version_sections(&symbols, &mut dynstr);
+        if !self.dynamic.is_empty() {
+            extra_sections.push(self.dynamic_section(&mut dynstr));
+        }
+        if self.extended_indices {
+            let mut indices = vec![0; 4];
+            for sym in &symbols {
+                push_u32(&mut indices, match sym.kind {
+                    SymbolKind::Function => 4,
+                    SymbolKind::Object | SymbolKind::Tls => 5,
+                    SymbolKind::Import => 0,
+                });
+            }
+            extra_sections.push((101, 18, indices, 1, 0, 4)); // .symtab_shndx, SHT_SYMTAB_SHNDX
+        }
+        if let Some(id) = &self.build_id {
+            let mut note = Vec::new();
+            push_u32(&mut note, 4);
+            push_u32(&mut note, id.len() as u32);
+            push_u32(&mut note, 3); // NT_GNU_BUILD_ID
+            note.extend_from_slice(b"GNU\0");
+            note.extend_from_slice(id);
+            align(&mut note, 4);
+            extra_sections.push((115, 7, note, 0, 0, 0)); // .note.gnu.build-id, SHT_NOTE
+        }
+
+        let phdrs_offset = 64u64;
+        let phnum = 1 + self.gnu_stack.is_some() as u64 + self.executable.is_some() as u64 + self.arm_exidx.is_some() as u64
+            + self.relro.is_some() as u64 + self.bss.is_some() as u64 + self.tls.is_some() as u64;
+        let interp_offset = phdrs_offset + phnum * 56;
+        let interp_size = self.executable.as_ref().map_or(0, |(interpreter, _)| interpreter.len() as u64 + 1);
+        let dynsym_offset = align_to(interp_offset + interp_size, 8);
+        let dynsym_size = (symbols.len() as u64 + 1) * 24;
+        let dynstr_offset = dynsym_offset + dynsym_size;
+        let rela_offset = align_to(dynstr_offset + dynstr.len() as u64, 8);
+        let rela_size = self.relocations.len() as u64 * 24;
+        let text_offset = align_to(rela_offset + rela_size, 16);
+        let data_offset = align_to(text_offset + self.text.len() as u64, 16);
+        let load_end = data_offset + self.data.len() as u64;
+        if !self.dynamic.is_empty() {
+            let dynamic = &mut extra_sections.last_mut().unwrap().2;
+            for (index, (_, value)) in self.dynamic.iter().enumerate() {
+                let address = match value {
+                    DynamicValue::Text(offset) => text_offset + offset,
+                    DynamicValue::Data(offset) => data_offset + offset,
+                    _ => continue,
+                };
+                dynamic[index * 16 + 8..index * 16 + 16].copy_from_slice(&address.to_le_bytes());
+            }
+        }
+
+        let shstrtab = b"\0.dynsym\0.dynstr\0.rela.dyn\0.text\0.data\0.shstrtab\0.gnu.version\0.gnu.version_d\0.gnu.version_r\0.dynamic\0.symtab_shndx\0.note.gnu.build-id\0";
+        let shstrtab_offset = load_end;
+        let extra_offset = align_to(shstrtab_offset + shstrtab.len() as u64, 8);
+        let extra_size: u64 = extra_sections.iter().map(|section| align_to(section.2.len() as u64, 8)).sum();
+        let shdrs_offset = extra_offset + extra_size;
+
+        let mut out = Vec::new();
+
+        // ELF header
+        out.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        out.extend_from_slice(&[0; 8]);
+        push_u16(&mut out, self.object_type.unwrap_or(3)); // ET_DYN
+        push_u16(&mut out, self.machine.unwrap_or(62)); // EM_X86_64
+        push_u32(&mut out, 1);
+        push_u64(&mut out, self.executable.as_ref().map_or(0, |(_, entry)| text_offset + entry)); // e_entry
+        push_u64(&mut out, phdrs_offset);
+        push_u64(&mut out, shdrs_offset);
+        push_u32(&mut out, 0);
+        push_u16(&mut out, 64);
+        push_u16(&mut out, 56);
+        push_u16(&mut out, phnum as u16);
+        push_u16(&mut out, 64);
+        push_u16(&mut out, 7 + extra_sections.len() as u16);
+        push_u16(&mut out, 6); // e_shstrndx
+
+        // PT_LOAD
+        push_u32(&mut out, 1);
+        push_u32(&mut out, 7); // RWX
+        push_u64(&mut out, 0);
+        push_u64(&mut out, 0);
+        push_u64(&mut out, 0);
+        push_u64(&mut out, load_end);
+        push_u64(&mut out, load_end);
+        push_u64(&mut out, 0x1000);
+
+        // PT_LOAD of .bss, with an offset past the end of the file as it doesn't matter
+        if let Some(size) = self.bss {
+            push_u32(&mut out, 1);
+            push_u32(&mut out, 6); // RW
+            push_u64(&mut out, u64::MAX / 2);
+            push_u64(&mut out, BSS_ADDRESS);
+            push_u64(&mut out, BSS_ADDRESS);
+            push_u64(&mut out, 0);
+            push_u64(&mut out, size);
+            push_u64(&mut out, 0x1000);
+        }
+
+        // PT_GNU_STACK
+        if let Some(flags) = self.gnu_stack {
+            push_u32(&mut out, 0x6474_e551);
+            push_u32(&mut out, flags);
+            out.extend_from_slice(&[0; 40]);
+            push_u64(&mut out, 16);
+        }
+
+        // PT_ARM_EXIDX
+        if let Some((offset, size)) = self.arm_exidx {
+            push_u32(&mut out, 0x7000_0001);
+            push_u32(&mut out, 4); // R
+            for _ in 0..3 {
+                push_u64(&mut out, data_offset + offset);
+            }
+            push_u64(&mut out, size);
+            push_u64(&mut out, size);
+            push_u64(&mut out, 4);
+        }
+
+        // PT_GNU_RELRO
+        if let Some((offset, size)) = self.relro {
+            push_u32(&mut out, 0x6474_e552);
+            push_u32(&mut out, 4); // R
+            for _ in 0..3 {
+                push_u64(&mut out, data_offset + offset);
+            }
+            push_u64(&mut out, size);
+            push_u64(&mut out, size);
+            push_u64(&mut out, 1);
+        }
+
+        // PT_TLS
+        if let Some((offset, file_size, mem_size)) = self.tls {
+            push_u32(&mut out, 7);
+            push_u32(&mut out, 4); // R
+            for _ in 0..3 {
+                push_u64(&mut out, data_offset + offset);
+            }
+            push_u64(&mut out, file_size);
+            push_u64(&mut out, mem_size);
+            push_u64(&mut out, 8);
+        }
+
+        // PT_INTERP
+        if let Some((interpreter, _)) = &self.executable {
+            push_u32(&mut out, 3);
+            push_u32(&mut out, 4); // R
+            push_u64(&mut out, interp_offset);
+            push_u64(&mut out, interp_offset);
+            push_u64(&mut out, interp_offset);
+            push_u64(&mut out, interp_size);
+            push_u64(&mut out, interp_size);
+            push_u64(&mut out, 1);
+            out.extend_from_slice(interpreter.as_bytes());
+            out.push(0);
+        }
+
+        // .dynsym
+        pad_to(&mut out, dynsym_offset);
+        out.extend_from_slice(&[0; 24]);
+        for (sym, name) in symbols.iter().zip(&name_offsets) {
+            push_u32(&mut out, *name);
+            let (kind, shndx, value) = match sym.kind {
+                SymbolKind::Function => (STT_FUNC, 4, text_offset + sym.offset),
+                SymbolKind::Object => (STT_OBJECT, 5, data_offset + sym.offset),
+                SymbolKind::Tls => (STT_TLS, 5, sym.offset),
+                SymbolKind::Import => (0, 0, 0),
+            };
+            let binding = if self.weak_imports.contains(&sym.name) { STB_WEAK } else { STB_GLOBAL };
+            out.push((binding << 4) | kind);
+            out.push(0);
+            push_u16(&mut out, if self.extended_indices && shndx != 0 { 0xffff } else { shndx }); // SHN_XINDEX
+            push_u64(&mut out, value);
+            push_u64(&mut out, sym.size);
+        }
+
+        // .dynstr
+        out.extend_from_slice(&dynstr);
+
+        // .rela.dyn
+        pad_to(&mut out, rela_offset);
+        for reloc in &self.relocations {
+            let index = reloc.symbol.as_ref()
+                .map(|name| symbols.iter().position(|sym| &sym.name == name).unwrap() as u64 + 1)
+                .unwrap_or(0);
+            push_u64(&mut out, data_offset + reloc.offset);
+            push_u64(&mut out, (index << 32) | reloc.rtype as u64);
+            push_u64(&mut out, reloc.addend as u64);
+        }
+
+        // .text
+        pad_to(&mut out, text_offset);
+        let mut text = self.text.clone();
+        for (disp_pos, next, slot) in &self.got_references {
+            let disp = (data_offset + slot) as i64 - (text_offset + next) as i64;
+            let disp_pos = *disp_pos as usize;
+            text[disp_pos..disp_pos + 4].copy_from_slice(&(disp as i32).to_le_bytes());
+        }
+        out.extend_from_slice(&text);
+
+        // .data
+        pad_to(&mut out, data_offset);
+        let mut data = self.data.clone();
+        if let Some((table, offsets)) = &self.relr {
+            for (index, entry) in relr_encode(offsets, data_offset).into_iter().enumerate() {
+                let at = *table as usize + index * 8;
+                data[at..at + 8].copy_from_slice(&entry.to_le_bytes());
+            }
+        }
+        out.extend_from_slice(&data);
+
+        // .shstrtab
+        out.extend_from_slice(shstrtab);
+
+        // Version and dynamic sections, outside the loaded image
+        let mut extra_headers = Vec::new();
+        pad_to(&mut out, extra_offset);
+        for (name, kind, data, link, info, entsize) in &extra_sections {
+            extra_headers.push((*name, *kind, 0, out.len() as u64, data.len() as u64, *link, *info, 8, *entsize));
+            out.extend_from_slice(data);
+            align(&mut out, 8);
+        }
+
+        // Section headers
+        pad_to(&mut out, shdrs_offset);
+        out.extend_from_slice(&[0; 64]);
+        let sections = [
+            (1, SHT_DYNSYM, 0x2, dynsym_offset, dynsym_size, 2, 1, 8, 24),
+            (9, SHT_STRTAB, 0x2, dynstr_offset, dynstr.len() as u64, 0, 0, 1, 0),
+            (17, SHT_RELA, 0x2, rela_offset, rela_size, 1, 0, 8, 24),
+            (27, SHT_PROGBITS, 0x6, text_offset, self.text.len() as u64, 0, 0, 16, 0),
+            (33, SHT_PROGBITS, 0x3, data_offset, self.data.len() as u64, 0, 0, 16, 0),
+            (39, SHT_STRTAB, 0, shstrtab_offset, shstrtab.len() as u64, 0, 0, 1, 0),
+        ];
+        for (name, kind, flags, offset, size, link, info, align, entsize) in sections.into_iter().chain(extra_headers) {
+            push_u32(&mut out, name);
+            push_u32(&mut out, kind);
+            push_u64(&mut out, flags);
+            push_u64(&mut out, if flags & 0x2 != 0 { offset } else { 0 });
+            push_u64(&mut out, offset);
+            push_u64(&mut out, size);
+            push_u32(&mut out, link);
+            push_u32(&mut out, info);
+            push_u64(&mut out, align);
+            push_u64(&mut out, entsize);
+        }
+
+        out
+    }
+}
+
+impl TestElf {
+    /// `.dynamic` in the same format as [`version_sections`](Self::version_sections). Addresses
+    /// are filled in by `build` once the layout is known.
+    fn dynamic_section(&self, dynstr: &mut Vec<u8>) -> (u32, u32, Vec<u8>, u32, u32, u64) {
+        let mut dynamic = Vec::new();
+        for (tag, value) in &self.dynamic {
+            push_u64(&mut dynamic, *tag);
+            match value {
+                DynamicValue::String(value) => {
+                    push_u64(&mut dynamic, dynstr.len() as u64);
+                    dynstr.extend_from_slice(value.as_bytes());
+                    dynstr.push(0);
+                }
+                DynamicValue::Value(value) => push_u64(&mut dynamic, *value),
+                DynamicValue::Text(_) | DynamicValue::Data(_) => push_u64(&mut dynamic, 0),
+            }
+        }
+        dynamic.extend_from_slice(&[0; 16]);
+        (92, 6, dynamic, 2, 0, 16)
+    }
+
+    /// `.gnu.version`, `.gnu.version_d` and `.gnu.version_r` as (name, type, data, link, info,
+    /// entry size), empty if no symbol is versioned
+    fn version_sections(&self, symbols: &[&Symbol], dynstr: &mut Vec<u8>) -> Vec<(u32, u32, Vec<u8>, u32, u32, u64)> {
+        if symbols.iter().all(|sym| sym.version.is_none()) {
+            return Vec::new();
+        }
+        let push_str = |dynstr: &mut Vec<u8>, value: &str| {
+            let offset = dynstr.len() as u32;
+            dynstr.extend_from_slice(value.as_bytes());
+            dynstr.push(0);
+            offset
+        };
+
+        // Index 1 is the library itself, definitions then requirements follow
+        let mut defined: Vec<&str> = Vec::new();
+        let mut required: Vec<&str> = Vec::new();
+        for sym in symbols {
+            if let Some((version, _)) = &sym.version {
+                let list = if sym.kind == SymbolKind::Import { &mut required } else { &mut defined };
+                if !list.contains(&version.as_str()) {
+                    list.push(version);
+                }
+            }
+        }
+        let index_of = |sym: &Symbol| match &sym.version {
+            None => 1,
+            Some((version, hidden)) => {
+                let index = if sym.kind == SymbolKind::Import {
+                    2 + defined.len() + required.iter().position(|v| v == version).unwrap()
+                } else {
+                    2 + defined.iter().position(|v| v == version).unwrap()
+                };
+                index as u16 | if *hidden { 0x8000 } else { 0 }
+            }
+        };
+
+        let mut versym = Vec::new();
+        push_u16(&mut versym, 0);
+        for sym in symbols {
+            push_u16(&mut versym, index_of(sym));
+        }
+        let mut sections = vec![(49, 0x6fff_ffff, versym, 1, 0, 2)];
+
+        let mut verdef = Vec::new();
+        let definitions: Vec<(u16, &str)> = [(1, "libtest.so")].into_iter().chain(defined.iter().map(|version| (0, *version))).collect();
+        for (index, (flags, name)) in definitions.iter().enumerate() {
+            let name = push_str(dynstr, name);
+            push_u16(&mut verdef, 1);
+            push_u16(&mut verdef, *flags);
+            push_u16(&mut verdef, index as u16 + 1);
+            push_u16(&mut verdef, 1);
+            push_u32(&mut verdef, 0);
+            push_u32(&mut verdef, 20);
+            push_u32(&mut verdef, if index + 1 == definitions.len() { 0 } else { 28 });
+            push_u32(&mut verdef, name);
+            push_u32(&mut verdef, 0);
+        }
+        sections.push((62, 0x6fff_fffd, verdef, 2, definitions.len() as u32, 0));
+
+        if !required.is_empty() {
+            let mut verneed = Vec::new();
+            let file = push_str(dynstr, "libdep.so");
+            push_u16(&mut verneed, 1);
+            push_u16(&mut verneed, required.len() as u16);
+            push_u32(&mut verneed, file);
+            push_u32(&mut verneed, 16);
+            push_u32(&mut verneed, 0);
+            for (index, version) in required.iter().enumerate() {
+                let name = push_str(dynstr, version);
+                push_u32(&mut verneed, 0);
+                push_u16(&mut verneed, 0);
+                push_u16(&mut verneed, (2 + defined.len() + index) as u16);
+                push_u32(&mut verneed, name);
+                push_u32(&mut verneed, if index + 1 == required.len() { 0 } else { 16 });
+            }
+            sections.push((77, 0x6fff_fffe, verneed, 2, 1, 0));
+        }
+        sections
+    }
+}
+
+fn align(buf: &mut Vec<u8>, alignment: usize) {
+    while buf.len() % alignment != 0 {
+        buf.push(0);
+    }
+}
+
+fn align_to(value: u64, alignment: u64) -> u64 {
+    (value + alignment - 1) / alignment * alignment
+}
+
+/// The `DT_RELR` entries relocating `offsets` in `.data`, which starts at `data_offset`
+fn relr_encode(offsets: &[u64], data_offset: u64) -> Vec<u64> {
+    let mut entries = Vec::new();
+    let mut addresses = offsets.iter().map(|offset| data_offset + offset).peekable();
+    while let Some(address) = addresses.next() {
+        entries.push(address);
+        let mut next = address + 8;
+        loop {
+            let mut bitmap = 0;
+            while let Some(bit) = addresses.peek().map(|address| (address - next) / 8).filter(|bit| *bit < 63) {
+                bitmap |= 1 << bit;
+                addresses.next();
+            }
+            if bitmap == 0 {
+                break;
+            }
+            entries.push(bitmap << 1 | 1);
+            next += 63 * 8;
+        }
+    }
+    entries
+}
+
+fn pad_to(buf: &mut Vec<u8>, len: u64) {
+    buf.resize(len as usize, 0);
+}
+
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
@@ -0,0 +1,372 @@
+//! Thread-local storage for loaded libraries.
+//!
+//! Every library with a `PT_TLS` segment is registered as a TLS module. Modules only reached
+//! through `__tls_get_addr` get lazily allocated per-thread blocks. Modules that need a fixed
+//! offset from the thread pointer (initial-exec and TLS descriptor relocations) are placed in
+//! a static arena instead. The arena is a thread-local of this crate, so it sits at the same
+//! offset from the thread pointer in every thread as long as this crate is part of the
+//! executable's static TLS (i.e. it isn't itself `dlopen`ed by the host).
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use std::cell::{RefCell, UnsafeCell};
+use std::collections::{HashMap, HashSet};
+use std::os::raw::c_void;
+use std::sync::{Arc, Mutex};
+
+use crate::android_library::AndroidLoaderErr;
+use crate::sysv64;
+
+/// Bytes of static TLS available per thread for all loaded libraries
+pub const STATIC_TLS_SIZE: usize = 4096;
+#[cfg_attr(not(any(target_arch = "arm", target_arch = "x86_64")), allow(dead_code))]
+const STATIC_TLS_ALIGN: usize = 64;
+
+pub(crate) struct TlsModule {
+    /// Initialization image (`.tdata`), the rest of the block is zeroed (`.tbss`)
+    image: Vec<u8>,
+    size: usize,
+    align: usize,
+    /// Offset of this module in the static arena, once it has been moved there
+    static_offset: Mutex<Option<usize>>,
+}
+
+#[repr(C)]
+pub(crate) struct TlsIndex {
+    module: usize,
+    offset: usize,
+}
+
+#[repr(C, align(64))]
+struct StaticTls(UnsafeCell<[u8; STATIC_TLS_SIZE]>);
+
+lazy_static! {
+    /// Registered modules, module ids start at 1
+    static ref MODULES: Mutex<Vec<Option<Arc<TlsModule>>>> = Mutex::new(Vec::new());
+    static ref STATIC_TLS_USED: Mutex<usize> = Mutex::new(0);
+}
+
+thread_local! {
+    static STATIC_TLS: StaticTls = const { StaticTls(UnsafeCell::new([0; STATIC_TLS_SIZE])) };
+    static BLOCKS: RefCell<HashMap<usize, Vec<u8>>> = RefCell::new(HashMap::new());
+    static INITIALIZED_STATIC: RefCell<HashSet<usize>> = RefCell::new(HashSet::new());
+}
+
+/// Register a TLS module for a library's `PT_TLS` segment and return its module id
+pub(crate) fn register_module(image: &[u8], size: usize, align: usize) -> usize {
+    let module = Arc::new(TlsModule {
+        image: image.to_vec(),
+        size,
+        align: align.max(1),
+        static_offset: Mutex::new(None),
+    });
+
+    let mut modules = MODULES.lock().unwrap();
+    modules.push(Some(module));
+    modules.len()
+}
+
+/// Forget a module when its library is unloaded. Its static arena slot is not reused.
+pub(crate) fn unregister_module(id: usize) {
+    if let Some(module) = MODULES.lock().unwrap().get_mut(id - 1) {
+        *module = None;
+    }
+    BLOCKS.with(|blocks| blocks.borrow_mut().remove(&id));
+}
+
+fn get_module(id: usize) -> Option<Arc<TlsModule>> {
+    MODULES.lock().unwrap().get(id.wrapping_sub(1)).cloned().flatten()
+}
+
+/// Offset from the thread pointer to the start of the module's block, moving the module into
+/// the static arena on first use
+#[cfg_attr(not(any(target_arch = "arm", target_arch = "x86_64")), allow(dead_code))]
+pub(crate) fn static_tp_offset(id: usize) -> Result<isize> {
+    let module = get_module(id).ok_or(AndroidLoaderErr::UnknownTlsModule(id))?;
+    let offset = static_offset(id, &module)?;
+    let thread_pointer = thread_pointer().ok_or(AndroidLoaderErr::StaticTlsUnsupported)?;
+
+    Ok((arena_base() + offset) as isize - thread_pointer as isize)
+}
+
+#[cfg_attr(not(any(target_arch = "arm", target_arch = "x86_64")), allow(dead_code))]
+fn static_offset(id: usize, module: &TlsModule) -> Result<usize> {
+    let mut static_offset = module.static_offset.lock().unwrap();
+    if let Some(offset) = *static_offset {
+        return Ok(offset);
+    }
+
+    let mut used = STATIC_TLS_USED.lock().unwrap();
+    let offset = (*used + module.align - 1) / module.align * module.align;
+    if module.align > STATIC_TLS_ALIGN || offset + module.size > STATIC_TLS_SIZE {
+        return Err(AndroidLoaderErr::StaticTlsExhausted(module.size).into());
+    }
+    *used = offset + module.size;
+    *static_offset = Some(offset);
+    drop(static_offset);
+
+    initialize_static(id, module, offset);
+    Ok(offset)
+}
+
+fn arena_base() -> usize {
+    STATIC_TLS.with(|arena| arena.0.get() as usize)
+}
+
+fn initialize_static(id: usize, module: &TlsModule, offset: usize) {
+    let fresh = INITIALIZED_STATIC.with(|initialized| initialized.borrow_mut().insert(id));
+    if fresh {
+        unsafe {
+            let block = (arena_base() + offset) as *mut u8;
+            std::ptr::write_bytes(block, 0, module.size);
+            std::ptr::copy_nonoverlapping(module.image.as_ptr(), block, module.image.len().min(module.size));
+        }
+    }
+}
+
+/// Copy the initialization images of every statically allocated module into the current
+/// thread's arena. Threads only reaching the TLS through `__tls_get_addr` don't need this,
+/// but threads accessing initial-exec variables with non-zero initializers do.
+pub fn initialize_current_thread() {
+    let modules = MODULES.lock().unwrap().clone();
+    for (index, module) in modules.iter().enumerate() {
+        if let Some(module) = module {
+            if let Some(offset) = *module.static_offset.lock().unwrap() {
+                initialize_static(index + 1, module, offset);
+            }
+        }
+    }
+}
+
+fn block_address(id: usize) -> Option<usize> {
+    let module = get_module(id)?;
+
+    if let Some(offset) = *module.static_offset.lock().unwrap() {
+        initialize_static(id, &module, offset);
+        return Some(arena_base() + offset);
+    }
+
+    BLOCKS.with(|blocks| {
+        let mut blocks = blocks.borrow_mut();
+        let block = blocks.entry(id).or_insert_with(|| {
+            let mut block = vec![0u8; module.size + module.align];
+            let start = block.as_ptr().align_offset(module.align);
+            block[start..start + module.image.len().min(module.size)].copy_from_slice(&module.image[..module.image.len().min(module.size)]);
+            block
+        });
+        let start = block.as_ptr().align_offset(module.align);
+        Some(block.as_ptr() as usize + start)
+    })
+}
+
+#[sysv64]
+pub(crate) unsafe fn tls_get_addr(index: *const TlsIndex) -> *mut c_void {
+    let index = &*index;
+    match block_address(index.module) {
+        Some(block) => (block + index.offset) as *mut c_void,
+        None => panic!("__tls_get_addr called for unknown TLS module {}", index.module),
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", not(target_family = "windows")))]
+#[allow(dead_code)]
+fn thread_pointer() -> Option<usize> {
+    let thread_pointer: usize;
+    unsafe { std::arch::asm!("mov {}, fs:0", out(reg) thread_pointer, options(nostack, readonly, preserves_flags)) };
+    Some(thread_pointer)
+}
+
+#[cfg(all(target_arch = "x86", not(target_family = "windows")))]
+#[allow(dead_code)]
+fn thread_pointer() -> Option<usize> {
+    let thread_pointer: usize;
+    unsafe { std::arch::asm!("mov {}, gs:0", out(reg) thread_pointer, options(nostack, readonly, preserves_flags)) };
+    Some(thread_pointer)
+}
+
+#[cfg(target_arch = "aarch64")]
+#[allow(dead_code)]
+fn thread_pointer() -> Option<usize> {
+    let thread_pointer: usize;
+    unsafe { std::arch::asm!("mrs {}, tpidr_el0", out(reg) thread_pointer, options(nostack, nomem, preserves_flags)) };
+    Some(thread_pointer)
+}
+
+#[cfg(target_arch = "arm")]
+fn thread_pointer() -> Option<usize> {
+    let thread_pointer: usize;
+    unsafe { std::arch::asm!("mrc p15, 0, {}, c13, c0, 3", out(reg) thread_pointer, options(nostack, nomem, preserves_flags)) };
+    Some(thread_pointer)
+}
+
+#[cfg(any(target_family = "windows", not(any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64", target_arch = "arm"))))]
+#[allow(dead_code)]
+fn thread_pointer() -> Option<usize> {
+    None
+}
+
+// ARM helpers must not clobber anything but r0, so they're written in assembly
+#[cfg(target_arch = "arm")]
+std::arch::global_asm!(
+    ".text",
+    ".globl android_loader_aeabi_read_tp",
+    ".type android_loader_aeabi_read_tp, %function",
+    "android_loader_aeabi_read_tp:",
+    "mrc p15, 0, r0, c13, c0, 3",
+    "bx lr",
+    ".globl android_loader_tlsdesc_static",
+    ".type android_loader_tlsdesc_static, %function",
+    "android_loader_tlsdesc_static:",
+    "ldr r0, [r0]",
+    "bx lr",
+);
+
+#[cfg(target_arch = "arm")]
+extern "C" {
+    /// `__aeabi_read_tp`: returns the thread pointer in r0
+    pub(crate) fn android_loader_aeabi_read_tp();
+    /// TLS descriptor resolver for statically allocated modules: the descriptor's argument
+    /// word already holds the offset from the thread pointer
+    pub(crate) fn android_loader_tlsdesc_static();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::{register_module, tls_get_addr, TlsIndex};
+    #[cfg(all(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "arm"), not(target_family = "windows")))]
+    use crate::tls::{static_tp_offset, thread_pointer};
+    #[cfg(all(target_arch = "x86_64", not(target_family = "windows")))]
+    use {
+        crate::android_library::{AndroidLibrary, AndroidLoaderErr},
+        crate::android_loader::AndroidLoader,
+        crate::test_elf::TestElf,
+        crate::tls::{initialize_current_thread, STATIC_TLS_SIZE},
+    };
+
+    #[test]
+    fn dynamic_blocks_are_per_thread() {
+        let module = register_module(&[1, 2, 3, 4], 16, 8);
+        let index = TlsIndex { module, offset: 2 };
+        let index_addr = &index as *const TlsIndex as usize;
+
+        let here = unsafe { tls_get_addr(&index) } as *mut u8;
+        unsafe {
+            assert_eq!(here.read(), 3);
+            assert_eq!(here.add(2).read(), 0);
+            here.write(42);
+        }
+
+        let there = std::thread::spawn(move || unsafe {
+            let there = tls_get_addr(index_addr as *const TlsIndex) as *mut u8;
+            (there as usize, there.read())
+        }).join().unwrap();
+        assert_ne!(there.0, here as usize);
+        assert_eq!(there.1, 3);
+    }
+
+    #[cfg(all(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "arm"), not(target_family = "windows")))]
+    #[test]
+    fn static_offset_is_stable_across_threads() {
+        let module = register_module(&[7; 8], 8, 8);
+        let offset = static_tp_offset(module).unwrap();
+        let index = TlsIndex { module, offset: 0 };
+        let index_addr = &index as *const TlsIndex as usize;
+
+        let block = unsafe { tls_get_addr(&index) } as usize;
+        assert_eq!(block as isize, thread_pointer().unwrap() as isize + offset);
+
+        let (block, thread_pointer, value) = std::thread::spawn(move || unsafe {
+            let block = tls_get_addr(index_addr as *const TlsIndex) as *const u8;
+            (block as usize, thread_pointer().unwrap(), block.read())
+        }).join().unwrap();
+        assert_eq!(block as isize, thread_pointer as isize + offset);
+        assert_eq!(value, 7);
+    }
+
+    #[cfg(all(target_arch = "x86_64", not(target_family = "windows")))]
+    #[test]
+    fn truncated_image() {
+        let mut elf = TestElf::new();
+        let image = elf.object("tls_image", &[1; 8]);
+        elf.tls(image, 8, 8);
+        let mut elf = elf.build();
+        let phoff = u64::from_le_bytes(elf[32..40].try_into().unwrap()) as usize;
+        let phnum = u16::from_le_bytes(elf[56..58].try_into().unwrap()) as usize;
+        let tls = (0..phnum).map(|index| phoff + index * 56).find(|header| elf[*header..*header + 4] == [7, 0, 0, 0]).unwrap();
+        elf[tls + 32..tls + 40].copy_from_slice(&u64::MAX.to_le_bytes()); // p_filesz
+        let err = AndroidLibrary::load_from_bytes(elf).err().unwrap();
+        assert!(matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::ElfParsingError(_))));
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn static_offset_follows_the_tcb() {
+        // Variant I: the thread pointer points at the two-word TCB and blocks come after it
+        let module = register_module(&[9; 4], 4, 4);
+        let offset = static_tp_offset(module).unwrap();
+        assert!(offset >= 16);
+        assert_eq!(offset % 4, 0);
+        assert_eq!(static_tp_offset(module).unwrap(), offset);
+        let block = (thread_pointer().unwrap() as isize + offset) as *const u8;
+        assert_eq!(unsafe { block.read() }, 9);
+    }
+
+    #[cfg(all(target_arch = "x86_64", not(target_family = "windows")))]
+    #[test]
+    fn static_tp_offset_errors() {
+        let err = static_tp_offset(usize::MAX).unwrap_err();
+        assert!(matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::UnknownTlsModule(usize::MAX))));
+        let err = static_tp_offset(register_module(&[], STATIC_TLS_SIZE + 1, 8)).unwrap_err();
+        assert!(matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::StaticTlsExhausted(_))));
+    }
+
+    #[cfg(all(target_arch = "x86_64", not(target_family = "windows")))]
+    #[test]
+    fn initial_exec_relocations() {
+        let mut elf = TestElf::new();
+        let image = elf.object("tls_image", &[0x11, 0, 0, 0, 0x22, 0, 0, 0]);
+        elf.tls(image, 8, 16);
+        elf.initial_exec("read_first", 0);
+        elf.initial_exec("read_second", 4);
+        elf.initial_exec("read_zeroed", 8);
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let read = |name: &str| -> extern "C" fn() -> u32 { unsafe { std::mem::transmute(library.get_symbol(name).unwrap()) } };
+        let (first, second, zeroed) = (read("read_first"), read("read_second"), read("read_zeroed"));
+        assert_eq!((first(), second(), zeroed()), (0x11, 0x22, 0));
+
+        // Other threads get their own copy once initialized
+        let there = std::thread::spawn(move || {
+            initialize_current_thread();
+            (first(), second(), zeroed())
+        }).join().unwrap();
+        assert_eq!(there, (0x11, 0x22, 0));
+    }
+
+    #[cfg(all(target_arch = "x86_64", not(target_family = "windows")))]
+    #[test]
+    fn initial_exec_imports() {
+        let mut provider = TestElf::new();
+        let image = provider.object("tls_image", &[0x11, 0, 0, 0, 0x22, 0, 0, 0]);
+        provider.tls(image, 8, 8);
+        provider.tls_variable("imported_tls_variable", 4, 4);
+        provider.soname("libtlsprovider.so");
+        let provider = AndroidLibrary::load_from_bytes(provider.build()).unwrap();
+
+        // The consumer has a block of its own, so reading the provider's is visible
+        let mut elf = TestElf::new();
+        let image = elf.object("own_tls_image", &[0x33, 0, 0, 0, 0x44, 0, 0, 0]);
+        elf.tls(image, 8, 8);
+        elf.initial_exec_import("read_imported", "imported_tls_variable");
+        let library = AndroidLoader::new().libc_provider(&provider).load_library_from_bytes(elf.build()).unwrap();
+        let read: extern "C" fn() -> u32 = unsafe { std::mem::transmute(library.get_symbol("read_imported").unwrap()) };
+        assert_eq!(read(), 0x22);
+
+        let mut elf = TestElf::new();
+        elf.initial_exec_import("read_missing", "missing_tls_variable");
+        let err = AndroidLibrary::load_from_bytes(elf.build()).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<AndroidLoaderErr>(),
+            Some(AndroidLoaderErr::UnresolvedTlsSymbol { name }) if name == "missing_tls_variable"
+        ));
+    }
+}
@@ -0,0 +1,179 @@
+//! Tiny generated stubs that call a shared handler with a per-stub context word.
+//!
+//! A stub replaces the caller's first argument with its context and forwards to the handler,
+//! which returns straight to the original caller. Handlers have the signature
+//! `#[sysv64] fn(context: usize) -> usize`.
+//!
+//! Stubs are kept in a [`TrampolineArena`], written while relocating and made executable once
+//! every relocation is applied.
+
+use anyhow::Result;
+use memmap2::{Mmap, MmapMut, MmapOptions};
+
+/// Bytes reserved for each stub
+#[cfg(not(target_arch = "x86"))]
+pub(crate) const TRAMPOLINE_SIZE: usize = 32;
+#[cfg(target_arch = "x86")]
+pub(crate) const TRAMPOLINE_SIZE: usize = 64;
+
+/// Room for a fixed number of stubs, each given a boxed context whose address it passes on
+pub(crate) struct TrampolineArena<T> {
+    capacity: usize,
+    /// Boxed so the stubs' context pointers stay valid
+    #[allow(clippy::vec_box)]
+    contexts: Vec<Box<T>>,
+    /// Stubs while relocating, made executable by `finish`
+    code: Option<MmapMut>,
+    executable: Option<Mmap>,
+}
+
+impl<T> TrampolineArena<T> {
+    /// Prepare room for up to `capacity` stubs
+    pub(crate) fn new(capacity: usize) -> Result<TrampolineArena<T>> {
+        let capacity = capacity.max(1);
+        Ok(TrampolineArena {
+            capacity,
+            contexts: Vec::new(),
+            code: Some(MmapOptions::new().len(capacity * TRAMPOLINE_SIZE).map_anon()?),
+            executable: None,
+        })
+    }
+
+    /// Add a stub for `context`, which `write` writes given its bytes and the context's
+    /// address, and return its address. `None` once full or finished.
+    pub(crate) fn stub(&mut self, context: T, write: impl FnOnce(&mut [u8], usize)) -> Option<usize> {
+        let index = self.contexts.len();
+        let code = self.code.as_mut().filter(|_| index < self.capacity)?;
+        let context = Box::new(context);
+        write(&mut code[index * TRAMPOLINE_SIZE..(index + 1) * TRAMPOLINE_SIZE], &*context as *const T as usize);
+        self.contexts.push(context);
+        Some(code.as_ptr() as usize + index * TRAMPOLINE_SIZE)
+    }
+
+    /// Make the stubs executable once every relocation is applied
+    pub(crate) fn finish(&mut self) -> Result<()> {
+        if let Some(code) = self.code.take() {
+            self.executable = Some(code.make_exec()?);
+        }
+        Ok(())
+    }
+
+    /// Index of the stub `address` is in, once finished
+    pub(crate) fn index_of(&self, address: usize) -> Option<usize> {
+        let base = self.executable.as_ref()?.as_ptr() as usize;
+        let index = address.checked_sub(base)? / TRAMPOLINE_SIZE;
+        if index < self.contexts.len() { Some(index) } else { None }
+    }
+
+    /// Context of stub `index`, in the order they were added
+    pub(crate) fn context(&self, index: usize) -> Option<&T> {
+        self.contexts.get(index).map(|context| &**context)
+    }
+
+    /// How many stubs were added
+    #[cfg_attr(not(any(target_arch = "x86_64", target_arch = "aarch64")), allow(dead_code))]
+    pub(crate) fn len(&self) -> usize {
+        self.contexts.len()
+    }
+}
+
+/// Write a stub into `code`, which must be at least [`TRAMPOLINE_SIZE`] bytes
+#[cfg(not(target_arch = "x86"))]
+pub(crate) fn write(code: &mut [u8], handler: usize, context: usize) {
+    write_forwarding(code, handler, 0, context);
+}
+
+/// Write a stub forwarding the caller's first `index` arguments followed by `context` to
+/// `target`, which returns straight to the original caller
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn write_forwarding(code: &mut [u8], target: usize, index: usize, context: usize) {
+    const REGISTERS: [[u8; 2]; 6] = [[0x48, 0xbf], [0x48, 0xbe], [0x48, 0xba], [0x48, 0xb9], [0x49, 0xb8], [0x49, 0xb9]];
+    // movabs <argument register>, context; movabs rax, target; jmp rax
+    code[0..2].copy_from_slice(&REGISTERS[index]);
+    code[2..10].copy_from_slice(&(context as u64).to_le_bytes());
+    code[10..12].copy_from_slice(&[0x48, 0xb8]);
+    code[12..20].copy_from_slice(&(target as u64).to_le_bytes());
+    code[20..22].copy_from_slice(&[0xff, 0xe0]);
+}
+
+/// Write a stub leaving every argument alone, which jumps to `target` with `context` in a
+/// scratch register: `r10` on x86_64, `x16` on aarch64
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn write_preserving(code: &mut [u8], target: usize, context: usize) {
+    // movabs r10, context; movabs r11, target; jmp r11
+    code[0..2].copy_from_slice(&[0x49, 0xba]);
+    code[2..10].copy_from_slice(&(context as u64).to_le_bytes());
+    code[10..12].copy_from_slice(&[0x49, 0xbb]);
+    code[12..20].copy_from_slice(&(target as u64).to_le_bytes());
+    code[20..23].copy_from_slice(&[0x41, 0xff, 0xe3]);
+}
+
+#[cfg(target_arch = "aarch64")]
+pub(crate) fn write_preserving(code: &mut [u8], target: usize, context: usize) {
+    let instructions: [u32; 4] = [
+        0x5800_0090, // ldr x16, #16
+        0x5800_00b1, // ldr x17, #20
+        0xd61f_0220, // br x17
+        0xd503_201f, // nop
+    ];
+    for (chunk, instruction) in code.chunks_exact_mut(4).zip(instructions) {
+        chunk.copy_from_slice(&instruction.to_le_bytes());
+    }
+    code[16..24].copy_from_slice(&(context as u64).to_le_bytes());
+    code[24..32].copy_from_slice(&(target as u64).to_le_bytes());
+}
+
+#[cfg(target_arch = "aarch64")]
+pub(crate) fn write_forwarding(code: &mut [u8], target: usize, index: usize, context: usize) {
+    assert!(index < 8);
+    let instructions: [u32; 4] = [
+        0x5800_0080 | index as u32, // ldr x<index>, #16
+        0x5800_00b0, // ldr x16, #20
+        0xd61f_0200, // br x16
+        0xd503_201f, // nop
+    ];
+    for (chunk, instruction) in code.chunks_exact_mut(4).zip(instructions) {
+        chunk.copy_from_slice(&instruction.to_le_bytes());
+    }
+    code[16..24].copy_from_slice(&(context as u64).to_le_bytes());
+    code[24..32].copy_from_slice(&(target as u64).to_le_bytes());
+}
+
+#[cfg(target_arch = "x86")]
+pub(crate) fn write(code: &mut [u8], handler: usize, context: usize) {
+    // The context goes on the stack, so this is a call rather than a tail call. The stack
+    // stays 16-byte aligned at the handler's entry.
+    code[0..3].copy_from_slice(&[0x83, 0xec, 0x08]); // sub esp, 8
+    code[3] = 0x68; // push context
+    code[4..8].copy_from_slice(&(context as u32).to_le_bytes());
+    code[8] = 0xb8; // mov eax, handler
+    code[9..13].copy_from_slice(&(handler as u32).to_le_bytes());
+    code[13..19].copy_from_slice(&[0xff, 0xd0, 0x83, 0xc4, 0x0c, 0xc3]); // call eax; add esp, 12; ret
+}
+
+#[cfg(target_arch = "x86")]
+pub(crate) fn write_forwarding(code: &mut [u8], target: usize, index: usize, context: usize) {
+    assert!(index < 6);
+    // Arguments are on the stack, so they're copied below a new frame with room for 6 words
+    let mut stub = vec![0x55, 0x89, 0xe5, 0x83, 0xec, 0x18]; // push ebp; mov ebp, esp; sub esp, 24
+    for word in 0..index as u8 {
+        stub.extend_from_slice(&[0x8b, 0x45, 8 + 4 * word]); // mov eax, [ebp + 8 + 4 * word]
+        stub.extend_from_slice(&[0x89, 0x44, 0x24, 4 * word]); // mov [esp + 4 * word], eax
+    }
+    stub.extend_from_slice(&[0xc7, 0x44, 0x24, 4 * index as u8]); // mov dword [esp + 4 * index], context
+    stub.extend_from_slice(&(context as u32).to_le_bytes());
+    stub.push(0xb8); // mov eax, target
+    stub.extend_from_slice(&(target as u32).to_le_bytes());
+    stub.extend_from_slice(&[0xff, 0xd0, 0xc9, 0xc3]); // call eax; leave; ret
+    code[..stub.len()].copy_from_slice(&stub);
+}
+
+#[cfg(target_arch = "arm")]
+pub(crate) fn write_forwarding(code: &mut [u8], target: usize, index: usize, context: usize) {
+    assert!(index < 4);
+    // ARM state; pc reads 8 bytes ahead
+    code[0..4].copy_from_slice(&(0xe59f_0000u32 | (index as u32) << 12).to_le_bytes()); // ldr r<index>, [pc]
+    code[4..8].copy_from_slice(&0xe59f_f000u32.to_le_bytes()); // ldr pc, [pc]
+    code[8..12].copy_from_slice(&(context as u32).to_le_bytes());
+    code[12..16].copy_from_slice(&(target as u32).to_le_bytes());
+}
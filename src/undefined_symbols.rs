@@ -0,0 +1,204 @@
+//! What happens when a library uses a symbol that nothing provides.
+
+use anyhow::Result;
+use log::error;
+use memmap2::{MmapMut, MmapOptions};
+use region::Protection;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::protections::{self, ProtectionSource};
+use crate::sysv64;
+use crate::trampoline::{self, TrampolineArena};
+
+/// Callback for [`UndefinedSymbolBehavior::Callback`], given the symbol's name
+pub type UndefinedSymbolCallback = dyn Fn(&str) + Send + Sync;
+
+#[derive(Clone)]
+pub enum UndefinedSymbolBehavior {
+    /// Panic with the symbol's name when it is called
+    Panic,
+    /// Log the symbol's name and abort the process when it is called
+    Abort,
+    /// Resolve every undefined symbol to its own inaccessible address, so using it faults
+    /// right at the call site. [`AndroidLibrary::undefined_symbol_at`](crate::android_library::AndroidLibrary::undefined_symbol_at)
+    /// maps the faulting address back to the symbol.
+    Fault,
+    /// Call back with the symbol's name when it is called, then return 0 to the library
+    Callback(Arc<UndefinedSymbolCallback>),
+}
+
+impl Default for UndefinedSymbolBehavior {
+    fn default() -> Self {
+        UndefinedSymbolBehavior::Panic
+    }
+}
+
+struct UndefinedSymbol {
+    name: String,
+    behavior: UndefinedSymbolBehavior,
+}
+
+/// The stubs handed out for one library's undefined symbols
+pub(crate) struct UndefinedSymbols {
+    behavior: UndefinedSymbolBehavior,
+    capacity: usize,
+    addresses: HashMap<String, usize>,
+    /// Trampolines to [`undefined_symbol_called`], unless faulting
+    stubs: Option<TrampolineArena<UndefinedSymbol>>,
+    /// Inaccessible region for [`UndefinedSymbolBehavior::Fault`], one byte per symbol, and
+    /// the symbols in order
+    fault_region: Option<(MmapMut, Vec<String>)>,
+}
+
+impl UndefinedSymbols {
+    /// Prepare room for up to `capacity` undefined symbols
+    pub(crate) fn new(behavior: UndefinedSymbolBehavior, capacity: usize) -> Result<UndefinedSymbols> {
+        let capacity = capacity.max(1);
+        let (stubs, fault_region) = match behavior {
+            UndefinedSymbolBehavior::Fault => {
+                let region = MmapOptions::new().len(capacity).map_anon()?;
+                unsafe { protections::protect(region.as_ptr(), capacity, Protection::NONE, ProtectionSource::Loader)? };
+                (None, Some((region, Vec::new())))
+            }
+            _ => (Some(TrampolineArena::new(capacity)?), None),
+        };
+
+        Ok(UndefinedSymbols { behavior, capacity, addresses: HashMap::new(), stubs, fault_region })
+    }
+
+    /// Address to resolve the undefined symbol `name` to
+    pub(crate) fn stub(&mut self, name: &str) -> usize {
+        if let Some(address) = self.addresses.get(name) {
+            return *address;
+        }
+
+        let address = match (&mut self.stubs, &mut self.fault_region) {
+            (Some(stubs), _) => {
+                let symbol = UndefinedSymbol { name: name.to_owned(), behavior: self.behavior.clone() };
+                stubs.stub(symbol, |code, context| trampoline::write(code, undefined_symbol_called as *const () as usize, context))
+                    .expect("more undefined symbols than symbol table entries, or requested after relocation")
+            }
+            (None, Some((region, names))) => {
+                assert!(names.len() < self.capacity, "more undefined symbols than symbol table entries");
+                names.push(name.to_owned());
+                region.as_ptr() as usize + names.len() - 1
+            }
+            (None, None) => unreachable!("undefined symbols are either stubbed or faulting"),
+        };
+
+        self.addresses.insert(name.to_owned(), address);
+        address
+    }
+
+    /// Make the stubs executable once every relocation is applied
+    pub(crate) fn finish(&mut self) -> Result<()> {
+        match &mut self.stubs {
+            Some(stubs) => stubs.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Name of the undefined symbol a stub or fault address belongs to
+    pub(crate) fn symbol_at(&self, address: usize) -> Option<&str> {
+        match (&self.stubs, &self.fault_region) {
+            (Some(stubs), _) => stubs.context(stubs.index_of(address)?).map(|symbol| symbol.name.as_str()),
+            (None, Some((region, names))) => names.get(address.checked_sub(region.as_ptr() as usize)?).map(String::as_str),
+            (None, None) => None,
+        }
+    }
+}
+
+#[sysv64]
+unsafe fn undefined_symbol_called(symbol: *const UndefinedSymbol) -> usize {
+    let symbol = &*symbol;
+    match &symbol.behavior {
+        UndefinedSymbolBehavior::Panic | UndefinedSymbolBehavior::Fault => {
+            panic!("tried to call an undefined symbol: {}", symbol.name)
+        }
+        UndefinedSymbolBehavior::Abort => {
+            error!("tried to call an undefined symbol: {}", symbol.name);
+            std::process::abort()
+        }
+        UndefinedSymbolBehavior::Callback(callback) => {
+            callback(&symbol.name);
+            0
+        }
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::process::Command;
+    use std::sync::{Arc, Mutex};
+
+    use crate::android_library::AndroidLibrary;
+    use crate::android_loader::AndroidLoader;
+    use crate::test_elf::{TestElf, R_X86_64_64};
+    use crate::undefined_symbols::UndefinedSymbolBehavior;
+
+    const CHILD_MODE: &str = "ANDROID_LOADER_TEST_UNDEFINED_MODE";
+
+    fn load(behavior: UndefinedSymbolBehavior) -> AndroidLibrary<'static> {
+        let mut elf = TestElf::new();
+        elf.thunk("call_missing", "undefined_test_missing");
+        let pointer = elf.object("missing_pointer", &[0; 8]);
+        elf.relocation(pointer, R_X86_64_64, Some("undefined_test_missing"), 0);
+        AndroidLoader::new().on_undefined_symbol(behavior).load_library_from_bytes(elf.build()).unwrap()
+    }
+
+    fn call_missing(library: &AndroidLibrary) -> usize {
+        let call: extern "C" fn() -> usize = unsafe { std::mem::transmute(library.get_symbol("call_missing").unwrap()) };
+        call()
+    }
+
+    #[test]
+    fn callback_mode() {
+        let called = Arc::new(Mutex::new(Vec::new()));
+        let log = called.clone();
+        let library = load(UndefinedSymbolBehavior::Callback(Arc::new(move |name: &str| log.lock().unwrap().push(name.to_owned()))));
+
+        assert_eq!(call_missing(&library), 0);
+        assert_eq!(*called.lock().unwrap(), ["undefined_test_missing"]);
+    }
+
+    #[test]
+    fn fault_mode_addresses() {
+        let library = load(UndefinedSymbolBehavior::Fault);
+        let address = unsafe { *(library.get_symbol("missing_pointer").unwrap() as *const usize) };
+        assert_eq!(library.undefined_symbol_at(address), Some("undefined_test_missing"));
+        assert_eq!(library.undefined_symbol_at(address + 1), None);
+    }
+
+    /// Runs in a child process spawned by `fatal_modes`, calls the missing symbol and dies
+    #[test]
+    fn undefined_symbol_child() {
+        let behavior = match std::env::var(CHILD_MODE).as_deref() {
+            Ok("panic") => UndefinedSymbolBehavior::Panic,
+            Ok("abort") => UndefinedSymbolBehavior::Abort,
+            Ok("fault") => UndefinedSymbolBehavior::Fault,
+            _ => return,
+        };
+        call_missing(&load(behavior));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fatal_modes() {
+        use std::os::unix::process::ExitStatusExt;
+
+        for (mode, signal) in [("panic", None), ("abort", Some(libc::SIGABRT)), ("fault", Some(libc::SIGSEGV))] {
+            let output = Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "undefined_symbols::tests::undefined_symbol_child", "--nocapture", "--test-threads=1"])
+                .env(CHILD_MODE, mode)
+                .output()
+                .unwrap();
+            assert!(!output.status.success(), "{mode} mode didn't stop the process");
+            if let Some(signal) = signal {
+                assert_eq!(output.status.signal(), Some(signal), "{mode} mode");
+            } else {
+                assert!(String::from_utf8_lossy(&output.stderr).contains("undefined_test_missing"));
+            }
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! GNU symbol versioning: `.gnu.version` assigns each dynamic symbol a version index, which
+//! `.gnu.version_d` names for definitions and `.gnu.version_r` for requirements.
+
+use std::collections::HashMap;
+use zero::read_str;
+
+pub(crate) const SHT_GNU_VERDEF: u32 = 0x6fff_fffd;
+pub(crate) const SHT_GNU_VERNEED: u32 = 0x6fff_fffe;
+pub(crate) const SHT_GNU_VERSYM: u32 = 0x6fff_ffff;
+
+/// Marks the definition naming the library itself rather than a version
+const VER_FLG_BASE: u16 = 1;
+const VERSYM_HIDDEN: u16 = 0x8000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct SymbolVersion {
+    pub(crate) name: String,
+    /// Only reachable by asking for this version explicitly (`foo@V1` rather than `foo@@V1`)
+    pub(crate) hidden: bool,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_ne_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<usize> {
+    Some(u32::from_ne_bytes(data.get(offset..offset + 4)?.try_into().ok()?) as usize)
+}
+
+fn read_name(strings: &[u8], offset: usize) -> Option<String> {
+    Some(read_str(strings.get(offset..)?).to_owned())
+}
+
+/// Version names by index from `.gnu.version_d`, stopping at the first malformed entry
+fn definitions(verdef: &[u8], strings: &[u8], names: &mut HashMap<u16, String>) -> Option<()> {
+    let mut offset = 0;
+    loop {
+        let flags = read_u16(verdef, offset + 2)?;
+        let index = read_u16(verdef, offset + 4)?;
+        let aux = read_u32(verdef, offset + 12)?;
+        let next = read_u32(verdef, offset + 16)?;
+        if flags & VER_FLG_BASE == 0 {
+            names.insert(index, read_name(strings, read_u32(verdef, offset + aux)?)?);
+        }
+        if next == 0 {
+            return Some(());
+        }
+        offset += next;
+    }
+}
+
+/// Version names by index from `.gnu.version_r`, stopping at the first malformed entry
+fn requirements(verneed: &[u8], strings: &[u8], names: &mut HashMap<u16, String>) -> Option<()> {
+    let mut offset = 0;
+    loop {
+        let count = read_u16(verneed, offset + 2)?;
+        let mut aux = offset + read_u32(verneed, offset + 8)?;
+        for _ in 0..count {
+            let index = read_u16(verneed, aux + 6)?;
+            names.insert(index, read_name(strings, read_u32(verneed, aux + 8)?)?);
+            aux += read_u32(verneed, aux + 12)?;
+        }
+        let next = read_u32(verneed, offset + 12)?;
+        if next == 0 {
+            return Some(());
+        }
+        offset += next;
+    }
+}
+
+/// Version of each dynamic symbol, `None` for unversioned ones. Empty without `.gnu.version`.
+pub(crate) fn symbol_versions(versym: Option<&[u8]>, verdef: Option<&[u8]>, verneed: Option<&[u8]>, strings: &[u8]) -> Vec<Option<SymbolVersion>> {
+    let versym = match versym {
+        Some(versym) => versym,
+        None => return Vec::new(),
+    };
+    let mut names = HashMap::new();
+    if let Some(verdef) = verdef {
+        definitions(verdef, strings, &mut names);
+    }
+    if let Some(verneed) = verneed {
+        requirements(verneed, strings, &mut names);
+    }
+
+    versym.chunks_exact(2)
+        .map(|entry| {
+            let entry = u16::from_ne_bytes([entry[0], entry[1]]);
+            names.get(&(entry & !VERSYM_HIDDEN)).map(|name| SymbolVersion { name: name.clone(), hidden: entry & VERSYM_HIDDEN != 0 })
+        })
+        .collect()
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use crate::android_library::AndroidLibrary;
+    use crate::registry;
+    use crate::test_elf::TestElf;
+
+    /// mov eax, value; ret
+    fn returning(value: u8) -> [u8; 6] {
+        [0xb8, value, 0, 0, 0, 0xc3]
+    }
+
+    #[test]
+    fn versioned_resolution() {
+        let mut first = TestElf::new();
+        first.versioned_function("versioned_answer", &returning(1), "LIB_1", false);
+        let first = AndroidLibrary::load_from_bytes(first.build()).unwrap();
+        let mut second = TestElf::new();
+        second.versioned_function("versioned_answer", &returning(0), "LIB_0", true);
+        second.versioned_function("versioned_answer", &returning(2), "LIB_2", false);
+        let second = AndroidLibrary::load_from_bytes(second.build()).unwrap();
+        registry::make_global(first.registry_id);
+        registry::make_global(second.registry_id);
+
+        let answer = |version: Option<&str>| {
+            let mut elf = TestElf::new();
+            elf.thunk("call_answer", "versioned_answer");
+            if let Some(version) = version {
+                elf.versioned_import("versioned_answer", version);
+            }
+            let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+            let call_answer: extern "C" fn() -> u32 = unsafe { std::mem::transmute(library.get_symbol("call_answer").unwrap()) };
+            call_answer()
+        };
+
+        assert_eq!(answer(Some("LIB_2")), 2);
+        assert_eq!(answer(Some("LIB_1")), 1);
+        // Hidden versions are only found by asking for them
+        assert_eq!(answer(Some("LIB_0")), 0);
+        // Unversioned and unknown requirements get the first default definition
+        assert_eq!(answer(None), 1);
+        assert_eq!(answer(Some("LIB_9")), 1);
+    }
+}
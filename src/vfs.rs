@@ -0,0 +1,256 @@
+//! The virtual filesystem behind the built-in file stubs (`open`, `stat`, `access`, ...).
+//!
+//! Loaded libraries never touch the host's disk through the stubs: every path goes to the
+//! [`VirtualFs`] given to the [loader](crate::android_loader::AndroidLoader::virtual_fs) that
+//! loaded the calling library, which by default denies everything. Errors are Linux `errno`
+//! values (see [`crate::stubs::errno`]).
+
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::sync::Arc;
+
+use crate::registry;
+use crate::stubs::errno::{EACCES, EBADF, EINVAL, EISDIR, ENOENT, ENOTDIR, EROFS};
+
+/// Result of a filesystem operation, failing with an `errno` value
+pub type FsResult<T> = Result<T, i32>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VirtualMetadata {
+    pub size: u64,
+    pub directory: bool,
+    /// Whether it's a symbolic link, as only [`VirtualFs::lstat`] reports them
+    pub symlink: bool,
+    /// Permission bits, without the file type
+    pub permissions: u32,
+    /// Modification time in seconds since the Unix epoch, also reported as the access and
+    /// status change times
+    pub modified: i64,
+}
+
+impl VirtualMetadata {
+    pub fn file(size: u64) -> VirtualMetadata {
+        VirtualMetadata { size, directory: false, symlink: false, permissions: 0o644, modified: 0 }
+    }
+
+    pub fn directory() -> VirtualMetadata {
+        VirtualMetadata { size: 4096, directory: true, symlink: false, permissions: 0o755, modified: 0 }
+    }
+
+    /// A link to `target`, which is its size
+    pub fn symlink(target: &str) -> VirtualMetadata {
+        VirtualMetadata { size: target.len() as u64, directory: false, symlink: true, permissions: 0o777, modified: 0 }
+    }
+
+    pub fn modified(mut self, seconds: i64) -> VirtualMetadata {
+        self.modified = seconds;
+        self
+    }
+}
+
+/// An entry of a directory listing
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VirtualDirEntry {
+    pub name: String,
+    pub directory: bool,
+}
+
+/// A file opened through a [`VirtualFs`]
+pub trait VirtualFile: Send {
+    fn read(&mut self, buffer: &mut [u8]) -> FsResult<usize>;
+
+    fn write(&mut self, _buffer: &[u8]) -> FsResult<usize> {
+        Err(EBADF)
+    }
+
+    fn seek(&mut self, position: SeekFrom) -> FsResult<u64>;
+
+    fn metadata(&self) -> FsResult<VirtualMetadata>;
+}
+
+pub trait VirtualFs: Send + Sync {
+    /// Metadata of an absolute, normalized path
+    fn stat(&self, path: &str) -> FsResult<VirtualMetadata>;
+
+    /// Like [`stat`](Self::stat), but of a symbolic link itself rather than what it points to
+    fn lstat(&self, path: &str) -> FsResult<VirtualMetadata> {
+        self.stat(path)
+    }
+
+    /// Open an absolute, normalized path with the library's `open` flags
+    fn open(&self, path: &str, flags: i32) -> FsResult<Box<dyn VirtualFile>>;
+
+    /// Check `access` permissions (`R_OK`, `W_OK`, `X_OK` bits, or `F_OK`) for a path
+    fn access(&self, path: &str, _mode: i32) -> FsResult<()> {
+        self.stat(path).map(|_| ())
+    }
+
+    /// The entries of the directory at an absolute, normalized path, without `.` and `..`
+    fn read_dir(&self, path: &str) -> FsResult<Vec<VirtualDirEntry>> {
+        match self.stat(path)? {
+            metadata if metadata.directory => Ok(Vec::new()),
+            _ => Err(ENOTDIR),
+        }
+    }
+
+    /// Resolve an absolute, normalized path to its canonical form, following any links
+    fn realpath(&self, path: &str) -> FsResult<String> {
+        self.stat(path).map(|_| path.to_owned())
+    }
+
+    /// Working directory relative paths are resolved against
+    fn current_dir(&self) -> String {
+        "/".to_owned()
+    }
+}
+
+/// The default filesystem: every access fails with `EACCES`
+pub struct DenyAllFs;
+
+impl VirtualFs for DenyAllFs {
+    fn stat(&self, _path: &str) -> FsResult<VirtualMetadata> {
+        Err(EACCES)
+    }
+
+    fn open(&self, _path: &str, _flags: i32) -> FsResult<Box<dyn VirtualFile>> {
+        Err(EACCES)
+    }
+}
+
+/// A read-only filesystem of in-memory files, with directories implied by the file paths
+#[derive(Default)]
+pub struct MemoryFs {
+    files: HashMap<String, Arc<Vec<u8>>>,
+    current_dir: Option<String>,
+    modified: i64,
+}
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs::default()
+    }
+
+    /// Add a file at an absolute path
+    pub fn file(mut self, path: &str, contents: impl Into<Vec<u8>>) -> MemoryFs {
+        self.files.insert(normalize("/", path), Arc::new(contents.into()));
+        self
+    }
+
+    /// The modification time every file and directory reports, in seconds since the Unix epoch
+    pub fn modified(mut self, seconds: i64) -> MemoryFs {
+        self.modified = seconds;
+        self
+    }
+
+    pub fn current_dir(mut self, path: &str) -> MemoryFs {
+        self.current_dir = Some(normalize("/", path));
+        self
+    }
+
+    fn is_directory(&self, path: &str) -> bool {
+        path == "/" || self.files.keys().any(|file| file.len() > path.len() && file.starts_with(path) && file.as_bytes()[path.len()] == b'/')
+    }
+}
+
+impl VirtualFs for MemoryFs {
+    fn stat(&self, path: &str) -> FsResult<VirtualMetadata> {
+        match self.files.get(path) {
+            Some(contents) => Ok(VirtualMetadata::file(contents.len() as u64).modified(self.modified)),
+            None if self.is_directory(path) => Ok(VirtualMetadata::directory().modified(self.modified)),
+            None => Err(ENOENT),
+        }
+    }
+
+    fn open(&self, path: &str, flags: i32) -> FsResult<Box<dyn VirtualFile>> {
+        const O_ACCMODE: i32 = 3;
+        const O_CREAT: i32 = 0o100;
+        if flags & O_ACCMODE != 0 || flags & O_CREAT != 0 {
+            return Err(EROFS);
+        }
+        match self.files.get(path) {
+            Some(contents) => Ok(Box::new(MemoryFile { contents: contents.clone(), position: 0, modified: self.modified })),
+            None if self.is_directory(path) => Err(EISDIR),
+            None => Err(ENOENT),
+        }
+    }
+
+    fn read_dir(&self, path: &str) -> FsResult<Vec<VirtualDirEntry>> {
+        if self.files.contains_key(path) {
+            return Err(ENOTDIR);
+        }
+        if !self.is_directory(path) {
+            return Err(ENOENT);
+        }
+        let prefix = if path == "/" { "/".to_owned() } else { format!("{path}/") };
+        let mut entries: Vec<VirtualDirEntry> = self.files.keys()
+            .filter_map(|file| file.strip_prefix(&prefix))
+            .map(|rest| match rest.split_once('/') {
+                Some((directory, _)) => VirtualDirEntry { name: directory.to_owned(), directory: true },
+                None => VirtualDirEntry { name: rest.to_owned(), directory: false },
+            })
+            .collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        entries.dedup();
+        Ok(entries)
+    }
+
+    fn current_dir(&self) -> String {
+        self.current_dir.clone().unwrap_or_else(|| "/".to_owned())
+    }
+}
+
+struct MemoryFile {
+    contents: Arc<Vec<u8>>,
+    position: u64,
+    modified: i64,
+}
+
+impl VirtualFile for MemoryFile {
+    fn read(&mut self, buffer: &mut [u8]) -> FsResult<usize> {
+        let start = (self.position as usize).min(self.contents.len());
+        let len = buffer.len().min(self.contents.len() - start);
+        buffer[..len].copy_from_slice(&self.contents[start..start + len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn seek(&mut self, position: SeekFrom) -> FsResult<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => offset_position(self.position, offset),
+            SeekFrom::End(offset) => offset_position(self.contents.len() as u64, offset),
+        };
+        self.position = position.ok_or(EINVAL)?;
+        Ok(self.position)
+    }
+
+    fn metadata(&self) -> FsResult<VirtualMetadata> {
+        Ok(VirtualMetadata::file(self.contents.len() as u64).modified(self.modified))
+    }
+}
+
+fn offset_position(base: u64, offset: i64) -> Option<u64> {
+    if offset < 0 { base.checked_sub(offset.unsigned_abs()) } else { base.checked_add(offset as u64) }
+}
+
+/// The filesystem of the library containing `caller`, [`DenyAllFs`] for libraries loaded
+/// without one and callers outside every library
+pub(crate) fn virtual_fs(caller: usize) -> Arc<dyn VirtualFs> {
+    registry::virtual_fs(caller).unwrap_or_else(|| Arc::new(DenyAllFs))
+}
+
+/// Make `path` absolute against `current_dir` and resolve `.` and `..` components
+pub(crate) fn normalize(current_dir: &str, path: &str) -> String {
+    let mut components: Vec<&str> = Vec::new();
+    let base = if path.starts_with('/') { "" } else { current_dir };
+    for component in base.split('/').chain(path.split('/')) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    format!("/{}", components.join("/"))
+}
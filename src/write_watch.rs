@@ -0,0 +1,250 @@
+//! Watching ranges of a loaded image for writes, e.g. to find the code behind an integrity
+//! check (Linux on x86_64).
+//!
+//! The pages of a watched range are made read-only. A write to one faults, and the `SIGSEGV`
+//! handler installed with the first watch makes the page writable again and single-steps the
+//! writing instruction with the trap flag, after which the `SIGTRAP` handler protects it again.
+//! The callback of a watch covering the written address is called from the `SIGSEGV` handler,
+//! before the write happens, so it should be quick and mustn't write to watched pages itself.
+//!
+//! Faults and traps that aren't the watches' go to the handlers installed before. While a
+//! write is being stepped its page is writable for every thread, so writes other threads make
+//! in that window aren't seen.
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use region::Protection;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::os::raw::{c_int, c_void};
+use std::sync::{Arc, Mutex, Once};
+
+use crate::protections::{self, ProtectionSource};
+
+/// The trap flag of `EFLAGS`, raising `SIGTRAP` after the next instruction
+const TRAP_FLAG: i64 = 0x100;
+
+/// A write to a watched range, about to happen
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchedWrite {
+    /// The address written to
+    pub address: usize,
+    /// The address of the writing instruction
+    pub pc: usize,
+}
+
+/// Callback for [`AndroidLibrary::watch_writes`](crate::android_library::AndroidLibrary::watch_writes)
+pub type WriteCallback = dyn Fn(&WatchedWrite) + Send + Sync;
+
+struct Watch {
+    id: usize,
+    range: Range<usize>,
+    callback: Arc<WriteCallback>,
+}
+
+/// A watched page, as the protection it had and how many watches cover it
+struct WatchedPage {
+    protection: Protection,
+    watches: usize,
+}
+
+#[derive(Default)]
+struct Watches {
+    watches: Vec<Watch>,
+    pages: HashMap<usize, WatchedPage>,
+    next_id: usize,
+}
+
+lazy_static! {
+    static ref WATCHES: Mutex<Watches> = Mutex::new(Watches::default());
+    /// The `SIGSEGV` and `SIGTRAP` handlers installed before the watches'
+    static ref PREVIOUS: Mutex<Vec<(c_int, libc::sigaction)>> = Mutex::new(Vec::new());
+}
+
+thread_local! {
+    /// The page made writable for the instruction being stepped on this thread, if any
+    static STEPPING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// A watch on a range, removed when dropped
+pub struct WriteWatch {
+    id: usize,
+}
+
+impl Drop for WriteWatch {
+    fn drop(&mut self) {
+        let mut watches = WATCHES.lock().unwrap();
+        let range = match watches.watches.iter().position(|watch| watch.id == self.id) {
+            Some(index) => watches.watches.remove(index).range,
+            None => return,
+        };
+        for page in pages(&range) {
+            let restore = match watches.pages.get_mut(&page) {
+                Some(watched) => {
+                    watched.watches -= 1;
+                    watched.watches == 0
+                }
+                None => false,
+            };
+            if restore {
+                let watched = watches.pages.remove(&page).unwrap();
+                // The library may have been dropped since
+                let _ = unsafe { protections::protect(page as *const u8, region::page::size(), watched.protection, ProtectionSource::Loader) };
+            }
+        }
+    }
+}
+
+/// The addresses of the pages `range` touches
+fn pages(range: &Range<usize>) -> impl Iterator<Item = usize> {
+    let page = region::page::size();
+    let start = range.start / page * page;
+    (start..range.end).step_by(page)
+}
+
+/// Watch `range`, which mustn't be empty, calling `callback` before each write to it
+pub(crate) fn watch(range: Range<usize>, callback: Arc<WriteCallback>) -> Result<WriteWatch> {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| unsafe {
+        install(libc::SIGSEGV, segv_handler as *const () as usize);
+        install(libc::SIGTRAP, trap_handler as *const () as usize);
+    });
+
+    let mut watches = WATCHES.lock().unwrap();
+    for page in pages(&range) {
+        if let Some(watched) = watches.pages.get_mut(&page) {
+            watched.watches += 1;
+            continue;
+        }
+        let protection = region::query(page as *const u8)?.protection();
+        unsafe { protections::protect(page as *const u8, region::page::size(), protection - Protection::WRITE, ProtectionSource::Loader)? };
+        watches.pages.insert(page, WatchedPage { protection, watches: 1 });
+    }
+    watches.next_id += 1;
+    let id = watches.next_id;
+    watches.watches.push(Watch { id, range, callback });
+    Ok(WriteWatch { id })
+}
+
+unsafe fn install(signal: c_int, handler: usize) {
+    let mut action: libc::sigaction = std::mem::zeroed();
+    action.sa_sigaction = handler;
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_NODEFER;
+    libc::sigemptyset(&mut action.sa_mask);
+    let mut previous: libc::sigaction = std::mem::zeroed();
+    if libc::sigaction(signal, &action, &mut previous) == 0 {
+        PREVIOUS.lock().unwrap().push((signal, previous));
+    }
+}
+
+/// Hand a signal that isn't the watches' to the handler installed before them
+unsafe fn chain(signal: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+    let previous = PREVIOUS.lock().unwrap().iter().find(|(installed, _)| *installed == signal).map(|(_, previous)| *previous);
+    match previous {
+        Some(previous) if previous.sa_sigaction == libc::SIG_IGN => {}
+        Some(previous) if previous.sa_sigaction != libc::SIG_DFL && previous.sa_flags & libc::SA_SIGINFO != 0 => {
+            let handler: extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) = std::mem::transmute(previous.sa_sigaction);
+            handler(signal, info, context);
+        }
+        Some(previous) if previous.sa_sigaction != libc::SIG_DFL => {
+            let handler: extern "C" fn(c_int) = std::mem::transmute(previous.sa_sigaction);
+            handler(signal);
+        }
+        // The faulting instruction runs again once this returns, and gets the default action
+        _ => {
+            libc::signal(signal, libc::SIG_DFL);
+        }
+    }
+}
+
+extern "C" fn segv_handler(signal: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+    unsafe {
+        let address = (*info).si_addr() as usize;
+        let page = address / region::page::size() * region::page::size();
+        let watches = WATCHES.lock().unwrap();
+        let protection = match watches.pages.get(&page) {
+            Some(watched) => watched.protection,
+            None => {
+                drop(watches);
+                return chain(signal, info, context);
+            }
+        };
+        let context = &mut *(context as *mut libc::ucontext_t);
+        let write = WatchedWrite { address, pc: context.uc_mcontext.gregs[libc::REG_RIP as usize] as usize };
+        let callbacks: Vec<Arc<WriteCallback>> = watches.watches.iter()
+            .filter(|watch| watch.range.contains(&address))
+            .map(|watch| watch.callback.clone())
+            .collect();
+        drop(watches);
+        for callback in callbacks {
+            callback(&write);
+        }
+        // It would have faulted anyway
+        if !protection.contains(Protection::WRITE) {
+            return chain(signal, info, context as *mut libc::ucontext_t as *mut c_void);
+        }
+
+        // Let the write through, and protect the page again after it
+        if region::protect(page as *const u8, region::page::size(), protection).is_ok() {
+            STEPPING.with(|stepping| stepping.set(page));
+            context.uc_mcontext.gregs[libc::REG_EFL as usize] |= TRAP_FLAG;
+        }
+    }
+}
+
+extern "C" fn trap_handler(signal: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+    let page = STEPPING.with(|stepping| stepping.replace(0));
+    if page == 0 {
+        return unsafe { chain(signal, info, context) };
+    }
+    unsafe {
+        let context = &mut *(context as *mut libc::ucontext_t);
+        context.uc_mcontext.gregs[libc::REG_EFL as usize] &= !TRAP_FLAG;
+        // Unless the last watch on it went away during the write
+        if let Some(watched) = WATCHES.lock().unwrap().pages.get(&page) {
+            let _ = region::protect(page as *const u8, region::page::size(), watched.protection - Protection::WRITE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::android_library::{AndroidLibrary, AndroidLoaderErr};
+    use crate::test_elf::TestElf;
+    use crate::write_watch::WatchedWrite;
+
+    #[test]
+    fn watched_writes() {
+        let mut elf = TestElf::new();
+        elf.function("watched_store", &[0x89, 0x37, 0xc3]); // mov [rdi], esi; ret
+        elf.object("watched_counter", &[0; 8]);
+        let library = AndroidLibrary::load_from_bytes(elf.build()).unwrap();
+        let store = library.get_symbol("watched_store").unwrap();
+        let store_fn: extern "C" fn(*mut u32, u32) = unsafe { std::mem::transmute(store) };
+        let counter = library.get_symbol("watched_counter").unwrap() as *mut u32;
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let watch = library
+            .watch_writes(counter as usize..counter as usize + 4, move |write| recorded.lock().unwrap().push(*write))
+            .unwrap();
+        store_fn(counter, 7);
+        // Outside the range but on its page: stepped through without a callback
+        store_fn(unsafe { counter.add(1) }, 9);
+        store_fn(counter, 11);
+        assert_eq!(unsafe { (*counter, *counter.add(1)) }, (11, 9));
+        let expected = WatchedWrite { address: counter as usize, pc: store as usize };
+        assert_eq!(*seen.lock().unwrap(), [expected, expected]);
+
+        drop(watch);
+        store_fn(counter, 13);
+        assert_eq!(unsafe { *counter }, 13);
+        assert_eq!(seen.lock().unwrap().len(), 2);
+
+        let err = library.watch_writes(0..4, |_| {}).err().unwrap();
+        assert!(matches!(err.downcast_ref::<AndroidLoaderErr>(), Some(AndroidLoaderErr::OutsideImage { .. })));
+    }
+}
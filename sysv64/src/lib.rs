@@ -1,21 +1,26 @@
 extern crate proc_macro;
-use proc_macro::TokenStream;
+use proc_macro::{TokenStream, TokenTree};
 
 #[proc_macro_attribute]
 pub fn sysv64(_args: TokenStream, input: TokenStream) -> TokenStream {
     let mut output = String::new();
-    let input = input.to_string();
 
-    let input_splitted: Vec<&str> = input.split("fn").collect();
+    // Split on the `fn` keyword itself, so names, docs and bodies may contain "fn"
+    let tokens: Vec<TokenTree> = input.into_iter().collect();
+    let fn_index = tokens.iter()
+        .position(|token| matches!(token, TokenTree::Ident(ident) if ident.to_string() == "fn"))
+        .expect("#[sysv64] can only be applied to functions");
+    let head = tokens[..fn_index].iter().cloned().collect::<TokenStream>().to_string();
+    let tail = tokens[fn_index + 1..].iter().cloned().collect::<TokenStream>().to_string();
 
     output.push_str("#[cfg(target_arch = \"x86_64\")]\n");
-    output.push_str(&input_splitted[0]);
-    output.push_str("extern \"sysv64\" fn");
-    output.push_str(&input_splitted[1]);
+    output.push_str(&head);
+    output.push_str(" extern \"sysv64\" fn ");
+    output.push_str(&tail);
     output.push_str("#[cfg(not(target_arch = \"x86_64\"))]\n");
-    output.push_str(&input_splitted[0]);
-    output.push_str("extern \"C\" fn");
-    output.push_str(&input_splitted[1]);
+    output.push_str(&head);
+    output.push_str(" extern \"C\" fn ");
+    output.push_str(&tail);
 
     output.parse().unwrap()
 }